@@ -0,0 +1,505 @@
+use crate::context::Priority;
+use hyper::{Body, Response, StatusCode};
+use log::{debug, error};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// Requests waiting for a slot once the limit is reached, all priorities included. Any more are shed
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+/// Queued requests are shed after this delay
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_millis(100);
+pub const DEFAULT_INITIAL_LIMIT: usize = 20;
+pub const DEFAULT_MAX_LIMIT: usize = 1000;
+/// Number of samples the long-term latency of `LimitAlgorithm::Gradient` is averaged on
+const LONG_TERM_SAMPLES: f64 = 500.0;
+
+/// How the limit of in-flight requests follows upstream latency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitAlgorithm {
+    /// Additive increase, multiplicative decrease: +1 per response as long as the limit is in use and latency stays under `latency_threshold`,
+    /// multiplied by `backoff` (i.e. 0.9) on slower responses or overload
+    Aimd { latency_threshold: Duration, backoff: f64 },
+    /// Latency of every response compared to the long-term average: the limit grows while they match, and shrinks as latency rises over `tolerance` times the average
+    /// `smoothing` (between 0 and 1) sets how fast the limit moves
+    Gradient { tolerance: f64, smoothing: f64 },
+}
+
+/// How a request ended, as a signal of upstream capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success(Duration),  // latency, until the response headers
+    Overload,           // timeout, connection failure, or upstream refusing the request (429, 502, 503, 504)
+}
+
+impl Outcome {
+
+    pub fn of(resp: &Response<Body>, latency: Duration) -> Self {
+        match resp.status() {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => Outcome::Overload,
+            _ => Outcome::Success(latency),
+        }
+    }
+}
+
+/// Caps the requests in flight to an Api, adapting the cap to the latency of the upstream: protects it under overload, without a fixed rate to tune
+/// Requests over the limit wait in a queue, briefly, and are shed with a 503 when it's full or they've waited for too long
+/// Queued requests get slots by `Priority`, then in order. A full queue makes room for a request by shedding the last one queued with a lower priority
+/// A request is in flight until its response headers are received, streamed bodies aren't limited
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    pub algorithm: LimitAlgorithm,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    pub queue_size: usize,
+    pub queue_timeout: Duration,
+    pub priority_queues: BTreeMap<Priority, PriorityQueue>,
+    state: Mutex<LimiterState>,
+}
+
+/// Depth and timeout of the queue of a priority, `ConcurrencyLimiter::queue_size` and `queue_timeout` otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityQueue {
+    pub size: usize,
+    pub timeout: Duration,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    limit: f64,
+    in_flight: usize,
+    handed_over: usize, // slots released to queued requests that haven't claimed them yet
+    queue: BTreeMap<Priority, VecDeque<oneshot::Sender<()>>>,
+    long_term_latency: Option<f64>, // seconds
+}
+
+/// A slot in flight, released when dropped. Report how the request went with `record`
+#[derive(Debug)]
+pub struct Permit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    started: Instant,
+}
+
+/// A queued request, waiting for a slot to be handed over
+/// If it goes away (i.e. the client does) after being handed one, the slot is passed on rather than leaked
+#[derive(Debug)]
+struct Waiter<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    slot: oneshot::Receiver<()>,
+}
+
+impl ConcurrencyLimiter {
+
+    pub fn new(algorithm: LimitAlgorithm) -> Self {
+        ConcurrencyLimiter {
+            algorithm,
+            min_limit: 1,
+            max_limit: DEFAULT_MAX_LIMIT,
+            queue_size: DEFAULT_QUEUE_SIZE,
+            queue_timeout: DEFAULT_QUEUE_TIMEOUT,
+            priority_queues: BTreeMap::new(),
+            state: Mutex::new(LimiterState { limit: DEFAULT_INITIAL_LIMIT as f64, in_flight: 0, handed_over: 0, queue: BTreeMap::new(), long_term_latency: None }),
+        }
+    }
+
+    /// The limit starts at `initial`, then moves between `min` and `max`
+    pub fn with_limits(&mut self, initial: usize, min: usize, max: usize) {
+        self.min_limit = min.max(1);
+        self.max_limit = max.max(self.min_limit);
+        if let Ok(mut state) = self.state.lock() {
+            state.limit = initial.clamp(self.min_limit, self.max_limit) as f64;
+        }
+    }
+
+    /// At most `size` requests wait for a slot, for up to `timeout`
+    pub fn with_queue(&mut self, size: usize, timeout: Duration) {
+        self.queue_size = size;
+        self.queue_timeout = timeout;
+    }
+
+    /// At most `size` requests of `priority` wait for a slot (within the overall queue size), for up to `timeout`
+    pub fn with_priority_queue(&mut self, priority: Priority, size: usize, timeout: Duration) {
+        self.priority_queues.insert(priority, PriorityQueue { size, timeout });
+    }
+
+    fn priority_queue(&self, priority: Priority) -> PriorityQueue {
+        self.priority_queues.get(&priority).copied().unwrap_or(PriorityQueue { size: self.queue_size, timeout: self.queue_timeout })
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().map(|state| state.limit as usize).unwrap_or(0)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().map(|state| state.in_flight).unwrap_or(0)
+    }
+
+    /// Waits for a slot, `None` if the request is shed
+    pub async fn acquire(&self, priority: Priority) -> Option<Permit<'_>> {
+        let queue = self.priority_queue(priority);
+        let mut waiting = {
+            let mut state = match self.state.lock() {
+                Ok(state) => state,
+                Err(e) => {
+                    error!("Concurrency limiter poisoned {:?}", e);
+                    return None
+                },
+            };
+            if state.in_flight + state.handed_over < state.limit as usize {
+                state.in_flight += 1;
+                return Some(Permit { limiter: self, started: Instant::now() })
+            }
+            // forget requests that stopped waiting
+            state.queue.retain(|_, waiters| {
+                waiters.retain(|waiter| !waiter.is_closed());
+                !waiters.is_empty()
+            });
+            let queued = state.queue.get(&priority).map_or(0, VecDeque::len);
+            let total: usize = state.queue.values().map(VecDeque::len).sum();
+            if queued >= queue.size || (total >= self.queue_size && !state.shed_below(priority)) {
+                debug!("Shedding request of priority {}, {} in flight and {} queued", priority.0, state.in_flight, total);
+                return None
+            }
+            let (tx, rx) = oneshot::channel();
+            state.queue.entry(priority).or_default().push_back(tx);
+            Waiter { limiter: self, slot: rx }
+        };
+        // slots are handed over by released permits
+        let handed_over = match tokio::time::timeout(queue.timeout, &mut waiting.slot).await {
+            Ok(received) => received.is_ok(),
+            Err(_) => {
+                waiting.slot.close();
+                waiting.slot.try_recv().is_ok() // handed over just in time
+            },
+        };
+        if handed_over {
+            Some(self.claim())
+        } else {
+            debug!("Shedding request of priority {}, queued for {:?} at most", priority.0, queue.timeout);
+            None
+        }
+    }
+
+    /// Takes a slot handed over to a queued request
+    fn claim(&self) -> Permit<'_> {
+        if let Ok(mut state) = self.state.lock() {
+            state.handed_over -= 1;
+            state.in_flight += 1;
+        }
+        Permit { limiter: self, started: Instant::now() }
+    }
+
+    fn adapt(&self, state: &mut LimiterState, outcome: Outcome) {
+        let limit = state.limit;
+        // a limit that isn't even half used says nothing about upstream capacity
+        let in_use = state.in_flight as f64 * 2.0 >= limit;
+        let next = match (self.algorithm, outcome) {
+            (LimitAlgorithm::Aimd { backoff, .. }, Outcome::Overload) => limit * backoff,
+            (LimitAlgorithm::Aimd { latency_threshold, backoff }, Outcome::Success(latency)) =>
+                if latency > latency_threshold {
+                    limit * backoff
+                } else if in_use {
+                    limit + 1.0
+                } else {
+                    limit
+                },
+            (LimitAlgorithm::Gradient { smoothing, .. }, Outcome::Overload) => limit * (1.0 - smoothing / 2.0),
+            (LimitAlgorithm::Gradient { tolerance, smoothing }, Outcome::Success(latency)) => {
+                let latency = latency.as_secs_f64().max(1e-6);
+                let long_term = state.long_term_latency.map_or(latency, |average| average + (latency - average) / LONG_TERM_SAMPLES);
+                // latency can stay higher after an overload: the average must eventually forget the latency before it
+                state.long_term_latency = Some(if long_term > 2.0 * latency { long_term * 0.95 } else { long_term });
+                let gradient = (tolerance * long_term / latency).clamp(0.5, 1.0);
+                if gradient >= 1.0 && !in_use {
+                    limit
+                } else {
+                    let target = limit * gradient + limit.sqrt(); // room for a few queued requests
+                    limit * (1.0 - smoothing) + target * smoothing
+                }
+            },
+        };
+        state.limit = next.clamp(self.min_limit as f64, self.max_limit as f64);
+    }
+
+    fn release(&self, outcome: Option<Outcome>) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => return error!("Concurrency limiter poisoned {:?}", e),
+        };
+        if let Some(outcome) = outcome {
+            self.adapt(&mut state, outcome);
+        }
+        state.in_flight -= 1;
+        state.hand_over();
+    }
+
+    /// Passes on a slot handed over to a request that went away
+    fn give_back(&self) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => return error!("Concurrency limiter poisoned {:?}", e),
+        };
+        state.handed_over -= 1;
+        state.hand_over();
+    }
+}
+
+impl LimiterState {
+
+    /// Hands free slots over to queued requests
+    fn hand_over(&mut self) {
+        while self.in_flight + self.handed_over < self.limit as usize {
+            match self.next_waiter() {
+                Some(waiter) => if waiter.send(()).is_ok() {
+                    self.handed_over += 1;
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Oldest request of the highest priority
+    fn next_waiter(&mut self) -> Option<oneshot::Sender<()>> {
+        let mut highest = self.queue.last_entry()?;
+        let waiter = highest.get_mut().pop_front();
+        if highest.get().is_empty() {
+            highest.remove();
+        }
+        waiter
+    }
+
+    /// Sheds the last request queued with a priority lower than `priority`, if any
+    fn shed_below(&mut self, priority: Priority) -> bool {
+        let mut lowest = match self.queue.first_entry() {
+            Some(lowest) if *lowest.key() < priority => lowest,
+            _ => return false,
+        };
+        lowest.get_mut().pop_back(); // dropping the sender wakes the request up
+        if lowest.get().is_empty() {
+            lowest.remove();
+        }
+        true
+    }
+}
+
+impl<'a> Permit<'a> {
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Releases the slot, adapting the limit to `outcome`
+    pub fn record(self, outcome: Outcome) {
+        self.limiter.release(Some(outcome));
+        std::mem::forget(self);
+    }
+}
+
+/// Requests dropped before their response (i.e. the client went away) don't tell anything about the upstream
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.limiter.release(None);
+    }
+}
+
+impl<'a> Drop for Waiter<'a> {
+    fn drop(&mut self) {
+        self.slot.close();
+        if self.slot.try_recv().is_ok() {
+            self.limiter.give_back();
+        }
+    }
+}
+
+/// 503, for shed requests
+pub fn service_unavailable() -> Response<Body> {
+    Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::concurrency::{ConcurrencyLimiter, LimitAlgorithm, Outcome};
+    use crate::conf::api::Api;
+    use crate::context::Priority;
+    use crate::gateway::start_local_gateway;
+    use crate::tests::{wait_for_gateway, wait_for_port};
+    use hyper::{Body, Client, Response, Server, StatusCode, Uri};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    const NORMAL: Priority = Priority(0);
+
+    fn aimd() -> ConcurrencyLimiter {
+        let mut limiter = ConcurrencyLimiter::new(LimitAlgorithm::Aimd { latency_threshold: Duration::from_millis(100), backoff: 0.5 });
+        limiter.with_limits(4, 2, 10);
+        limiter
+    }
+
+    #[tokio::test]
+    async fn aimd_follows_latency() {
+        let limiter = aimd();
+        let fast = Outcome::Success(Duration::from_millis(10));
+        limiter.acquire(NORMAL).await.unwrap().record(fast);
+        assert_eq!(4, limiter.limit()); // not in use
+        let permits = vec![limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap()];
+        for permit in permits {
+            permit.record(fast);
+        }
+        assert_eq!(6, limiter.limit());
+        limiter.acquire(NORMAL).await.unwrap().record(Outcome::Success(Duration::from_millis(500)));
+        assert_eq!(3, limiter.limit());
+        limiter.acquire(NORMAL).await.unwrap().record(Outcome::Overload);
+        assert_eq!(2, limiter.limit()); // min
+        assert_eq!(0, limiter.in_flight());
+    }
+
+    #[tokio::test]
+    async fn gradient_follows_latency() {
+        let mut limiter = ConcurrencyLimiter::new(LimitAlgorithm::Gradient { tolerance: 1.5, smoothing: 0.2 });
+        limiter.with_limits(10, 1, 100);
+        let mut in_flight = Vec::new();
+        for _ in 0..20 {
+            in_flight.push(limiter.acquire(NORMAL).await.unwrap());
+            if in_flight.len() > 8 {
+                in_flight.remove(0).record(Outcome::Success(Duration::from_millis(10)));
+            }
+        }
+        let grown = limiter.limit();
+        assert!(grown > 10);
+        drop(in_flight);
+        for _ in 0..20 {
+            limiter.acquire(NORMAL).await.unwrap().record(Outcome::Success(Duration::from_millis(100)));
+        }
+        assert!(limiter.limit() < grown / 2);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_get_released_slots() {
+        let mut limiter = aimd();
+        limiter.with_limits(2, 2, 2);
+        limiter.with_queue(1, Duration::from_secs(5));
+        let first = limiter.acquire(NORMAL).await.unwrap();
+        let _second = limiter.acquire(NORMAL).await.unwrap();
+        let (queued, shed) = tokio::join!(limiter.acquire(NORMAL), async {
+            let shed = limiter.acquire(NORMAL).await.is_none(); // the queue is full
+            first.record(Outcome::Success(Duration::from_millis(1)));
+            shed
+        });
+        assert!(queued.is_some());
+        assert!(shed);
+        assert_eq!(2, limiter.in_flight());
+    }
+
+    #[tokio::test]
+    async fn queued_requests_time_out() {
+        let mut limiter = aimd();
+        limiter.with_queue(10, Duration::from_millis(20));
+        let _permits = [limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap(), limiter.acquire(NORMAL).await.unwrap()];
+        assert!(limiter.acquire(NORMAL).await.is_none());
+        assert_eq!(4, limiter.in_flight());
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_go_first() {
+        let mut limiter = aimd();
+        limiter.with_limits(1, 1, 1);
+        limiter.with_queue(10, Duration::from_secs(5));
+        let first = limiter.acquire(NORMAL).await.unwrap();
+        let served = Mutex::new(vec![]);
+        let serve = |priority: i32| {
+            let (limiter, served) = (&limiter, &served);
+            async move {
+                let permit = limiter.acquire(Priority(priority)).await;
+                served.lock().unwrap().push(priority);
+                drop(permit);
+            }
+        };
+        tokio::join!(serve(-1), serve(5), serve(0), async { drop(first) });
+        assert_eq!(vec![5, 0, -1], *served.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn lower_priorities_are_shed_first() {
+        let mut limiter = aimd();
+        limiter.with_limits(1, 1, 1);
+        limiter.with_queue(2, Duration::from_secs(5));
+        let first = limiter.acquire(NORMAL).await.unwrap();
+        let low = || async { limiter.acquire(Priority(-1)).await.is_some() };
+        let (oldest, latest, high, _) = tokio::join!(low(), low(), async { limiter.acquire(Priority(1)).await.is_some() }, async { drop(first) });
+        assert_eq!((true, false, true), (oldest, latest, high));
+    }
+
+    #[tokio::test]
+    async fn priorities_have_their_own_queue() {
+        let mut limiter = aimd();
+        limiter.with_limits(1, 1, 1);
+        limiter.with_priority_queue(Priority(-1), 1, Duration::from_secs(5));
+        limiter.with_priority_queue(Priority(1), 1, Duration::from_millis(10));
+        let _first = limiter.acquire(NORMAL).await.unwrap();
+        let (queued, full, timed_out) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(50), limiter.acquire(Priority(-1))),
+            limiter.acquire(Priority(-1)),
+            limiter.acquire(Priority(1)),
+        );
+        assert!(queued.is_err()); // still waiting
+        assert!(full.is_none());
+        assert!(timed_out.is_none());
+    }
+
+    #[tokio::test]
+    async fn slots_of_requests_gone_are_passed_on() {
+        let mut limiter = aimd();
+        limiter.with_limits(1, 1, 1);
+        limiter.with_queue(10, Duration::from_secs(5));
+        let first = limiter.acquire(NORMAL).await.unwrap();
+        let mut gone = Box::pin(limiter.acquire(NORMAL));
+        assert!(tokio::time::timeout(Duration::from_millis(10), &mut gone).await.is_err()); // queued
+        drop(first); // hands the slot over, the request isn't polled again
+        drop(gone);
+        assert_eq!(0, limiter.in_flight());
+        let next = tokio::time::timeout(Duration::from_millis(50), limiter.acquire(NORMAL)).await.unwrap();
+        assert!(next.is_some());
+        assert_eq!(1, limiter.in_flight());
+    }
+
+    async fn slow_server(port: u16, delay: Duration) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |_req| async move {
+                tokio::time::sleep(delay).await;
+                Ok::<_, Infallible>(Response::new(Body::from("done")))
+            }))
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn overload_is_shed() {
+        let gw_port = 13400;
+        let backend_port = 13401;
+        tokio::spawn(async move { slow_server(backend_port, Duration::from_millis(200)).await });
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, "/slow".to_string()).unwrap();
+            let mut limiter = aimd();
+            limiter.with_limits(2, 2, 2);
+            limiter.with_queue(1, Duration::from_secs(5));
+            api.limit_concurrency(limiter);
+            start_local_gateway(gw_port, vec![api]).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        let client = Client::new();
+        let url = Uri::from_str(&format!("http://127.0.0.1:{}/slow", gw_port)).unwrap();
+        let responses = futures::future::join_all((0..4).map(|_| client.get(url.clone()))).await;
+        let mut statuses = responses.into_iter().map(|resp| resp.unwrap().status()).collect::<Vec<_>>();
+        statuses.sort();
+        assert_eq!(vec![StatusCode::OK, StatusCode::OK, StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE], statuses);
+    }
+}
@@ -1,9 +1,21 @@
 use crate::conf::endpoint::{HttpEndpoint};
+use crate::concurrency::{self, ConcurrencyLimiter, Outcome};
 use std::string::ParseError;
-use hyper::{Request, Body, Response, Error};
-use crate::handlers::{HandlerResponse, GlobalHandler, ResponseFinalizer, ScopedHandler, ScopedHandlerFactory};
-use crate::conf::endpoint::HttpEndpoint::{Plain, Ssl};
-use futures::{FutureExt, TryFutureExt};
+use hyper::{Request, Body, Response, StatusCode};
+use crate::handlers::{HandlerResponse, GlobalHandler, RequestGuard, RequestTransformer, ResponseFinalizer, ScopedHandler, ScopedHandlerFactory};
+use futures::StreamExt;
+use log::error;
+use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+use crate::conf::streaming::StreamingConfig;
+use crate::context::{ClientRequest, CorrelationId, Priority, ResponseHeaders, Streaming, UpstreamRequest};
+use crate::conf::tls::UpstreamTlsConfig;
+use crate::tls::TlsError;
+use crate::proxy_protocol::ProxyProtocolVersion;
+use crate::websocket::PendingUpgrade;
+use std::time::Duration;
+use tokio::time::Instant;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(Debug)]
 pub struct Api {
@@ -11,29 +23,67 @@ pub struct Api {
     pub endpoints: Vec<HttpEndpoint>,
     pub global_handlers: Vec<Box<dyn GlobalHandler>>,
     pub finalizer: Option<Box<dyn ResponseFinalizer>>,
-    pub scoped_handlers: Vec<Box<dyn ScopedHandlerFactory>>
+    pub request_transformer: Option<Box<dyn RequestTransformer>>,
+    pub guards: Vec<Box<dyn RequestGuard>>,
+    pub scoped_handlers: Vec<Box<dyn ScopedHandlerFactory>>,
+    pub websocket_idle_timeout: Duration,
+    pub timeout: Option<Duration>,
+    pub streaming: StreamingConfig,
+    pub concurrency: Option<ConcurrencyLimiter>,
+    pub strip_prefix: bool, // the prefix is removed from the path sent upstream
 }
 
+/// Proxied WebSocket connections are closed after this delay without any traffic
+pub const DEFAULT_WEBSOCKET_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
 impl Api  {
 
-    pub fn http(host: &str, port: u16, prefix: String) -> Result<Self, ParseError> {
-        Ok(Api {
+    /// Settings shared by every constructor, without any endpoint
+    fn defaults(prefix: String) -> Self {
+        Api {
             prefix,
-            endpoints: vec![HttpEndpoint::http(host, port)?],
+            endpoints: vec![],
             global_handlers: vec![],
             finalizer: None,
-            scoped_handlers: vec![]
-        })
+            request_transformer: None,
+            guards: vec![],
+            scoped_handlers: vec![],
+            websocket_idle_timeout: DEFAULT_WEBSOCKET_IDLE_TIMEOUT,
+            timeout: None,
+            streaming: StreamingConfig::default(),
+            concurrency: None,
+            strip_prefix: true,
+        }
     }
 
-    pub fn https(host: &str, prefix: String) -> Result<Self, ParseError> {
-        Ok(Api {
-            prefix,
-            endpoints: vec![HttpEndpoint::https(host)?],
-            global_handlers: vec![],
-            finalizer: None,
-            scoped_handlers: vec![]
-        })
+    pub fn http(host: &str, port: u16, prefix: String) -> Result<Self, ParseError> {
+        Ok(Api { endpoints: vec![HttpEndpoint::http(host, port)?], ..Api::defaults(prefix) })
+    }
+
+    pub fn http_with(host: &str, port: u16, prefix: String, protocol: &ProtocolConfig) -> Result<Self, ParseError> {
+        Ok(Api { endpoints: vec![HttpEndpoint::http_with(host, port, protocol)?], ..Api::defaults(prefix) })
+    }
+
+    /// Routes the calls to a gRPC service (i.e. "helloworld.Greeter"), or to a single method ("helloworld.Greeter/SayHello")
+    /// to an upstream speaking cleartext HTTP/2 (h2c). Paths are forwarded untouched: "/helloworld.Greeter/SayHello"
+    pub fn grpc(host: &str, port: u16, service: &str) -> Result<Self, ParseError> {
+        let mut api = Api::http_with(host, port, format!("/{}", service), &ProtocolConfig::new(HttpProtocol::Http2))?;
+        api.keep_prefix();
+        Ok(api)
+    }
+
+    /// Upstream listening on a Unix domain socket: "unix:/path/to.sock"
+    #[cfg(unix)]
+    pub fn unix(address: &str, prefix: String) -> Result<Self, ParseError> {
+        Ok(Api { endpoints: vec![HttpEndpoint::unix(address)?], ..Api::defaults(prefix) })
+    }
+
+    pub fn https(host: &str, prefix: String) -> Result<Self, TlsError> {
+        Ok(Api { endpoints: vec![HttpEndpoint::https(host)?], ..Api::defaults(prefix) })
+    }
+
+    pub fn https_with(host: &str, prefix: String, tls: &UpstreamTlsConfig, protocol: &ProtocolConfig) -> Result<Self, TlsError> {
+        Ok(Api { endpoints: vec![HttpEndpoint::https_with(host, tls, protocol)?], ..Api::defaults(prefix) })
     }
 
     pub fn add_global_handler(&mut self, handler: Box<dyn GlobalHandler>) {
@@ -44,59 +94,273 @@ impl Api  {
         self.scoped_handlers.push(handler);
     }
 
+    /// Guards are invoked in order, after the handlers
+    pub fn add_guard(&mut self, guard: Box<dyn RequestGuard>) {
+        self.guards.push(guard);
+    }
+
     pub fn finalize_with(&mut self, finalizer: Box<dyn ResponseFinalizer>) {
         self.finalizer = Some(finalizer);
     }
 
+    /// Invoked after every handler, right before sending the request upstream
+    pub fn transform_requests_with(&mut self, transformer: Box<dyn RequestTransformer>) {
+        self.request_transformer = Some(transformer);
+    }
+
+    pub fn with_websocket_idle_timeout(&mut self, timeout: Duration) {
+        self.websocket_idle_timeout = timeout;
+    }
+
+    /// Upstream responses must be received within `timeout`, or the client gets a 504
+    /// Only response headers are awaited for streaming responses, their body can last forever
+    pub fn with_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Streams responses with this media type (i.e. "application/grpc"), on top of `StreamingConfig` defaults
+    pub fn stream_content_type(&mut self, content_type: &str) {
+        self.streaming.content_types.push(content_type.to_string());
+    }
+
+    /// Forwards the full request path upstream, prefix included
+    pub fn keep_prefix(&mut self) {
+        self.strip_prefix = false;
+    }
+
+    /// Every endpoint of this Api expects connections to start with a PROXY protocol header
+    pub fn send_proxy_protocol(&mut self, version: ProxyProtocolVersion) {
+        for endpoint in &mut self.endpoints {
+            endpoint.with_proxy_protocol(version);
+        }
+    }
+
+    /// Streams every response of this Api
+    pub fn stream_all(&mut self) {
+        self.streaming.always = true;
+    }
+
+    /// Caps the requests in flight to the upstream, see `ConcurrencyLimiter`
+    pub fn limit_concurrency(&mut self, limiter: ConcurrencyLimiter) {
+        self.concurrency = Some(limiter);
+    }
+
 
     /// Proxies a request to the appropriate endpoint
     /// Invoking every handlers on request / response
-    pub async fn proxy(&self, mut req: Request<Body>) -> Result<Response<Body>, Error> {
+    /// Scoped handlers see every response sent back to the client: from upstream, or from a handler, guard or transformer that broke the chain
+    pub async fn proxy(&self, mut req: Request<Body>) -> Response<Body> {
         let endpoint = self.endpoint_for(&req);
         let hooks_for_roundtrip: Vec<Box<dyn ScopedHandler>> = self.scoped_handlers.iter().map(|hf| hf.create()).collect();
-        endpoint.target_req_uri(&self.prefix, &mut req);
+        let client_req = ClientRequest { method: req.method().clone(), uri: req.uri().clone(), version: req.version() };
+        req.extensions_mut().insert(client_req);
+        endpoint.target_req_uri(if self.strip_prefix { &self.prefix } else { "" }, &mut req);
+        let upgrade = PendingUpgrade::on(&mut req);
+        let (mut resp, response_headers) = self.forward(endpoint, req, &hooks_for_roundtrip, upgrade).await;
+        if let Some(ResponseHeaders(headers)) = response_headers {
+            for (name, value) in headers {
+                if let Some(name) = name {
+                    resp.headers_mut().entry(name).or_insert(value);
+                }
+            }
+        }
+        for hook in &hooks_for_roundtrip {
+            hook.handle_res(&mut resp);
+        }
+        resp
+    }
+
+    /// Invokes handlers, guards and transformer on the request, then sends it upstream unless one of them answered
+    /// Returns the response along with the headers handlers want on it, whichever produced it
+    async fn forward(&self, endpoint: &HttpEndpoint, mut req: Request<Body>, hooks: &[Box<dyn ScopedHandler>], upgrade: Option<PendingUpgrade>) -> (Response<Body>, Option<ResponseHeaders>) {
         for handler in &self.global_handlers {
             if let HandlerResponse::Break(resp) = handler.handle_req(&mut req) {
-                return Ok(resp)
+                for hook in hooks {
+                    hook.handle_req(&mut req);
+                }
+                return (resp, req.extensions_mut().remove::<ResponseHeaders>())
             }
         }
-        for hook in &hooks_for_roundtrip {
+        for hook in hooks {
             hook.handle_req(&mut req);
         }
-        self.send(endpoint, req, &hooks_for_roundtrip).await
+        for guard in &self.guards {
+            if let HandlerResponse::Break(resp) = guard.handle_req(&mut req).await {
+                return (resp, req.extensions_mut().remove::<ResponseHeaders>())
+            }
+        }
+        let response_headers = req.extensions_mut().remove::<ResponseHeaders>();
+        if let Some(transformer) = &self.request_transformer {
+            req = match transformer.transform(req).await {
+                Ok(req) => req,
+                Err(resp) => return (resp, response_headers),
+            };
+        }
+        let correlation_id = req.extensions().get::<CorrelationId>().cloned();
+        let resp = match self.send(endpoint, req, upgrade).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                match correlation_id {
+                    Some(id) => error!("Request {} failed: {:?}", id, e),
+                    None => error!("Request failed: {:?}", e),
+                }
+                Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::empty()).unwrap()
+            },
+        };
+        (resp, response_headers)
     }
 
     /// Sends the request to upstream and handles the response
-    async fn send(&self, endpoint: &HttpEndpoint, req: Request<Body>, hooks: &[Box<dyn ScopedHandler>]) -> Result<Response<Body>, Error> {
-        match endpoint {
-            Plain(e) => e.client.request(req),
-            Ssl(e) => e.client.request(req),
-        }.map(|res| {
-            if res.is_err() { return res }
-            let mut resp = res.unwrap();
-            for handler in &self.global_handlers {
-                if let HandlerResponse::Break(overriden) = handler.handle_res(&mut resp) {
-                    return Ok(overriden)
-                }
-            }
-            Ok(resp)
-        }).and_then(|res| async move {
-            let mut resp = if let Some(transformer) = &self.finalizer {
-                transformer.transform(res).await
-            } else {
-                res
+    /// If the upstream accepts a WebSocket upgrade, both connections are spliced once the response is sent back to the client
+    async fn send(&self, endpoint: &HttpEndpoint, req: Request<Body>, upgrade: Option<PendingUpgrade>) -> Result<Response<Body>, BoxError> {
+        let permit = match &self.concurrency {
+            Some(limiter) => match limiter.acquire(req.extensions().get::<Priority>().copied().unwrap_or_default()).await {
+                Some(permit) => Some(permit),
+                None => return Ok(concurrency::service_unavailable()),
+            },
+            None => None,
+        };
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let upstream_req = UpstreamRequest { method: req.method().clone(), uri: req.uri().clone() };
+        let tag = CorrelationId::log_tag(req.extensions());
+        let response = endpoint.request(req);
+        let response = match deadline {
+            None => response.await,
+            Some(deadline) => match tokio::time::timeout_at(deadline, response).await {
+                Ok(resp) => resp,
+                Err(_) => {
+                    if let Some(permit) = permit {
+                        permit.record(Outcome::Overload);
+                    }
+                    return Ok(Response::builder().status(StatusCode::GATEWAY_TIMEOUT).body(Body::empty()).unwrap())
+                },
+            },
+        };
+        if let Some(permit) = permit {
+            let outcome = match &response {
+                Ok(resp) => Outcome::of(resp, permit.elapsed()),
+                Err(_) => Outcome::Overload,
             };
-            for hook in hooks {
-                hook.handle_res(&mut resp);
+            permit.record(outcome);
+        }
+        let mut resp = response?;
+        resp.extensions_mut().insert(upstream_req);
+        if let Some(upgrade) = upgrade {
+            upgrade.accept(&mut resp, self.websocket_idle_timeout, tag);
+        }
+        let streaming = self.streaming.is_streaming(&resp);
+        if streaming {
+            resp.extensions_mut().insert(Streaming);
+        } else if let Some(deadline) = deadline {
+            let (parts, body) = resp.into_parts();
+            resp = Response::from_parts(parts, body_with_deadline(body, deadline));
+        }
+        for handler in &self.global_handlers {
+            if let HandlerResponse::Break(overriden) = handler.handle_res(&mut resp) {
+                return Ok(overriden)
             }
-            Ok(resp)
-        }).await
+        }
+        if let Some(finalizer) = &self.finalizer {
+            if !streaming || !finalizer.buffers_body() {
+                resp = finalizer.transform(resp).await;
+            }
+        }
+        Ok(resp)
     }
 
     pub fn endpoint_for(&self, _req: &Request<Body>) -> &HttpEndpoint {
         // TODO: decision tree (based on health checks, response times, etc.)
         // How to deal with multiple ? Should this be moved to another trait?
-        self.endpoints.get(0).unwrap()
+        self.endpoints.first().unwrap()
     }
 }
 
+/// Fails the body if it isn't fully received by `deadline`
+fn body_with_deadline(body: Body, deadline: Instant) -> Body {
+    Body::wrap_stream(futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => Some((chunk.map_err(BoxError::from), Some(body))),
+            Ok(None) => None,
+            Err(_) => Some((Err(BoxError::from("Upstream response body timed out")), None)),
+        }
+    }))
+}
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::tests::{wait_for_gateway, wait_for_port};
+    use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
+    use hyper::body::HttpBody;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    /// Accepts connections and never answers
+    async fn mute_server(port: u16) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let mut connections = vec![];
+        loop {
+            connections.push(listener.accept().await.unwrap());
+        }
+    }
+
+    /// Sends the headers and a first chunk, then never ends the body
+    async fn stalled_server(port: u16) {
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    if sender.send_data("first".into()).await.is_ok() {
+                        futures::future::pending::<()>().await;
+                    }
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        Server::bind(&([127, 0, 0, 1], port).into()).serve(make_svc).await.unwrap();
+    }
+
+    fn api(backend_port: u16, prefix: &str, timeout: Option<Duration>) -> Api {
+        let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+        if let Some(timeout) = timeout {
+            api.with_timeout(timeout);
+        }
+        api
+    }
+
+    #[tokio::test]
+    async fn upstream_responses_time_out() {
+        let gw_port = 13710;
+        let mute_port = 13711;
+        let stalled_port = 13712;
+        tokio::spawn(async move { mute_server(mute_port).await });
+        tokio::spawn(async move { stalled_server(stalled_port).await });
+        tokio::spawn(async move {
+            start_local_gateway(gw_port, vec![
+                api(mute_port, "/mute", Some(Duration::from_millis(200))),
+                api(stalled_port, "/stalled", Some(Duration::from_millis(200))),
+                api(stalled_port, "/untimed", None),
+            ]).await
+        });
+        wait_for_port(mute_port).await;
+        wait_for_port(stalled_port).await;
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let get = |prefix: &str| client.get(format!("http://127.0.0.1:{}{}", gw_port, prefix).parse::<Uri>().unwrap());
+
+        let resp = tokio::time::timeout(Duration::from_secs(5), get("/mute")).await.unwrap().unwrap();
+        assert_eq!(StatusCode::GATEWAY_TIMEOUT, resp.status());
+
+        let resp = tokio::time::timeout(Duration::from_secs(5), get("/stalled")).await.unwrap().unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = tokio::time::timeout(Duration::from_secs(5), hyper::body::to_bytes(resp.into_body())).await.unwrap();
+        assert!(body.is_err()); // cut once the deadline is reached
+
+        let mut resp = tokio::time::timeout(Duration::from_secs(5), get("/untimed")).await.unwrap().unwrap();
+        assert_eq!(&b"first"[..], &resp.body_mut().data().await.unwrap().unwrap()[..]);
+        assert!(tokio::time::timeout(Duration::from_millis(400), resp.body_mut().data()).await.is_err()); // still open past 200ms
+    }
+}
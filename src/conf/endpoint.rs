@@ -1,42 +1,187 @@
 use std::string::ParseError;
-use hyper::client::HttpConnector;
-use hyper::{Client, Request, Body};
-use hyper::http::uri::PathAndQuery;
-use hyper_tls::HttpsConnector;
+use hyper::client::{Builder, HttpConnector};
+use hyper::client::connect::Connection;
+use hyper::header::{HeaderValue, HOST};
+use hyper::{Client, Request, Response, Body, Version};
+use futures::TryFutureExt;
+use crate::context::{ClientInfo, CorrelationId};
+use crate::proxy_protocol::{self, ProxyHeader, ProxyProtocolVersion};
+use log::debug;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use hyper::http::uri::{Authority, PathAndQuery};
+use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+use crate::conf::tls::UpstreamTlsConfig;
+use crate::tls::TlsError;
+use crate::tls::client::UpstreamConnector;
+#[cfg(unix)]
+use crate::unix::UnixConnector;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
 
 #[derive(Debug, Clone)]
 pub enum HttpEndpoint {
     Plain(Endpoint<HttpConnector>),
-    Ssl(Endpoint<HttpsConnector<HttpConnector>>)
+    Ssl(Endpoint<UpstreamConnector>, UpstreamConnector),   // the connector also opens PROXY protocol connections
+    #[cfg(unix)]
+    Unix(Endpoint<UnixConnector>),  // address is the socket path
 }
 
 #[derive(Debug, Clone)]
 pub struct Endpoint<T> {
     pub address: String,
     pub client: Client<T>,
+    pub protocol: HttpProtocol,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,   // connections start with a PROXY protocol header
 }
 
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, BoxError>> + Send>>;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
 impl HttpEndpoint {
 
     pub fn http(host: &str, port: u16) -> Result<Self, ParseError> {
+        HttpEndpoint::http_with(host, port, &ProtocolConfig::new(HttpProtocol::Http1))
+    }
+
+    /// `HttpProtocol::Http2` speaks cleartext HTTP/2 with prior knowledge (h2c), `Auto` means HTTP/1.1 here
+    pub fn http_with(host: &str, port: u16, protocol: &ProtocolConfig) -> Result<Self, ParseError> {
         Ok(HttpEndpoint::Plain(Endpoint {
             address: format!("{}:{}", host, port),
-            // TODO: configure client according to endpoint conf (retry / timeout / protocol (HTTP2/HTTPS) etc.)
-            client: Client::builder().build_http(),
+            // TODO: configure client according to endpoint conf (retry / timeout etc.)
+            client: client_builder(protocol).build_http(),
+            protocol: protocol.protocol,
+            proxy_protocol: None,
         }))
     }
 
-    pub fn https(address: &str) -> Result<Self, ParseError> {
+    /// HTTPS endpoint trusting the system roots
+    /// Only an invalid TLS setup is an error: system roots rustls can't load are logged, and handshakes with the endpoint then fail
+    pub fn https(address: &str) -> Result<Self, TlsError> {
+        HttpEndpoint::https_with(address, &UpstreamTlsConfig::default(), &ProtocolConfig::new(HttpProtocol::Http1))
+    }
+
+    /// HTTPS endpoint with custom TLS settings (CA, client certificate, SNI, ...)
+    /// With `HttpProtocol::Auto`, HTTP/2 is used if the upstream picks `h2` through ALPN
+    pub fn https_with(address: &str, tls: &UpstreamTlsConfig, protocol: &ProtocolConfig) -> Result<Self, TlsError> {
+        let connector = UpstreamConnector::new(tls, &protocol.alpn_protocols())?;
         Ok(HttpEndpoint::Ssl(Endpoint {
             address: address.to_string(),
-            // TODO: configure client according to endpoint conf (retry / timeout / protocol (HTTP2/HTTPS) etc.)
-            client: Client::builder().build(HttpsConnector::new()),
+            client: client_builder(protocol).build(connector.clone()),
+            protocol: protocol.protocol,
+            proxy_protocol: None,
+        }, connector))
+    }
+
+    /// Upstream listening on a Unix domain socket: "unix:/path/to.sock" (or just the path)
+    #[cfg(unix)]
+    pub fn unix(address: &str) -> Result<Self, ParseError> {
+        HttpEndpoint::unix_with(address, &ProtocolConfig::new(HttpProtocol::Http1))
+    }
+
+    /// `HttpProtocol::Http2` speaks HTTP/2 with prior knowledge over the socket
+    #[cfg(unix)]
+    pub fn unix_with(address: &str, protocol: &ProtocolConfig) -> Result<Self, ParseError> {
+        let path = address.strip_prefix("unix:").unwrap_or(address);
+        Ok(HttpEndpoint::Unix(Endpoint {
+            address: path.to_string(),
+            client: client_builder(protocol).build(UnixConnector::new(path)),
+            protocol: protocol.protocol,
+            proxy_protocol: None,
         }))
     }
 
+    /// Every connection to this endpoint starts with a PROXY protocol header carrying the client addresses (see `context::ClientInfo`)
+    /// Such connections are specific to one client: they aren't pooled, each request gets its own
+    pub fn with_proxy_protocol(&mut self, version: ProxyProtocolVersion) {
+        match self {
+            HttpEndpoint::Plain(e) => e.proxy_protocol = Some(version),
+            HttpEndpoint::Ssl(e, _) => e.proxy_protocol = Some(version),
+            #[cfg(unix)]
+            HttpEndpoint::Unix(e) => e.proxy_protocol = Some(version),
+        }
+    }
+
+    fn proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        match self {
+            HttpEndpoint::Plain(e) => e.proxy_protocol,
+            HttpEndpoint::Ssl(e, _) => e.proxy_protocol,
+            #[cfg(unix)]
+            HttpEndpoint::Unix(e) => e.proxy_protocol,
+        }
+    }
+
+    /// Sends a request targeting this endpoint (see `target_req_uri`)
+    pub fn request(&self, req: Request<Body>) -> ResponseFuture {
+        if let Some(version) = self.proxy_protocol() {
+            let endpoint = self.clone();
+            return Box::pin(async move { endpoint.request_with_proxy_header(version, req).await })
+        }
+        match self {
+            HttpEndpoint::Plain(e) => Box::pin(e.client.request(req).err_into()),
+            HttpEndpoint::Ssl(e, _) => Box::pin(e.client.request(req).err_into()),
+            #[cfg(unix)]
+            HttpEndpoint::Unix(e) => Box::pin(e.client.request(req).err_into()),
+        }
+    }
+
+    /// Connection failures are returned as errors, like the pooled client does
+    async fn request_with_proxy_header(&self, version: ProxyProtocolVersion, mut req: Request<Body>) -> Result<Response<Body>, BoxError> {
+        let client = req.extensions().get::<ClientInfo>().map(|info| ProxyHeader { source: info.remote_addr, destination: info.local_addr });
+        let header = proxy_protocol::encode(version, client);
+        let host = req.uri().host().unwrap_or_default().to_string();
+        let tag = CorrelationId::log_tag(req.extensions());
+        let (io, h2) = self.connect_with(&header, &host).await?;
+        let (mut sender, connection) = hyper::client::conn::Builder::new().http2_only(h2).handshake(io).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Upstream connection closed{}: {}", tag, e);
+            }
+        });
+        if !h2 { // origin-form, as the pooled client would send it
+            if !req.headers().contains_key(HOST) {
+                if let Some(authority) = req.uri().authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()) {
+                    req.headers_mut().insert(HOST, authority);
+                }
+            }
+            *req.uri_mut() = req.uri().path_and_query().map(|path| path.as_str()).unwrap_or("/").parse().unwrap();
+        }
+        Ok(sender.send_request(req).await?)
+    }
+
+    /// Opens a new connection and writes `header` first, returns whether HTTP/2 must be spoken on it
+    async fn connect_with(&self, header: &[u8], host: &str) -> Result<(Box<dyn Io>, bool), BoxError> {
+        Ok(match self {
+            HttpEndpoint::Plain(e) => {
+                let mut tcp = TcpStream::connect(&e.address).await?;
+                tcp.write_all(header).await?;
+                (Box::new(tcp), e.protocol == HttpProtocol::Http2)
+            },
+            HttpEndpoint::Ssl(e, connector) => {
+                let mut tcp = TcpStream::connect(with_default_port(&e.address, 443)).await?;
+                tcp.write_all(header).await?;
+                let tls = connector.handshake(host, tcp).await?;
+                let h2 = e.protocol == HttpProtocol::Http2 || tls.connected().is_negotiated_h2();
+                (Box::new(tls), h2)
+            },
+            #[cfg(unix)]
+            HttpEndpoint::Unix(e) => {
+                let mut unix = tokio::net::UnixStream::connect(&e.address).await?;
+                unix.write_all(header).await?;
+                (Box::new(unix), e.protocol == HttpProtocol::Http2)
+            },
+        })
+    }
+
     /// Changes the request URI to target this endpoint
+    /// The request version is reset: the protocol spoken upstream only depends on the endpoint settings
     pub fn target_req_uri(&self, prefix: &str, req: &mut Request<Body>) {
+        *req.version_mut() = Version::HTTP_11;
         let path = build_path(req.uri().path_and_query(), prefix.len());
         *req.uri_mut() = match self {
             HttpEndpoint::Plain(e) => format!(
@@ -44,16 +189,38 @@ impl HttpEndpoint {
                 e.address.clone(),
                 path
             ),
-            HttpEndpoint::Ssl(e) => format!(
+            HttpEndpoint::Ssl(e, _) => format!(
                 "https://{}{}",
                 e.address.clone(),
                 path
             ),
+            #[cfg(unix)]
+            HttpEndpoint::Unix(_) => format!("http://localhost{}", path), // the connector ignores the authority
         }.parse().unwrap();
     }
 
 }
 
+fn client_builder(protocol: &ProtocolConfig) -> Builder {
+    let settings = &protocol.http2;
+    let mut builder = Client::builder();
+    builder.http2_only(protocol.protocol == HttpProtocol::Http2)
+        .http2_initial_stream_window_size(settings.initial_stream_window_size)
+        .http2_initial_connection_window_size(settings.initial_connection_window_size)
+        .http2_adaptive_window(settings.adaptive_window)
+        .http2_max_frame_size(settings.max_frame_size)
+        .http2_keep_alive_interval(settings.keep_alive_interval);
+    builder
+}
+
+/// "host" or "[::1]" get `port`, addresses with a port are left untouched
+fn with_default_port(address: &str, port: u16) -> String {
+    match address.parse::<Authority>() {
+        Ok(authority) if authority.port_u16().is_some() => address.to_string(),
+        _ => format!("{}:{}", address, port),
+    }
+}
+
 fn build_path(path: Option<&PathAndQuery>, from: usize) -> &str {
     let full_path = path.map(PathAndQuery::as_str).unwrap_or("");
     if from > full_path.len() {
@@ -61,4 +228,27 @@ fn build_path(path: Option<&PathAndQuery>, from: usize) -> &str {
     } else {
         &full_path[from..]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::conf::endpoint::{with_default_port, HttpEndpoint};
+    use crate::proxy_protocol::ProxyProtocolVersion;
+    use hyper::{Body, Request};
+
+    #[test]
+    fn default_port_is_added_to_addresses_without_one() {
+        assert_eq!("example.com:443", with_default_port("example.com", 443));
+        assert_eq!("example.com:8443", with_default_port("example.com:8443", 443));
+        assert_eq!("[::1]:443", with_default_port("[::1]", 443));
+        assert_eq!("[::1]:8443", with_default_port("[::1]:8443", 443));
+    }
+
+    #[tokio::test]
+    async fn connection_failures_are_errors_with_proxy_protocol() {
+        let mut endpoint = HttpEndpoint::http("127.0.0.1", 13706).unwrap(); // nothing listens there
+        endpoint.with_proxy_protocol(ProxyProtocolVersion::V1);
+        let mut req = Request::get("/").body(Body::empty()).unwrap();
+        endpoint.target_req_uri("", &mut req);
+        assert!(endpoint.request(req).await.is_err());
+    }
+}
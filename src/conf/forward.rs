@@ -0,0 +1,52 @@
+use crate::conf::tcp::DEFAULT_CONNECT_TIMEOUT;
+use crate::conf::tls::UpstreamTlsConfig;
+use crate::handlers::GlobalHandler;
+use crate::handlers::destinations::AllowedDestinations;
+use std::time::Duration;
+
+/// Tunnels are closed after this delay without any traffic
+pub const DEFAULT_TUNNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Egress proxy mode: absolute-form HTTP/1 requests ("GET http://host/path") are forwarded to their destination,
+/// `CONNECT host:port` requests open a tunnel to it. Origin-form requests are still routed to the gateway Apis
+/// Only allowed destinations can be reached, `allow_all` turns the gateway into an open proxy
+/// Global handlers are invoked before connecting to the destination: use them for logging, rate limiting...
+#[derive(Debug)]
+pub struct ForwardProxyConfig {
+    pub allowed: Option<AllowedDestinations>,   // any destination if none
+    pub global_handlers: Vec<Box<dyn GlobalHandler>>,
+    pub connect_timeout: Duration,
+    pub tunnel_idle_timeout: Duration,
+    pub tls: UpstreamTlsConfig,     // verifies "https://" destinations, its server name is ignored
+}
+
+impl ForwardProxyConfig {
+
+    /// Destinations outside of `allowed` are answered with a 403
+    pub fn new(allowed: AllowedDestinations) -> Self {
+        ForwardProxyConfig { allowed: Some(allowed), ..ForwardProxyConfig::allow_all() }
+    }
+
+    /// Every destination can be reached, unless a global handler rejects it
+    pub fn allow_all() -> Self {
+        ForwardProxyConfig {
+            allowed: None,
+            global_handlers: vec![],
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            tunnel_idle_timeout: DEFAULT_TUNNEL_IDLE_TIMEOUT,
+            tls: UpstreamTlsConfig::default(),
+        }
+    }
+
+    pub fn add_global_handler(&mut self, handler: Box<dyn GlobalHandler>) {
+        self.global_handlers.push(handler);
+    }
+
+    pub fn with_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    pub fn with_tunnel_idle_timeout(&mut self, timeout: Duration) {
+        self.tunnel_idle_timeout = timeout;
+    }
+}
@@ -0,0 +1,53 @@
+use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+use crate::conf::tls::TlsConfig;
+use std::net::SocketAddr;
+
+/// Where and how the gateway accepts client connections
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub protocol: ProtocolConfig,
+    pub tls: Option<TlsConfig>,
+    pub proxy_protocol: bool,   // every connection starts with a PROXY protocol header (v1 or v2), connections without one are closed
+    pub http3_port: Option<u16>,    // UDP port advertised to clients through `Alt-Svc`
+}
+
+impl ListenerConfig {
+
+    /// Cleartext listener on localhost, serving HTTP/1.1 and HTTP/2 with prior knowledge (h2c)
+    pub fn local(port: u16) -> Self {
+        ListenerConfig::new(([127, 0, 0, 1], port).into())
+    }
+
+    pub fn new(address: SocketAddr) -> Self {
+        ListenerConfig { address, protocol: ProtocolConfig::default(), tls: None, proxy_protocol: false, http3_port: None }
+    }
+
+    /// Unless the protocol is `Auto`, the ALPN protocols of `tls` are replaced by the ones matching the listener protocol
+    pub fn with_tls(&mut self, tls: TlsConfig) {
+        self.tls = Some(tls);
+    }
+
+    pub fn with_protocol(&mut self, protocol: ProtocolConfig) {
+        self.protocol = protocol;
+    }
+
+    /// Behind an L4 load balancer: the client address is read from the PROXY protocol header it sends first
+    pub fn accept_proxy_protocol(&mut self) {
+        self.proxy_protocol = true;
+    }
+
+    /// Responses advertise an HTTP/3 listener on `port` (see `http3::start_http3_gateway`), clients may switch to it for subsequent requests
+    pub fn advertise_http3(&mut self, port: u16) {
+        self.http3_port = Some(port);
+    }
+
+    /// TLS settings, with ALPN protocols matching the listener protocol
+    pub(crate) fn tls_config(&self) -> Option<TlsConfig> {
+        let mut tls = self.tls.clone()?;
+        if self.protocol.protocol != HttpProtocol::Auto {
+            tls.with_alpn_protocols(self.protocol.alpn_protocols());
+        }
+        Some(tls)
+    }
+}
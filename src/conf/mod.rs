@@ -1,2 +1,17 @@
 pub mod endpoint;
 pub mod api;
+pub mod tls;
+pub mod protocol;
+pub mod listener;
+pub mod streaming;
+pub mod tcp;
+pub mod forward;
+
+/// Host name matching shared by SNI routes and forward proxy allow-lists, case-insensitive
+/// A wildcard covers exactly one label, as in TLS certificates: "*.example.com" matches "a.example.com", not "example.com" nor "a.b.example.com"
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.split_once('.').map(|(label, parent)| !label.is_empty() && parent.eq_ignore_ascii_case(domain)).unwrap_or(false),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
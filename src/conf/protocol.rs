@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+/// HTTP versions spoken on a connection (by a listener to its clients, or by the gateway to an upstream)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpProtocol {
+    Http1,  // HTTP/1.1 only
+    Http2,  // HTTP/2 only, with prior knowledge over cleartext connections (h2c)
+    Auto,   // negotiated through ALPN over TLS. Over cleartext, listeners detect the HTTP/2 preface and upstreams are sent HTTP/1.1
+}
+
+/// HTTP/2 tuning, `None` keeps hyper's defaults
+#[derive(Debug, Clone, Default)]
+pub struct Http2Settings {
+    pub max_concurrent_streams: Option<u32>,    // listeners only, upstreams announce their own limit
+    pub initial_stream_window_size: Option<u32>,
+    pub initial_connection_window_size: Option<u32>,
+    pub adaptive_window: bool,                  // overrides both window sizes, based on bandwidth-delay estimations
+    pub max_frame_size: Option<u32>,
+    pub keep_alive_interval: Option<Duration>,  // interval between PING frames
+}
+
+/// Protocol settings of a listener or an `HttpEndpoint`
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    pub protocol: HttpProtocol,
+    pub http2: Http2Settings,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        ProtocolConfig::new(HttpProtocol::Auto)
+    }
+}
+
+impl ProtocolConfig {
+
+    pub fn new(protocol: HttpProtocol) -> Self {
+        ProtocolConfig { protocol, http2: Http2Settings::default() }
+    }
+
+    pub fn with_max_concurrent_streams(&mut self, max: u32) {
+        self.http2.max_concurrent_streams = Some(max);
+    }
+
+    pub fn with_window_sizes(&mut self, stream: u32, connection: u32) {
+        self.http2.initial_stream_window_size = Some(stream);
+        self.http2.initial_connection_window_size = Some(connection);
+    }
+
+    pub fn with_adaptive_window(&mut self) {
+        self.http2.adaptive_window = true;
+    }
+
+    pub fn with_max_frame_size(&mut self, size: u32) {
+        self.http2.max_frame_size = Some(size);
+    }
+
+    pub fn with_keep_alive_interval(&mut self, interval: Duration) {
+        self.http2.keep_alive_interval = Some(interval);
+    }
+
+    /// Protocols advertised through ALPN on TLS connections
+    pub fn alpn_protocols(&self) -> Vec<String> {
+        match self.protocol {
+            HttpProtocol::Http1 => vec!["http/1.1".to_string()],
+            HttpProtocol::Http2 => vec!["h2".to_string()],
+            HttpProtocol::Auto => vec!["h2".to_string(), "http/1.1".to_string()],
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use hyper::{Body, Response, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use crate::grpc;
+
+/// Which upstream responses are streamed: relayed chunk by chunk as they arrive, for as long as they last
+/// Streaming responses are marked with `context::Streaming`, are never buffered by finalizers, and aren't subject to the Api timeout once their headers are received
+/// Upgraded connections (`101 Switching Protocols`) and gRPC responses are always streamed
+#[derive(Debug, Clone)]
+pub struct StreamingConfig {
+    pub content_types: Vec<String>,   // media types, without parameters (i.e. "text/event-stream")
+    pub always: bool,                 // every response of the Api is streamed, whatever its content type
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            content_types: vec![
+                "text/event-stream".to_string(),
+                "application/x-ndjson".to_string(),
+                "application/stream+json".to_string(),
+            ],
+            always: false,
+        }
+    }
+}
+
+impl StreamingConfig {
+
+    pub fn is_streaming(&self, res: &Response<Body>) -> bool {
+        if self.always || res.status() == StatusCode::SWITCHING_PROTOCOLS || grpc::is_grpc(res.headers()) {
+            return true
+        }
+        let media_type = match res.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) {
+            Some(content_type) => content_type.split(';').next().unwrap_or("").trim(),
+            None => return false,
+        };
+        self.content_types.iter().any(|streamed| streamed.eq_ignore_ascii_case(media_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::streaming::StreamingConfig;
+    use crate::context::Streaming;
+    use crate::gateway::start_local_gateway;
+    use crate::handlers::{GlobalHandler, HandlerResponse, ResponseFinalizer};
+    use crate::handlers::HandlerResponse::Continue;
+    use crate::tests::{wait_for_gateway, wait_for_port, unwrap_body_as_str, body_as_str};
+    use async_trait::async_trait;
+    use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri};
+    use hyper::body::HttpBody;
+    use hyper::header::CONTENT_TYPE;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    #[test]
+    fn streaming_by_content_type() {
+        let with_type = |content_type: &str| Response::builder().header(CONTENT_TYPE, content_type).body(Body::empty()).unwrap();
+        let conf = StreamingConfig::default();
+        assert!(conf.is_streaming(&with_type("text/event-stream")));
+        assert!(conf.is_streaming(&with_type("Application/X-NDJSON; charset=utf-8")));
+        assert!(!conf.is_streaming(&with_type("application/json")));
+        assert!(!conf.is_streaming(&Response::new(Body::empty())));
+        let always = StreamingConfig { content_types: vec![], always: true };
+        assert!(always.is_streaming(&with_type("application/json")));
+    }
+
+    /// Sends a first chunk, then a second one when notified
+    async fn two_chunks_server(port: u16, content_type: &'static str, next: Arc<Notify>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(move |_conn| {
+            let next = next.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                    let next = next.clone();
+                    async move {
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            if sender.send_data("data: 1\n\n".into()).await.is_ok() {
+                                next.notified().await;
+                                let _ = sender.send_data("data: 2\n\n".into()).await;
+                            }
+                        });
+                        Ok::<_, Infallible>(Response::builder().header(CONTENT_TYPE, content_type).body(body).unwrap())
+                    }
+                }))
+            }
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct Buffering;
+
+    #[async_trait]
+    impl ResponseFinalizer for Buffering {
+        async fn transform(&self, res: Response<Body>) -> Response<Body> {
+            match body_as_str(res).await {
+                Ok(body) => Response::new(format!("buffered {}", body.len()).into()),
+                Err(_) => Response::builder().status(StatusCode::INTERNAL_SERVER_ERROR).body(Body::empty()).unwrap(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct StreamingFlag;
+
+    impl GlobalHandler for StreamingFlag {
+        fn handle_req(&self, _req: &mut Request<Body>) -> HandlerResponse {
+            Continue
+        }
+
+        fn handle_res(&self, res: &mut Response<Body>) -> HandlerResponse {
+            let streaming = res.extensions().get::<Streaming>().is_some();
+            res.headers_mut().insert("X-Streaming", streaming.to_string().parse().unwrap());
+            Continue
+        }
+    }
+
+    fn api(backend_port: u16, prefix: &str, timeout: Duration) -> Api {
+        let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+        api.with_timeout(timeout);
+        api.add_global_handler(Box::new(StreamingFlag));
+        api.finalize_with(Box::new(Buffering));
+        api
+    }
+
+    #[tokio::test]
+    async fn event_stream_is_relayed_chunk_by_chunk() {
+        let gw_port = 12500;
+        let backend_port = 12501;
+        let next = Arc::new(Notify::new());
+        let backend_next = next.clone();
+        tokio::spawn(async move { two_chunks_server(backend_port, "text/event-stream", backend_next).await });
+        tokio::spawn(async move {
+            start_local_gateway(gw_port, vec![api(backend_port, "/events", Duration::from_millis(200))]).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        let url = Uri::from_str(&format!("http://127.0.0.1:{}/events", gw_port)).unwrap();
+        let mut resp = Client::new().get(url).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("true", resp.headers().get("X-Streaming").unwrap());
+        let first = tokio::time::timeout(Duration::from_secs(5), resp.body_mut().data()).await.unwrap().unwrap().unwrap();
+        assert_eq!(&b"data: 1\n\n"[..], &first[..]);
+        tokio::time::sleep(Duration::from_millis(400)).await; // longer than the Api timeout
+        next.notify_one();
+        assert_eq!("data: 2\n\n", unwrap_body_as_str(resp).await);
+    }
+
+    #[tokio::test]
+    async fn buffered_responses_time_out() {
+        let gw_port = 12510;
+        let slow_port = 12511;
+        let patient_port = 12512;
+        let next = Arc::new(Notify::new());
+        let backend_next = next.clone();
+        tokio::spawn(async move { two_chunks_server(slow_port, "application/json", Arc::new(Notify::new())).await });
+        tokio::spawn(async move { two_chunks_server(patient_port, "application/json", backend_next).await });
+        tokio::spawn(async move {
+            start_local_gateway(gw_port, vec![
+                api(slow_port, "/slow", Duration::from_millis(200)),
+                api(patient_port, "/patient", Duration::from_secs(5)),
+            ]).await
+        });
+        wait_for_port(slow_port).await;
+        wait_for_port(patient_port).await;
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+
+        let url = Uri::from_str(&format!("http://127.0.0.1:{}/slow", gw_port)).unwrap();
+        let resp = tokio::time::timeout(Duration::from_secs(5), client.get(url)).await.unwrap().unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, resp.status()); // the finalizer could not read the whole body in time
+
+        let url = Uri::from_str(&format!("http://127.0.0.1:{}/patient", gw_port)).unwrap();
+        let pending = tokio::spawn(async move { client.get(url).await.unwrap() });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        next.notify_one();
+        let resp = pending.await.unwrap();
+        assert_eq!("buffered 18", unwrap_body_as_str(resp).await);
+    }
+
+}
@@ -0,0 +1,91 @@
+use crate::conf::host_matches;
+use crate::proxy_protocol::ProxyProtocolVersion;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Connections are closed after this delay without any traffic, in either direction
+pub const DEFAULT_TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Upstreams that can't be reached within this delay are given up on (TCP upstreams, forward proxy destinations)
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// L4 listener: connections are forwarded as raw bytes, never parsed as HTTP
+/// Without SNI routes, every connection goes to the default upstream
+/// With SNI routes, TLS connections are routed according to the server name of their ClientHello, without being terminated
+#[derive(Debug, Clone)]
+pub struct TcpListenerConfig {
+    pub address: SocketAddr,
+    pub routes: Vec<SniRoute>,
+    pub default: Option<TcpUpstream>,   // connections matching no route (or without SNI) are closed if there's none
+    pub idle_timeout: Duration,
+    pub proxy_protocol: bool,   // connections start with a PROXY protocol header (v1 or v2), see `accept_proxy_protocol`
+}
+
+/// Exact server name ("db.example.com") or a wildcard matching a single label ("*.example.com" matches "a.example.com", not "a.b.example.com")
+#[derive(Debug, Clone)]
+pub struct SniRoute {
+    pub server_name: String,
+    pub upstream: TcpUpstream,
+}
+
+/// Pool of upstream addresses ("host:port"), picked round-robin
+/// An endpoint that can't be reached within `connect_timeout` is skipped in favor of the next one
+#[derive(Debug, Clone)]
+pub struct TcpUpstream {
+    pub endpoints: Vec<String>,
+    pub connect_timeout: Duration,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,   // header sent before any byte of the client
+}
+
+impl TcpUpstream {
+
+    pub fn new(endpoints: Vec<String>) -> Self {
+        TcpUpstream { endpoints, connect_timeout: DEFAULT_CONNECT_TIMEOUT, proxy_protocol: None }
+    }
+
+    pub fn with_connect_timeout(&mut self, timeout: Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    /// Upstream connections start with a PROXY protocol header carrying the client addresses
+    pub fn send_proxy_protocol(&mut self, version: ProxyProtocolVersion) {
+        self.proxy_protocol = Some(version);
+    }
+}
+
+impl TcpListenerConfig {
+
+    /// Forwards every connection to `upstream`
+    pub fn forward(address: SocketAddr, upstream: TcpUpstream) -> Self {
+        TcpListenerConfig { address, routes: vec![], default: Some(upstream), idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT, proxy_protocol: false }
+    }
+
+    /// TLS passthrough, add routes with `route`
+    pub fn passthrough(address: SocketAddr) -> Self {
+        TcpListenerConfig { address, routes: vec![], default: None, idle_timeout: DEFAULT_TCP_IDLE_TIMEOUT, proxy_protocol: false }
+    }
+
+    /// Routes are tried in order
+    pub fn route(&mut self, server_name: &str, upstream: TcpUpstream) {
+        self.routes.push(SniRoute { server_name: server_name.to_ascii_lowercase(), upstream });
+    }
+
+    pub fn with_default(&mut self, upstream: TcpUpstream) {
+        self.default = Some(upstream);
+    }
+
+    pub fn with_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Behind another proxy: the client addresses are read from a PROXY protocol header, connections without one are closed
+    pub fn accept_proxy_protocol(&mut self) {
+        self.proxy_protocol = true;
+    }
+}
+
+impl SniRoute {
+
+    pub fn matches(&self, server_name: &str) -> bool {
+        host_matches(&self.server_name, server_name)
+    }
+}
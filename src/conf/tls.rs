@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// TLS protocol versions a listener can accept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+/// A certificate chain + private key, both PEM encoded
+/// `server_names` are the SNI names this certificate is served for
+#[derive(Debug, Clone)]
+pub struct CertificateConf {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub server_names: Vec<String>,
+}
+
+/// Client certificates verification (mTLS), against a PEM encoded CA bundle
+#[derive(Debug, Clone)]
+pub enum ClientAuth {
+    Optional(PathBuf),  // certificates are verified if the client sends one, requiring it is up to the Api (see `ClientCertHandler`)
+    Required(PathBuf),  // the handshake fails if the client doesn't send a valid certificate
+}
+
+/// TLS termination settings for a gateway listener
+/// The first certificate is the default one, served when SNI is missing or doesn't match any other certificate
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub certificates: Vec<CertificateConf>,
+    pub versions: Vec<TlsVersion>,
+    pub cipher_suites: Vec<String>, // rustls names (i.e. "TLS13_AES_128_GCM_SHA256"), empty means safe defaults
+    pub alpn_protocols: Vec<String>,
+    pub watch_interval: Option<Duration>, // reload certificates whenever their files are modified
+    pub client_auth: Option<ClientAuth>,
+}
+
+impl TlsConfig {
+
+    pub fn new<P: Into<PathBuf>>(cert_path: P, key_path: P) -> Self {
+        TlsConfig {
+            certificates: vec![CertificateConf {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+                server_names: vec![],
+            }],
+            versions: vec![TlsVersion::Tls12, TlsVersion::Tls13],
+            cipher_suites: vec![],
+            alpn_protocols: vec!["h2".to_string(), "http/1.1".to_string()],
+            watch_interval: None,
+            client_auth: None,
+        }
+    }
+
+    /// Serves this certificate to clients asking for one of the `server_names` through SNI
+    pub fn add_certificate<P: Into<PathBuf>>(&mut self, cert_path: P, key_path: P, server_names: Vec<String>) {
+        self.certificates.push(CertificateConf {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            server_names,
+        });
+    }
+
+    pub fn with_versions(&mut self, versions: Vec<TlsVersion>) {
+        self.versions = versions;
+    }
+
+    pub fn with_cipher_suites(&mut self, cipher_suites: Vec<String>) {
+        self.cipher_suites = cipher_suites;
+    }
+
+    pub fn with_alpn_protocols(&mut self, alpn_protocols: Vec<String>) {
+        self.alpn_protocols = alpn_protocols;
+    }
+
+    /// Checks certificate and key files for modifications every `interval`, and reloads them if needed
+    pub fn watch_files(&mut self, interval: Duration) {
+        self.watch_interval = Some(interval);
+    }
+
+    pub fn with_client_auth(&mut self, client_auth: ClientAuth) {
+        self.client_auth = Some(client_auth);
+    }
+}
+
+/// TLS settings used to reach an upstream endpoint
+/// Defaults: system trusted roots, no client certificate, SNI taken from the endpoint address
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTlsConfig {
+    pub ca_path: Option<PathBuf>,                   // PEM bundle trusted on top of system roots (i.e. internal PKI)
+    pub client_cert: Option<(PathBuf, PathBuf)>,    // PEM certificate chain + PKCS8 key, presented to upstreams requiring mTLS
+    pub server_name: Option<String>,                // sent as SNI and checked against the upstream certificate
+    pub insecure_skip_verify: bool,                 // accepts any upstream certificate, for development only
+}
+
+impl UpstreamTlsConfig {
+
+    pub fn trust_ca<P: Into<PathBuf>>(&mut self, ca_path: P) {
+        self.ca_path = Some(ca_path.into());
+    }
+
+    pub fn with_client_cert<P: Into<PathBuf>>(&mut self, cert_path: P, key_path: P) {
+        self.client_cert = Some((cert_path.into(), key_path.into()));
+    }
+
+    pub fn with_server_name(&mut self, server_name: &str) {
+        self.server_name = Some(server_name.to_string());
+    }
+
+    /// Don't use in production: upstream certificates and host names aren't verified at all
+    pub fn insecure_skip_verify(&mut self) {
+        self.insecure_skip_verify = true;
+    }
+}
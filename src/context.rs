@@ -0,0 +1,213 @@
+use std::net::IpAddr;
+use std::fmt::{Display, Formatter};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::watch;
+use hyper::{HeaderMap, Method, Uri, Version};
+use hyper::http::Extensions;
+use serde_json::{Map, Value};
+use std::net::SocketAddr;
+use x509_parser::extensions::GeneralName;
+
+/// Identity of a client authenticated by the listener through its TLS certificate (mTLS)
+/// Inserted in the request extensions, so that handlers can read it with `req.extensions().get::<ClientCertificate>()`
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    pub subject: String,    // i.e. "CN=client, O=Org"
+    pub sans: Vec<String>,  // subject alternative names, prefixed by their type: "DNS:", "IP:", "URI:" or "email:"
+    pub der: Vec<u8>,
+}
+
+impl ClientCertificate {
+
+    /// Reads subject and subject alternative names of an (already verified) DER encoded certificate
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext.value.general_names.iter().filter_map(format_san).collect(),
+            _ => vec![],
+        };
+        Some(ClientCertificate {
+            subject: cert.subject().to_string(),
+            sans,
+            der: der.to_vec(),
+        })
+    }
+}
+
+fn format_san(name: &GeneralName) -> Option<String> {
+    match name {
+        GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+        GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+        GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+        GeneralName::IPAddress(bytes) => match bytes.len() {
+            4 => <[u8; 4]>::try_from(*bytes).ok().map(|ip| format!("IP:{}", IpAddr::from(ip))),
+            16 => <[u8; 16]>::try_from(*bytes).ok().map(|ip| format!("IP:{}", IpAddr::from(ip))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Traffic of a proxied WebSocket connection, updated until the connection is closed
+/// Inserted as an `Arc<WebSocketStats>` in the extensions of the upgrade request and of the `101 Switching Protocols` response,
+/// so that handlers can keep it and read the counters while the connection is open, or once it's closed
+#[derive(Debug)]
+pub struct WebSocketStats {
+    pub bytes_to_upstream: AtomicU64,
+    pub bytes_to_client: AtomicU64,
+    pub messages_to_upstream: AtomicU64,    // complete data messages (text or binary, fragmented or not), control frames aren't counted
+    pub messages_to_client: AtomicU64,
+    closed: (watch::Sender<bool>, watch::Receiver<bool>),
+}
+
+impl Default for WebSocketStats {
+    fn default() -> Self {
+        WebSocketStats {
+            bytes_to_upstream: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+            messages_to_upstream: AtomicU64::new(0),
+            messages_to_client: AtomicU64::new(0),
+            closed: watch::channel(false),
+        }
+    }
+}
+
+impl WebSocketStats {
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.1.borrow()
+    }
+
+    /// Resolves once both connections have been closed (or the upgrade failed)
+    pub async fn closed(&self) {
+        let mut closed = self.closed.1.clone();
+        while !*closed.borrow() {
+            if closed.changed().await.is_err() {
+                return
+            }
+        }
+    }
+
+    pub(crate) fn to_upstream(&self, bytes: usize, messages: u64) {
+        self.bytes_to_upstream.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_to_upstream.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    pub(crate) fn to_client(&self, bytes: usize, messages: u64) {
+        self.bytes_to_client.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.messages_to_client.fetch_add(messages, Ordering::Relaxed);
+    }
+
+    pub(crate) fn close(&self) {
+        let _ = self.closed.0.send(true);
+    }
+}
+
+/// Marks responses relayed to the client as they're received (see `StreamingConfig`)
+/// Inserted in the response extensions before handlers are invoked
+#[derive(Debug, Clone, Copy)]
+pub struct Streaming;
+
+/// The request sent upstream (after handlers and transformers), inserted in the extensions of its response
+/// so that response handlers and finalizers know which call they're dealing with
+#[derive(Debug, Clone)]
+pub struct UpstreamRequest {
+    pub method: Method,
+    pub uri: Uri,
+}
+
+/// The request as received from the client, before the Api rewrote it for its upstream, inserted in the request extensions
+#[derive(Debug, Clone)]
+pub struct ClientRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+}
+
+/// Addresses of the client connection: as accepted by the listener, or carried by a PROXY protocol header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub remote_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+}
+
+/// Headers a handler wants on the response sent back to the client (i.e. rate limit status), inserted in the request extensions
+/// The Api adds them to every response, without overriding headers already set: by the upstream, or by a handler answering itself
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaders(pub HeaderMap);
+
+impl ResponseHeaders {
+
+    /// Adds `headers` to the ones already set for the request
+    pub fn append(req_extensions: &mut hyper::http::Extensions, headers: HeaderMap) {
+        match req_extensions.get_mut::<ResponseHeaders>() {
+            Some(existing) => existing.0.extend(headers),
+            None => { req_extensions.insert(ResponseHeaders(headers)); },
+        }
+    }
+}
+
+/// Rank of a request in the queue of a `ConcurrencyLimiter` once the upstream is saturated, set by handlers in the request extensions
+/// (i.e. from the tier of an API key, or for health-critical paths). Higher ranks go first, and lower ones are shed first
+/// Requests without one have the default priority, 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+pub struct Priority(pub i32);
+
+/// Identifies a request across the logs of the gateway and of its upstreams, set by `CorrelationIdHandler` in the request extensions
+/// Access logs and the errors of a request (upstream, WebSocket, forward proxy) carry it, L4 connections (see `tcp`) have none
+/// For W3C trace context, the trace ID of the `traceparent` header
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CorrelationId(pub String);
+
+impl CorrelationId {
+
+    /// Appended to the logs of a request: " (request <id>)", empty if the request has no correlation ID
+    pub(crate) fn log_tag(extensions: &Extensions) -> String {
+        extensions.get::<CorrelationId>().map(|id| format!(" (request {})", id)).unwrap_or_default()
+    }
+}
+
+impl Display for CorrelationId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Metadata of the API key a request was authenticated with, set by `ApiKeyHandler` in the request extensions
+/// The key itself is never kept: stores only know its hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub id: String,
+    pub owner: String,
+    pub plan: String,               // i.e. "free", "premium", for rate limits or priorities
+    pub prefixes: Vec<String>,      // paths the key grants access to, every path if empty
+    pub created_at: u64,            // seconds since the UNIX epoch
+}
+
+impl ApiKey {
+
+    /// Whether the key grants access to `path`: one of its prefixes matches whole segments of it
+    pub fn allows(&self, path: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix).map(|rest| rest.is_empty() || rest.starts_with('/')).unwrap_or(false)
+        })
+    }
+}
+
+/// Claims of the JWT a request was authenticated with, set by `JwtHandler` in the request extensions once the token is validated
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct JwtClaims(pub Map<String, Value>);
+
+impl JwtClaims {
+
+    pub fn get(&self, claim: &str) -> Option<&Value> {
+        self.0.get(claim)
+    }
+
+    /// The `sub` claim
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
+}
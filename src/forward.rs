@@ -0,0 +1,231 @@
+use crate::conf::forward::ForwardProxyConfig;
+use crate::context::CorrelationId;
+use crate::handlers::{GlobalHandler, HandlerResponse};
+use crate::tls::TlsError;
+use crate::tls::client::UpstreamConnector;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Version};
+use log::debug;
+use tokio::net::TcpStream;
+
+/// Runtime state of a `ForwardProxyConfig`
+#[derive(Debug)]
+pub(crate) struct ForwardProxy {
+    conf: ForwardProxyConfig,
+    client: Client<UpstreamConnector>,
+}
+
+impl ForwardProxy {
+
+    pub(crate) fn new(conf: ForwardProxyConfig) -> Result<Self, TlsError> {
+        let mut tls = conf.tls.clone();
+        tls.server_name = None; // each destination is checked against its own name
+        let mut connector = UpstreamConnector::new(&tls, &[])?;
+        connector.set_connect_timeout(conf.connect_timeout);
+        Ok(ForwardProxy { conf, client: Client::builder().build(connector) })
+    }
+
+    /// HTTP/2 requests always carry a scheme and an authority, only CONNECT can be told apart
+    pub(crate) fn is_forward(req: &Request<Body>) -> bool {
+        req.method() == Method::CONNECT || (req.version() <= Version::HTTP_11 && req.uri().scheme().is_some())
+    }
+
+    pub(crate) async fn proxy(&self, mut req: Request<Body>) -> Response<Body> {
+        let allowed = self.conf.allowed.iter().map(|allowed| allowed as &dyn GlobalHandler);
+        for handler in allowed.chain(self.conf.global_handlers.iter().map(Box::as_ref)) {
+            if let HandlerResponse::Break(resp) = handler.handle_req(&mut req) {
+                return resp
+            }
+        }
+        let mut resp = if req.method() == Method::CONNECT {
+            self.tunnel(req).await
+        } else if !matches!(req.uri().scheme_str(), Some("http") | Some("https")) {
+            status(StatusCode::BAD_REQUEST)
+        } else {
+            for header in &["proxy-connection", "proxy-authorization"] {
+                req.headers_mut().remove(*header);
+            }
+            let tag = CorrelationId::log_tag(req.extensions());
+            match self.client.request(req).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    debug!("Could not forward request{}: {}", tag, e);
+                    status(StatusCode::BAD_GATEWAY)
+                },
+            }
+        };
+        for handler in &self.conf.global_handlers {
+            if let HandlerResponse::Break(overriden) = handler.handle_res(&mut resp) {
+                return overriden
+            }
+        }
+        resp
+    }
+
+    /// Connects to the destination, then splices it with the client connection once the `200` response is sent
+    async fn tunnel(&self, req: Request<Body>) -> Response<Body> {
+        let authority = match req.uri().authority() {
+            Some(authority) if authority.port_u16().is_some() => authority.to_string(),
+            _ => return status(StatusCode::BAD_REQUEST),
+        };
+        let tag = CorrelationId::log_tag(req.extensions());
+        let upstream = match tokio::time::timeout(self.conf.connect_timeout, TcpStream::connect(&authority)).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                debug!("Could not open tunnel to {}{}: {}", authority, tag, e);
+                return status(StatusCode::BAD_GATEWAY)
+            },
+            Err(_) => return status(StatusCode::GATEWAY_TIMEOUT),
+        };
+        let idle_timeout = self.conf.tunnel_idle_timeout;
+        tokio::spawn(async move {
+            match hyper::upgrade::on(req).await {
+                Ok(client) => if let Err(e) = crate::tcp::relay(client, upstream, idle_timeout, None).await {
+                    debug!("Tunnel to {} closed{}: {}", authority, tag, e);
+                },
+                Err(e) => debug!("Tunnel to {} not established{}: {}", authority, tag, e),
+            }
+        });
+        status(StatusCode::OK)
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::forward::ForwardProxyConfig;
+    use crate::conf::listener::ListenerConfig;
+    use crate::conf::tls::TlsConfig;
+    use crate::forward::ForwardProxy;
+    use crate::gateway::start_forward_proxy;
+    use crate::handlers::destinations::AllowedDestinations;
+    use crate::tests::{self_signed, test_server, tls_test_server, wait_for_gateway, wait_for_port, unwrap_body_as_str};
+    use hyper::{Body, Method, Request, Response, Server, StatusCode};
+    use hyper::client::conn::SendRequest;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn echo_server(port: u16) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    }
+
+    /// Absolute-form URIs are sent as is on raw connections (unlike `Client`, which rewrites them to origin-form)
+    async fn proxy_connection(port: u16) -> SendRequest<Body> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        sender
+    }
+
+    #[tokio::test]
+    async fn forward_requests_and_tunnels() {
+        let proxy_port = 13000;
+        let origin_port = 13001;
+        let echo_port = 13002;
+        let api_port = 13003;
+        tokio::spawn(async move { test_server("origin", origin_port).await });
+        tokio::spawn(async move { echo_server(echo_port).await });
+        tokio::spawn(async move { test_server("api", api_port).await });
+        let mut allowed = AllowedDestinations::hosts(&["127.0.0.1"]);
+        allowed.with_ports(&[origin_port, echo_port]);
+        let mut proxy = ForwardProxyConfig::new(allowed);
+        proxy.with_tunnel_idle_timeout(Duration::from_secs(5));
+        let apis = vec![Api::http("127.0.0.1", api_port, "/api".to_string()).unwrap()];
+        tokio::spawn(async move { start_forward_proxy(ListenerConfig::local(proxy_port), proxy, apis).await });
+        wait_for_port(origin_port).await;
+        wait_for_port(echo_port).await;
+        wait_for_port(api_port).await;
+        wait_for_gateway(proxy_port).await;
+
+        let mut sender = proxy_connection(proxy_port).await;
+        let req = Request::get(format!("http://127.0.0.1:{}/anything", origin_port)).body(Body::empty()).unwrap();
+        assert_eq!("origin", unwrap_body_as_str(sender.send_request(req).await.unwrap()).await);
+        let req = Request::get(format!("http://127.0.0.1:{}/", api_port)).body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, sender.send_request(req).await.unwrap().status());
+        let req = Request::get("/api").body(Body::empty()).unwrap(); // origin-form: routed to the Apis
+        assert_eq!("api", unwrap_body_as_str(sender.send_request(req).await.unwrap()).await);
+
+        let connect = |authority: String| Request::builder().method(Method::CONNECT).uri(authority).body(Body::empty()).unwrap();
+        let mut sender = proxy_connection(proxy_port).await;
+        assert_eq!(StatusCode::FORBIDDEN, sender.send_request(connect("example.com:443".to_string())).await.unwrap().status());
+        let resp = sender.send_request(connect(format!("127.0.0.1:{}", echo_port))).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let mut tunnel = hyper::upgrade::on(resp).await.unwrap();
+        tunnel.write_all(b"through the tunnel").await.unwrap();
+        let mut buf = [0u8; 18];
+        tokio::time::timeout(Duration::from_secs(5), tunnel.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(b"through the tunnel", &buf);
+    }
+
+    #[tokio::test]
+    async fn https_destinations() {
+        let proxy_port = 13004;
+        let origin_port = 13005;
+        let cert = self_signed(&["localhost"]);
+        let origin_tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        tokio::spawn(async move { tls_test_server("secure origin", origin_port, origin_tls).await });
+        let mut proxy = ForwardProxyConfig::new(AllowedDestinations::hosts(&["localhost"]));
+        proxy.tls.trust_ca(&cert.cert_path);
+        tokio::spawn(async move { start_forward_proxy(ListenerConfig::local(proxy_port), proxy, vec![]).await });
+        wait_for_port(origin_port).await;
+        wait_for_gateway(proxy_port).await;
+
+        let mut sender = proxy_connection(proxy_port).await;
+        let req = Request::get(format!("https://localhost:{}/", origin_port)).body(Body::empty()).unwrap();
+        assert_eq!("secure origin", unwrap_body_as_str(sender.send_request(req).await.unwrap()).await);
+        let req = Request::get("ftp://localhost/file").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, sender.send_request(req).await.unwrap().status());
+    }
+
+    /// Replies with the X-Forwarded-For header it received
+    async fn forwarded_for_server(port: u16) {
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let forwarded_for = req.headers().get("x-forwarded-for").map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+                Ok::<_, Infallible>(Response::new(Body::from(forwarded_for)))
+            }))
+        });
+        Server::bind(&([127, 0, 0, 1], port).into()).serve(make_svc).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_address_is_only_disclosed_to_apis() {
+        let proxy_port = 13708;
+        let origin_port = 13709;
+        tokio::spawn(async move { forwarded_for_server(origin_port).await });
+        let mut allowed = AllowedDestinations::hosts(&["127.0.0.1"]);
+        allowed.with_ports(&[origin_port]);
+        let apis = vec![Api::http("127.0.0.1", origin_port, "/api".to_string()).unwrap()];
+        tokio::spawn(async move { start_forward_proxy(ListenerConfig::local(proxy_port), ForwardProxyConfig::new(allowed), apis).await });
+        wait_for_port(origin_port).await;
+        wait_for_gateway(proxy_port).await;
+
+        let mut sender = proxy_connection(proxy_port).await;
+        let req = Request::get(format!("http://127.0.0.1:{}/", origin_port)).body(Body::empty()).unwrap();
+        assert_eq!("", unwrap_body_as_str(sender.send_request(req).await.unwrap()).await);
+        let req = Request::get("/api").body(Body::empty()).unwrap();
+        assert_eq!("127.0.0.1", unwrap_body_as_str(sender.send_request(req).await.unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn destinations_are_denied_unless_allowed() {
+        let unreachable = || Request::get("http://127.0.0.1:13006/").body(Body::empty()).unwrap();
+        let proxy = ForwardProxy::new(ForwardProxyConfig::new(AllowedDestinations::hosts(&["example.com"]))).unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, proxy.proxy(unreachable()).await.status());
+        let open = ForwardProxy::new(ForwardProxyConfig::allow_all()).unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, open.proxy(unreachable()).await.status());
+    }
+
+}
@@ -4,9 +4,28 @@ use futures::task::{Context, Poll};
 use std::pin::Pin;
 use std::future::Future;
 use crate::conf::api::Api;
-use log::info;
+use crate::conf::forward::ForwardProxyConfig;
+use crate::conf::listener::ListenerConfig;
+use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+use crate::conf::tls::TlsConfig;
+use crate::context::{ClientCertificate, ClientInfo};
+use crate::forward::ForwardProxy;
+use crate::proxy_protocol;
+use crate::grpc;
+use crate::tls::TlsError;
+use crate::tls::server::{TlsListener, negotiated_h2, peer_certificate};
+use hyper::header::{HeaderValue, ALT_SVC};
+use hyper::server::conn::Http;
+use log::{info, debug, error};
 use std::sync::Arc;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use tokio::net::TcpListener;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// Clients remember an advertised HTTP/3 endpoint for this long (in seconds)
+const ALT_SVC_MAX_AGE: u32 = 86400;
 
 type PinnedResponseFuture = Pin<Box<dyn Future<Output = Result<Response<Body>, Error>> + Send>>;
 type PinnedGatewayFuture = Pin<Box<dyn Future<Output = Result<Gateway, Error>> + Send>>;
@@ -19,8 +38,175 @@ pub async fn start_local_gateway(port: u16, apis: Vec<Api>) -> Result<(), Error>
     server.await
 }
 
+/// Same as `start_local_gateway` but terminating TLS on the listener
+/// HTTP/2 is served to clients negotiating `h2` through ALPN
+pub async fn start_tls_gateway(port: u16, apis: Vec<Api>, tls: TlsConfig) -> Result<(), GatewayError> {
+    let mut listener = ListenerConfig::local(port);
+    listener.with_tls(tls);
+    start_gateway(listener, apis).await
+}
+
+/// Serves the gateway through an existing `TlsListener`
+/// Keep a clone of the listener to reload its certificates on demand
+pub async fn start_tls_listener(port: u16, apis: Vec<Api>, tls: TlsListener) -> Result<(), GatewayError> {
+    serve(ListenerConfig::local(port), apis, Some(tls), None).await
+}
+
+/// Starts a gateway with the listener settings: address, TLS, HTTP protocol(s) spoken to clients
+pub async fn start_gateway(listener: ListenerConfig, apis: Vec<Api>) -> Result<(), GatewayError> {
+    let tls = match listener.tls_config() {
+        Some(tls) => Some(TlsListener::new(tls)?),
+        None => None,
+    };
+    serve(listener, apis, tls, None).await
+}
+
+/// Starts a gateway acting as a forward proxy as well, see `ForwardProxyConfig`
+pub async fn start_forward_proxy(listener: ListenerConfig, proxy: ForwardProxyConfig, apis: Vec<Api>) -> Result<(), GatewayError> {
+    let tls = match listener.tls_config() {
+        Some(tls) => Some(TlsListener::new(tls)?),
+        None => None,
+    };
+    serve(listener, apis, tls, Some(Arc::new(ForwardProxy::new(proxy)?))).await
+}
+
+async fn serve(conf: ListenerConfig, apis: Vec<Api>, tls: Option<TlsListener>, forward: Option<Arc<ForwardProxy>>) -> Result<(), GatewayError> {
+    if let Some((tls, interval)) = tls.as_ref().and_then(|tls| tls.conf().watch_interval.map(|interval| (tls, interval))) {
+        tokio::spawn(tls.clone().watch(interval));
+    }
+    let apis: Vec<Arc<Api>> = apis.into_iter().map(Arc::new).collect();
+    let listener = TcpListener::bind(conf.address).await?;
+    info!("Listening on {}://{}", if tls.is_some() { "https" } else { "http" }, conf.address);
+    let protocol = Arc::new(conf.protocol);
+    let alt_svc = conf.http3_port.map(|port| HeaderValue::from_str(&format!("h3=\":{}\"; ma={}", port, ALT_SVC_MAX_AGE)).unwrap());
+    loop {
+        let (mut stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                continue
+            }
+        };
+        let tls = tls.clone();
+        let protocol = protocol.clone();
+        let mut gateway = Gateway::new(apis.clone());
+        gateway.forward = forward.clone();
+        gateway.alt_svc = alt_svc.clone();
+        let proxy_protocol = conf.proxy_protocol;
+        tokio::spawn(async move {
+            let mut client_info = ClientInfo { remote_addr, local_addr: stream.local_addr().unwrap_or(remote_addr) };
+            if proxy_protocol {
+                match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(&mut stream)).await {
+                    Ok(Ok(Some(header))) => client_info = ClientInfo { remote_addr: header.source, local_addr: header.destination },
+                    Ok(Ok(None)) => {},
+                    Ok(Err(e)) => return debug!("Invalid PROXY protocol header from {}: {}", remote_addr, e),
+                    Err(_) => return debug!("No PROXY protocol header received from {}", remote_addr),
+                }
+            }
+            gateway.client_info = Some(client_info);
+            let served = match tls {
+                None => http(&protocol, false).serve_connection(stream, gateway).with_upgrades().await,
+                Some(tls) => match tls.accept(stream).await {
+                    Err(e) => return debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Ok(stream) => {
+                        let h2 = negotiated_h2(&stream);
+                        gateway.client_cert = peer_certificate(&stream).and_then(|der| ClientCertificate::parse(&der));
+                        http(&protocol, h2).serve_connection(stream, gateway).with_upgrades().await
+                    }
+                },
+            };
+            if let Err(e) = served {
+                debug!("Connection with {} closed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+/// Serves the gateway on a Unix domain socket, speaking HTTP/1.1 or HTTP/2 with prior knowledge
+/// A socket left at `path` by a previous run is replaced
+#[cfg(unix)]
+pub async fn start_unix_gateway(path: &Path, apis: Vec<Api>) -> Result<(), GatewayError> {
+    use std::os::unix::fs::FileTypeExt;
+    if std::fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) {
+        std::fs::remove_file(path)?;
+    }
+    let apis: Vec<Arc<Api>> = apis.into_iter().map(Arc::new).collect();
+    let listener = tokio::net::UnixListener::bind(path)?;
+    info!("Listening on unix:{}", path.display());
+    let protocol = ProtocolConfig::default();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                continue
+            }
+        };
+        let served = http(&protocol, false).serve_connection(stream, Gateway::new(apis.clone())).with_upgrades();
+        tokio::spawn(async move {
+            if let Err(e) = served.await {
+                debug!("Unix socket connection closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Connection settings for the listener protocol
+/// Without ALPN negotiation (cleartext or protocol not advertised by the client), `Auto` detects the HTTP/2 preface
+fn http(protocol: &ProtocolConfig, negotiated_h2: bool) -> Http {
+    let mut http = Http::new();
+    match protocol.protocol {
+        HttpProtocol::Http1 => http.http1_only(true),
+        HttpProtocol::Http2 => http.http2_only(true),
+        HttpProtocol::Auto => http.http2_only(negotiated_h2),
+    };
+    let settings = &protocol.http2;
+    http.http2_max_concurrent_streams(settings.max_concurrent_streams)
+        .http2_initial_stream_window_size(settings.initial_stream_window_size)
+        .http2_initial_connection_window_size(settings.initial_connection_window_size)
+        .http2_adaptive_window(settings.adaptive_window)
+        .http2_max_frame_size(settings.max_frame_size)
+        .http2_keep_alive_interval(settings.keep_alive_interval);
+    http
+}
+
+/// Why a gateway couldn't be started
+#[derive(Debug)]
+pub enum GatewayError {
+    Io(std::io::Error),
+    Tls(TlsError),
+}
+
+impl Display for GatewayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GatewayError::Io(e) => write!(f, "Could not listen: {}", e),
+            GatewayError::Tls(e) => write!(f, "Invalid TLS configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+impl From<std::io::Error> for GatewayError {
+    fn from(e: std::io::Error) -> Self {
+        GatewayError::Io(e)
+    }
+}
+
+impl From<TlsError> for GatewayError {
+    fn from(e: TlsError) -> Self {
+        GatewayError::Tls(e)
+    }
+}
+
+#[derive(Clone)]
 pub struct Gateway {
-    by_path: HashMap<String, Arc<Api>>
+    by_path: HashMap<String, Arc<Api>>,
+    client_cert: Option<ClientCertificate>, // verified during the TLS handshake, shared by every request of the connection
+    forward: Option<Arc<ForwardProxy>>,
+    pub(crate) client_info: Option<ClientInfo>,
+    alt_svc: Option<HeaderValue>,
 }
 
 impl Service<Request<Body>> for Gateway {
@@ -32,30 +218,39 @@ impl Service<Request<Body>> for Gateway {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if let Some(client_cert) = &self.client_cert {
+            req.extensions_mut().insert(client_cert.clone());
+        }
+        if let Some(client_info) = self.client_info {
+            req.extensions_mut().insert(client_info);
+        }
+        if let Some(forward) = self.forward.clone().filter(|_| ForwardProxy::is_forward(&req)) {
+            return Box::pin(async move { Ok(forward.proxy(req).await) }) // the client address isn't disclosed to destinations
+        }
+        if let Some(client_info) = self.client_info {
+            append_forwarded_for(&mut req, client_info);
+        }
         let api: Option<Arc<Api>> = self.match_path(&req).cloned();
+        let alt_svc = self.alt_svc.clone();
         Box::pin(
             async move {
                 let path = req.uri().path();
                 if path == "/health" {
                     return Ok(Response::builder().status(200).body(Body::empty()).unwrap())
                 }
-                match api {
-                    Some(api) => {
-                        let resp = api.proxy(req).await;
-                        if resp.is_err() {
-                            log::error!("{:?}", resp);
-                            return Ok(Response::builder()
-                                .status(StatusCode::BAD_GATEWAY)
-                                .body(Body::empty()).unwrap())
-                        }
-                        resp
-                    },
+                let grpc = grpc::is_grpc(req.headers());
+                let mut resp = match api {
+                    Some(api) => api.proxy(req).await,
                     None =>
-                        Ok(Response::builder()
+                        Response::builder()
                             .status(StatusCode::NOT_FOUND)
-                            .body(Body::empty()).unwrap())
+                            .body(Body::empty()).unwrap()
+                };
+                if let Some(alt_svc) = alt_svc {
+                    resp.headers_mut().entry(ALT_SVC).or_insert(alt_svc);
                 }
+                Ok(if grpc { grpc::translate_error(resp) } else { resp })
             }
         )
     }
@@ -63,18 +258,34 @@ impl Service<Request<Body>> for Gateway {
 }
 
 impl Gateway {
-    fn new(apis: Vec<Arc<Api>>) -> Self {
+    pub(crate) fn new(apis: Vec<Arc<Api>>) -> Self {
         let mut map = HashMap::with_capacity(apis.len());
         for api in apis {
             map.insert(api.prefix.clone(), api);
         }
-        Gateway { by_path: map }
+        Gateway { by_path: map, client_cert: None, forward: None, client_info: None, alt_svc: None }
     }
+    /// Matches the first path segment, or the first two ones if an Api is registered for both (i.e. gRPC methods: "/package.Service/Method")
+    /// Requests without an origin-form path (CONNECT, "OPTIONS *") match no Api
     fn match_path(&self, req: &Request<Body>) -> Option<&Arc<Api>> {
         let path = req.uri().path();
-        let a = &path[1..].find('/');
+        let a = &path.strip_prefix('/')?.find('/');
         let id = a.map(|fst| &path[0..fst + 1]).unwrap_or(path);
-        self.by_path.get(&id.to_string())
+        let two_segments = path[id.len()..].get(1..)
+            .map(|rest| rest.find('/').map(|snd| &path[0..id.len() + snd + 1]).unwrap_or(path));
+        two_segments.and_then(|id| self.by_path.get(id)).or_else(|| self.by_path.get(id))
+    }
+}
+
+/// Appends the client IP to `X-Forwarded-For`, after the ones set by proxies in front of the gateway
+fn append_forwarded_for(req: &mut Request<Body>, client_info: ClientInfo) {
+    let client_ip = client_info.remote_addr.ip().to_string();
+    let forwarded_for = match req.headers().get(X_FORWARDED_FOR).and_then(|value| value.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, client_ip),
+        None => client_ip,
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        req.headers_mut().insert(X_FORWARDED_FOR, value);
     }
 }
 
@@ -103,9 +314,18 @@ mod tests {
     use std::convert::Infallible;
     use hyper::service::{make_service_fn, service_fn};
     use std::net::SocketAddr;
-    use crate::gateway::{start_local_gateway};
+    use crate::gateway::{start_local_gateway, start_gateway, Gateway};
+    use hyper::Method;
+    use hyper::service::Service;
+    use std::sync::Arc;
     use crate::conf::api::Api;
-    use crate::tests::{test_server, wait_for_gateway, unwrap_body_as_str};
+    use crate::conf::listener::ListenerConfig;
+    use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+    use crate::conf::tls::{TlsConfig, UpstreamTlsConfig};
+    use crate::tests::{test_server, wait_for_gateway, wait_for_port, self_signed, unwrap_body_as_str};
+    use hyper::Version;
+    use hyper::client::HttpConnector;
+    use hyper::server::conn::Http;
     use std::str::FromStr;
     use hyper::http::HeaderValue;
     use log::*;
@@ -153,6 +373,40 @@ mod tests {
 
     }
 
+    async fn echo_version_server(port: u16) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::<Body>::new(format!("{:?}", req.version()).into()))
+            }))
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    async fn tls_echo_version_server(port: u16, tls: TlsConfig) {
+        let acceptor = crate::tls::server::TlsListener::new(tls).unwrap();
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let h2 = crate::tls::server::negotiated_h2(&stream);
+                    let service = service_fn(|req: Request<Body>| async move {
+                        Ok::<_, Infallible>(Response::<Body>::new(format!("{:?}", req.version()).into()))
+                    });
+                    let _ = Http::new().http2_only(h2).serve_connection(stream, service).await;
+                }
+            });
+        }
+    }
+
+    async fn get_version(client: &Client<HttpConnector>, url: String) -> Result<(Version, String), hyper::Error> {
+        let resp = client.get(Uri::from_str(&url).unwrap()).await?;
+        assert_eq!(StatusCode::OK, resp.status());
+        Ok((resp.version(), unwrap_body_as_str(resp).await))
+    }
+
     #[tokio::test]
     async fn test() {
         let gw_port = 3000;
@@ -331,4 +585,90 @@ mod tests {
 
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn listener_protocols() {
+        let auto_port = 12300;
+        let http1_port = 12301;
+        let http2_port = 12302;
+        let backend_port = 12303;
+        tokio::spawn(async move { echo_version_server(backend_port).await });
+        for (port, protocol) in [(auto_port, HttpProtocol::Auto), (http1_port, HttpProtocol::Http1), (http2_port, HttpProtocol::Http2)] {
+            tokio::spawn(async move {
+                let mut protocol = ProtocolConfig::new(protocol);
+                protocol.with_max_concurrent_streams(2);
+                protocol.with_window_sizes(1024 * 1024, 4 * 1024 * 1024);
+                let mut listener = ListenerConfig::local(port);
+                listener.with_protocol(protocol);
+                start_gateway(listener, vec![Api::http("127.0.0.1", backend_port, "/version".to_string()).unwrap()]).await
+            });
+            wait_for_port(port).await;
+        }
+        let http1 = Client::new();
+        let h2c = Client::builder().http2_only(true).build_http();
+        let url = |port: u16| format!("http://127.0.0.1:{}/version", port);
+
+        assert_eq!((Version::HTTP_11, "HTTP/1.1".to_string()), get_version(&http1, url(auto_port)).await.unwrap());
+        assert_eq!(Version::HTTP_2, get_version(&h2c, url(auto_port)).await.unwrap().0);
+        assert_eq!(Version::HTTP_11, get_version(&http1, url(http1_port)).await.unwrap().0);
+        assert!(get_version(&h2c, url(http1_port)).await.is_err());
+        assert_eq!(Version::HTTP_2, get_version(&h2c, url(http2_port)).await.unwrap().0);
+        assert!(get_version(&http1, url(http2_port)).await.is_err());
+
+        // more concurrent requests than allowed streams: queued by the client, all served
+        let requests = (0..10).map(|_| get_version(&h2c, url(auto_port)));
+        for resp in futures::future::join_all(requests).await {
+            assert_eq!(Version::HTTP_2, resp.unwrap().0);
+        }
+    }
+
+    #[tokio::test]
+    async fn upstream_protocols() {
+        let gw_port = 12310;
+        let backend_port = 12311;
+        let tls_backend_port = 12312;
+        let cert = self_signed(&["localhost"]);
+        let mut upstream_tls = UpstreamTlsConfig::default();
+        upstream_tls.trust_ca(&cert.cert_path);
+        upstream_tls.with_server_name("localhost");
+        let backend_tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        let tls_address = format!("127.0.0.1:{}", tls_backend_port);
+        tokio::spawn(async move { echo_version_server(backend_port).await });
+        tokio::spawn(async move { tls_echo_version_server(tls_backend_port, backend_tls).await });
+        tokio::spawn(async move {
+            let mut h2c = ProtocolConfig::new(HttpProtocol::Http2);
+            h2c.with_adaptive_window();
+            let apis = vec![
+                Api::http("127.0.0.1", backend_port, "/default".to_string()).unwrap(),
+                Api::http_with("127.0.0.1", backend_port, "/h2c".to_string(), &h2c).unwrap(),
+                Api::http_with("127.0.0.1", backend_port, "/auto".to_string(), &ProtocolConfig::default()).unwrap(),
+                Api::https_with(&tls_address, "/tls_http1".to_string(), &upstream_tls, &ProtocolConfig::new(HttpProtocol::Http1)).unwrap(),
+                Api::https_with(&tls_address, "/tls_auto".to_string(), &upstream_tls, &ProtocolConfig::default()).unwrap(),
+                Api::https_with(&tls_address, "/tls_http2".to_string(), &upstream_tls, &ProtocolConfig::new(HttpProtocol::Http2)).unwrap(),
+            ];
+            start_local_gateway(gw_port, apis).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_port(tls_backend_port).await;
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let upstream_version = |prefix: &str| get_version(&client, format!("http://127.0.0.1:{}{}", gw_port, prefix));
+
+        assert_eq!("HTTP/1.1", upstream_version("/default").await.unwrap().1);
+        assert_eq!("HTTP/2.0", upstream_version("/h2c").await.unwrap().1);
+        assert_eq!("HTTP/1.1", upstream_version("/auto").await.unwrap().1);
+        assert_eq!("HTTP/1.1", upstream_version("/tls_http1").await.unwrap().1);
+        assert_eq!("HTTP/2.0", upstream_version("/tls_auto").await.unwrap().1);
+        assert_eq!("HTTP/2.0", upstream_version("/tls_http2").await.unwrap().1);
+    }
+
+
+    #[tokio::test]
+    async fn requests_without_a_path_match_no_api() {
+        let mut gateway = Gateway::new(vec![Arc::new(Api::http("127.0.0.1", 13707, "/api".to_string()).unwrap())]);
+        let connect = Request::builder().method(Method::CONNECT).uri("example.com:443").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, gateway.call(connect).await.unwrap().status());
+        let options = Request::builder().method(Method::OPTIONS).uri("*").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, gateway.call(options).await.unwrap().status());
+    }
+
+}
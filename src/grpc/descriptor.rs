@@ -0,0 +1,522 @@
+use crate::grpc::wire::{Reader, WireValue};
+use hyper::Method;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// `google.api.http` extension number, on `google.protobuf.MethodOptions`
+const HTTP_RULE_EXTENSION: u32 = 72295728;
+
+/// Messages, enums and methods of a `FileDescriptorSet` (i.e. `protoc --include_imports --descriptor_set_out=...`)
+/// Type names are fully qualified, without leading dot: "library.v1.Shelf"
+#[derive(Debug, Default)]
+pub struct DescriptorPool {
+    pub messages: HashMap<String, Arc<MessageDescriptor>>,
+    pub enums: HashMap<String, EnumDescriptor>,
+    pub methods: Vec<MethodDescriptor>,
+}
+
+#[derive(Debug)]
+pub struct MessageDescriptor {
+    pub name: String,
+    pub fields: Vec<FieldDescriptor>,
+    pub map_entry: bool,    // synthetic message of a map field, with `key` (1) and `value` (2) fields
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDescriptor {
+    pub name: String,
+    pub json_name: String,
+    pub number: u32,
+    pub field_type: FieldType,
+    pub repeated: bool,
+    pub type_name: String,  // message or enum type
+}
+
+/// `FieldDescriptorProto.Type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Double, Float, Int64, Uint64, Int32, Fixed64, Fixed32, Bool, String, Group, Message, Bytes, Uint32, Enum, Sfixed32, Sfixed64, Sint32, Sint64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EnumDescriptor {
+    pub values: Vec<(String, i32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MethodDescriptor {
+    pub service: String,    // "library.v1.LibraryService"
+    pub name: String,       // "GetShelf"
+    pub input_type: String,
+    pub output_type: String,
+    pub client_streaming: bool,
+    pub server_streaming: bool,
+    pub http_rule: Option<HttpRule>,
+}
+
+/// `google.api.HttpRule`: how a REST call maps to a method
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRule {
+    pub method: Method,
+    pub path: String,                   // template, i.e. "/v1/shelves/{shelf}/books/{book.id}"
+    pub body: Option<String>,           // "*" for the whole request message, or a field name
+    pub response_body: Option<String>,  // field of the response message returned instead of the whole message
+    pub additional_bindings: Vec<HttpRule>,
+}
+
+impl MethodDescriptor {
+
+    /// gRPC path of the method: "/library.v1.LibraryService/GetShelf"
+    pub fn path(&self) -> String {
+        format!("/{}/{}", self.service, self.name)
+    }
+}
+
+impl MessageDescriptor {
+
+    pub fn field(&self, number: u32) -> Option<&FieldDescriptor> {
+        self.fields.iter().find(|field| field.number == number)
+    }
+
+    /// Accepts both the JSON and the original field names
+    pub fn field_by_name(&self, name: &str) -> Option<&FieldDescriptor> {
+        self.fields.iter().find(|field| field.json_name == name || field.name == name)
+    }
+}
+
+impl DescriptorPool {
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut pool = DescriptorPool::default();
+        let mut set = Reader::new(bytes);
+        while let Some((number, value)) = set.next_field()? {
+            if let (1, WireValue::Bytes(file)) = (number, value) {
+                pool.add_file(file)?;
+            }
+        }
+        Ok(pool)
+    }
+
+    pub fn message(&self, name: &str) -> Result<&Arc<MessageDescriptor>, String> {
+        self.messages.get(name).ok_or_else(|| format!("Unknown message type {}", name))
+    }
+
+    fn add_file(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let mut package = String::new();
+        let (mut messages, mut enums, mut services) = (vec![], vec![], vec![]);
+        let mut file = Reader::new(bytes);
+        while let Some((number, value)) = file.next_field()? {
+            match (number, value) {
+                (2, WireValue::Bytes(name)) => package = string(name)?,
+                (4, WireValue::Bytes(message)) => messages.push(message),
+                (5, WireValue::Bytes(enum_type)) => enums.push(enum_type),
+                (6, WireValue::Bytes(service)) => services.push(service),
+                _ => {},
+            }
+        }
+        for message in messages {
+            self.add_message(&package, message)?;
+        }
+        for enum_type in enums {
+            self.add_enum(&package, enum_type)?;
+        }
+        for service in services {
+            self.add_service(&package, service)?;
+        }
+        Ok(())
+    }
+
+    fn add_message(&mut self, scope: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let (mut fields, mut nested, mut enums) = (vec![], vec![], vec![]);
+        let mut map_entry = false;
+        let mut message = Reader::new(bytes);
+        while let Some((number, value)) = message.next_field()? {
+            match (number, value) {
+                (1, WireValue::Bytes(value)) => name = string(value)?,
+                (2, WireValue::Bytes(field)) => fields.push(field_descriptor(field)?),
+                (3, WireValue::Bytes(message)) => nested.push(message),
+                (4, WireValue::Bytes(enum_type)) => enums.push(enum_type),
+                (7, WireValue::Bytes(options)) => map_entry = bool_option(options, 7)?,
+                _ => {},
+            }
+        }
+        let full_name = qualified(scope, &name);
+        for message in nested {
+            self.add_message(&full_name, message)?;
+        }
+        for enum_type in enums {
+            self.add_enum(&full_name, enum_type)?;
+        }
+        self.messages.insert(full_name.clone(), Arc::new(MessageDescriptor { name: full_name, fields, map_entry }));
+        Ok(())
+    }
+
+    fn add_enum(&mut self, scope: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let mut descriptor = EnumDescriptor::default();
+        let mut enum_type = Reader::new(bytes);
+        while let Some((number, value)) = enum_type.next_field()? {
+            match (number, value) {
+                (1, WireValue::Bytes(value)) => name = string(value)?,
+                (2, WireValue::Bytes(value)) => {
+                    let (mut value_name, mut value_number) = (String::new(), 0);
+                    let mut enum_value = Reader::new(value);
+                    while let Some((number, value)) = enum_value.next_field()? {
+                        match (number, value) {
+                            (1, WireValue::Bytes(value)) => value_name = string(value)?,
+                            (2, WireValue::Varint(value)) => value_number = value as i32,
+                            _ => {},
+                        }
+                    }
+                    descriptor.values.push((value_name, value_number));
+                },
+                _ => {},
+            }
+        }
+        self.enums.insert(qualified(scope, &name), descriptor);
+        Ok(())
+    }
+
+    fn add_service(&mut self, package: &str, bytes: &[u8]) -> Result<(), String> {
+        let mut name = String::new();
+        let mut methods = vec![];
+        let mut service = Reader::new(bytes);
+        while let Some((number, value)) = service.next_field()? {
+            match (number, value) {
+                (1, WireValue::Bytes(value)) => name = string(value)?,
+                (2, WireValue::Bytes(method)) => methods.push(method),
+                _ => {},
+            }
+        }
+        let service = qualified(package, &name);
+        for bytes in methods {
+            let mut method = MethodDescriptor {
+                service: service.clone(),
+                name: String::new(),
+                input_type: String::new(),
+                output_type: String::new(),
+                client_streaming: false,
+                server_streaming: false,
+                http_rule: None,
+            };
+            let mut reader = Reader::new(bytes);
+            while let Some((number, value)) = reader.next_field()? {
+                match (number, value) {
+                    (1, WireValue::Bytes(value)) => method.name = string(value)?,
+                    (2, WireValue::Bytes(value)) => method.input_type = type_name(value)?,
+                    (3, WireValue::Bytes(value)) => method.output_type = type_name(value)?,
+                    (4, WireValue::Bytes(options)) => method.http_rule = method_http_rule(options)?,
+                    (5, WireValue::Varint(value)) => method.client_streaming = value != 0,
+                    (6, WireValue::Varint(value)) => method.server_streaming = value != 0,
+                    _ => {},
+                }
+            }
+            self.methods.push(method);
+        }
+        Ok(())
+    }
+}
+
+impl FieldType {
+    fn from_number(number: u64) -> Result<Self, String> {
+        use FieldType::*;
+        Ok(match number {
+            1 => Double, 2 => Float, 3 => Int64, 4 => Uint64, 5 => Int32, 6 => Fixed64, 7 => Fixed32, 8 => Bool, 9 => String,
+            10 => Group, 11 => Message, 12 => Bytes, 13 => Uint32, 14 => Enum, 15 => Sfixed32, 16 => Sfixed64, 17 => Sint32, 18 => Sint64,
+            other => return Err(format!("Unknown field type {}", other)),
+        })
+    }
+}
+
+fn field_descriptor(bytes: &[u8]) -> Result<FieldDescriptor, String> {
+    let mut field = FieldDescriptor {
+        name: String::new(),
+        json_name: String::new(),
+        number: 0,
+        field_type: FieldType::Int32,
+        repeated: false,
+        type_name: String::new(),
+    };
+    let mut reader = Reader::new(bytes);
+    while let Some((number, value)) = reader.next_field()? {
+        match (number, value) {
+            (1, WireValue::Bytes(value)) => field.name = string(value)?,
+            (3, WireValue::Varint(value)) => field.number = value as u32,
+            (4, WireValue::Varint(label)) => field.repeated = label == 3,
+            (5, WireValue::Varint(value)) => field.field_type = FieldType::from_number(value)?,
+            (6, WireValue::Bytes(value)) => field.type_name = type_name(value)?,
+            (10, WireValue::Bytes(value)) => field.json_name = string(value)?,
+            _ => {},
+        }
+    }
+    if field.json_name.is_empty() {
+        field.json_name = json_name(&field.name);
+    }
+    Ok(field)
+}
+
+fn method_http_rule(options: &[u8]) -> Result<Option<HttpRule>, String> {
+    let mut reader = Reader::new(options);
+    while let Some((number, value)) = reader.next_field()? {
+        if let (HTTP_RULE_EXTENSION, WireValue::Bytes(rule)) = (number, value) {
+            return http_rule(rule).map(Some)
+        }
+    }
+    Ok(None)
+}
+
+fn http_rule(bytes: &[u8]) -> Result<HttpRule, String> {
+    let mut rule = HttpRule { method: Method::GET, path: String::new(), body: None, response_body: None, additional_bindings: vec![] };
+    let mut reader = Reader::new(bytes);
+    while let Some((number, value)) = reader.next_field()? {
+        match (number, value) {
+            (2, WireValue::Bytes(path)) => rule.path = string(path)?,
+            (3, WireValue::Bytes(path)) => { rule.method = Method::PUT; rule.path = string(path)? },
+            (4, WireValue::Bytes(path)) => { rule.method = Method::POST; rule.path = string(path)? },
+            (5, WireValue::Bytes(path)) => { rule.method = Method::DELETE; rule.path = string(path)? },
+            (6, WireValue::Bytes(path)) => { rule.method = Method::PATCH; rule.path = string(path)? },
+            (8, WireValue::Bytes(custom)) => {
+                let mut custom = Reader::new(custom);
+                while let Some((number, value)) = custom.next_field()? {
+                    match (number, value) {
+                        (1, WireValue::Bytes(kind)) => rule.method = Method::from_str(&string(kind)?).map_err(|e| e.to_string())?,
+                        (2, WireValue::Bytes(path)) => rule.path = string(path)?,
+                        _ => {},
+                    }
+                }
+            },
+            (7, WireValue::Bytes(body)) => rule.body = Some(string(body)?).filter(|body| !body.is_empty()),
+            (12, WireValue::Bytes(body)) => rule.response_body = Some(string(body)?).filter(|body| !body.is_empty()),
+            (11, WireValue::Bytes(binding)) => rule.additional_bindings.push(http_rule(binding)?),
+            _ => {},
+        }
+    }
+    Ok(rule)
+}
+
+fn bool_option(options: &[u8], option: u32) -> Result<bool, String> {
+    let mut reader = Reader::new(options);
+    while let Some((number, value)) = reader.next_field()? {
+        if let (n, WireValue::Varint(value)) = (number, value) {
+            if n == option {
+                return Ok(value != 0)
+            }
+        }
+    }
+    Ok(false)
+}
+
+fn string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+}
+
+/// ".library.v1.Shelf" -> "library.v1.Shelf"
+fn type_name(bytes: &[u8]) -> Result<String, String> {
+    Ok(string(bytes)?.trim_start_matches('.').to_string())
+}
+
+fn qualified(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", scope, name)
+    }
+}
+
+/// `protoc` rule: "shelf_id" -> "shelfId"
+fn json_name(name: &str) -> String {
+    let mut json = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            json.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            json.push(c);
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::grpc::descriptor::{DescriptorPool, FieldType, HttpRule, HTTP_RULE_EXTENSION};
+    use crate::grpc::wire::{write_bytes, write_tag, write_varint, VARINT};
+    use hyper::Method;
+
+    const OPTIONAL: u64 = 1;
+    const REPEATED: u64 = 3;
+
+    fn varint_field(buf: &mut Vec<u8>, number: u32, value: u64) {
+        write_tag(buf, number, VARINT);
+        write_varint(buf, value);
+    }
+
+    fn field(name: &str, number: u64, label: u64, field_type: u64, type_name: Option<&str>) -> Vec<u8> {
+        let mut field = vec![];
+        write_bytes(&mut field, 1, name.as_bytes());
+        varint_field(&mut field, 3, number);
+        varint_field(&mut field, 4, label);
+        varint_field(&mut field, 5, field_type);
+        if let Some(type_name) = type_name {
+            write_bytes(&mut field, 6, format!(".library.v1.{}", type_name).as_bytes());
+        }
+        field
+    }
+
+    fn message(name: &str, fields: &[Vec<u8>], nested: &[Vec<u8>], map_entry: bool) -> Vec<u8> {
+        let mut message = vec![];
+        write_bytes(&mut message, 1, name.as_bytes());
+        for field in fields {
+            write_bytes(&mut message, 2, field);
+        }
+        for nested in nested {
+            write_bytes(&mut message, 3, nested);
+        }
+        if map_entry {
+            let mut options = vec![];
+            varint_field(&mut options, 7, 1);
+            write_bytes(&mut message, 7, &options);
+        }
+        message
+    }
+
+    /// `kind` is the HttpRule field of the method: 2 (get), 4 (post), ...
+    fn http_rule(kind: u32, path: &str, body: Option<&str>, response_body: Option<&str>) -> Vec<u8> {
+        let mut rule = vec![];
+        write_bytes(&mut rule, kind, path.as_bytes());
+        if let Some(body) = body {
+            write_bytes(&mut rule, 7, body.as_bytes());
+        }
+        if let Some(response_body) = response_body {
+            write_bytes(&mut rule, 12, response_body.as_bytes());
+        }
+        rule
+    }
+
+    fn method(name: &str, input: &str, output: &str, server_streaming: bool, rule: Option<Vec<u8>>) -> Vec<u8> {
+        let mut method = vec![];
+        write_bytes(&mut method, 1, name.as_bytes());
+        write_bytes(&mut method, 2, format!(".library.v1.{}", input).as_bytes());
+        write_bytes(&mut method, 3, format!(".library.v1.{}", output).as_bytes());
+        if let Some(rule) = rule {
+            let mut options = vec![];
+            write_bytes(&mut options, HTTP_RULE_EXTENSION, &rule);
+            write_bytes(&mut method, 4, &options);
+        }
+        if server_streaming {
+            varint_field(&mut method, 6, 1);
+        }
+        method
+    }
+
+    /// What `protoc` would generate for:
+    /// ```proto
+    /// package library.v1;
+    /// enum Genre { GENRE_UNSPECIFIED = 0; FICTION = 1; }
+    /// message Shelf {
+    ///   int64 id = 1; string theme = 2; repeated string tags = 3; map<string, int32> counts = 4;
+    ///   Genre genre = 5; repeated int32 ratings = 6; double score = 7; bytes cover = 8;
+    /// }
+    /// message GetShelfRequest { int64 shelf = 1; bool verbose = 2; repeated string tags = 3; }
+    /// message CreateShelfRequest { Shelf shelf = 1; }
+    /// message ListShelvesRequest { int32 page_size = 1; }
+    /// message ListShelvesResponse { repeated Shelf shelves = 1; string next_page_token = 2; }
+    /// service LibraryService {
+    ///   rpc GetShelf(GetShelfRequest) returns (Shelf) { option (google.api.http) = { get: "/v1/shelves/{shelf}" }; }
+    ///   rpc CreateShelf(CreateShelfRequest) returns (Shelf) { option (google.api.http) = { post: "/v1/shelves" body: "shelf" }; }
+    ///   rpc ListShelves(ListShelvesRequest) returns (ListShelvesResponse) { option (google.api.http) = { get: "/v1/shelves" response_body: "shelves" }; }
+    ///   rpc DeleteShelf(GetShelfRequest) returns (Shelf);
+    ///   rpc WatchShelves(ListShelvesRequest) returns (stream Shelf) { option (google.api.http) = { get: "/v1/shelves:watch" }; }
+    /// }
+    /// ```
+    pub(crate) fn library_descriptor_set() -> Vec<u8> {
+        let counts_entry = message("CountsEntry", &[field("key", 1, OPTIONAL, 9, None), field("value", 2, OPTIONAL, 5, None)], &[], true);
+        let shelf = message("Shelf", &[
+            field("id", 1, OPTIONAL, 3, None),
+            field("theme", 2, OPTIONAL, 9, None),
+            field("tags", 3, REPEATED, 9, None),
+            field("counts", 4, REPEATED, 11, Some("Shelf.CountsEntry")),
+            field("genre", 5, OPTIONAL, 14, Some("Genre")),
+            field("ratings", 6, REPEATED, 5, None),
+            field("score", 7, OPTIONAL, 1, None),
+            field("cover", 8, OPTIONAL, 12, None),
+        ], &[counts_entry], false);
+        let get_shelf = message("GetShelfRequest", &[
+            field("shelf", 1, OPTIONAL, 3, None),
+            field("verbose", 2, OPTIONAL, 8, None),
+            field("tags", 3, REPEATED, 9, None),
+        ], &[], false);
+        let create_shelf = message("CreateShelfRequest", &[field("shelf", 1, OPTIONAL, 11, Some("Shelf"))], &[], false);
+        let list_shelves = message("ListShelvesRequest", &[field("page_size", 1, OPTIONAL, 5, None)], &[], false);
+        let shelves = message("ListShelvesResponse", &[
+            field("shelves", 1, REPEATED, 11, Some("Shelf")),
+            field("next_page_token", 2, OPTIONAL, 9, None),
+        ], &[], false);
+
+        let mut genre = vec![];
+        write_bytes(&mut genre, 1, b"Genre");
+        for (number, name) in ["GENRE_UNSPECIFIED", "FICTION"].iter().enumerate() {
+            let mut value = vec![];
+            write_bytes(&mut value, 1, name.as_bytes());
+            varint_field(&mut value, 2, number as u64);
+            write_bytes(&mut genre, 2, &value);
+        }
+
+        let mut service = vec![];
+        write_bytes(&mut service, 1, b"LibraryService");
+        for method in &[
+            method("GetShelf", "GetShelfRequest", "Shelf", false, Some(http_rule(2, "/v1/shelves/{shelf}", None, None))),
+            method("CreateShelf", "CreateShelfRequest", "Shelf", false, Some(http_rule(4, "/v1/shelves", Some("shelf"), None))),
+            method("ListShelves", "ListShelvesRequest", "ListShelvesResponse", false, Some(http_rule(2, "/v1/shelves", None, Some("shelves")))),
+            method("DeleteShelf", "GetShelfRequest", "Shelf", false, None),
+            method("WatchShelves", "ListShelvesRequest", "Shelf", true, Some(http_rule(2, "/v1/shelves:watch", None, None))),
+        ] {
+            write_bytes(&mut service, 2, method);
+        }
+
+        let mut file = vec![];
+        write_bytes(&mut file, 1, b"library.proto");
+        write_bytes(&mut file, 2, b"library.v1");
+        for message in &[shelf, get_shelf, create_shelf, list_shelves, shelves] {
+            write_bytes(&mut file, 4, message);
+        }
+        write_bytes(&mut file, 5, &genre);
+        write_bytes(&mut file, 6, &service);
+        let mut set = vec![];
+        write_bytes(&mut set, 1, &file);
+        set
+    }
+
+    #[test]
+    fn decode_descriptor_set() {
+        let pool = DescriptorPool::decode(&library_descriptor_set()).unwrap();
+        let shelf = pool.message("library.v1.Shelf").unwrap();
+        let counts = shelf.field_by_name("counts").unwrap();
+        assert_eq!((4, FieldType::Message, true), (counts.number, counts.field_type, counts.repeated));
+        assert_eq!("library.v1.Shelf.CountsEntry", counts.type_name);
+        assert!(pool.message("library.v1.Shelf.CountsEntry").unwrap().map_entry);
+        assert_eq!("pageSize", pool.message("library.v1.ListShelvesRequest").unwrap().field(1).unwrap().json_name);
+        assert_eq!(vec![("GENRE_UNSPECIFIED".to_string(), 0), ("FICTION".to_string(), 1)], pool.enums["library.v1.Genre"].values);
+
+        assert_eq!(5, pool.methods.len());
+        let create = &pool.methods[1];
+        assert_eq!("/library.v1.LibraryService/CreateShelf", create.path());
+        assert_eq!("library.v1.CreateShelfRequest", create.input_type);
+        assert_eq!(Some(HttpRule {
+            method: Method::POST,
+            path: "/v1/shelves".to_string(),
+            body: Some("shelf".to_string()),
+            response_body: None,
+            additional_bindings: vec![],
+        }), create.http_rule);
+        assert_eq!(Some("shelves".to_string()), pool.methods[2].http_rule.as_ref().unwrap().response_body);
+        assert_eq!(None, pool.methods[3].http_rule);
+        assert!(pool.methods[4].server_streaming);
+    }
+
+}
@@ -0,0 +1,283 @@
+use crate::grpc::descriptor::{DescriptorPool, FieldDescriptor, FieldType, MessageDescriptor};
+use crate::grpc::wire::{self, Reader, WireValue};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json::{Map, Number, Value};
+
+// Protobuf messages <-> JSON, following the proto3 JSON mapping: https://developers.google.com/protocol-buffers/docs/proto3#json
+// 64 bits integers are written as strings, enums by name, bytes in base64. Fields with default values are omitted.
+// Well known types (Timestamp, Duration, wrappers...) are mapped as regular messages.
+
+/// Encodes a JSON object as a `message`
+pub fn encode(pool: &DescriptorPool, message: &MessageDescriptor, json: &Value) -> Result<Vec<u8>, String> {
+    let object = json.as_object().ok_or_else(|| format!("Expected a JSON object for {}", message.name))?;
+    let mut buf = vec![];
+    for (name, value) in object {
+        let field = message.field_by_name(name)
+            .ok_or_else(|| format!("Unknown field {} in {}", name, message.name))?;
+        match value {
+            Value::Null => {},
+            Value::Object(entries) if field.repeated && is_map(pool, field) => {
+                let entry = pool.message(&field.type_name)?;
+                let (key_field, value_field) = (entry.field(1).ok_or("Map entry without key")?, entry.field(2).ok_or("Map entry without value")?);
+                for (key, value) in entries {
+                    let mut encoded = vec![];
+                    encode_value(pool, &mut encoded, key_field, &Value::String(key.clone()))?;
+                    encode_value(pool, &mut encoded, value_field, value)?;
+                    wire::write_bytes(&mut buf, field.number, &encoded);
+                }
+            },
+            Value::Array(values) if field.repeated => for value in values {
+                encode_value(pool, &mut buf, field, value)?;
+            },
+            value => encode_value(pool, &mut buf, field, value)?,
+        }
+    }
+    Ok(buf)
+}
+
+/// Decodes a `message` as a JSON object
+pub fn decode(pool: &DescriptorPool, message: &MessageDescriptor, bytes: &[u8]) -> Result<Value, String> {
+    let mut object = Map::new();
+    let mut reader = Reader::new(bytes);
+    while let Some((number, wire_value)) = reader.next_field()? {
+        let field = match message.field(number) {
+            Some(field) => field,
+            None => continue, // unknown fields are dropped
+        };
+        if field.repeated && is_map(pool, field) {
+            let (key, value) = decode_map_entry(pool, field, wire_value)?;
+            let entries = object.entry(field.json_name.clone()).or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(entries) = entries {
+                entries.insert(key, value);
+            }
+        } else if field.repeated {
+            let values = object.entry(field.json_name.clone()).or_insert_with(|| Value::Array(vec![]));
+            if let Value::Array(values) = values {
+                match wire_value {
+                    WireValue::Bytes(packed) if is_packable(field.field_type) => {
+                        let mut packed = Reader::new(packed);
+                        while !packed.is_empty() {
+                            values.push(decode_value(pool, field, next_packed(&mut packed, field.field_type)?)?);
+                        }
+                    },
+                    wire_value => values.push(decode_value(pool, field, wire_value)?),
+                }
+            }
+        } else {
+            object.insert(field.json_name.clone(), decode_value(pool, field, wire_value)?);
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+fn is_map(pool: &DescriptorPool, field: &FieldDescriptor) -> bool {
+    field.field_type == FieldType::Message && pool.messages.get(&field.type_name).map(|entry| entry.map_entry).unwrap_or(false)
+}
+
+fn is_packable(field_type: FieldType) -> bool {
+    !matches!(field_type, FieldType::String | FieldType::Bytes | FieldType::Message | FieldType::Group)
+}
+
+/// Packed repeated scalars are concatenated without tags
+fn next_packed<'a>(packed: &mut Reader<'a>, field_type: FieldType) -> Result<WireValue<'a>, String> {
+    match field_type {
+        FieldType::Double | FieldType::Fixed64 | FieldType::Sfixed64 => packed.fixed64().map(WireValue::Fixed64),
+        FieldType::Float | FieldType::Fixed32 | FieldType::Sfixed32 => packed.fixed32().map(WireValue::Fixed32),
+        _ => packed.varint().map(WireValue::Varint),
+    }
+}
+
+fn decode_map_entry(pool: &DescriptorPool, field: &FieldDescriptor, wire_value: WireValue) -> Result<(String, Value), String> {
+    let entry = pool.message(&field.type_name)?;
+    let bytes = match wire_value {
+        WireValue::Bytes(bytes) => bytes,
+        _ => return Err(format!("Invalid map entry for {}", field.name)),
+    };
+    let mut decoded = decode(pool, entry, bytes)?;
+    let key = match decoded.get("key") {
+        Some(Value::String(key)) => key.clone(),
+        Some(key) => key.to_string(),
+        None => String::new(),
+    };
+    let value = decoded.get_mut("value").map(Value::take).unwrap_or(Value::Null);
+    Ok((key, value))
+}
+
+fn encode_value(pool: &DescriptorPool, buf: &mut Vec<u8>, field: &FieldDescriptor, value: &Value) -> Result<(), String> {
+    let invalid = || format!("Invalid value {} for field {}", value, field.name);
+    match field.field_type {
+        FieldType::Message | FieldType::Group => {
+            let message = pool.message(&field.type_name)?;
+            wire::write_bytes(buf, field.number, &encode(pool, message, value)?);
+        },
+        FieldType::String => wire::write_bytes(buf, field.number, value.as_str().ok_or_else(invalid)?.as_bytes()),
+        FieldType::Bytes => {
+            let decoded = BASE64.decode(value.as_str().ok_or_else(invalid)?).map_err(|_| invalid())?;
+            wire::write_bytes(buf, field.number, &decoded);
+        },
+        FieldType::Double => {
+            wire::write_tag(buf, field.number, wire::FIXED64);
+            buf.extend_from_slice(&as_f64(value).ok_or_else(invalid)?.to_le_bytes());
+        },
+        FieldType::Float => {
+            wire::write_tag(buf, field.number, wire::FIXED32);
+            buf.extend_from_slice(&(as_f64(value).ok_or_else(invalid)? as f32).to_le_bytes());
+        },
+        FieldType::Fixed64 | FieldType::Sfixed64 => {
+            wire::write_tag(buf, field.number, wire::FIXED64);
+            buf.extend_from_slice(&(as_i64(value).ok_or_else(invalid)? as u64).to_le_bytes());
+        },
+        FieldType::Fixed32 | FieldType::Sfixed32 => {
+            wire::write_tag(buf, field.number, wire::FIXED32);
+            buf.extend_from_slice(&(as_i64(value).ok_or_else(invalid)? as u32).to_le_bytes());
+        },
+        FieldType::Bool => {
+            let flag = match value {
+                Value::Bool(flag) => *flag,
+                Value::String(flag) => flag.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            };
+            wire::write_tag(buf, field.number, wire::VARINT);
+            wire::write_varint(buf, flag as u64);
+        },
+        FieldType::Enum => {
+            let number = match value {
+                Value::String(name) => pool.enums.get(&field.type_name)
+                    .and_then(|enum_type| enum_type.values.iter().find(|(value, _)| value == name))
+                    .map(|(_, number)| *number as i64)
+                    .ok_or_else(invalid)?,
+                value => as_i64(value).ok_or_else(invalid)?,
+            };
+            wire::write_tag(buf, field.number, wire::VARINT);
+            wire::write_varint(buf, number as u64);
+        },
+        FieldType::Sint32 | FieldType::Sint64 => {
+            wire::write_tag(buf, field.number, wire::VARINT);
+            wire::write_varint(buf, wire::zigzag_encode(as_i64(value).ok_or_else(invalid)?));
+        },
+        FieldType::Uint64 => {
+            let number = match value {
+                Value::String(number) => number.parse().map_err(|_| invalid())?,
+                value => value.as_u64().ok_or_else(invalid)?,
+            };
+            wire::write_tag(buf, field.number, wire::VARINT);
+            wire::write_varint(buf, number);
+        },
+        FieldType::Int32 | FieldType::Int64 | FieldType::Uint32 => {
+            wire::write_tag(buf, field.number, wire::VARINT);
+            wire::write_varint(buf, as_i64(value).ok_or_else(invalid)? as u64);
+        },
+    }
+    Ok(())
+}
+
+fn decode_value(pool: &DescriptorPool, field: &FieldDescriptor, wire_value: WireValue) -> Result<Value, String> {
+    let invalid = || format!("Invalid encoding for field {}", field.name);
+    Ok(match (field.field_type, wire_value) {
+        (FieldType::Message, WireValue::Bytes(bytes)) | (FieldType::Group, WireValue::Bytes(bytes)) =>
+            decode(pool, pool.message(&field.type_name)?, bytes)?,
+        (FieldType::String, WireValue::Bytes(bytes)) => Value::String(String::from_utf8(bytes.to_vec()).map_err(|_| invalid())?),
+        (FieldType::Bytes, WireValue::Bytes(bytes)) => Value::String(BASE64.encode(bytes)),
+        (FieldType::Double, WireValue::Fixed64(bits)) => float(f64::from_bits(bits)),
+        (FieldType::Float, WireValue::Fixed32(bits)) => float(f32::from_bits(bits) as f64),
+        (FieldType::Fixed64, WireValue::Fixed64(value)) => Value::String(value.to_string()),
+        (FieldType::Sfixed64, WireValue::Fixed64(value)) => Value::String((value as i64).to_string()),
+        (FieldType::Fixed32, WireValue::Fixed32(value)) => Value::from(value),
+        (FieldType::Sfixed32, WireValue::Fixed32(value)) => Value::from(value as i32),
+        (FieldType::Bool, WireValue::Varint(value)) => Value::Bool(value != 0),
+        (FieldType::Enum, WireValue::Varint(value)) => {
+            let number = value as i32;
+            pool.enums.get(&field.type_name)
+                .and_then(|enum_type| enum_type.values.iter().find(|(_, value)| *value == number))
+                .map(|(name, _)| Value::String(name.clone()))
+                .unwrap_or_else(|| Value::from(number))
+        },
+        (FieldType::Int32, WireValue::Varint(value)) => Value::from(value as i32),
+        (FieldType::Uint32, WireValue::Varint(value)) => Value::from(value as u32),
+        (FieldType::Sint32, WireValue::Varint(value)) => Value::from(wire::zigzag_decode(value) as i32),
+        (FieldType::Int64, WireValue::Varint(value)) => Value::String((value as i64).to_string()),
+        (FieldType::Uint64, WireValue::Varint(value)) => Value::String(value.to_string()),
+        (FieldType::Sint64, WireValue::Varint(value)) => Value::String(wire::zigzag_decode(value).to_string()),
+        _ => return Err(invalid()),
+    })
+}
+
+/// NaN and infinities are written as strings
+fn float(value: f64) -> Value {
+    Number::from_f64(value).map(Value::Number).unwrap_or_else(|| Value::String(
+        if value.is_nan() { "NaN" } else if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    ))
+}
+
+/// Numbers can be sent as JSON numbers or strings (query parameters, path variables)
+fn as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64().or_else(|| number.as_f64().filter(|n| n.fract() == 0.0).map(|n| n as i64)),
+        Value::String(number) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(number) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::descriptor::DescriptorPool;
+    use crate::grpc::descriptor::tests::library_descriptor_set;
+    use crate::grpc::json::{decode, encode};
+    use crate::grpc::wire::{write_bytes, write_varint};
+    use serde_json::json;
+
+    #[test]
+    fn json_round_trip() {
+        let pool = DescriptorPool::decode(&library_descriptor_set()).unwrap();
+        let shelf = pool.message("library.v1.Shelf").unwrap();
+        let json = json!({
+            "id": "-9007199254740993",
+            "theme": "sci-fi",
+            "tags": ["a", "b"],
+            "counts": { "x": 1, "y": -2 },
+            "genre": "FICTION",
+            "ratings": [5, 3],
+            "score": 4.5,
+            "cover": "AQID",
+        });
+        let encoded = encode(&pool, shelf, &json).unwrap();
+        assert_eq!(json, decode(&pool, shelf, &encoded).unwrap());
+
+        // original field names, numbers as strings, enums by number
+        let lenient = json!({ "id": 12, "genre": 1, "score": "2", "ratings": ["1"] });
+        assert_eq!(
+            json!({ "id": "12", "genre": "FICTION", "score": 2.0, "ratings": [1] }),
+            decode(&pool, shelf, &encode(&pool, shelf, &lenient).unwrap()).unwrap()
+        );
+        let request = pool.message("library.v1.ListShelvesRequest").unwrap();
+        assert_eq!(json!({ "pageSize": 3 }), decode(&pool, request, &encode(&pool, request, &json!({ "page_size": 3 })).unwrap()).unwrap());
+
+        assert!(encode(&pool, shelf, &json!({ "unknown": 1 })).is_err());
+        assert!(encode(&pool, shelf, &json!({ "id": "twelve" })).is_err());
+        assert!(encode(&pool, shelf, &json!({ "genre": "POETRY" })).is_err());
+        assert!(encode(&pool, shelf, &json!([1])).is_err());
+    }
+
+    #[test]
+    fn packed_repeated_fields() {
+        let pool = DescriptorPool::decode(&library_descriptor_set()).unwrap();
+        let shelf = pool.message("library.v1.Shelf").unwrap();
+        let mut packed = vec![];
+        for rating in &[1u64, 300, 2] {
+            write_varint(&mut packed, *rating);
+        }
+        let mut message = vec![];
+        write_bytes(&mut message, 6, &packed);
+        assert_eq!(json!({ "ratings": [1, 300, 2] }), decode(&pool, shelf, &message).unwrap());
+    }
+
+}
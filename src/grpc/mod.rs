@@ -0,0 +1,243 @@
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{CONTENT_TYPE, HeaderMap, HeaderValue};
+
+pub mod descriptor;
+pub mod json;
+pub mod transcoding;
+pub(crate) mod wire;
+
+/// gRPC status codes, see https://grpc.github.io/grpc/core/md_doc_statuscodes.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+impl Code {
+
+    /// Maps HTTP statuses produced by the gateway (or its handlers) to gRPC codes
+    /// Follows https://github.com/grpc/grpc/blob/master/doc/http-grpc-status-mapping.md, but for rate limits and timeouts which have their own codes
+    pub fn from_http(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Code::Internal,
+            StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+            StatusCode::FORBIDDEN => Code::PermissionDenied,
+            StatusCode::NOT_FOUND => Code::Unimplemented,
+            StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+            StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+            _ => Code::Unknown,
+        }
+    }
+
+    /// Unknown codes are mapped to `Code::Unknown`
+    pub fn from_u32(code: u32) -> Self {
+        use Code::*;
+        match code {
+            0 => Ok, 1 => Cancelled, 3 => InvalidArgument, 4 => DeadlineExceeded, 5 => NotFound, 6 => AlreadyExists,
+            7 => PermissionDenied, 8 => ResourceExhausted, 9 => FailedPrecondition, 10 => Aborted, 11 => OutOfRange,
+            12 => Unimplemented, 13 => Internal, 14 => Unavailable, 15 => DataLoss, 16 => Unauthenticated,
+            _ => Unknown,
+        }
+    }
+
+    /// HTTP status of a gRPC error, for REST clients (see `google.rpc.Code`)
+    pub fn to_http(self) -> StatusCode {
+        match self {
+            Code::Ok => StatusCode::OK,
+            Code::Cancelled => StatusCode::from_u16(499).unwrap(),
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => StatusCode::BAD_REQUEST,
+            Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Code::NotFound => StatusCode::NOT_FOUND,
+            Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+            Code::PermissionDenied => StatusCode::FORBIDDEN,
+            Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+            Code::Unknown | Code::Internal | Code::DataLoss => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// `application/grpc`, `application/grpc+proto`, ...
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
+/// "Trailers-Only" response: the status is sent in headers, with an empty body
+pub fn error_response(code: Code, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", (code as u8).to_string())
+        .header("grpc-message", HeaderValue::from_str(&encode_message(message)).unwrap())
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// gRPC clients can't read plain HTTP errors: responses to gRPC requests that aren't gRPC responses are translated
+pub(crate) fn translate_error(res: Response<Body>) -> Response<Body> {
+    if is_grpc(res.headers()) {
+        return res
+    }
+    let message = format!("Gateway responded {}", res.status());
+    error_response(Code::from_http(res.status()), &message)
+}
+
+/// Percent-encodes everything but printable ASCII characters (and '%' itself)
+fn encode_message(message: &str) -> String {
+    message.bytes().map(|byte| match byte {
+        b'%' => "%25".to_string(),
+        0x20..=0x7E => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::grpc::{encode_message, Code};
+    use crate::handlers::{GlobalHandler, HandlerResponse};
+    use crate::handlers::HandlerResponse::{Break, Continue};
+    use crate::tests::{wait_for_gateway, wait_for_port};
+    use hyper::{Body, Client, HeaderMap, Request, Response, Server, StatusCode};
+    use hyper::body::{Bytes, HttpBody};
+    use hyper::client::HttpConnector;
+    use hyper::header::CONTENT_TYPE;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    fn framed(message: &[u8]) -> Bytes {
+        let mut frame = vec![0u8];
+        frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        frame.extend_from_slice(message);
+        frame.into()
+    }
+
+    /// Replies with a single message "<name> <path>", and custom trailers
+    async fn grpc_server(port: u16, name: &'static str) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(move |_conn| async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| async move {
+                let (mut sender, body) = Body::channel();
+                let message = format!("{} {}", name, req.uri().path());
+                tokio::spawn(async move {
+                    let _ = hyper::body::to_bytes(req.into_body()).await;
+                    let _ = sender.send_data(framed(message.as_bytes())).await;
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    trailers.insert("x-served-by", name.parse().unwrap());
+                    let _ = sender.send_trailers(trailers).await;
+                });
+                Ok::<_, Infallible>(Response::builder().header(CONTENT_TYPE, "application/grpc").body(body).unwrap())
+            }))
+        });
+        Server::bind(&addr).http2_only(true).serve(make_svc).await.unwrap();
+    }
+
+    #[derive(Debug)]
+    struct DenyAll;
+
+    impl GlobalHandler for DenyAll {
+        fn handle_req(&self, _req: &mut Request<Body>) -> HandlerResponse {
+            Break(Response::builder().status(StatusCode::UNAUTHORIZED).body(Body::empty()).unwrap())
+        }
+
+        fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+            Continue
+        }
+    }
+
+    /// Returns the response headers, the body and the trailers
+    async fn call(client: &Client<HttpConnector>, port: u16, path: &str) -> (HeaderMap, Bytes, Option<HeaderMap>) {
+        let req = Request::builder()
+            .method("POST")
+            .uri(format!("http://127.0.0.1:{}{}", port, path))
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("te", "trailers")
+            .body(Body::from(framed(b"request")))
+            .unwrap();
+        let mut resp = client.request(req).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let mut body = vec![];
+        while let Some(chunk) = resp.body_mut().data().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = resp.body_mut().trailers().await.unwrap();
+        (resp.headers().clone(), body.into(), trailers)
+    }
+
+    #[tokio::test]
+    async fn grpc_is_proxied_with_trailers() {
+        let gw_port = 12600;
+        let greeter_port = 12601;
+        let goodbye_port = 12602;
+        let down_port = 12603;
+        tokio::spawn(async move { grpc_server(greeter_port, "greeter").await });
+        tokio::spawn(async move { grpc_server(goodbye_port, "goodbye").await });
+        tokio::spawn(async move {
+            let mut denied = Api::grpc("127.0.0.1", greeter_port, "helloworld.Admin").unwrap();
+            denied.add_global_handler(Box::new(DenyAll));
+            let apis = vec![
+                Api::grpc("127.0.0.1", greeter_port, "helloworld.Greeter").unwrap(),
+                Api::grpc("127.0.0.1", goodbye_port, "helloworld.Greeter/SayGoodbye").unwrap(),
+                Api::grpc("127.0.0.1", down_port, "helloworld.Unavailable").unwrap(),
+                denied,
+            ];
+            start_local_gateway(gw_port, apis).await
+        });
+        wait_for_port(greeter_port).await;
+        wait_for_port(goodbye_port).await;
+        wait_for_gateway(gw_port).await;
+        let client = Client::builder().http2_only(true).build_http();
+
+        let (_, body, trailers) = call(&client, gw_port, "/helloworld.Greeter/SayHello").await;
+        assert_eq!(framed(b"greeter /helloworld.Greeter/SayHello"), body);
+        let trailers = trailers.unwrap();
+        assert_eq!("0", trailers.get("grpc-status").unwrap());
+        assert_eq!("greeter", trailers.get("x-served-by").unwrap());
+
+        let (_, body, _) = call(&client, gw_port, "/helloworld.Greeter/SayGoodbye").await;
+        assert_eq!(framed(b"goodbye /helloworld.Greeter/SayGoodbye"), body);
+
+        for (path, code) in [
+            ("/helloworld.Missing/SayHello", Code::Unimplemented),
+            ("/helloworld.Unavailable/SayHello", Code::Unavailable),
+            ("/helloworld.Admin/Shutdown", Code::Unauthenticated),
+        ] {
+            let (headers, body, _) = call(&client, gw_port, path).await;
+            assert_eq!("application/grpc", headers.get(CONTENT_TYPE).unwrap());
+            assert_eq!((code as u8).to_string(), headers.get("grpc-status").unwrap().to_str().unwrap(), "{}", path);
+            assert!(headers.contains_key("grpc-message"));
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn messages_are_percent_encoded() {
+        assert_eq!("Gateway responded 404 Not Found", encode_message("Gateway responded 404 Not Found"));
+        assert_eq!("100%25 d%C3%A9j%C3%A0%0A", encode_message("100% déjà\n"));
+    }
+
+}
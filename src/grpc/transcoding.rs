@@ -0,0 +1,671 @@
+use crate::conf::api::Api;
+use crate::context::UpstreamRequest;
+use crate::grpc::{json, Code};
+use crate::grpc::descriptor::{DescriptorPool, FieldType, HttpRule, MessageDescriptor, MethodDescriptor};
+use crate::handlers::{RequestTransformer, ResponseFinalizer};
+use async_trait::async_trait;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue, TE};
+use log::warn;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Exposes unary gRPC methods as REST/JSON resources, following their `google.api.http` annotations
+/// (https://cloud.google.com/endpoints/docs/grpc/transcoding) or rules added by hand
+/// JSON requests are encoded as protobuf messages and sent upstream as gRPC calls, the gRPC responses are decoded back to JSON
+/// The upstream endpoint of the Api must speak HTTP/2 (see `Api::http_with`)
+#[derive(Debug)]
+pub struct GrpcTranscoder {
+    pool: DescriptorPool,
+    methods: HashMap<String, TranscodedMethod>,  // by gRPC path
+    bindings: Vec<Binding>,
+}
+
+#[derive(Debug)]
+struct TranscodedMethod {
+    input: Arc<MessageDescriptor>,
+    output: Arc<MessageDescriptor>,
+    response_body: Option<String>,
+}
+
+/// A REST call (HTTP method + path template) bound to a gRPC method
+#[derive(Debug)]
+struct Binding {
+    http_method: Method,
+    template: PathTemplate,
+    body: Option<String>,
+    grpc_path: String,
+}
+
+#[derive(Debug)]
+pub enum TranscodingError {
+    Io(std::io::Error),
+    Descriptor(String),     // the descriptor set can't be read
+    UnknownMethod(String),
+    InvalidRule(String),    // the path template or body field of an HTTP rule doesn't match the method
+}
+
+impl Display for TranscodingError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranscodingError::Io(e) => write!(f, "I/O error: {}", e),
+            TranscodingError::Descriptor(e) => write!(f, "Invalid descriptor set: {}", e),
+            TranscodingError::UnknownMethod(method) => write!(f, "Unknown gRPC method {}", method),
+            TranscodingError::InvalidRule(e) => write!(f, "Invalid HTTP rule: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TranscodingError {}
+
+impl From<std::io::Error> for TranscodingError {
+    fn from(e: std::io::Error) -> Self {
+        TranscodingError::Io(e)
+    }
+}
+
+impl GrpcTranscoder {
+
+    /// Reads a descriptor set generated by `protoc --include_imports --descriptor_set_out=<path>`
+    pub fn from_descriptor_set(path: &Path) -> Result<Self, TranscodingError> {
+        GrpcTranscoder::new(&std::fs::read(path)?)
+    }
+
+    /// Binds every method annotated with `google.api.http` in the encoded descriptor set
+    /// Streaming methods can't be transcoded, their annotations are ignored
+    pub fn new(descriptor_set: &[u8]) -> Result<Self, TranscodingError> {
+        let pool = DescriptorPool::decode(descriptor_set).map_err(TranscodingError::Descriptor)?;
+        let mut transcoder = GrpcTranscoder { pool, methods: HashMap::new(), bindings: vec![] };
+        let annotated: Vec<(String, HttpRule)> = transcoder.pool.methods.iter()
+            .filter_map(|method| method.http_rule.clone().map(|rule| (method.path(), rule)))
+            .collect();
+        for (path, rule) in annotated {
+            transcoder.add_rule(&path, rule)?;
+        }
+        Ok(transcoder)
+    }
+
+    /// Binds a method ("/library.v1.LibraryService/GetShelf", or without the leading slash), on top of its annotations
+    pub fn add_rule(&mut self, method: &str, rule: HttpRule) -> Result<(), TranscodingError> {
+        let grpc_path = format!("/{}", method.trim_start_matches('/'));
+        let descriptor = self.pool.methods.iter()
+            .find(|candidate| candidate.path() == grpc_path)
+            .ok_or_else(|| TranscodingError::UnknownMethod(method.to_string()))?;
+        if descriptor.client_streaming || descriptor.server_streaming {
+            warn!("Streaming method {} can't be transcoded, ignoring its HTTP rules", grpc_path);
+            return Ok(())
+        }
+        let transcoded = self.transcoded_method(descriptor).map_err(TranscodingError::InvalidRule)?;
+        for rule in std::iter::once(&rule).chain(rule.additional_bindings.iter()) {
+            let template = PathTemplate::parse(&rule.path).map_err(TranscodingError::InvalidRule)?;
+            for variable in &template.variables {
+                field_path(&self.pool, &transcoded.input, variable).map_err(TranscodingError::InvalidRule)?;
+            }
+            if let Some(body) = rule.body.as_ref().filter(|body| *body != "*") {
+                field_path(&self.pool, &transcoded.input, body).map_err(TranscodingError::InvalidRule)?;
+            }
+            self.bindings.push(Binding { http_method: rule.method.clone(), template, body: rule.body.clone(), grpc_path: grpc_path.clone() });
+        }
+        let entry = self.methods.entry(grpc_path).or_insert(transcoded);
+        if entry.response_body.is_none() {
+            entry.response_body = rule.response_body;
+        }
+        Ok(())
+    }
+
+    /// Transcodes the calls of `api`: the whole client path is matched against the path templates,
+    /// so the Api keeps its prefix, and its request transformer and response finalizer are replaced
+    pub fn apply_to(self, api: &mut Api) {
+        let transcoder = Arc::new(self);
+        api.keep_prefix();
+        api.transform_requests_with(Box::new(TranscodeRequests(transcoder.clone())));
+        api.finalize_with(Box::new(TranscodeResponses(transcoder)));
+    }
+
+    fn transcoded_method(&self, method: &MethodDescriptor) -> Result<TranscodedMethod, String> {
+        Ok(TranscodedMethod {
+            input: self.pool.message(&method.input_type)?.clone(),
+            output: self.pool.message(&method.output_type)?.clone(),
+            response_body: None,
+        })
+    }
+
+    fn binding(&self, method: &Method, path: &str) -> Option<(&Binding, Vec<(String, String)>)> {
+        self.bindings.iter()
+            .filter(|binding| binding.http_method == method)
+            .find_map(|binding| binding.template.matches(path).map(|variables| (binding, variables)))
+    }
+
+    /// REST request -> JSON representation of the input message
+    fn request_message(&self, binding: &Binding, variables: Vec<(String, String)>, query: Option<&str>, body: &[u8]) -> Result<Value, String> {
+        let input = &self.methods[&binding.grpc_path].input;
+        let parse_body = || -> Result<Value, String> {
+            if body.is_empty() {
+                return Ok(json!({}))
+            }
+            serde_json::from_slice(body).map_err(|e| format!("Invalid JSON body: {}", e))
+        };
+        let mut message = match binding.body.as_deref() {
+            Some("*") => parse_body()?,
+            Some(field) => {
+                let mut message = json!({});
+                set_field(&self.pool, input, &mut message, field, parse_body()?, false)?;
+                message
+            },
+            None => json!({}),
+        };
+        if !message.is_object() {
+            return Err("The JSON body must be an object".to_string())
+        }
+        let bound: Vec<String> = variables.iter().map(|(name, _)| name.clone()).chain(binding.body.clone()).collect();
+        for (name, value) in variables {
+            set_field(&self.pool, input, &mut message, &name, Value::String(value), false)?;
+        }
+        if binding.body.as_deref() != Some("*") {
+            for (name, value) in query.into_iter().flat_map(query_params) {
+                // parameters bound elsewhere, or matching no field, are ignored
+                let is_bound = bound.iter().any(|bound| name == *bound || name.starts_with(&format!("{}.", bound)));
+                if !is_bound && field_path(&self.pool, input, &name).is_ok() {
+                    set_field(&self.pool, input, &mut message, &name, Value::String(value), true)?;
+                }
+            }
+        }
+        Ok(message)
+    }
+
+    async fn transcode_request(&self, req: Request<Body>) -> Result<Request<Body>, Response<Body>> {
+        let (binding, variables) = self.binding(req.method(), req.uri().path())
+            .ok_or_else(|| json_error(StatusCode::NOT_FOUND, Code::NotFound, &format!("No gRPC method bound to {} {}", req.method(), req.uri().path())))?;
+        let (mut parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await
+            .map_err(|e| json_error(StatusCode::BAD_REQUEST, Code::InvalidArgument, &e.to_string()))?;
+        let input = &self.methods[&binding.grpc_path].input;
+        let encoded = self.request_message(binding, variables, parts.uri.query(), &body)
+            .and_then(|message| json::encode(&self.pool, input, &message))
+            .map_err(|e| json_error(StatusCode::BAD_REQUEST, Code::InvalidArgument, &e))?;
+
+        let mut uri = Uri::builder();
+        if let (Some(scheme), Some(authority)) = (parts.uri.scheme(), parts.uri.authority()) {
+            uri = uri.scheme(scheme.clone()).authority(authority.clone());
+        }
+        parts.uri = uri.path_and_query(binding.grpc_path.as_str()).build()
+            .map_err(|e| json_error(StatusCode::INTERNAL_SERVER_ERROR, Code::Internal, &e.to_string()))?;
+        parts.method = Method::POST;
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        parts.headers.insert(TE, HeaderValue::from_static("trailers"));
+        Ok(Request::from_parts(parts, Body::from(framed(&encoded))))
+    }
+
+    async fn transcode_response(&self, res: Response<Body>) -> Response<Body> {
+        let method = match res.extensions().get::<UpstreamRequest>().and_then(|req| self.methods.get(req.uri.path())) {
+            Some(method) => method,
+            None => return res,
+        };
+        if !crate::grpc::is_grpc(res.headers()) {
+            return res // the gateway or the upstream responded with a plain HTTP error
+        }
+        if let Some(error) = grpc_error(res.headers()) {
+            return error // "Trailers-Only" response
+        }
+        let (parts, mut body) = res.into_parts();
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(e) => return json_error(StatusCode::BAD_GATEWAY, Code::Unavailable, &e.to_string()),
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => if let Some(error) = grpc_error(&trailers) {
+                return error
+            },
+            Ok(None) => {},
+            Err(e) => return json_error(StatusCode::BAD_GATEWAY, Code::Unavailable, &e.to_string()),
+        }
+        let decoded = unframed(&data)
+            .and_then(|message| json::decode(&self.pool, &method.output, message))
+            .map_err(|e| json_error(StatusCode::BAD_GATEWAY, Code::Internal, &e));
+        let mut decoded = match decoded {
+            Ok(decoded) => decoded,
+            Err(error) => return error,
+        };
+        if let Some(field) = &method.response_body {
+            let repeated = method.output.field_by_name(field).map(|field| field.repeated).unwrap_or(false);
+            let json_name = method.output.field_by_name(field).map(|field| field.json_name.as_str()).unwrap_or(field);
+            decoded = decoded.get_mut(json_name).map(Value::take)
+                .unwrap_or(if repeated { Value::Array(vec![]) } else { Value::Null });
+        }
+
+        let mut res = Response::new(Body::from(decoded.to_string()));
+        for (name, value) in parts.headers.iter() {
+            if name != CONTENT_TYPE && name != CONTENT_LENGTH && !name.as_str().starts_with("grpc-") {
+                res.headers_mut().append(name, value.clone());
+            }
+        }
+        res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        *res.extensions_mut() = parts.extensions;
+        res
+    }
+}
+
+#[derive(Debug)]
+struct TranscodeRequests(Arc<GrpcTranscoder>);
+
+#[async_trait]
+impl RequestTransformer for TranscodeRequests {
+    async fn transform(&self, req: Request<Body>) -> Result<Request<Body>, Response<Body>> {
+        self.0.transcode_request(req).await
+    }
+}
+
+#[derive(Debug)]
+struct TranscodeResponses(Arc<GrpcTranscoder>);
+
+#[async_trait]
+impl ResponseFinalizer for TranscodeResponses {
+    async fn transform(&self, res: Response<Body>) -> Response<Body> {
+        self.0.transcode_response(res).await
+    }
+
+    /// gRPC responses are streamed, unary ones being made of a single message they're read here
+    fn buffers_body(&self) -> bool {
+        false
+    }
+}
+
+/// `google.api.HttpRule` path template: "/v1/{name=shelves/*/books/*}:publish"
+#[derive(Debug, PartialEq)]
+struct PathTemplate {
+    segments: Vec<(Segment, Option<usize>)>,   // with the index of the variable capturing it, if any
+    variables: Vec<String>,                     // field paths: "name", "book.id"
+    verb: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Any,    // "*": a single segment
+    Rest,   // "**": the remaining segments, last in the template
+}
+
+impl PathTemplate {
+
+    fn parse(template: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid path template {}", template);
+        let path = template.strip_prefix('/').ok_or_else(invalid)?;
+        let (path, verb) = match path.rfind(':') {
+            Some(index) if !path[index..].contains('/') && !path[index..].contains('}') => (&path[..index], Some(path[index + 1..].to_string())),
+            _ => (path, None),
+        };
+        let mut parsed = PathTemplate { segments: vec![], variables: vec![], verb };
+        for token in split_outside_braces(path) {
+            match token.strip_prefix('{').and_then(|variable| variable.strip_suffix('}')) {
+                Some(variable) => {
+                    let (field, pattern) = match variable.split_once('=') {
+                        Some((field, pattern)) => (field, pattern),
+                        None => (variable, "*"),
+                    };
+                    let index = parsed.variables.len();
+                    parsed.variables.push(field.to_string());
+                    for segment in pattern.split('/') {
+                        parsed.segments.push((Segment::parse(segment).ok_or_else(invalid)?, Some(index)));
+                    }
+                },
+                None => parsed.segments.push((Segment::parse(token).ok_or_else(invalid)?, None)),
+            }
+        }
+        let rest = parsed.segments.iter().position(|(segment, _)| *segment == Segment::Rest);
+        if rest.map(|index| index != parsed.segments.len() - 1).unwrap_or(false) {
+            return Err(invalid())
+        }
+        Ok(parsed)
+    }
+
+    /// Values of the variables, percent-decoded, if `path` matches
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut path = path.strip_prefix('/')?;
+        if let Some(verb) = &self.verb {
+            path = path.strip_suffix(verb.as_str())?.strip_suffix(':')?;
+        }
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut captured: Vec<Vec<&str>> = vec![vec![]; self.variables.len()];
+        let mut remaining = &parts[..];
+        for (segment, variable) in &self.segments {
+            let taken = match segment {
+                Segment::Literal(literal) => remaining.first().filter(|part| *part == literal).map(|_| 1)?,
+                Segment::Any => remaining.first().filter(|part| !part.is_empty()).map(|_| 1)?,
+                Segment::Rest => remaining.len(),
+            };
+            if let Some(variable) = variable {
+                captured[*variable].extend_from_slice(&remaining[..taken]);
+            }
+            remaining = &remaining[taken..];
+        }
+        if !remaining.is_empty() {
+            return None
+        }
+        Some(self.variables.iter().cloned()
+            .zip(captured.into_iter().map(|parts| parts.iter().map(|part| percent_decode(part, false)).collect::<Vec<_>>().join("/")))
+            .collect())
+    }
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Option<Self> {
+        match segment {
+            "" => None,
+            "*" => Some(Segment::Any),
+            "**" => Some(Segment::Rest),
+            literal => Some(Segment::Literal(literal.to_string())),
+        }
+    }
+}
+
+fn split_outside_braces(path: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let (mut depth, mut start) = (0, 0);
+    for (index, c) in path.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '/' if depth == 0 => {
+                tokens.push(&path[start..index]);
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+    tokens.push(&path[start..]);
+    tokens
+}
+
+/// Resolves a dotted field path ("book.id") from `message`, every field but the last one must be a message
+fn field_path<'a>(pool: &'a DescriptorPool, message: &'a MessageDescriptor, path: &str) -> Result<Vec<(String, bool)>, String> {
+    let mut resolved = vec![];
+    let mut current = message;
+    let names: Vec<&str> = path.split('.').collect();
+    for (index, name) in names.iter().enumerate() {
+        let field = current.field_by_name(name).ok_or_else(|| format!("No field {} in {}", path, message.name))?;
+        resolved.push((field.json_name.clone(), field.repeated));
+        if index < names.len() - 1 {
+            if field.field_type != FieldType::Message || field.repeated {
+                return Err(format!("Field {} of {} isn't a message", name, current.name))
+            }
+            current = pool.message(&field.type_name)?;
+        }
+    }
+    Ok(resolved)
+}
+
+/// Sets (or appends to, if `append` and the field is repeated) a field of the JSON message
+fn set_field(pool: &DescriptorPool, message: &MessageDescriptor, json: &mut Value, path: &str, value: Value, append: bool) -> Result<(), String> {
+    let fields = field_path(pool, message, path)?;
+    let mut current = json;
+    for (name, _) in &fields[..fields.len() - 1] {
+        current = current.as_object_mut()
+            .ok_or_else(|| format!("Field {} is set twice", path))?
+            .entry(name.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    let (name, repeated) = &fields[fields.len() - 1];
+    let object = current.as_object_mut().ok_or_else(|| format!("Field {} is set twice", path))?;
+    if append && *repeated {
+        match object.entry(name.clone()).or_insert_with(|| Value::Array(vec![])) {
+            Value::Array(values) => values.push(value),
+            _ => return Err(format!("Field {} is set twice", path)),
+        }
+    } else {
+        object.insert(name.clone(), value);
+    }
+    Ok(())
+}
+
+fn query_params(query: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    query.split('&').filter(|param| !param.is_empty()).map(|param| {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        (percent_decode(name, true), percent_decode(value, true))
+    })
+}
+
+fn percent_decode(value: &str, plus_as_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes.get(index + 1..index + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+                continue
+            },
+            (b'+', _) if plus_as_space => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// gRPC length-prefixed message, uncompressed
+fn framed(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+/// First message of a gRPC body
+fn unframed(data: &[u8]) -> Result<&[u8], String> {
+    if data.len() < 5 {
+        return Err("The gRPC response contains no message".to_string())
+    }
+    if data[0] != 0 {
+        return Err("Compressed gRPC messages aren't supported".to_string())
+    }
+    let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    data.get(5..5 + len).ok_or_else(|| "Truncated gRPC message".to_string())
+}
+
+/// JSON error for a non-OK `grpc-status`
+fn grpc_error(headers: &HeaderMap) -> Option<Response<Body>> {
+    let status = headers.get("grpc-status")?.to_str().ok()?.parse::<u32>().ok()?;
+    let code = Code::from_u32(status);
+    if code == Code::Ok {
+        return None
+    }
+    let message = headers.get("grpc-message")
+        .and_then(|message| message.to_str().ok())
+        .map(|message| percent_decode(message, false))
+        .unwrap_or_default();
+    Some(json_error(code.to_http(), code, &message))
+}
+
+/// `google.rpc.Status` as JSON
+fn json_error(status: StatusCode, code: Code, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "code": code as u8, "message": message }).to_string()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::protocol::{HttpProtocol, ProtocolConfig};
+    use crate::gateway::start_local_gateway;
+    use crate::grpc::descriptor::{DescriptorPool, HttpRule};
+    use crate::grpc::descriptor::tests::library_descriptor_set;
+    use crate::grpc::json;
+    use crate::grpc::transcoding::{framed, unframed, GrpcTranscoder, PathTemplate, TranscodingError};
+    use crate::tests::{wait_for_gateway, wait_for_port, unwrap_body_as_str};
+    use hyper::{Body, Client, HeaderMap, Method, Request, Response, Server, StatusCode, Uri};
+    use hyper::header::CONTENT_TYPE;
+    use hyper::service::{make_service_fn, service_fn};
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn variables(template: &str, path: &str) -> Option<Vec<(String, String)>> {
+        PathTemplate::parse(template).unwrap().matches(path)
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn path_templates() {
+        assert_eq!(vars(&[]), variables("/v1/shelves", "/v1/shelves"));
+        assert_eq!(vars(&[("shelf", "12")]), variables("/v1/shelves/{shelf}", "/v1/shelves/12"));
+        assert_eq!(None, variables("/v1/shelves/{shelf}", "/v1/shelves"));
+        assert_eq!(None, variables("/v1/shelves/{shelf}", "/v1/shelves/12/books"));
+        assert_eq!(vars(&[("name", "shelves/1/books/2")]), variables("/v1/{name=shelves/*/books/*}", "/v1/shelves/1/books/2"));
+        assert_eq!(vars(&[("book.id", "my book")]), variables("/v1/books/{book.id}", "/v1/books/my%20book"));
+        assert_eq!(vars(&[("path", "a/b/c")]), variables("/files/{path=**}", "/files/a/b/c"));
+        assert_eq!(vars(&[("name", "1")]), variables("/v1/shelves/{name}:publish", "/v1/shelves/1:publish"));
+        assert_eq!(None, variables("/v1/shelves/{name}:publish", "/v1/shelves/1"));
+        assert_eq!(vars(&[]), variables("/v1/*/books", "/v1/anything/books"));
+        assert!(PathTemplate::parse("v1/shelves").is_err());
+        assert!(PathTemplate::parse("/v1/**/books").is_err());
+        assert!(PathTemplate::parse("/v1//books").is_err());
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let mut transcoder = GrpcTranscoder::new(&library_descriptor_set()).unwrap();
+        let rule = |path: &str| HttpRule { method: Method::GET, path: path.to_string(), body: None, response_body: None, additional_bindings: vec![] };
+        assert!(matches!(transcoder.add_rule("library.v1.LibraryService/Unknown", rule("/v1/unknown")), Err(TranscodingError::UnknownMethod(_))));
+        assert!(matches!(transcoder.add_rule("library.v1.LibraryService/DeleteShelf", rule("/v1/shelves/{unknown}")), Err(TranscodingError::InvalidRule(_))));
+        assert!(transcoder.add_rule("/library.v1.LibraryService/DeleteShelf", rule("/v1/shelves/{shelf}")).is_ok());
+        assert!(GrpcTranscoder::new(&[0x0A, 0x05, 0x01]).is_err());
+    }
+
+    fn grpc_response(message: &[u8], status: u8, error: &str) -> Response<Body> {
+        let (mut sender, body) = Body::channel();
+        let message = message.to_vec();
+        let error = error.to_string();
+        tokio::spawn(async move {
+            if status == 0 {
+                let _ = sender.send_data(framed(&message).into()).await;
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", status.to_string().parse().unwrap());
+            trailers.insert("grpc-message", error.parse().unwrap());
+            let _ = sender.send_trailers(trailers).await;
+        });
+        Response::builder().header(CONTENT_TYPE, "application/grpc").body(body).unwrap()
+    }
+
+    /// Library service, speaking h2c and decoding its messages with the same descriptors
+    async fn library_server(port: u16) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let pool = Arc::new(DescriptorPool::decode(&library_descriptor_set()).unwrap());
+        let make_svc = make_service_fn(move |_conn| {
+            let pool = pool.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let pool = pool.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let method = pool.methods.iter().find(|method| method.path() == path).unwrap();
+                        let (input, output) = (pool.message(&method.input_type).unwrap(), pool.message(&method.output_type).unwrap());
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let request = json::decode(&pool, input, unframed(&body).unwrap()).unwrap();
+                        let response = match path.rsplit('/').next().unwrap() {
+                            "GetShelf" if request["shelf"] == "404" => return Ok::<_, Infallible>(grpc_response(&[], 5, "Shelf 404 not found")),
+                            "GetShelf" | "DeleteShelf" => json!({
+                                "id": request["shelf"],
+                                "theme": if request["verbose"] == true { "verbose" } else { "short" },
+                                "tags": request.get("tags").cloned().unwrap_or_else(|| json!(["a", "b"])),
+                                "counts": { "x": 1 },
+                                "genre": "FICTION",
+                            }),
+                            "CreateShelf" => {
+                                let mut shelf = request["shelf"].clone();
+                                shelf["id"] = json!("42");
+                                shelf
+                            },
+                            "ListShelves" => {
+                                let size = request["pageSize"].as_i64().unwrap_or(0);
+                                json!({ "shelves": (1..=size).map(|id| json!({ "id": id.to_string() })).collect::<Vec<_>>(), "nextPageToken": "next" })
+                            },
+                            other => panic!("unexpected method {}", other),
+                        };
+                        let encoded = json::encode(&pool, output, &response).unwrap();
+                        Ok::<_, Infallible>(grpc_response(&encoded, 0, ""))
+                    }
+                }))
+            }
+        });
+        Server::bind(&addr).http2_only(true).serve(make_svc).await.unwrap();
+    }
+
+    async fn call(client: &Client<hyper::client::HttpConnector>, method: Method, url: String, body: &str) -> (StatusCode, Value) {
+        let req = Request::builder().method(method).uri(Uri::from_str(&url).unwrap()).body(Body::from(body.to_string())).unwrap();
+        let resp = client.request(req).await.unwrap();
+        let status = resp.status();
+        assert_eq!("application/json", resp.headers().get(CONTENT_TYPE).unwrap());
+        (status, serde_json::from_str(&unwrap_body_as_str(resp).await).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rest_calls_are_transcoded() {
+        let gw_port = 12700;
+        let backend_port = 12701;
+        tokio::spawn(async move { library_server(backend_port).await });
+        let mut api = Api::http_with("127.0.0.1", backend_port, "/v1".to_string(), &ProtocolConfig::new(HttpProtocol::Http2)).unwrap();
+        let mut transcoder = GrpcTranscoder::new(&library_descriptor_set()).unwrap();
+        transcoder.add_rule("library.v1.LibraryService/DeleteShelf", HttpRule {
+            method: Method::DELETE,
+            path: "/v1/shelves/{shelf}".to_string(),
+            body: None,
+            response_body: None,
+            additional_bindings: vec![],
+        }).unwrap();
+        transcoder.apply_to(&mut api);
+        tokio::spawn(async move { start_local_gateway(gw_port, vec![api]).await });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let url = |path: &str| format!("http://127.0.0.1:{}{}", gw_port, path);
+
+        let (status, shelf) = call(&client, Method::GET, url("/v1/shelves/7?verbose=true&tags=x&tags=y+z&ignored=1"), "").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "id": "7", "theme": "verbose", "tags": ["x", "y z"], "counts": { "x": 1 }, "genre": "FICTION" }), shelf);
+
+        let (status, shelf) = call(&client, Method::POST, url("/v1/shelves"), r#"{"theme":"sci-fi","genre":"FICTION"}"#).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(json!({ "id": "42", "theme": "sci-fi", "genre": "FICTION" }), shelf);
+
+        let (_, shelves) = call(&client, Method::GET, url("/v1/shelves?pageSize=2"), "").await;
+        assert_eq!(json!([{ "id": "1" }, { "id": "2" }]), shelves); // response_body: "shelves"
+
+        let (_, shelf) = call(&client, Method::DELETE, url("/v1/shelves/3"), "").await;
+        assert_eq!("3", shelf["id"]);
+
+        let (status, error) = call(&client, Method::GET, url("/v1/shelves/404"), "").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!(json!({ "code": 5, "message": "Shelf 404 not found" }), error);
+
+        let (status, _) = call(&client, Method::PUT, url("/v1/shelves/3"), "").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let (status, error) = call(&client, Method::POST, url("/v1/shelves"), "{not json").await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(3, error["code"]);
+
+        let (status, _) = call(&client, Method::GET, url("/v1/shelves/abc"), "").await; // shelf is an int64
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+}
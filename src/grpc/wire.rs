@@ -0,0 +1,136 @@
+// Protocol buffers wire format, see https://developers.google.com/protocol-buffers/docs/encoding
+
+use std::convert::TryInto;
+
+pub(crate) const VARINT: u8 = 0;
+pub(crate) const FIXED64: u8 = 1;
+pub(crate) const LENGTH_DELIMITED: u8 = 2;
+pub(crate) const FIXED32: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Reads the fields of an encoded message, in order
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    pub(crate) fn next_field(&mut self) -> Result<Option<(u32, WireValue<'a>)>, String> {
+        if self.buf.is_empty() {
+            return Ok(None)
+        }
+        let tag = self.varint()?;
+        let number = (tag >> 3) as u32;
+        let value = match (tag & 0x7) as u8 {
+            VARINT => WireValue::Varint(self.varint()?),
+            FIXED64 => WireValue::Fixed64(self.fixed64()?),
+            LENGTH_DELIMITED => {
+                let len = self.varint()? as usize;
+                WireValue::Bytes(self.take(len)?)
+            },
+            FIXED32 => WireValue::Fixed32(self.fixed32()?),
+            wire_type => return Err(format!("Unsupported wire type {} for field {}", wire_type, number)),
+        };
+        Ok(Some((number, value)))
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= ((byte & 0x7F) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value)
+            }
+        }
+        Err("Truncated varint".to_string())
+    }
+
+    pub(crate) fn fixed64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn fixed32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < len {
+            return Err("Truncated message".to_string())
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn write_tag(buf: &mut Vec<u8>, number: u32, wire_type: u8) {
+    write_varint(buf, ((number as u64) << 3) | wire_type as u64);
+}
+
+pub(crate) fn write_bytes(buf: &mut Vec<u8>, number: u32, bytes: &[u8]) {
+    write_tag(buf, number, LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+pub(crate) fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub(crate) fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::wire::*;
+
+    #[test]
+    fn fields_round_trip() {
+        let mut buf = vec![];
+        write_tag(&mut buf, 1, VARINT);
+        write_varint(&mut buf, 300);
+        write_bytes(&mut buf, 2, b"testing");
+        write_tag(&mut buf, 3, FIXED32);
+        buf.extend_from_slice(&1.5f32.to_le_bytes());
+        write_tag(&mut buf, 4, VARINT);
+        write_varint(&mut buf, zigzag_encode(-2));
+        assert_eq!(&[0x08, 0xAC, 0x02], &buf[..3]); // from the encoding guide
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(Some((1, WireValue::Varint(300))), reader.next_field().unwrap());
+        assert_eq!(Some((2, WireValue::Bytes(b"testing"))), reader.next_field().unwrap());
+        assert_eq!(Some((3, WireValue::Fixed32(1.5f32.to_bits()))), reader.next_field().unwrap());
+        match reader.next_field().unwrap() {
+            Some((4, WireValue::Varint(value))) => assert_eq!(-2, zigzag_decode(value)),
+            other => panic!("unexpected field {:?}", other),
+        }
+        assert_eq!(None, reader.next_field().unwrap());
+        assert!(Reader::new(&[0x08, 0xAC]).next_field().is_err());
+    }
+
+}
@@ -0,0 +1,234 @@
+use crate::context::ApiKey;
+use crate::handlers::api_keys::{generate, hash, new_id, now, to_json};
+use crate::handlers::api_keys::store::{KeyStore, KeyStoreError};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use log::{error, info};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const DEFAULT_PLAN: &str = "default";
+
+/// Lifecycle of API keys over HTTP, for operators: served on its own address (i.e. localhost, or an internal network), see `serve`
+/// - `GET /keys`, `GET /keys/{id}`: metadata of the keys
+/// - `POST /keys` with `{"owner": "alice", "plan": "free", "prefixes": ["/books"]}`: creates a key
+/// - `POST /keys/{id}/rotate`: replaces a key by a new one, with the same metadata
+/// - `DELETE /keys/{id}`: revokes a key
+///
+/// Keys are only returned once, when created or rotated: stores only know their hash
+#[derive(Debug, Clone)]
+pub struct KeyAdmin {
+    store: Arc<dyn KeyStore>,
+    token: Option<String>,  // hashed
+}
+
+impl KeyAdmin {
+
+    pub fn new(store: Arc<dyn KeyStore>) -> Self {
+        KeyAdmin { store, token: None }
+    }
+
+    /// Requires `Authorization: Bearer <token>` on every call
+    pub fn with_token(&mut self, token: &str) {
+        self.token = Some(hash(token));
+    }
+
+    pub async fn serve(self, address: SocketAddr) -> Result<(), hyper::Error> {
+        let admin = Arc::new(self);
+        let make_svc = make_service_fn(move |_conn| {
+            let admin = admin.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let admin = admin.clone();
+                    async move { Ok::<_, Infallible>(admin.handle(req).await) }
+                }))
+            }
+        });
+        info!("API key admin listening on {}", address);
+        Server::bind(&address).serve(make_svc).await
+    }
+
+    pub async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        if let Some(token) = &self.token {
+            let authorized = req.headers().get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok()?.strip_prefix("Bearer "))
+                .map(|bearer| hash(bearer) == *token)
+                .unwrap_or(false);
+            if !authorized {
+                return empty(StatusCode::UNAUTHORIZED)
+            }
+        }
+        let segments: Vec<String> = req.uri().path().trim_matches('/').split('/').map(str::to_string).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let result = match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["keys"]) => self.store.list().await
+                .map(|keys| json_response(StatusCode::OK, Value::from(keys.iter().map(to_json).collect::<Vec<_>>()))),
+            (&Method::POST, ["keys"]) => match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => self.create(&body).await,
+                Err(e) => Ok(bad_request(&e.to_string())),
+            },
+            (&Method::GET, ["keys", id]) => self.store.get(id).await
+                .map(|key| key.map(|key| json_response(StatusCode::OK, to_json(&key))).unwrap_or_else(|| empty(StatusCode::NOT_FOUND))),
+            (&Method::DELETE, ["keys", id]) => self.store.revoke(id).await
+                .map(|revoked| empty(if revoked { StatusCode::NO_CONTENT } else { StatusCode::NOT_FOUND })),
+            (&Method::POST, ["keys", id, "rotate"]) => self.rotate(id).await,
+            (_, ["keys"]) | (_, ["keys", _]) | (_, ["keys", _, "rotate"]) => Ok(empty(StatusCode::METHOD_NOT_ALLOWED)),
+            _ => Ok(empty(StatusCode::NOT_FOUND)),
+        };
+        result.unwrap_or_else(|e| {
+            error!("API key admin failed: {}", e);
+            empty(StatusCode::SERVICE_UNAVAILABLE)
+        })
+    }
+
+    async fn create(&self, body: &[u8]) -> Result<Response<Body>, KeyStoreError> {
+        let request: Value = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(e) => return Ok(bad_request(&e.to_string())),
+        };
+        let owner = match request.get("owner").and_then(Value::as_str) {
+            Some(owner) if !owner.is_empty() => owner.to_string(),
+            _ => return Ok(bad_request("owner is required")),
+        };
+        let plan = match request.get("plan") {
+            None => DEFAULT_PLAN.to_string(),
+            Some(plan) => match plan.as_str() {
+                Some(plan) => plan.to_string(),
+                None => return Ok(bad_request("plan must be a string")),
+            },
+        };
+        let prefixes = match request.get("prefixes") {
+            None => vec![],
+            Some(prefixes) => match prefixes.as_array().and_then(|prefixes| prefixes.iter().map(|prefix| prefix.as_str().filter(|prefix| prefix.starts_with('/')).map(str::to_string)).collect::<Option<Vec<_>>>()) {
+                Some(prefixes) => prefixes,
+                None => return Ok(bad_request("prefixes must be an array of paths")),
+            },
+        };
+        let key = ApiKey { id: new_id(), owner, plan, prefixes, created_at: now() };
+        let secret = generate();
+        self.store.save(&hash(&secret), key.clone()).await?;
+        info!("API key {} created for {}", key.id, key.owner);
+        let mut resp = with_secret(StatusCode::CREATED, &key, secret);
+        resp.headers_mut().insert(LOCATION, format!("/keys/{}", key.id).parse().unwrap());
+        Ok(resp)
+    }
+
+    async fn rotate(&self, id: &str) -> Result<Response<Body>, KeyStoreError> {
+        let key = match self.store.get(id).await? {
+            Some(key) => key,
+            None => return Ok(empty(StatusCode::NOT_FOUND)),
+        };
+        let secret = generate();
+        self.store.save(&hash(&secret), key.clone()).await?;
+        info!("API key {} rotated", key.id);
+        Ok(with_secret(StatusCode::OK, &key, secret))
+    }
+}
+
+fn with_secret(status: StatusCode, key: &ApiKey, secret: String) -> Response<Body> {
+    let mut body = to_json(key);
+    body["key"] = Value::from(secret);
+    json_response(status, body)
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn bad_request(message: &str) -> Response<Body> {
+    json_response(StatusCode::BAD_REQUEST, json!({"error": message}))
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::handlers::api_keys::ApiKeyHandler;
+    use crate::handlers::api_keys::admin::KeyAdmin;
+    use crate::handlers::api_keys::store::MemoryKeyStore;
+    use crate::tests::{test_server, unwrap_body_as_str, wait_for_gateway, wait_for_port};
+    use hyper::{Body, Client, Method, Request, StatusCode};
+    use hyper::client::HttpConnector;
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    async fn call(client: &Client<HttpConnector>, method: Method, uri: String, body: Option<Value>) -> (StatusCode, Value) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", "Bearer s3cr3t")
+            .body(body.map(|body| Body::from(body.to_string())).unwrap_or_else(Body::empty))
+            .unwrap();
+        let resp = client.request(req).await.unwrap();
+        let status = resp.status();
+        let body = unwrap_body_as_str(resp).await;
+        (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+    }
+
+    fn with_api_key(uri: &str, key: &Value) -> Request<Body> {
+        Request::builder().uri(uri).header("X-Api-Key", key.as_str().unwrap()).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn key_lifecycle() {
+        let gw_port = 13500;
+        let backend_port = 13501;
+        let admin_port = 13502;
+        let store = Arc::new(MemoryKeyStore::new());
+        tokio::spawn(async move { test_server("Granted!", backend_port).await });
+        let keys = store.clone();
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, "/books".to_string()).unwrap();
+            api.add_guard(Box::new(ApiKeyHandler::new(keys)));
+            start_local_gateway(gw_port, vec![api]).await.unwrap();
+        });
+        let mut admin = KeyAdmin::new(store);
+        admin.with_token("s3cr3t");
+        tokio::spawn(admin.serve(SocketAddr::from(([127, 0, 0, 1], admin_port))));
+        wait_for_gateway(gw_port).await;
+        wait_for_port(admin_port).await;
+        let client = Client::new();
+        let keys = format!("http://127.0.0.1:{}/keys", admin_port);
+        let books = format!("http://127.0.0.1:{}/books", gw_port);
+
+        let resp = client.get(keys.parse().unwrap()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        let (status, _) = call(&client, Method::POST, keys.clone(), Some(json!({"plan": "free"}))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        let (status, created) = call(&client, Method::POST, keys.clone(), Some(json!({"owner": "alice", "plan": "free", "prefixes": ["/books"]}))).await;
+        assert_eq!(StatusCode::CREATED, status);
+        assert_eq!("free", created["plan"]);
+        let id = created["id"].as_str().unwrap().to_string();
+        let (status, listed) = call(&client, Method::GET, keys.clone(), None).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1, listed.as_array().unwrap().len());
+        assert_eq!(Value::Null, listed[0]["key"]); // never returned again
+        assert_eq!(StatusCode::OK, client.request(with_api_key(&books, &created["key"])).await.unwrap().status());
+
+        let (status, rotated) = call(&client, Method::POST, format!("{}/{}/rotate", keys, id), None).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(created["created_at"], rotated["created_at"]);
+        assert_eq!(StatusCode::FORBIDDEN, client.request(with_api_key(&books, &created["key"])).await.unwrap().status());
+        assert_eq!(StatusCode::OK, client.request(with_api_key(&books, &rotated["key"])).await.unwrap().status());
+
+        let (status, _) = call(&client, Method::DELETE, format!("{}/{}", keys, id), None).await;
+        assert_eq!(StatusCode::NO_CONTENT, status);
+        let (status, _) = call(&client, Method::GET, format!("{}/{}", keys, id), None).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!(StatusCode::FORBIDDEN, client.request(with_api_key(&books, &rotated["key"])).await.unwrap().status());
+        let (status, _) = call(&client, Method::PUT, format!("{}/{}", keys, id), None).await;
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, status);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::context::{ApiKey, ClientRequest};
+use crate::handlers::{HandlerResponse, RequestGuard};
+use crate::handlers::api_keys::store::KeyStore;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{HeaderName, InvalidHeaderName};
+use log::error;
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod admin;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const KEY_PREFIX: &str = "ik_";
+
+/// Authenticates requests by the API key they carry, against a `KeyStore`
+/// Requests without a key get a 401, requests with an unknown key, or a key not granting access to the requested path, a 403
+/// The key header isn't forwarded upstream: the metadata of the key is stored as `ApiKey` in the request extensions instead
+#[derive(Debug, Clone)]
+pub struct ApiKeyHandler {
+    pub header: HeaderName,
+    store: Arc<dyn KeyStore>,
+}
+
+impl ApiKeyHandler {
+
+    /// Keys read from the `X-Api-Key` header
+    pub fn new(store: Arc<dyn KeyStore>) -> Self {
+        ApiKeyHandler { header: HeaderName::from_static(API_KEY_HEADER), store }
+    }
+
+    pub fn with_header(&mut self, header: &str) -> Result<(), InvalidHeaderName> {
+        self.header = HeaderName::try_from(header)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RequestGuard for ApiKeyHandler {
+    async fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        let hash = match req.headers().get(&self.header).map(|value| value.to_str()) {
+            None => return HandlerResponse::Break(status(StatusCode::UNAUTHORIZED)),
+            Some(Err(_)) => return HandlerResponse::Break(status(StatusCode::BAD_REQUEST)),
+            Some(Ok(key)) => hash(key),
+        };
+        let path = match req.extensions().get::<ClientRequest>() {
+            Some(client_req) => client_req.uri.path().to_string(),
+            None => req.uri().path().to_string(),
+        };
+        match self.store.find(&hash).await {
+            Err(e) => {
+                error!("Could not look up API key: {}", e);
+                HandlerResponse::Break(status(StatusCode::SERVICE_UNAVAILABLE))
+            },
+            Ok(Some(key)) if key.allows(&path) => {
+                req.headers_mut().remove(&self.header);
+                req.extensions_mut().insert(key);
+                HandlerResponse::Continue
+            },
+            Ok(_) => HandlerResponse::Break(status(StatusCode::FORBIDDEN)),
+        }
+    }
+}
+
+/// A new random key (256 bits), i.e. "ik_Qm9vW6ZK1k0dT2..."
+pub fn generate() -> String {
+    format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>()))
+}
+
+/// What stores know of a key: its SHA-256, hex encoded (same as `sha256sum`)
+/// Generated keys are long and random enough for a fast hash, there's nothing to brute force
+pub fn hash(key: &str) -> String {
+    ring::digest::digest(&ring::digest::SHA256, key.as_bytes()).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn new_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
+
+pub(crate) fn to_json(key: &ApiKey) -> Value {
+    json!({"id": key.id, "owner": key.owner, "plan": key.plan, "prefixes": key.prefixes, "created_at": key.created_at})
+}
+
+pub(crate) fn from_json(value: &Value) -> Option<ApiKey> {
+    let prefixes = value.get("prefixes").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default();
+    Some(ApiKey {
+        id: value.get("id")?.as_str()?.to_string(),
+        owner: value.get("owner")?.as_str()?.to_string(),
+        plan: value.get("plan").and_then(Value::as_str).unwrap_or_default().to_string(),
+        prefixes: prefixes.iter().map(|prefix| prefix.as_str().map(str::to_string)).collect::<Option<_>>()?,
+        created_at: value.get("created_at").and_then(Value::as_u64).unwrap_or_default(),
+    })
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::ApiKey;
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::handlers::api_keys::{generate, hash, ApiKeyHandler};
+    use crate::handlers::api_keys::store::{KeyStore, MemoryKeyStore};
+    use crate::handlers::rate_limiting::{Algorithm, RateLimiter, RateLimitKey};
+    use crate::tests::{test_server, wait_for_gateway};
+    use hyper::{Body, Client, Request, StatusCode, Uri};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn key(id: &str, prefixes: &[&str]) -> ApiKey {
+        ApiKey { id: id.to_string(), owner: "alice".to_string(), plan: "free".to_string(), prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(), created_at: 0 }
+    }
+
+    #[test]
+    fn keys_are_hashed() {
+        // echo -n "foo" | sha256sum
+        assert_eq!("2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae", hash("foo"));
+        let (first, second) = (generate(), generate());
+        assert!(first.starts_with("ik_"));
+        assert_eq!(46, first.len());
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn prefixes_match_whole_segments() {
+        let restricted = key("1", &["/books/", "/authors"]);
+        assert!(restricted.allows("/books"));
+        assert!(restricted.allows("/books/1"));
+        assert!(restricted.allows("/authors/2/books"));
+        assert!(!restricted.allows("/bookstores"));
+        assert!(!restricted.allows("/admin"));
+        assert!(key("2", &[]).allows("/admin"));
+    }
+
+    fn with_api_key(uri: &Uri, key: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header("X-Api-Key", key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions() {
+        let gw_port = 11000;
+        let backend_port = 11001;
+        let prefix = "/subscribers_only";
+        tokio::spawn(async move {
+            test_server("Granted!", backend_port).await;
+        });
+        let store = Arc::new(MemoryKeyStore::new());
+        let keys = store.clone();
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+            api.add_guard(Box::new(ApiKeyHandler::new(keys)));
+            let per_key = RateLimiter::keyed(Algorithm::Gcra { limit: 1, period: Duration::from_secs(60), burst: 2 }, Box::new(RateLimitKey::ApiKey), 10).unwrap();
+            api.add_guard(Box::new(per_key));
+            start_local_gateway(gw_port, vec![api]).await.unwrap();
+        });
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let uri = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix).as_str()).unwrap();
+        let elsewhere = Uri::from_str(format!("http://127.0.0.1:{}{}/elsewhere", gw_port, prefix).as_str()).unwrap();
+        let subscribed = generate();
+        store.save(&hash(&subscribed), key("1", &[])).await.unwrap();
+        let restricted = generate();
+        store.save(&hash(&restricted), key("2", &["/subscribers_only/elsewhere"])).await.unwrap();
+
+        let resp = client.get(uri.clone()).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status()); // No Api-Key => 401
+        let resp = client.request(with_api_key(&uri, "Something")).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, resp.status()); // Api-Key is present but not subscribed => 403
+        let resp = client.request(with_api_key(&uri, &subscribed)).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status()); // Valid subscription => OK
+        let resp = client.request(with_api_key(&uri, &restricted)).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, resp.status()); // Not granted for this path => 403
+        let resp = client.request(with_api_key(&elsewhere, &restricted)).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = client.request(with_api_key(&elsewhere, &subscribed)).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = client.request(with_api_key(&uri, &subscribed)).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status()); // Limited by key: 2 requests already made with this one
+
+        // Revoke the Api-Key
+        assert!(store.revoke("2").await.unwrap());
+        let resp = client.request(with_api_key(&elsewhere, &restricted)).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());  // Key is no longer valid
+
+        let resp = client.request(with_api_key(&uri, ".भारत")).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());  // Non ASCII char in header is considered invalid
+    }
+}
@@ -0,0 +1,127 @@
+use crate::context::ApiKey;
+use crate::handlers::api_keys::store::{KeyStore, KeyStoreError};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    hash TEXT NOT NULL UNIQUE,
+    owner TEXT NOT NULL,
+    plan TEXT NOT NULL,
+    prefixes TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+)";
+const COLUMNS: &str = "id, owner, plan, prefixes, created_at";
+
+/// Keys of a SQLite database (table `api_keys`, created if needed), shared by the gateway instances of a host
+/// Queries run on the blocking thread pool
+#[derive(Debug, Clone)]
+pub struct SqliteKeyStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl From<rusqlite::Error> for KeyStoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        KeyStoreError::Database(e.to_string())
+    }
+}
+
+impl SqliteKeyStore {
+
+    pub fn open(path: &Path) -> Result<Self, KeyStoreError> {
+        let connection = Connection::open(path)?;
+        connection.execute(CREATE_TABLE, [])?;
+        Ok(SqliteKeyStore { connection: Arc::new(Mutex::new(connection)) })
+    }
+
+    async fn run<T, F>(&self, query: F) -> Result<T, KeyStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().map_err(|_| KeyStoreError::Poisoned)?;
+            Ok(query(&connection)?)
+        })
+        .await
+        .map_err(|e| KeyStoreError::Database(e.to_string()))?
+    }
+}
+
+fn api_key(row: &Row) -> rusqlite::Result<ApiKey> {
+    let prefixes: String = row.get(3)?;
+    let prefixes = serde_json::from_str::<Vec<String>>(&prefixes)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e)))?;
+    Ok(ApiKey { id: row.get(0)?, owner: row.get(1)?, plan: row.get(2)?, prefixes, created_at: row.get(4)? })
+}
+
+#[async_trait]
+impl KeyStore for SqliteKeyStore {
+    async fn find(&self, hash: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        let hash = hash.to_string();
+        self.run(move |connection| {
+            connection.query_row(&format!("SELECT {} FROM api_keys WHERE hash = ?1", COLUMNS), [hash], api_key).optional()
+        }).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        let id = id.to_string();
+        self.run(move |connection| {
+            connection.query_row(&format!("SELECT {} FROM api_keys WHERE id = ?1", COLUMNS), [id], api_key).optional()
+        }).await
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, KeyStoreError> {
+        self.run(|connection| {
+            let mut statement = connection.prepare(&format!("SELECT {} FROM api_keys ORDER BY created_at, id", COLUMNS))?;
+            let keys = statement.query_map([], api_key)?.collect();
+            keys
+        }).await
+    }
+
+    async fn save(&self, hash: &str, key: ApiKey) -> Result<(), KeyStoreError> {
+        let hash = hash.to_string();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO api_keys (id, hash, owner, plan, prefixes, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![key.id, hash, key.owner, key.plan, Value::from(key.prefixes).to_string(), key.created_at],
+            ).map(|_| ())
+        }).await
+    }
+
+    async fn revoke(&self, id: &str) -> Result<bool, KeyStoreError> {
+        let id = id.to_string();
+        self.run(move |connection| connection.execute("DELETE FROM api_keys WHERE id = ?1", [id]).map(|deleted| deleted > 0)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::ApiKey;
+    use crate::handlers::api_keys::hash;
+    use crate::handlers::api_keys::sqlite::SqliteKeyStore;
+    use crate::handlers::api_keys::store::KeyStore;
+    use crate::tests::temp_dir;
+
+    #[tokio::test]
+    async fn keys_are_persisted() {
+        let path = temp_dir().join("keys.db");
+        let key = ApiKey { id: "1".to_string(), owner: "alice".to_string(), plan: "free".to_string(), prefixes: vec!["/books".to_string()], created_at: 1 };
+        let store = SqliteKeyStore::open(&path).unwrap();
+        store.save(&hash("first"), key.clone()).await.unwrap();
+        store.save(&hash("rotated"), key.clone()).await.unwrap();
+        store.save(&hash("second"), ApiKey { id: "2".to_string(), prefixes: vec![], created_at: 0, ..key.clone() }).await.unwrap();
+
+        let reopened = SqliteKeyStore::open(&path).unwrap();
+        assert_eq!(None, reopened.find(&hash("first")).await.unwrap());
+        assert_eq!(Some(key.clone()), reopened.find(&hash("rotated")).await.unwrap());
+        assert_eq!(vec!["2", "1"], reopened.list().await.unwrap().iter().map(|key| key.id.as_str()).collect::<Vec<_>>());
+        assert!(reopened.revoke("1").await.unwrap());
+        assert!(!reopened.revoke("1").await.unwrap());
+        assert_eq!(None, store.get("1").await.unwrap());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::context::ApiKey;
+use crate::handlers::api_keys::{from_json, to_json};
+use async_trait::async_trait;
+use log::{error, info};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Where API keys live, indexed by their hash (see `hash`): keys themselves are never stored
+#[async_trait]
+pub trait KeyStore: Send + Sync + Debug {
+    /// The key with this hash
+    async fn find(&self, hash: &str) -> Result<Option<ApiKey>, KeyStoreError>;
+    async fn get(&self, id: &str) -> Result<Option<ApiKey>, KeyStoreError>;
+    /// Every key, oldest first
+    async fn list(&self) -> Result<Vec<ApiKey>, KeyStoreError>;
+    /// Adds a key, or replaces the one with the same ID: its previous hash no longer matches (i.e. on rotation)
+    async fn save(&self, hash: &str, key: ApiKey) -> Result<(), KeyStoreError>;
+    /// Whether there was a key with this ID
+    async fn revoke(&self, id: &str) -> Result<bool, KeyStoreError>;
+}
+
+#[derive(Debug)]
+pub enum KeyStoreError {
+    Io(std::io::Error),
+    Invalid(String),    // unreadable keys, i.e. a malformed file
+    Database(String),
+    Poisoned,
+}
+
+impl Display for KeyStoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyStoreError::Io(e) => write!(f, "Key store unreachable: {}", e),
+            KeyStoreError::Invalid(e) => write!(f, "Invalid API keys: {}", e),
+            KeyStoreError::Database(e) => write!(f, "Key database error: {}", e),
+            KeyStoreError::Poisoned => write!(f, "API keys poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for KeyStoreError {}
+
+impl From<std::io::Error> for KeyStoreError {
+    fn from(e: std::io::Error) -> Self {
+        KeyStoreError::Io(e)
+    }
+}
+
+/// Keys known by this gateway instance only, lost on restart
+#[derive(Debug, Default)]
+pub struct MemoryKeyStore {
+    keys: RwLock<HashMap<String, ApiKey>>, // by hash
+}
+
+impl MemoryKeyStore {
+
+    pub fn new() -> Self {
+        MemoryKeyStore::default()
+    }
+
+    fn entries(&self) -> Result<Vec<(String, ApiKey)>, KeyStoreError> {
+        let keys = self.keys.read().map_err(|_| KeyStoreError::Poisoned)?;
+        let mut entries: Vec<(String, ApiKey)> = keys.iter().map(|(hash, key)| (hash.clone(), key.clone())).collect();
+        entries.sort_by(|(_, a), (_, b)| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        Ok(entries)
+    }
+
+    fn insert(&self, hash: &str, key: ApiKey) -> Result<(), KeyStoreError> {
+        let mut keys = self.keys.write().map_err(|_| KeyStoreError::Poisoned)?;
+        keys.retain(|_, existing| existing.id != key.id);
+        keys.insert(hash.to_string(), key);
+        Ok(())
+    }
+
+    fn remove(&self, id: &str) -> Result<bool, KeyStoreError> {
+        let mut keys = self.keys.write().map_err(|_| KeyStoreError::Poisoned)?;
+        let before = keys.len();
+        keys.retain(|_, existing| existing.id != id);
+        Ok(keys.len() < before)
+    }
+
+    fn replace_all(&self, entries: Vec<(String, ApiKey)>) -> Result<(), KeyStoreError> {
+        *self.keys.write().map_err(|_| KeyStoreError::Poisoned)? = entries.into_iter().collect();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryKeyStore {
+    async fn find(&self, hash: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        Ok(self.keys.read().map_err(|_| KeyStoreError::Poisoned)?.get(hash).cloned())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        Ok(self.keys.read().map_err(|_| KeyStoreError::Poisoned)?.values().find(|key| key.id == id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, KeyStoreError> {
+        Ok(self.entries()?.into_iter().map(|(_, key)| key).collect())
+    }
+
+    async fn save(&self, hash: &str, key: ApiKey) -> Result<(), KeyStoreError> {
+        self.insert(hash, key)
+    }
+
+    async fn revoke(&self, id: &str) -> Result<bool, KeyStoreError> {
+        self.remove(id)
+    }
+}
+
+/// Keys of a JSON file, reloaded once it's modified (i.e. deployed by a configuration management tool), see `watch`
+/// The file is an array of keys: `[{"id": "1", "hash": "<sha256 of the key>", "owner": "alice", "plan": "free", "prefixes": ["/books"], "created_at": 0}]`
+/// Keys saved or revoked through the store are written back to it
+#[derive(Debug)]
+pub struct FileKeyStore {
+    path: PathBuf,
+    keys: MemoryKeyStore,
+    modified: Mutex<Option<SystemTime>>,   // of the file, when it was last read or written
+}
+
+impl FileKeyStore {
+
+    /// A missing file gives an empty store, created on the first save
+    pub fn open(path: &Path) -> Result<Self, KeyStoreError> {
+        let store = FileKeyStore { path: path.to_path_buf(), keys: MemoryKeyStore::new(), modified: Mutex::new(None) };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Reads the file again if it was modified since, returns whether it was
+    pub fn reload(&self) -> Result<bool, KeyStoreError> {
+        let mut modified = self.modified.lock().map_err(|_| KeyStoreError::Poisoned)?;
+        let current = match std::fs::metadata(&self.path) {
+            Ok(metadata) => Some(metadata.modified()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if current == *modified {
+            return Ok(false)
+        }
+        *modified = current; // not read again until it's fixed, if invalid
+        let entries = match current {
+            Some(_) => parse(&std::fs::read(&self.path)?)?,
+            None => vec![],
+        };
+        self.keys.replace_all(entries)?;
+        Ok(true)
+    }
+
+    /// Checks the file for modifications every `interval`, forever. Keeps the previous keys if it's invalid
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        info!("Watching API keys of {} every {:?}", self.path.display(), interval);
+        loop {
+            tokio::time::sleep(interval).await;
+            match self.reload() {
+                Ok(true) => info!("API keys reloaded from {}", self.path.display()),
+                Ok(false) => {},
+                Err(e) => error!("Could not reload API keys from {}: {}", self.path.display(), e),
+            }
+        }
+    }
+
+    /// Writes the keys in memory (through a temporary file, so that a crash never leaves a partial file)
+    fn write(&self, modified: &mut Option<SystemTime>) -> Result<(), KeyStoreError> {
+        let records: Vec<Value> = self.keys.entries()?.into_iter().map(|(hash, key)| {
+            let mut record = to_json(&key);
+            record["hash"] = Value::from(hash);
+            record
+        }).collect();
+        let mut tmp = self.path.clone();
+        tmp.set_extension("tmp");
+        std::fs::write(&tmp, Value::from(records).to_string())?;
+        std::fs::rename(tmp, &self.path)?;
+        *modified = Some(std::fs::metadata(&self.path)?.modified()?);
+        Ok(())
+    }
+}
+
+fn parse(content: &[u8]) -> Result<Vec<(String, ApiKey)>, KeyStoreError> {
+    let records: Vec<Value> = serde_json::from_slice(content).map_err(|e| KeyStoreError::Invalid(e.to_string()))?;
+    records.iter()
+        .map(|record| {
+            let hash = record.get("hash").and_then(Value::as_str).map(str::to_ascii_lowercase);
+            hash.zip(from_json(record)).ok_or_else(|| KeyStoreError::Invalid(record.to_string()))
+        })
+        .collect()
+}
+
+#[async_trait]
+impl KeyStore for FileKeyStore {
+    async fn find(&self, hash: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        self.keys.find(hash).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<ApiKey>, KeyStoreError> {
+        self.keys.get(id).await
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, KeyStoreError> {
+        self.keys.list().await
+    }
+
+    async fn save(&self, hash: &str, key: ApiKey) -> Result<(), KeyStoreError> {
+        let mut modified = self.modified.lock().map_err(|_| KeyStoreError::Poisoned)?;
+        self.keys.insert(hash, key)?;
+        self.write(&mut modified)
+    }
+
+    async fn revoke(&self, id: &str) -> Result<bool, KeyStoreError> {
+        let mut modified = self.modified.lock().map_err(|_| KeyStoreError::Poisoned)?;
+        let revoked = self.keys.remove(id)?;
+        if revoked {
+            self.write(&mut modified)?;
+        }
+        Ok(revoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::ApiKey;
+    use crate::handlers::api_keys::hash;
+    use crate::handlers::api_keys::store::{FileKeyStore, KeyStore, MemoryKeyStore};
+    use crate::tests::temp_dir;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn key(id: &str, created_at: u64) -> ApiKey {
+        ApiKey { id: id.to_string(), owner: "alice".to_string(), plan: "free".to_string(), prefixes: vec!["/books".to_string()], created_at }
+    }
+
+    #[tokio::test]
+    async fn saved_keys_replace_keys_with_the_same_id() {
+        let store = MemoryKeyStore::new();
+        store.save(&hash("first"), key("1", 2)).await.unwrap();
+        store.save(&hash("second"), key("2", 1)).await.unwrap();
+        store.save(&hash("rotated"), key("1", 2)).await.unwrap();
+        assert_eq!(None, store.find(&hash("first")).await.unwrap());
+        assert_eq!(Some(key("1", 2)), store.find(&hash("rotated")).await.unwrap());
+        assert_eq!(vec![key("2", 1), key("1", 2)], store.list().await.unwrap());
+        assert!(store.revoke("2").await.unwrap());
+        assert!(!store.revoke("2").await.unwrap());
+        assert_eq!(None, store.get("2").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn files_are_watched() {
+        let path = temp_dir().join("keys.json");
+        let store = Arc::new(FileKeyStore::open(&path).unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+        store.save(&hash("first"), key("1", 0)).await.unwrap();
+        assert_eq!(Some(key("1", 0)), FileKeyStore::open(&path).unwrap().get("1").await.unwrap());
+        assert!(!store.reload().unwrap()); // written by the store itself
+
+        tokio::spawn(store.clone().watch(Duration::from_millis(10)));
+        let deployed = format!(r#"[{{"id": "2", "hash": "{}", "owner": "bob", "prefixes": []}}]"#, hash("second"));
+        std::fs::write(&path, deployed).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(None, store.find(&hash("first")).await.unwrap());
+        assert_eq!("bob", store.find(&hash("second")).await.unwrap().unwrap().owner);
+
+        std::fs::write(&path, "not JSON").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(store.find(&hash("second")).await.unwrap().is_some()); // previous keys are kept
+    }
+}
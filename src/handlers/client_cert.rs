@@ -0,0 +1,172 @@
+use crate::context::ClientCertificate;
+use crate::handlers::{GlobalHandler, HandlerResponse};
+use crate::handlers::HandlerResponse::{Continue, Break};
+use hyper::{Request, Response, Body, StatusCode};
+use hyper::header::{HeaderName, HeaderValue, InvalidHeaderName};
+use std::str::FromStr;
+use log::warn;
+
+/// mTLS policy for an Api: requires a client certificate (verified by the TLS listener, see `ClientAuth`)
+/// and forwards the client identity upstream in headers
+/// Headers with the same names sent by the client are always removed, so that they can't be spoofed
+#[derive(Debug, Clone)]
+pub struct ClientCertHandler {
+    pub required: bool,
+    pub subject_header: Option<HeaderName>,
+    pub sans_header: Option<HeaderName>,    // comma separated list of subject alternative names
+}
+
+impl ClientCertHandler {
+
+    /// Requests without a verified client certificate are rejected with 401
+    pub fn required() -> Self {
+        ClientCertHandler { required: true, subject_header: None, sans_header: None }
+    }
+
+    /// Requests without a client certificate are forwarded, but without identity headers
+    pub fn optional() -> Self {
+        ClientCertHandler { required: false, subject_header: None, sans_header: None }
+    }
+
+    /// i.e. `X-Client-Cert-Subject`
+    pub fn forward_subject_as(&mut self, header: &str) -> Result<(), InvalidHeaderName> {
+        self.subject_header = Some(HeaderName::from_str(header)?);
+        Ok(())
+    }
+
+    pub fn forward_sans_as(&mut self, header: &str) -> Result<(), InvalidHeaderName> {
+        self.sans_header = Some(HeaderName::from_str(header)?);
+        Ok(())
+    }
+}
+
+impl GlobalHandler for ClientCertHandler {
+    fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        for header in self.subject_header.iter().chain(self.sans_header.iter()) {
+            req.headers_mut().remove(header);
+        }
+        let identity = match req.extensions().get::<ClientCertificate>() {
+            None if self.required => return Break(Response::builder().status(StatusCode::UNAUTHORIZED).body(Body::empty()).unwrap()),
+            None => return Continue,
+            Some(identity) => identity.clone(),
+        };
+        if let Some(header) = &self.subject_header {
+            insert(req, header, &identity.subject);
+        }
+        if let Some(header) = &self.sans_header {
+            insert(req, header, &identity.sans.join(","));
+        }
+        Continue
+    }
+
+    fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+        Continue
+    }
+}
+
+fn insert(req: &mut Request<Body>, header: &HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            req.headers_mut().insert(header.clone(), value);
+        },
+        Err(_) => warn!("Client certificate value {} can't be forwarded in {}", value, header),
+    }
+}
+
+#[cfg(all(test, feature = "rustls"))] // client certificates are only requested by the rustls listener
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::tls::{TlsConfig, ClientAuth};
+    use crate::gateway::start_tls_gateway;
+    use crate::handlers::client_cert::ClientCertHandler;
+    use crate::tests::{self_signed, test_ca, signed_by, tls_connect_as, wait_for_port, unwrap_body_as_str, TestCert};
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use hyper::client::conn;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use tokio_rustls::rustls::version::TLS13;
+
+    async fn echo_identity_server(port: u16) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let header = |name: &str| req.headers().get(name).map(|v| v.to_str().unwrap().to_string()).unwrap_or_default();
+                let body = format!("{}|{}", header("X-Client-Cert-Subject"), header("X-Client-Cert-San"));
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }))
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    async fn get(port: u16, server: &TestCert, client: Option<&TestCert>, path: &str) -> Result<Response<Body>, std::io::Error> {
+        let tls = tls_connect_as(client, port, "localhost", &[server], vec![], &[&TLS13]).await?;
+        let (mut sender, connection) = conn::handshake(tls).await.unwrap();
+        tokio::spawn(connection);
+        let req = Request::builder()
+            .uri(path)
+            .header("Host", "localhost")
+            .header("X-Client-Cert-Subject", "CN=spoofed")
+            .body(Body::empty())
+            .unwrap();
+        sender.send_request(req).await.map_err(std::io::Error::other)
+    }
+
+    #[tokio::test]
+    async fn client_cert_required_by_prefix() {
+        let gw_port = 12100;
+        let backend_port = 12101;
+        let server = self_signed(&["localhost"]);
+        let ca = test_ca();
+        let client = signed_by(&ca, "client-1", &["client-1.internal"]);
+        let mut tls = TlsConfig::new(&server.cert_path, &server.key_path);
+        tls.with_client_auth(ClientAuth::Optional(ca.cert_path.clone()));
+        tokio::spawn(async move { echo_identity_server(backend_port).await });
+        tokio::spawn(async move {
+            let open = Api::http("127.0.0.1", backend_port, "/open".to_string()).unwrap();
+            let mut secured = Api::http("127.0.0.1", backend_port, "/secured".to_string()).unwrap();
+            let mut handler = ClientCertHandler::required();
+            handler.forward_subject_as("X-Client-Cert-Subject").unwrap();
+            handler.forward_sans_as("X-Client-Cert-San").unwrap();
+            secured.add_global_handler(Box::new(handler));
+            start_tls_gateway(gw_port, vec![open, secured], tls).await
+        });
+        wait_for_port(gw_port).await;
+
+        let resp = get(gw_port, &server, None, "/open").await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let resp = get(gw_port, &server, None, "/secured").await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+
+        let resp = get(gw_port, &server, Some(&client), "/secured").await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("CN=client-1|DNS:client-1.internal", unwrap_body_as_str(resp).await);
+
+        // not signed by the configured CA
+        let untrusted = signed_by(&test_ca(), "client-2", &[]);
+        assert!(get(gw_port, &server, Some(&untrusted), "/open").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_cert_required_by_listener() {
+        let gw_port = 12110;
+        let backend_port = 12111;
+        let server = self_signed(&["localhost"]);
+        let ca = test_ca();
+        let client = signed_by(&ca, "client-1", &[]);
+        let mut tls = TlsConfig::new(&server.cert_path, &server.key_path);
+        tls.with_client_auth(ClientAuth::Required(ca.cert_path.clone()));
+        tokio::spawn(async move { echo_identity_server(backend_port).await });
+        tokio::spawn(async move {
+            let api = Api::http("127.0.0.1", backend_port, "/open".to_string()).unwrap();
+            start_tls_gateway(gw_port, vec![api], tls).await
+        });
+        wait_for_port(gw_port).await;
+        assert!(get(gw_port, &server, None, "/open").await.is_err());
+        let resp = get(gw_port, &server, Some(&client), "/open").await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        // no handler on this Api: the spoofed header reaches the backend untouched
+        assert_eq!("CN=spoofed|", unwrap_body_as_str(resp).await);
+    }
+
+}
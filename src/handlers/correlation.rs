@@ -1,3 +1,114 @@
+use crate::context::{CorrelationId, ResponseHeaders};
+use crate::handlers::{GlobalHandler, HandlerResponse};
+use hyper::{Body, HeaderMap, Request, Response};
+use hyper::header::{HeaderName, HeaderValue, InvalidHeaderName};
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const TRACEPARENT: &str = "traceparent";
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// How missing correlation IDs are generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    UuidV4,         // i.e. "0b9c8a3e-1d5f-4c7a-9e2b-6f4d3c2b1a09"
+    Ulid,           // sortable by creation time, i.e. "01ARZ3NDEKTSV4RRFFQ69G5FAV"
+    TraceParent,    // W3C trace context, i.e. "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+}
+
+/// Makes sure every request carries a correlation ID: forwarded upstream if the client sent one, generated otherwise
+/// The ID is echoed on the response, and stored as `CorrelationId` in the request extensions (the trace ID of a `traceparent`)
+#[derive(Debug, Clone)]
+pub struct CorrelationIdHandler {
+    pub header: HeaderName,
+    pub format: IdFormat,
+}
+
+impl CorrelationIdHandler {
+
+    pub fn new(header: &str, format: IdFormat) -> Result<Self, InvalidHeaderName> {
+        Ok(CorrelationIdHandler { header: HeaderName::try_from(header)?, format })
+    }
+
+    /// `traceparent` headers, as defined by https://www.w3.org/TR/trace-context/
+    pub fn trace_context() -> Self {
+        CorrelationIdHandler { header: HeaderName::from_static(TRACEPARENT), format: IdFormat::TraceParent }
+    }
+
+    pub fn generate(&self) -> String {
+        match self.format {
+            IdFormat::UuidV4 => uuid_v4(),
+            IdFormat::Ulid => ulid(),
+            IdFormat::TraceParent => format!("00-{:032x}-{:016x}-01", rand::random::<u128>().max(1), rand::random::<u64>().max(1)),
+        }
+    }
+
+    /// The correlation ID carried by `value`, if it's valid: a `traceparent` must be well-formed, any visible ASCII otherwise
+    fn correlation_id(&self, value: &HeaderValue) -> Option<String> {
+        let value = value.to_str().ok().filter(|value| !value.is_empty())?;
+        match self.format {
+            IdFormat::TraceParent => trace_id(value).map(str::to_string),
+            _ => Some(value.to_string()),
+        }
+    }
+}
+
+impl GlobalHandler for CorrelationIdHandler {
+    fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        let received = req.headers().get(&self.header).and_then(|value| Some((value.clone(), self.correlation_id(value)?)));
+        let (value, id) = match received {
+            Some(received) => received,
+            None => {
+                let generated = self.generate();
+                let value = HeaderValue::from_str(&generated).unwrap(); // generated IDs are ASCII
+                let id = self.correlation_id(&value).unwrap_or(generated);
+                req.headers_mut().insert(self.header.clone(), value.clone());
+                (value, id)
+            },
+        };
+        req.extensions_mut().insert(CorrelationId(id));
+        let mut echoed = HeaderMap::new();
+        echoed.insert(self.header.clone(), value);
+        ResponseHeaders::append(req.extensions_mut(), echoed);
+        HandlerResponse::Continue
+    }
+
+    fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+        HandlerResponse::Continue
+    }
+}
+
+fn uuid_v4() -> String {
+    let mut bytes = rand::random::<[u8; 16]>();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+    bytes[8] = (bytes[8] & 0x3f) | 0x80; // RFC 4122 variant
+    let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// 48 bits of milliseconds since the UNIX epoch, then 80 random bits, in Crockford's base 32
+fn ulid() -> String {
+    let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() & ((1 << 48) - 1);
+    let value = (millis << 80) | (rand::random::<u128>() & ((1 << 80) - 1));
+    (0..26).map(|i| CROCKFORD_BASE32[((value >> (125 - 5 * i)) & 31) as usize] as char).collect()
+}
+
+/// Trace ID of a `traceparent` value: "{version}-{trace-id}-{parent-id}-{flags}", lowercase hex, trace and parent IDs can't be all zeros
+fn trace_id(traceparent: &str) -> Option<&str> {
+    let parts = traceparent.split('-').collect::<Vec<&str>>();
+    let lower_hex = |part: &str, len: usize| part.len() == len && part.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    let (version, trace_id, parent_id, flags) = match parts[..] {
+        [version, trace_id, parent_id, flags, ..] => (version, trace_id, parent_id, flags),
+        _ => return None,
+    };
+    // later versions may append fields
+    let valid = lower_hex(version, 2) && version != "ff" && (version != "00" || parts.len() == 4)
+        && lower_hex(trace_id, 32) && trace_id.bytes().any(|b| b != b'0')
+        && lower_hex(parent_id, 16) && parent_id.bytes().any(|b| b != b'0')
+        && lower_hex(flags, 2);
+    if valid { Some(trace_id) } else { None }
+}
+
 #[cfg(test)]
 mod tests {
     use hyper::service::{make_service_fn, service_fn};
@@ -5,37 +116,55 @@ mod tests {
     use hyper::{Server, StatusCode, Body, Response, Client, Uri, Request};
     use std::convert::Infallible;
     use crate::conf::api::Api;
+    use crate::context::CorrelationId;
     use crate::gateway::start_local_gateway;
+    use crate::handlers::GlobalHandler;
+    use crate::handlers::correlation::{trace_id, CorrelationIdHandler, IdFormat};
+    use crate::handlers::rate_limiting::{Algorithm, RateLimiter};
     use crate::tests::{wait_for_gateway, unwrap_body_as_str};
     use std::str::FromStr;
+    use std::time::Duration;
     use uuid::Uuid;
-    use hyper::header::{HeaderValue, HeaderName};
+    use hyper::header::HeaderValue;
     use log::*;
-    use crate::handlers::{GlobalHandler, HandlerResponse};
-    use crate::handlers::HandlerResponse::Continue;
-
 
-    #[derive(Debug, Clone)]
-    pub struct CorrelationIdHandler {
-        header_name: String
+    #[test]
+    fn ids_are_generated() {
+        let uuid = CorrelationIdHandler::new("X-Request-Id", IdFormat::UuidV4).unwrap().generate();
+        assert_eq!(Some(4), Uuid::parse_str(&uuid).ok().map(|uuid| uuid.get_version_num()));
+        let ulid = CorrelationIdHandler::new("X-Request-Id", IdFormat::Ulid).unwrap().generate();
+        assert_eq!(26, ulid.len());
+        assert!(ulid.starts_with('0')); // the timestamp only takes 48 of the 50 leading bits
+        let traceparent = CorrelationIdHandler::trace_context().generate();
+        assert_eq!(55, traceparent.len());
+        assert!(trace_id(&traceparent).is_some());
+        assert!(CorrelationIdHandler::new("X Request Id", IdFormat::UuidV4).is_err());
     }
 
-    impl GlobalHandler for CorrelationIdHandler {
-        fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
-            let req_headers = req.headers_mut();
-            if req_headers.get(self.header_name.clone()).is_none() {
-                let correlation: String = Uuid::new_v4().to_hyphenated().to_string();
-                let name = self.header_name.as_str();
-                req_headers.insert(HeaderName::from_str(name).unwrap(), HeaderValue::from_str(correlation.as_str()).unwrap());
-            }
-            Continue
-        }
+    #[test]
+    fn traceparent_is_validated() {
+        assert_eq!(Some("4bf92f3577b34da6a3ce929d0e0e4736"), trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert_eq!(Some("4bf92f3577b34da6a3ce929d0e0e4736"), trace_id("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-future"));
+        assert_eq!(None, trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-future"));
+        assert_eq!(None, trace_id("00-00000000000000000000000000000000-00f067aa0ba902b7-01"));
+        assert_eq!(None, trace_id("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"));
+        assert_eq!(None, trace_id("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"));
+        assert_eq!(None, trace_id("00-4bf92f3577b34da6a3ce929d0e0e4736"));
 
-        fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
-            Continue
-        }
+        let handler = CorrelationIdHandler::trace_context();
+        let mut req = Request::get("/").header("traceparent", "not-a-trace").body(Body::empty()).unwrap();
+        handler.handle_req(&mut req);
+        let id = req.extensions().get::<CorrelationId>().unwrap().0.clone();
+        assert_eq!(Some(id.as_str()), trace_id(req.headers()["traceparent"].to_str().unwrap()));
     }
 
+    #[test]
+    fn logs_are_tagged_with_the_correlation_id() {
+        let mut req = Request::get("/").header("x-request-id", "42").body(Body::empty()).unwrap();
+        assert_eq!("", CorrelationId::log_tag(req.extensions()));
+        CorrelationIdHandler::new("x-request-id", IdFormat::UuidV4).unwrap().handle_req(&mut req);
+        assert_eq!(" (request 42)", CorrelationId::log_tag(req.extensions()));
+    }
 
     async fn echo_correlation_server(header: &'static str, backend_port: u16) {
         let addr = SocketAddr::from(([127, 0, 0, 1], backend_port));
@@ -74,15 +203,17 @@ mod tests {
         });
         tokio::spawn(async move {
             let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
-            api.add_global_handler(Box::new(CorrelationIdHandler { header_name: header.to_string() }));
+            api.add_global_handler(Box::new(CorrelationIdHandler::new(header, IdFormat::UuidV4).unwrap()));
             start_local_gateway(gw_port, vec![api]).await
         });
         wait_for_gateway(gw_port).await;
         let client = Client::new();
         let resp = client.get(Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix).as_str()).unwrap()).await.unwrap();
         assert_eq!(200, resp.status());
+        let echoed = resp.headers()[header].to_str().unwrap().to_string();
         let body = unwrap_body_as_str(resp).await;
         assert!(Uuid::parse_str(body.as_str()).is_ok());
+        assert_eq!(body, echoed);
     }
 
     #[tokio::test]
@@ -96,7 +227,7 @@ mod tests {
         });
         tokio::spawn(async move {
             let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
-            api.add_global_handler(Box::new(CorrelationIdHandler { header_name: header.to_string() }));
+            api.add_global_handler(Box::new(CorrelationIdHandler::new(header, IdFormat::UuidV4).unwrap()));
             start_local_gateway(gw_port, vec![api]).await
         });
         wait_for_gateway(gw_port).await;
@@ -111,8 +242,35 @@ mod tests {
             .unwrap();
         let resp = client.request(req).await.unwrap();
         assert_eq!(200, resp.status());
+        assert_eq!(custom_correlation, resp.headers()[header]);
         let body = unwrap_body_as_str(resp).await;
         assert_eq!(body, custom_correlation);
     }
 
+    #[tokio::test]
+    async fn correlation_id_is_echoed_on_gateway_responses() {
+        let gw_port = 13704;
+        let backend_port = 13705; // nothing listens there
+        let header = "X-Correlation-Id";
+        tokio::spawn(async move {
+            let mut limited = Api::http("127.0.0.1", backend_port, "/limited".to_string()).unwrap();
+            limited.add_global_handler(Box::new(CorrelationIdHandler::new(header, IdFormat::Ulid).unwrap()));
+            limited.add_guard(Box::new(RateLimiter::new(Algorithm::TokenBucket { capacity: 1, refill_period: Duration::from_secs(3600) }).unwrap()));
+            let mut unreachable = Api::http("127.0.0.1", backend_port, "/unreachable".to_string()).unwrap();
+            unreachable.add_global_handler(Box::new(CorrelationIdHandler::new(header, IdFormat::Ulid).unwrap()));
+            start_local_gateway(gw_port, vec![limited, unreachable]).await
+        });
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let get = |path: &str| client.get(Uri::from_str(&format!("http://127.0.0.1:{}{}", gw_port, path)).unwrap());
+
+        assert_eq!(StatusCode::BAD_GATEWAY, get("/limited").await.unwrap().status()); // the only token
+        let resp = get("/limited").await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+        assert_eq!(26, resp.headers()[header].len());
+        let resp = get("/unreachable").await.unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, resp.status());
+        assert_eq!(26, resp.headers()[header].len());
+    }
+
 }
\ No newline at end of file
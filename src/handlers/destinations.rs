@@ -0,0 +1,75 @@
+use crate::conf::host_matches;
+use crate::handlers::{GlobalHandler, HandlerResponse};
+use crate::handlers::HandlerResponse::{Continue, Break};
+use hyper::{Request, Response, Body, StatusCode, Method};
+
+/// Egress policy of a `ForwardProxyConfig`: only destinations matching the allow-list can be reached, others are rejected with 403
+/// Hosts are exact names or IPs ("api.example.com", "10.0.0.1"), or wildcards matching a single label, like SNI routes ("*.example.com" allows "a.example.com", not "a.b.example.com")
+#[derive(Debug, Clone)]
+pub struct AllowedDestinations {
+    pub hosts: Vec<String>,
+    pub ports: Vec<u16>,    // empty means any port
+}
+
+impl AllowedDestinations {
+
+    pub fn hosts(hosts: &[&str]) -> Self {
+        AllowedDestinations { hosts: hosts.iter().map(|host| host.to_ascii_lowercase()).collect(), ports: vec![] }
+    }
+
+    pub fn with_ports(&mut self, ports: &[u16]) {
+        self.ports = ports.to_vec();
+    }
+
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host_allowed = self.hosts.iter().any(|allowed| host_matches(allowed, host));
+        host_allowed && (self.ports.is_empty() || self.ports.contains(&port))
+    }
+}
+
+impl GlobalHandler for AllowedDestinations {
+    fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        let default_port = if req.method() == Method::CONNECT || req.uri().scheme_str() == Some("https") { 443 } else { 80 };
+        let allowed = req.uri().host()
+            .map(|host| self.allows(host, req.uri().port_u16().unwrap_or(default_port)))
+            .unwrap_or(false);
+        if allowed {
+            Continue
+        } else {
+            Break(Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).unwrap())
+        }
+    }
+
+    fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+        Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::destinations::AllowedDestinations;
+
+    #[test]
+    fn allowed_hosts_and_ports() {
+        let mut allowed = AllowedDestinations::hosts(&["API.example.com", "*.internal", "::1"]);
+        assert!(allowed.allows("api.example.com", 80));
+        assert!(!allowed.allows("www.example.com", 80));
+        assert!(allowed.allows("db.internal", 5432));
+        assert!(!allowed.allows("db.eu.internal", 5432));
+        assert!(!allowed.allows("internal", 5432));
+        assert!(!allowed.allows("notinternal", 5432));
+        assert!(allowed.allows("[::1]", 8080));
+        allowed.with_ports(&[443]);
+        assert!(allowed.allows("api.example.com", 443));
+        assert!(!allowed.allows("api.example.com", 80));
+    }
+
+    #[test]
+    fn wildcards_match_a_single_label() {
+        let allowed = AllowedDestinations::hosts(&["*.example.com"]);
+        assert!(allowed.allows("a.example.com", 443));
+        assert!(!allowed.allows("a.b.example.com", 443));
+        assert!(!allowed.allows("example.com", 443));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::handlers::ResponseFinalizer;
+use async_trait::async_trait;
+use hyper::{Body, Response, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, TRANSFER_ENCODING};
+use log::{debug, error};
+use serde_json::{Map, Value};
+use std::fmt::{Display, Formatter};
+
+/// A step of a `JsonTransform`, locations are JSON pointers (RFC 6901): "/author/name", "/tags/0"
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonOperation {
+    Extract(String),            // replaces the document by the value at this location, `null` if there's none
+    Allow(Vec<String>),         // keeps these locations only
+    Deny(Vec<String>),          // removes these locations
+    Rename(String, String),     // moves the value at the first location to the second one
+    Wrap(String, Value),        // inserts the document at this key of an envelope object (i.e. `{"data": document, "version": 1}`)
+}
+
+/// A location that isn't a JSON pointer: not empty, and not starting with '/'
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPointer(pub String);
+
+impl Display for InvalidPointer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid JSON pointer {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPointer {}
+
+/// Transforms JSON responses, applying its operations in order
+/// Allow, deny and rename operations apply to every element of arrays, so that list and single resources can share a transform
+/// Status and headers of the upstream response are kept, but for `Content-Length` (and `ETag`, no longer matching the body)
+/// Only successful responses are transformed by default, and responses that aren't JSON (or are compressed) are left untouched
+#[derive(Debug, Clone, Default)]
+pub struct JsonTransform {
+    pub operations: Vec<JsonOperation>,
+    pub transform_errors: bool,
+}
+
+impl JsonTransform {
+
+    pub fn new() -> Self {
+        JsonTransform::default()
+    }
+
+    /// Replaces responses by the value at `pointer`
+    pub fn pointer(pointer: &str) -> Result<Self, InvalidPointer> {
+        let mut transform = JsonTransform::new();
+        transform.extract(pointer)?;
+        Ok(transform)
+    }
+
+    pub fn extract(&mut self, pointer: &str) -> Result<(), InvalidPointer> {
+        self.operations.push(JsonOperation::Extract(valid(pointer)?));
+        Ok(())
+    }
+
+    pub fn allow(&mut self, pointers: &[&str]) -> Result<(), InvalidPointer> {
+        let pointers = pointers.iter().map(|pointer| valid(pointer)).collect::<Result<_, _>>()?;
+        self.operations.push(JsonOperation::Allow(pointers));
+        Ok(())
+    }
+
+    pub fn deny(&mut self, pointers: &[&str]) -> Result<(), InvalidPointer> {
+        let pointers = pointers.iter().map(|pointer| valid(pointer)).collect::<Result<_, _>>()?;
+        self.operations.push(JsonOperation::Deny(pointers));
+        Ok(())
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), InvalidPointer> {
+        self.operations.push(JsonOperation::Rename(valid(from)?, valid(to)?));
+        Ok(())
+    }
+
+    /// `{"<key>": document}`
+    pub fn wrap(&mut self, key: &str) {
+        self.operations.push(JsonOperation::Wrap(key.to_string(), Value::Object(Map::new())));
+    }
+
+    /// Inserts the document at `key` of `envelope`, which must be an object
+    pub fn wrap_in(&mut self, key: &str, envelope: Value) {
+        self.operations.push(JsonOperation::Wrap(key.to_string(), envelope));
+    }
+
+    /// Transforms error responses (4xx, 5xx) too
+    pub fn transform_errors(&mut self) {
+        self.transform_errors = true;
+    }
+
+    pub fn apply(&self, mut document: Value) -> Value {
+        for operation in &self.operations {
+            document = match operation {
+                JsonOperation::Extract(pointer) => document.pointer_mut(pointer).map(Value::take).unwrap_or(Value::Null),
+                JsonOperation::Wrap(key, envelope) => {
+                    let mut envelope = match envelope {
+                        Value::Object(envelope) => envelope.clone(),
+                        _ => Map::new(),
+                    };
+                    envelope.insert(key.clone(), document);
+                    Value::Object(envelope)
+                },
+                _ => match document {
+                    Value::Array(elements) => Value::Array(elements.into_iter().map(|element| edit(operation, element)).collect()),
+                    document => edit(operation, document),
+                },
+            }
+        }
+        document
+    }
+
+    fn applies_to(&self, res: &Response<Body>) -> bool {
+        let status = res.status();
+        let transformed_status = status.is_success() || (self.transform_errors && (status.is_client_error() || status.is_server_error()));
+        let encoded = res.headers().get(CONTENT_ENCODING).map(|encoding| encoding != "identity").unwrap_or(false);
+        let json = match res.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()) {
+            Some(content_type) => {
+                let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+                media_type == "application/json" || media_type.ends_with("+json")
+            },
+            None => true, // if it parses
+        };
+        transformed_status && !encoded && json
+    }
+}
+
+#[async_trait]
+impl ResponseFinalizer for JsonTransform {
+    async fn transform(&self, res: Response<Body>) -> Response<Body> {
+        if !self.applies_to(&res) {
+            return res
+        }
+        let (mut parts, body) = res.into_parts();
+        let bytes = match hyper::body::to_bytes(body).await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                error!("Could not read upstream response body: {}", e);
+                return Response::builder().status(StatusCode::BAD_GATEWAY).body(Body::empty()).unwrap()
+            },
+        };
+        let document = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(document) => document,
+            Err(e) => {
+                debug!("Response body left untouched, it isn't JSON: {}", e);
+                return Response::from_parts(parts, Body::from(bytes))
+            },
+        };
+        let transformed = self.apply(document).to_string();
+        parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(transformed.len()));
+        parts.headers.entry(CONTENT_TYPE).or_insert(HeaderValue::from_static("application/json"));
+        parts.headers.remove(ETAG);
+        parts.headers.remove(TRANSFER_ENCODING); // the body is sent with its new length
+        Response::from_parts(parts, Body::from(transformed))
+    }
+}
+
+fn valid(pointer: &str) -> Result<String, InvalidPointer> {
+    if pointer.is_empty() || pointer.starts_with('/') {
+        Ok(pointer.to_string())
+    } else {
+        Err(InvalidPointer(pointer.to_string()))
+    }
+}
+
+/// Allow, deny and rename operations, on a single document
+fn edit(operation: &JsonOperation, mut document: Value) -> Value {
+    match operation {
+        JsonOperation::Allow(pointers) => {
+            let mut allowed = Value::Object(Map::new());
+            for pointer in pointers {
+                if let Some(value) = document.pointer_mut(pointer) {
+                    insert(&mut allowed, pointer, value.take());
+                }
+            }
+            allowed
+        },
+        JsonOperation::Deny(pointers) => {
+            for pointer in pointers {
+                remove(&mut document, pointer);
+            }
+            document
+        },
+        JsonOperation::Rename(from, to) => {
+            if let Some(value) = remove(&mut document, from) {
+                insert(&mut document, to, value);
+            }
+            document
+        },
+        JsonOperation::Extract(_) | JsonOperation::Wrap(..) => document,
+    }
+}
+
+/// Reference tokens of a pointer, unescaped
+fn tokens(pointer: &str) -> Vec<String> {
+    pointer.split('/').skip(1).map(|token| token.replace("~1", "/").replace("~0", "~")).collect()
+}
+
+fn remove(document: &mut Value, pointer: &str) -> Option<Value> {
+    let (parent, last) = pointer.rsplit_once('/')?;
+    let last = tokens(&format!("/{}", last)).pop()?;
+    match document.pointer_mut(parent)? {
+        Value::Object(fields) => fields.remove(&last),
+        Value::Array(elements) => last.parse::<usize>().ok().filter(|index| *index < elements.len()).map(|index| elements.remove(index)),
+        _ => None,
+    }
+}
+
+/// Sets the value at `pointer`, creating the missing objects on the way (values in the way are replaced)
+fn insert(document: &mut Value, pointer: &str, value: Value) {
+    let mut current = document;
+    for token in tokens(pointer) {
+        let index = match current {
+            Value::Array(elements) => token.parse::<usize>().ok().filter(|index| *index < elements.len()),
+            _ => None,
+        };
+        current = match index {
+            Some(index) => &mut current.as_array_mut().unwrap()[index],
+            None => {
+                if !current.is_object() {
+                    *current = Value::Object(Map::new());
+                }
+                current.as_object_mut().unwrap().entry(token).or_insert(Value::Null)
+            },
+        };
+    }
+    *current = value;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{test_server, wait_for_gateway, unwrap_body_as_str};
+    use serde_json::{json, Value};
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::handlers::json_transform::{InvalidPointer, JsonTransform};
+    use hyper::{Client, Uri, StatusCode, Response, Body, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    fn book() -> Value {
+        json!({"id": 1, "title": "Dune", "author": {"name": "Frank Herbert", "born": 1920}, "isbn": "0441013597", "tags": ["sf", "classic"]})
+    }
+
+    #[test]
+    fn operations() {
+        let mut allow = JsonTransform::new();
+        allow.allow(&["/id", "/author/name", "/missing"]).unwrap();
+        assert_eq!(json!({"id": 1, "author": {"name": "Frank Herbert"}}), allow.apply(book()));
+        assert_eq!(json!([{"id": 1, "author": {"name": "Frank Herbert"}}, {"id": 2}]), allow.apply(json!([book(), {"id": 2, "title": "Emma"}])));
+
+        let mut deny = JsonTransform::new();
+        deny.deny(&["/isbn", "/author/born", "/tags/0"]).unwrap();
+        assert_eq!(json!({"id": 1, "title": "Dune", "author": {"name": "Frank Herbert"}, "tags": ["classic"]}), deny.apply(book()));
+
+        let mut rename = JsonTransform::new();
+        rename.rename("/author/name", "/writer").unwrap();
+        rename.rename("/title", "/titles/en").unwrap();
+        rename.allow(&["/writer", "/titles"]).unwrap();
+        assert_eq!(json!({"writer": "Frank Herbert", "titles": {"en": "Dune"}}), rename.apply(book()));
+
+        let mut envelope = JsonTransform::pointer("/author").unwrap();
+        envelope.wrap_in("data", json!({"version": 1}));
+        assert_eq!(json!({"data": {"name": "Frank Herbert", "born": 1920}, "version": 1}), envelope.apply(book()));
+        assert_eq!(Value::Null, JsonTransform::pointer("/missing").unwrap().apply(book()));
+        assert_eq!(json!({"a/b": 1}), JsonTransform::pointer("/x~1y").unwrap().apply(json!({"x/y": {"a/b": 1}})));
+        assert_eq!(Err(InvalidPointer("title".to_string())), JsonTransform::new().deny(&["title"]));
+    }
+
+    async fn typed_server(port: u16) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|req: hyper::Request<Body>| async move {
+                let resp = match req.uri().path() {
+                    "/text" => Response::builder().header("content-type", "text/plain").body(Body::from("{\"id\": 1}")),
+                    "/chunked" => {
+                        let document = book().to_string();
+                        let (head, tail) = document.split_at(10);
+                        let chunks: Vec<Result<String, Infallible>> = vec![Ok(head.to_string()), Ok(tail.to_string())];
+                        Response::builder().header("content-type", "application/json").body(Body::wrap_stream(futures::stream::iter(chunks)))
+                    },
+                    "/error" => Response::builder().status(StatusCode::NOT_FOUND).header("content-type", "application/problem+json").body(Body::from("{\"title\": \"Not found\"}")),
+                    _ => Response::builder()
+                        .status(StatusCode::CREATED)
+                        .header("content-type", "application/vnd.books+json; charset=utf-8")
+                        .header("etag", "\"v1\"")
+                        .header("location", "/books/1")
+                        .body(Body::from(book().to_string())),
+                };
+                Ok::<_, Infallible>(resp.unwrap())
+            }))
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pointer() {
+        let gw_port = 10_000;
+        let backend_port = 10_001;
+        let typed_port = 10_002;
+        let prefix_1 = "/json_string";
+        let prefix_2 = "/json_array_snd";
+        tokio::spawn(async move {
+            let json = json!({"string": "value", "array": ["A", "B", 42]});
+            test_server(&json.to_string(), backend_port).await
+        });
+        tokio::spawn(async move { typed_server(typed_port).await });
+        tokio::spawn(async move {
+            let mut api_1 = Api::http("127.0.0.1", backend_port, prefix_1.to_string()).unwrap();
+            api_1.finalize_with(Box::new(JsonTransform::pointer("/string").unwrap()));
+            let mut api_2 = Api::http("127.0.0.1", backend_port, prefix_2.to_string()).unwrap();
+            api_2.finalize_with(Box::new(JsonTransform::pointer("/array/2").unwrap()));
+            let mut api_3 = Api::http("127.0.0.1", typed_port, "/books".to_string()).unwrap();
+            let mut transform = JsonTransform::new();
+            transform.allow(&["/id", "/title"]).unwrap();
+            transform.wrap("data");
+            api_3.finalize_with(Box::new(transform));
+            start_local_gateway(gw_port, vec![api_1, api_2, api_3]).await.unwrap();
+        });
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix_1).as_str()).unwrap();
+        let resp = client.get(url).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("application/json", resp.headers()["content-type"]);
+        assert_eq!("7", resp.headers()["content-length"]);
+        let body = unwrap_body_as_str(resp).await;
+        assert_eq!(json!("value").to_string(), body);
+
+        let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix_2).as_str()).unwrap();
+        let resp = client.get(url).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        let body = unwrap_body_as_str(resp).await;
+        assert_eq!(json!(42).to_string(), body);
+
+        let get = |path: &str| client.get(Uri::from_str(&format!("http://127.0.0.1:{}/books{}", gw_port, path)).unwrap());
+        let resp = get("").await.unwrap();
+        assert_eq!(StatusCode::CREATED, resp.status());
+        assert_eq!("application/vnd.books+json; charset=utf-8", resp.headers()["content-type"]);
+        assert_eq!("/books/1", resp.headers()["location"]);
+        assert!(!resp.headers().contains_key("etag"));
+        let expected = json!({"data": {"id": 1, "title": "Dune"}}).to_string();
+        assert_eq!(expected.len().to_string(), resp.headers()["content-length"]);
+        assert_eq!(expected, unwrap_body_as_str(resp).await);
+
+        let resp = get("/chunked").await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(!resp.headers().contains_key("transfer-encoding"));
+        assert_eq!(1, resp.headers().get_all("content-length").iter().count());
+        assert_eq!(expected.len().to_string(), resp.headers()["content-length"]);
+        assert_eq!(expected, unwrap_body_as_str(resp).await);
+
+        let resp = get("/text").await.unwrap();
+        assert_eq!("{\"id\": 1}", unwrap_body_as_str(resp).await);
+        let resp = get("/error").await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!("{\"title\": \"Not found\"}", unwrap_body_as_str(resp).await);
+    }
+
+}
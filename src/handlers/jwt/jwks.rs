@@ -0,0 +1,354 @@
+use crate::conf::endpoint::HttpEndpoint;
+use crate::handlers::jwt::JwtAlgorithm;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hyper::{Body, Request, Uri};
+use hyper::header::ACCEPT;
+use log::{debug, error, info};
+use ring::{hmac, signature};
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Keys are fetched again once they're this old
+pub const DEFAULT_JWKS_CACHE: Duration = Duration::from_secs(300);
+/// Minimum delay between two fetches, whether the previous one failed or a token was signed by an unknown key
+pub const DEFAULT_JWKS_COOLDOWN: Duration = Duration::from_secs(30);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum JwksError {
+    Io(std::io::Error),
+    Http(String),       // the JWKS URL couldn't be fetched
+    Invalid(String),    // not a JWK set, or a key that can't be used
+}
+
+impl Display for JwksError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwksError::Io(e) => write!(f, "Could not read JWKS: {}", e),
+            JwksError::Http(e) => write!(f, "Could not fetch JWKS: {}", e),
+            JwksError::Invalid(e) => write!(f, "Invalid JWKS: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for JwksError {}
+
+impl From<std::io::Error> for JwksError {
+    fn from(e: std::io::Error) -> Self {
+        JwksError::Io(e)
+    }
+}
+
+/// A key verifying token signatures, its type gives the only algorithm it's used with
+#[derive(Debug, Clone)]
+pub struct Jwk {
+    pub kid: Option<String>,
+    pub algorithm: JwtAlgorithm,
+    key: PublicKey,
+}
+
+#[derive(Debug, Clone)]
+enum PublicKey {
+    Hmac(hmac::Key),
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ec(Vec<u8>),        // uncompressed P-256 point
+    Ed25519(Vec<u8>),
+}
+
+impl Jwk {
+
+    /// Shared secret, for HS256
+    pub fn hmac(kid: Option<&str>, secret: &[u8]) -> Self {
+        Jwk { kid: kid.map(str::to_string), algorithm: JwtAlgorithm::Hs256, key: PublicKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, secret)) }
+    }
+
+    /// A JWK (RFC 7517): "oct", "RSA", "EC" on P-256 or "OKP" on Ed25519
+    pub fn parse(jwk: &Value) -> Result<Self, JwksError> {
+        let field = |name: &str| -> Result<Vec<u8>, JwksError> {
+            let value = jwk.get(name).and_then(Value::as_str).ok_or_else(|| JwksError::Invalid(format!("missing {}", name)))?;
+            URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).map_err(|e| JwksError::Invalid(format!("{}: {}", name, e)))
+        };
+        let curve = jwk.get("crv").and_then(Value::as_str);
+        let (algorithm, key) = match (jwk.get("kty").and_then(Value::as_str), curve) {
+            (Some("oct"), _) => (JwtAlgorithm::Hs256, PublicKey::Hmac(hmac::Key::new(hmac::HMAC_SHA256, &field("k")?))),
+            (Some("RSA"), _) => (JwtAlgorithm::Rs256, PublicKey::Rsa { n: field("n")?, e: field("e")? }),
+            (Some("EC"), Some("P-256")) => {
+                let (x, y) = (field("x")?, field("y")?);
+                if x.len() != 32 || y.len() != 32 {
+                    return Err(JwksError::Invalid("P-256 coordinates must be 32 bytes long".to_string()))
+                }
+                (JwtAlgorithm::Es256, PublicKey::Ec([&[4][..], &x, &y].concat()))
+            },
+            (Some("OKP"), Some("Ed25519")) => (JwtAlgorithm::EdDsa, PublicKey::Ed25519(field("x")?)),
+            (kty, crv) => return Err(JwksError::Invalid(format!("unsupported key type {:?} {:?}", kty, crv))),
+        };
+        if let Some(alg) = jwk.get("alg").and_then(Value::as_str) {
+            if alg != algorithm.name() {
+                return Err(JwksError::Invalid(format!("{} key can't be used with {}", algorithm.name(), alg)))
+            }
+        }
+        Ok(Jwk { kid: jwk.get("kid").and_then(Value::as_str).map(str::to_string), algorithm, key })
+    }
+
+    /// Whether `signature` of `message` was made by this key
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match &self.key {
+            PublicKey::Hmac(key) => hmac::verify(key, message, signature).is_ok(),
+            PublicKey::Rsa { n, e } => signature::RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            PublicKey::Ec(point) => signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point).verify(message, signature).is_ok(),
+            PublicKey::Ed25519(key) => signature::UnparsedPublicKey::new(&signature::ED25519, key).verify(message, signature).is_ok(),
+        }
+    }
+}
+
+/// Signature keys of a JWK set (`{"keys": [...]}`), keys meant for encryption or of unsupported types are skipped
+pub fn parse_jwks(content: &[u8]) -> Result<Vec<Jwk>, JwksError> {
+    let jwks: Value = serde_json::from_slice(content).map_err(|e| JwksError::Invalid(e.to_string()))?;
+    let keys = jwks.get("keys").and_then(Value::as_array).ok_or_else(|| JwksError::Invalid("no keys".to_string()))?;
+    Ok(keys.iter()
+        .filter(|jwk| jwk.get("use").and_then(Value::as_str).map(|usage| usage == "sig").unwrap_or(true))
+        .filter_map(|jwk| match Jwk::parse(jwk) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                debug!("Skipping JWK {}: {}", jwk.get("kid").unwrap_or(&Value::Null), e);
+                None
+            },
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+enum JwksSource {
+    Keys,   // never refreshed
+    File(PathBuf),
+    Url(Box<HttpEndpoint>, Uri),
+}
+
+#[derive(Debug, Default)]
+struct Cache {
+    keys: Arc<Vec<Jwk>>,
+    fetched_at: Option<Instant>,
+    attempted_at: Option<Instant>,
+}
+
+/// Keys tokens are verified with, cached and fetched again once expired
+/// Expired keys keep being used while they're fetched again in the background
+/// Rotations are picked up as soon as a token is signed by an unknown key ID: the set is fetched again (at most once per cooldown)
+/// If a fetch fails, the previous keys are used until the next one succeeds
+#[derive(Debug)]
+pub struct Jwks {
+    source: JwksSource,
+    cache_for: Duration,
+    cooldown: Duration,
+    cache: RwLock<Cache>,
+    refreshing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Jwks {
+
+    fn with_source(source: JwksSource) -> Self {
+        Jwks {
+            source,
+            cache_for: DEFAULT_JWKS_CACHE,
+            cooldown: DEFAULT_JWKS_COOLDOWN,
+            cache: RwLock::new(Cache::default()),
+            refreshing: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Fixed keys, i.e. a shared HS256 secret
+    pub fn from_keys(keys: Vec<Jwk>) -> Self {
+        let jwks = Jwks::with_source(JwksSource::Keys);
+        jwks.cache.write().unwrap().keys = Arc::new(keys);
+        jwks
+    }
+
+    /// JWK set of a local file, read again when the cache expires
+    pub fn file(path: &Path) -> Self {
+        Jwks::with_source(JwksSource::File(path.to_path_buf()))
+    }
+
+    /// JWK set published by an identity provider, i.e. "https://idp.example.com/.well-known/jwks.json"
+    pub fn url(url: &str) -> Result<Self, JwksError> {
+        let uri = Uri::from_str(url).map_err(|e| JwksError::Http(e.to_string()))?;
+        let host = uri.host().ok_or_else(|| JwksError::Http(format!("no host in {}", url)))?;
+        let endpoint = match uri.scheme_str() {
+            Some("https") => HttpEndpoint::https(uri.authority().map(|authority| authority.as_str()).unwrap_or(host))
+                .map_err(|e| JwksError::Http(e.to_string()))?,
+            Some("http") => HttpEndpoint::http(host, uri.port_u16().unwrap_or(80))
+                .map_err(|e| JwksError::Http(e.to_string()))?,
+            _ => return Err(JwksError::Http(format!("unsupported scheme in {}", url))),
+        };
+        Ok(Jwks::with_source(JwksSource::Url(Box::new(endpoint), uri)))
+    }
+
+    pub fn cache_for(&mut self, ttl: Duration) {
+        self.cache_for = ttl;
+    }
+
+    /// See `DEFAULT_JWKS_COOLDOWN`
+    pub fn with_cooldown(&mut self, cooldown: Duration) {
+        self.cooldown = cooldown;
+    }
+
+    /// Keys that may have signed a token: the one with its key ID if any, every key of its algorithm otherwise
+    /// Only waits for a fetch when no key matches, stale keys are refreshed in the background
+    pub async fn keys_for(self: &Arc<Self>, kid: Option<&str>, algorithm: JwtAlgorithm) -> Result<Vec<Jwk>, JwksError> {
+        let (keys, stale) = {
+            let cache = self.cache.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            (cache.keys.clone(), self.is_stale(&cache))
+        };
+        let matching = select(&keys, kid, algorithm);
+        if matches!(self.source, JwksSource::Keys) {
+            return Ok(matching)
+        }
+        if !matching.is_empty() {
+            if stale {
+                self.refresh_in_background();
+            }
+            return Ok(matching)
+        }
+        let _refreshing = self.refreshing.lock().await;
+        let keys = self.refresh(false).await?;
+        Ok(select(&keys, kid, algorithm))
+    }
+
+    fn is_stale(&self, cache: &Cache) -> bool {
+        cache.fetched_at.map(|fetched_at| fetched_at.elapsed() >= self.cache_for).unwrap_or(true)
+    }
+
+    /// Unless a fetch is already running
+    fn refresh_in_background(self: &Arc<Self>) {
+        if let Ok(refreshing) = self.refreshing.clone().try_lock_owned() {
+            let jwks = self.clone();
+            tokio::spawn(async move {
+                let _ = jwks.refresh(true).await; // failures are logged, the previous keys are kept
+                drop(refreshing);
+            });
+        }
+    }
+
+    /// Fetches the keys again, if they're stale, or if a key is missing and the cooldown is over
+    /// Callers hold `refreshing`, so that a single fetch runs at once
+    async fn refresh(&self, found: bool) -> Result<Arc<Vec<Jwk>>, JwksError> {
+        let (keys, has_keys) = {
+            let cache = self.cache.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let cooled_down = cache.attempted_at.map(|attempted_at| attempted_at.elapsed() >= self.cooldown).unwrap_or(true);
+            if !cooled_down || (found && !self.is_stale(&cache)) { // fetched meanwhile, or too recently
+                return Ok(cache.keys.clone())
+            }
+            (cache.keys.clone(), cache.fetched_at.is_some())
+        };
+        let attempted_at = Instant::now();
+        let fetched = self.fetch().await;
+        let mut cache = self.cache.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        cache.attempted_at = Some(attempted_at);
+        match fetched {
+            Ok(fetched) => {
+                info!("{} JWKS keys loaded", fetched.len());
+                cache.keys = Arc::new(fetched);
+                cache.fetched_at = Some(attempted_at);
+                Ok(cache.keys.clone())
+            },
+            Err(e) if has_keys => {
+                error!("{}, keeping the previous keys", e);
+                Ok(keys)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn fetch(&self) -> Result<Vec<Jwk>, JwksError> {
+        match &self.source {
+            JwksSource::Keys => Ok(self.cache.read().unwrap_or_else(|poisoned| poisoned.into_inner()).keys.to_vec()),
+            JwksSource::File(path) => parse_jwks(&tokio::fs::read(path).await?),
+            JwksSource::Url(endpoint, uri) => {
+                let req = Request::get(uri.clone()).header(ACCEPT, "application/json").body(Body::empty()).unwrap();
+                let resp = tokio::time::timeout(FETCH_TIMEOUT, endpoint.request(req)).await
+                    .map_err(|_| JwksError::Http(format!("{} timed out", uri)))?
+                    .map_err(|e| JwksError::Http(e.to_string()))?;
+                if !resp.status().is_success() {
+                    return Err(JwksError::Http(format!("{} answered {}", uri, resp.status())))
+                }
+                let body = tokio::time::timeout(FETCH_TIMEOUT, hyper::body::to_bytes(resp.into_body())).await
+                    .map_err(|_| JwksError::Http(format!("{} timed out", uri)))?
+                    .map_err(|e| JwksError::Http(e.to_string()))?;
+                parse_jwks(&body)
+            },
+        }
+    }
+}
+
+/// Keys without an ID may sign any token, they're tried when none has the token's key ID
+fn select(keys: &[Jwk], kid: Option<&str>, algorithm: JwtAlgorithm) -> Vec<Jwk> {
+    let of_algorithm = || keys.iter().filter(|key| key.algorithm == algorithm);
+    let matching: Vec<Jwk> = of_algorithm().filter(|key| kid.is_none() || key.kid.as_deref() == kid).cloned().collect();
+    if !matching.is_empty() || kid.is_none() {
+        return matching
+    }
+    of_algorithm().filter(|key| key.kid.is_none()).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::jwt::JwtAlgorithm;
+    use crate::handlers::jwt::jwks::{Jwk, Jwks, JwksError};
+    use crate::tests::temp_dir;
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn previous_keys_are_kept_if_the_file_is_broken() {
+        let path = temp_dir().join("jwks.json");
+        let mut jwks = Jwks::file(&path);
+        jwks.cache_for(Duration::from_millis(20));
+        jwks.with_cooldown(Duration::from_millis(20));
+        let jwks = Arc::new(jwks);
+        assert!(matches!(jwks.keys_for(None, JwtAlgorithm::Hs256).await, Err(JwksError::Io(_))));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::write(&path, json!({"keys": [{"kty": "oct", "kid": "1", "k": "czNjcjN0"}]}).to_string()).unwrap();
+        assert_eq!(1, jwks.keys_for(Some("1"), JwtAlgorithm::Hs256).await.unwrap().len());
+        assert!(jwks.keys_for(Some("1"), JwtAlgorithm::Rs256).await.unwrap().is_empty());
+
+        std::fs::write(&path, "{\"keys\": ").unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(1, jwks.keys_for(None, JwtAlgorithm::Hs256).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn stale_keys_are_used_while_fetched_again() {
+        let path = temp_dir().join("jwks.json");
+        std::fs::write(&path, json!({"keys": [{"kty": "oct", "kid": "1", "k": "czNjcjN0"}]}).to_string()).unwrap();
+        let mut jwks = Jwks::file(&path);
+        jwks.cache_for(Duration::from_millis(20));
+        jwks.with_cooldown(Duration::from_millis(20));
+        let jwks = Arc::new(jwks);
+        assert_eq!(1, jwks.keys_for(Some("1"), JwtAlgorithm::Hs256).await.unwrap().len());
+
+        std::fs::write(&path, json!({"keys": [{"kty": "oct", "kid": "2", "k": "czNjcjN0"}]}).to_string()).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(1, jwks.keys_for(Some("1"), JwtAlgorithm::Hs256).await.unwrap().len()); // stale, fetched in the background
+        let _refreshed = jwks.refreshing.lock().await;
+        assert_eq!(Some("2"), jwks.cache.read().unwrap().keys[0].kid.as_deref());
+    }
+
+    #[tokio::test]
+    async fn keys_without_id_verify_tokens_with_an_unknown_id() {
+        let jwks = Arc::new(Jwks::from_keys(vec![Jwk::hmac(Some("1"), b"s3cr3t"), Jwk::hmac(None, b"s3cr3t")]));
+        let kids = |keys: Vec<Jwk>| keys.into_iter().map(|key| key.kid).collect::<Vec<_>>();
+        assert_eq!(vec![Some("1".to_string())], kids(jwks.keys_for(Some("1"), JwtAlgorithm::Hs256).await.unwrap()));
+        assert_eq!(vec![None], kids(jwks.keys_for(Some("2"), JwtAlgorithm::Hs256).await.unwrap()));
+        assert_eq!(2, jwks.keys_for(None, JwtAlgorithm::Hs256).await.unwrap().len());
+        assert!(jwks.keys_for(Some("2"), JwtAlgorithm::Rs256).await.unwrap().is_empty());
+    }
+}
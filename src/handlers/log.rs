@@ -1,67 +1,428 @@
+use crate::context::{ClientInfo, ClientRequest, CorrelationId, UpstreamRequest};
+use crate::handlers::{HandlerResponse, ScopedHandler, ScopedHandlerFactory};
+use crate::handlers::rate_limiting::civil_from_days;
+use hyper::{Body, HeaderMap, Request, Response};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, InvalidHeaderName, CONTENT_LENGTH, REFERER, USER_AGENT};
+use log::Level;
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Access log lines are logged with this target, so that they can be routed apart from the other logs
+pub const ACCESS_LOG_TARGET: &str = "itinerarium::access";
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Common,     // NCSA Common Log Format: client IP, time, request line, status and bytes
+    Combined,   // Common Log Format, plus referer and user agent
+    Json,       // one object per line, with the selected fields and headers
+}
+
+/// Fields of JSON lines, Common and Combined formats have a fixed layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogField {
+    Timestamp,      // when the request was received, RFC 3339 in UTC
+    ClientIp,
+    Method,
+    Path,           // as requested by the client, without the query
+    Protocol,       // HTTP version of the client request
+    Status,
+    Upstream,       // authority the request was sent to, if it was
+    Latency,        // milliseconds until the response headers
+    Bytes,          // response body size, when known upfront
+    CorrelationId,  // see `CorrelationIdHandler`
+    UserAgent,
+    Referer,
+}
+
+/// Logs one line per round trip (through the `log` crate, see `ACCESS_LOG_TARGET`), once the response headers are known
+/// Add it as a scoped handler: it sees every response of its Api sent back to the client, including the ones of handlers breaking the chain
+/// Requests no Api answers aren't logged: unknown paths (404), "/health", and forward proxy or CONNECT traffic
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    pub format: LogFormat,
+    pub fields: Vec<LogField>,
+    pub request_headers: Vec<HeaderName>,   // logged by JSON lines, if present
+    pub response_headers: Vec<HeaderName>,
+    pub sample_rate: f64,
+    pub level: Level,
+}
+
+impl AccessLog {
+
+    /// Every field, no headers, every round trip
+    pub fn new(format: LogFormat) -> Self {
+        AccessLog {
+            format,
+            fields: vec![
+                LogField::Timestamp, LogField::ClientIp, LogField::Method, LogField::Path, LogField::Protocol, LogField::Status,
+                LogField::Upstream, LogField::Latency, LogField::Bytes, LogField::CorrelationId, LogField::UserAgent, LogField::Referer,
+            ],
+            request_headers: vec![],
+            response_headers: vec![],
+            sample_rate: 1.0,
+            level: Level::Info,
+        }
+    }
+
+    pub fn with_fields(&mut self, fields: Vec<LogField>) {
+        self.fields = fields;
+    }
+
+    /// Logs the values of these request headers, headers carrying credentials are better left out
+    pub fn log_request_headers(&mut self, names: &[&str]) -> Result<(), InvalidHeaderName> {
+        for name in names {
+            self.request_headers.push(HeaderName::try_from(*name)?);
+        }
+        Ok(())
+    }
+
+    pub fn log_response_headers(&mut self, names: &[&str]) -> Result<(), InvalidHeaderName> {
+        for name in names {
+            self.response_headers.push(HeaderName::try_from(*name)?);
+        }
+        Ok(())
+    }
+
+    /// Logs only a `rate` (between 0 and 1) of the round trips, picked at random. Server errors (5xx) are always logged
+    pub fn sample(&mut self, rate: f64) {
+        self.sample_rate = rate;
+    }
+
+    pub fn with_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    fn line(&self, exchange: &Exchange) -> String {
+        match self.format {
+            LogFormat::Common => common_log_format(exchange),
+            LogFormat::Combined => format!("{} \"{}\" \"{}\"", common_log_format(exchange), quoted(&exchange.referer), quoted(&exchange.user_agent)),
+            LogFormat::Json => self.json(exchange).to_string(),
+        }
+    }
+
+    fn json(&self, exchange: &Exchange) -> Value {
+        let mut line = Map::new();
+        for field in &self.fields {
+            let (name, value) = match field {
+                LogField::Timestamp => ("timestamp", Value::from(rfc3339(exchange.received))),
+                LogField::ClientIp => ("client_ip", optional(exchange.client_ip.clone())),
+                LogField::Method => ("method", Value::from(exchange.method.clone())),
+                LogField::Path => ("path", Value::from(exchange.path.clone())),
+                LogField::Protocol => ("protocol", Value::from(exchange.protocol.clone())),
+                LogField::Status => ("status", Value::from(exchange.status)),
+                LogField::Upstream => ("upstream", optional(exchange.upstream.clone())),
+                LogField::Latency => ("latency_ms", Value::from(exchange.latency.as_secs_f64() * 1000.0)),
+                LogField::Bytes => ("bytes", optional(exchange.bytes)),
+                LogField::CorrelationId => ("correlation_id", optional(exchange.correlation_id.clone())),
+                LogField::UserAgent => ("user_agent", optional(exchange.user_agent.clone())),
+                LogField::Referer => ("referer", optional(exchange.referer.clone())),
+            };
+            if !value.is_null() {
+                line.insert(name.to_string(), value);
+            }
+        }
+        for (name, headers) in [("request_headers", &exchange.request_headers), ("response_headers", &exchange.response_headers)] {
+            if !headers.is_empty() {
+                let headers = headers.iter().map(|(name, value)| (name.clone(), Value::from(value.clone()))).collect();
+                line.insert(name.to_string(), Value::Object(headers));
+            }
+        }
+        Value::Object(line)
+    }
+}
+
+impl ScopedHandlerFactory for AccessLog {
+    fn create(&self) -> Box<dyn ScopedHandler> {
+        Box::new(RoundTrip { log: self.clone(), started: Instant::now(), exchange: Mutex::new(None) })
+    }
+}
+
+#[derive(Debug)]
+struct RoundTrip {
+    log: AccessLog,
+    started: Instant,
+    exchange: Mutex<Option<Exchange>>,
+}
+
+/// What's logged of a round trip, unknown values are `None`
+#[derive(Debug, Clone, Default)]
+struct Exchange {
+    received: Duration, // since the UNIX epoch
+    client_ip: Option<String>,
+    method: String,
+    target: String,     // path and query
+    path: String,
+    protocol: String,
+    status: u16,
+    upstream: Option<String>,
+    latency: Duration,
+    bytes: Option<u64>,
+    correlation_id: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    request_headers: Vec<(String, String)>,
+    response_headers: Vec<(String, String)>,
+}
+
+impl ScopedHandler for RoundTrip {
+    fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        let header = |name: &HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+        let (method, uri, version) = match req.extensions().get::<ClientRequest>() {
+            Some(client_req) => (&client_req.method, &client_req.uri, client_req.version),
+            None => (req.method(), req.uri(), req.version()),
+        };
+        let exchange = Exchange {
+            received: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            client_ip: req.extensions().get::<ClientInfo>().map(|info| info.remote_addr.ip().to_string()),
+            method: method.to_string(),
+            target: uri.path_and_query().map(|target| target.to_string()).unwrap_or_else(|| uri.path().to_string()),
+            path: uri.path().to_string(),
+            protocol: format!("{:?}", version),
+            correlation_id: req.extensions().get::<CorrelationId>().map(|id| id.0.clone()),
+            user_agent: header(&USER_AGENT),
+            referer: header(&REFERER),
+            request_headers: allowed(&self.log.request_headers, req.headers()),
+            ..Exchange::default()
+        };
+        if let Ok(mut logged) = self.exchange.lock() {
+            *logged = Some(exchange);
+        }
+        HandlerResponse::Continue
+    }
+
+    fn handle_res(&self, res: &mut Response<Body>) -> HandlerResponse {
+        let mut exchange = match self.exchange.lock().ok().and_then(|mut logged| logged.take()) {
+            Some(exchange) => exchange,
+            None => return HandlerResponse::Continue,
+        };
+        let sampled = res.status().is_server_error() || self.log.sample_rate >= 1.0 || rand::random::<f64>() < self.log.sample_rate;
+        if !sampled {
+            return HandlerResponse::Continue
+        }
+        exchange.status = res.status().as_u16();
+        exchange.latency = self.started.elapsed();
+        exchange.upstream = res.extensions().get::<UpstreamRequest>().and_then(|upstream| upstream.uri.authority()).map(|authority| authority.to_string());
+        exchange.bytes = res.body().size_hint().exact().or_else(|| {
+            res.headers().get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+        });
+        exchange.response_headers = allowed(&self.log.response_headers, res.headers());
+        log::log!(target: ACCESS_LOG_TARGET, self.log.level, "{}", self.log.line(&exchange));
+        HandlerResponse::Continue
+    }
+}
+
+fn optional<T: Into<Value>>(value: Option<T>) -> Value {
+    value.map_or(Value::Null, Into::into)
+}
+
+fn allowed(names: &[HeaderName], headers: &HeaderMap) -> Vec<(String, String)> {
+    names.iter()
+        .filter_map(|name| Some((name.to_string(), headers.get(name)?.to_str().ok()?.to_string())))
+        .collect()
+}
+
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326`
+fn common_log_format(exchange: &Exchange) -> String {
+    let (year, month, day, time) = civil_time(exchange.received);
+    format!(
+        "{} - - [{:02}/{}/{}:{} +0000] \"{} {} {}\" {} {}",
+        exchange.client_ip.as_deref().unwrap_or("-"),
+        day, MONTHS[month as usize - 1], year, time,
+        exchange.method, quoted(&Some(exchange.target.clone())), exchange.protocol,
+        exchange.status,
+        exchange.bytes.map(|bytes| bytes.to_string()).unwrap_or_else(|| "-".to_string()),
+    )
+}
+
+fn quoted(value: &Option<String>) -> String {
+    match value {
+        Some(value) => value.replace('\\', "\\\\").replace('"', "\\\""),
+        None => "-".to_string(),
+    }
+}
+
+/// (year, month, day, "hh:mm:ss") in UTC
+fn civil_time(since_epoch: Duration) -> (u64, u64, u64, String) {
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let time = format!("{:02}:{:02}:{:02}", secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    (year, month, day, time)
+}
+
+fn rfc3339(since_epoch: Duration) -> String {
+    let (year, month, day, time) = civil_time(since_epoch);
+    format!("{}-{:02}-{:02}T{}.{:03}Z", year, month, day, time, since_epoch.subsec_millis())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::gateway::start_local_gateway;
-    use hyper::{Client, Uri, Request, Body, Response};
-    use ::log::{Level, LevelFilter};
-    use simple_logger::SimpleLogger;
-    use std::str::FromStr;
-    use crate::tests::{test_server, wait_for_gateway};
     use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
     use crate::handlers::{GlobalHandler, HandlerResponse};
-    use crate::handlers::HandlerResponse::Continue;
+    use crate::handlers::correlation::{CorrelationIdHandler, IdFormat};
+    use crate::handlers::log::{AccessLog, Exchange, LogField, LogFormat, ACCESS_LOG_TARGET};
+    use crate::tests::{test_server, wait_for_gateway};
+    use hyper::{Body, Client, Request, Response, StatusCode};
+    use log::{Log, Metadata, Record};
+    use serde_json::Value;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Access log lines logged by the tests, other logs are dropped
+    static LINES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == ACCESS_LOG_TARGET
+        }
 
-    #[derive(Debug, Clone)]
-    pub struct LogRequestInterceptor {
-        pub level: Level,
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                LINES.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
     }
 
-    impl GlobalHandler for LogRequestInterceptor {
-        fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
-            log::log!(self.level, "{:?}", req);
-            Continue
+    fn capture() {
+        if log::set_logger(&Capture).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
         }
+    }
 
-        fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
-            Continue
+    fn logged(marker: &str) -> Vec<String> {
+        LINES.lock().unwrap().iter().filter(|line| line.contains(marker)).cloned().collect()
+    }
+
+    fn exchange() -> Exchange {
+        Exchange {
+            received: Duration::from_millis(971_186_136_042), // 2000-10-10T13:55:36.042Z
+            client_ip: Some("10.0.0.1".to_string()),
+            method: "GET".to_string(),
+            target: "/books?page=2".to_string(),
+            path: "/books".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: 200,
+            upstream: Some("127.0.0.1:8080".to_string()),
+            latency: Duration::from_micros(12_500),
+            bytes: Some(2326),
+            correlation_id: None,
+            user_agent: Some("curl/8.0 \"quoted\"".to_string()),
+            referer: None,
+            request_headers: vec![("accept".to_string(), "application/json".to_string())],
+            response_headers: vec![],
         }
     }
 
-    #[derive(Debug, Clone)]
-    pub struct LogResponseInterceptor {
-        pub level: Level,
+    #[test]
+    fn log_formats() {
+        let common = AccessLog::new(LogFormat::Common);
+        assert_eq!("10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /books?page=2 HTTP/1.1\" 200 2326", common.line(&exchange()));
+        let combined = AccessLog::new(LogFormat::Combined);
+        assert_eq!(
+            "10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /books?page=2 HTTP/1.1\" 200 2326 \"-\" \"curl/8.0 \\\"quoted\\\"\"",
+            combined.line(&exchange())
+        );
+        let mut json = AccessLog::new(LogFormat::Json);
+        json.with_fields(vec![LogField::Timestamp, LogField::Path, LogField::Status, LogField::Latency, LogField::CorrelationId]);
+        let line: Value = serde_json::from_str(&json.line(&exchange())).unwrap();
+        assert_eq!(serde_json::json!({
+            "timestamp": "2000-10-10T13:55:36.042Z",
+            "path": "/books",
+            "status": 200,
+            "latency_ms": 12.5,
+            "request_headers": { "accept": "application/json" },
+        }), line);
     }
 
-    impl GlobalHandler for LogResponseInterceptor {
-        fn handle_req(&self, _req: &mut Request<Body>) -> HandlerResponse {
-            Continue
+    #[derive(Debug)]
+    struct Unauthorized;
+
+    impl GlobalHandler for Unauthorized {
+        fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+            match req.headers().get("authorization") {
+                Some(_) => HandlerResponse::Continue,
+                None => HandlerResponse::Break(Response::builder().status(StatusCode::UNAUTHORIZED).body(Body::empty()).unwrap()),
+            }
         }
 
-        fn handle_res(&self, req: &mut Response<Body>) -> HandlerResponse {
-            log::log!(self.level, "{:?}", req);
-            Continue
+        fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+            HandlerResponse::Continue
         }
     }
 
     #[tokio::test]
-    async fn test_log_request() {
-        let gw_port = 6000;
-        let backend_port = 6001;
-        let path = "/logged";
-        SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap();
+    async fn one_line_per_round_trip() {
+        capture();
+        let gw_port = 13700;
+        let backend_port = 13701;
+        tokio::spawn(async move { test_server("request logged", backend_port).await });
         tokio::spawn(async move {
-            test_server("request logged", backend_port).await
+            let mut api = Api::http("127.0.0.1", backend_port, "/logged".to_string()).unwrap();
+            api.add_global_handler(Box::new(CorrelationIdHandler::new("x-request-id", IdFormat::UuidV4).unwrap()));
+            api.add_global_handler(Box::new(Unauthorized));
+            let mut log = AccessLog::new(LogFormat::Json);
+            log.log_request_headers(&["x-marker"]).unwrap();
+            log.log_response_headers(&["x-request-id"]).unwrap();
+            api.add_scoped_handler(Box::new(log));
+            start_local_gateway(gw_port, vec![api]).await
         });
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let request = |authorization: Option<&str>| {
+            let mut req = Request::get(format!("http://127.0.0.1:{}/logged/books?page=2", gw_port)).header("x-marker", "one-line-per-round-trip");
+            if let Some(authorization) = authorization {
+                req = req.header("authorization", authorization);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let resp = client.request(request(Some("Bearer token"))).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(StatusCode::UNAUTHORIZED, client.request(request(None)).await.unwrap().status());
+        let unknown = Request::get(format!("http://127.0.0.1:{}/unknown", gw_port)).header("x-marker", "one-line-per-round-trip").body(Body::empty()).unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, client.request(unknown).await.unwrap().status()); // answered by no Api, not logged
+
+        let lines = logged("one-line-per-round-trip");
+        assert_eq!(2, lines.len());
+        let ok: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!("GET", ok["method"]);
+        assert_eq!("/logged/books", ok["path"]);
+        assert_eq!("HTTP/1.1", ok["protocol"]);
+        assert_eq!(200, ok["status"]);
+        assert_eq!(format!("127.0.0.1:{}", backend_port), ok["upstream"]);
+        assert_eq!(14, ok["bytes"]);
+        assert_eq!(resp.headers()["x-request-id"].to_str().unwrap(), ok["correlation_id"]);
+        assert_eq!(ok["correlation_id"], ok["response_headers"]["x-request-id"]);
+        assert!(ok["latency_ms"].as_f64().unwrap() > 0.0);
+        let unauthorized: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(401, unauthorized["status"]);
+        assert!(unauthorized.get("upstream").is_none());
+    }
+
+    #[tokio::test]
+    async fn round_trips_are_sampled() {
+        capture();
+        let gw_port = 13702;
+        let backend_port = 13703;
+        tokio::spawn(async move { test_server("sampled", backend_port).await });
         tokio::spawn(async move {
-            let mut api = Api::http("127.0.0.1", backend_port, path.to_string()).unwrap();
-            api.add_global_handler(Box::new(LogRequestInterceptor { level: Level::Info }));
-            api.add_global_handler(Box::new(LogResponseInterceptor { level: Level::Warn }));
+            let mut api = Api::http("127.0.0.1", backend_port, "/sampled".to_string()).unwrap();
+            let mut log = AccessLog::new(LogFormat::Common);
+            log.sample(0.0);
+            api.add_scoped_handler(Box::new(log));
             start_local_gateway(gw_port, vec![api]).await
         });
         wait_for_gateway(gw_port).await;
         let client = Client::new();
-        let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, path).as_str()).unwrap();
-        let resp = client.get(url).await.unwrap();
-        assert_eq!(200, resp.status());
+        let resp = client.get(format!("http://127.0.0.1:{}/sampled", gw_port).parse().unwrap()).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(logged("\"GET /sampled").is_empty());
     }
-
-}
\ No newline at end of file
+}
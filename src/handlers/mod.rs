@@ -2,11 +2,14 @@ use hyper::{Response, Body, Request};
 use std::fmt::Debug;
 use async_trait::async_trait;
 
-mod log;
-mod rate_limiting;
-mod json_pointer;
-mod correlation;
-mod subscriptions;
+pub mod log;
+pub mod rate_limiting;
+pub mod json_transform;
+pub mod jwt;
+pub mod correlation;
+pub mod api_keys;
+pub mod client_cert;
+pub mod destinations;
 
 /// Controls the Gateway flow
 /// After an Handler has been invoked, should it move on and invoke the next Handler in the chain
@@ -35,11 +38,31 @@ pub trait ScopedHandlerFactory: Send + Debug + Sync {
     fn create(&self) -> Box<dyn ScopedHandler>;
 }
 
+/// Async checks on the request before it's sent upstream (i.e. against a remote store), once global and scoped handlers have been invoked
+/// Breaking returns the response immediately
+#[async_trait]
+pub trait RequestGuard: Send + Debug + Sync {
+    async fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse;
+}
+
+/// Takes ownership of the request before it's sent upstream, maps it, and returns a new request
+/// Async cause reading the request body may be async. Returning a response instead breaks the chain and sends it back to the client
+#[async_trait]
+pub trait RequestTransformer: Send + Debug + Sync {
+    async fn transform(&self, req: Request<Body>) -> Result<Request<Body>, Response<Body>>;
+}
+
 /// Takes ownership of the upstream response, maps it, and return a new response
 /// Async cause reading the response body may be async
 #[async_trait]
 pub trait ResponseFinalizer: Send + Debug + Sync {
     async fn transform(&self, res: Response<Body>) -> Response<Body>;
+
+    /// Finalizers reading the whole body aren't invoked on streaming responses
+    /// Return false if this one leaves the body untouched (or maps it chunk by chunk)
+    fn buffers_body(&self) -> bool {
+        true
+    }
 }
 
 #[cfg(test)]
@@ -229,7 +252,7 @@ mod tests {
         });
         wait_for_gateway(gw_port).await;
         let client = Client::new();
-        let reqs: Vec<ResponseFuture> = (1..10_usize).into_iter().map(|i| {
+        let reqs: Vec<ResponseFuture> = (1..10_usize).map(|i| {
             let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, path).as_str()).unwrap();
             let req = Request::builder()
                 .uri(url)
@@ -305,7 +328,7 @@ mod tests {
         });
         wait_for_gateway(gw_port).await;
         let nb_req = 10;
-        let reqs: Vec<ResponseFuture> = (0..nb_req).into_iter()
+        let reqs: Vec<ResponseFuture> = (0..nb_req)
             .map(|_| {
             let client = Client::new();
             let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, path).as_str()).unwrap();
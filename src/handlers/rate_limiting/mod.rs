@@ -0,0 +1,646 @@
+use crate::context::{ApiKey, ClientCertificate, ClientInfo, JwtClaims, ResponseHeaders};
+use crate::handlers::{HandlerResponse, RequestGuard};
+use crate::handlers::rate_limiting::store::{MemoryStore, RateLimitStore, StoreError};
+use async_trait::async_trait;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use hyper::header::{HeaderName, HeaderValue, RETRY_AFTER};
+use log::error;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub mod redis;
+pub mod store;
+
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+/// Keyed limiters track this many keys by default, the least recently seen ones are forgotten first
+pub const DEFAULT_MAX_KEYS: usize = 100_000;
+const SECONDS_PER_DAY: u64 = 86400;
+
+/// How requests are counted, every algorithm keeps a constant amount of memory per limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// Bursts of up to `capacity` requests, then one request every `refill_period`
+    TokenBucket { capacity: u64, refill_period: Duration },
+    /// At most `limit` requests over any `window`, estimated from the counts of the current and previous windows
+    SlidingWindow { limit: u64, window: Duration },
+    /// Generic cell rate algorithm: `limit` requests per `period`, evenly spaced, with bursts of up to `burst` requests
+    Gcra { limit: u64, period: Duration, burst: u64 },
+    /// `limit` requests per calendar day or month (UTC), all available again as soon as the next one starts
+    Quota { limit: u64, period: QuotaPeriod },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Day,
+    Month,
+}
+
+/// State of a single limit. Timestamps are durations since the UNIX epoch, so that states can be shared between gateway instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitState {
+    Bucket { credit: Duration, updated: Duration },    // time accumulated towards new tokens, one token per `refill_period`
+    Window { start: Duration, current: u64, previous: u64 },
+    Gcra { theoretical_arrival: Duration },
+    Quota { period_start: Duration, used: u64 },
+}
+
+/// Limits that can't be enforced, i.e. over a zero period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidAlgorithm(pub &'static str);
+
+impl Display for InvalidAlgorithm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid rate limit: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidAlgorithm {}
+
+/// Outcome of a request against a limit, reported to clients through `RateLimit-*` headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: Duration,                // until the limit is fully available again
+    pub retry_after: Option<Duration>,  // until the next request would be allowed, when this one isn't
+}
+
+impl Algorithm {
+
+    /// Rejects zero periods, and counts too large for their period (durations are counted in nanoseconds, up to 2^64)
+    pub fn validate(&self) -> Result<(), InvalidAlgorithm> {
+        match *self {
+            Algorithm::TokenBucket { refill_period, .. } if refill_period.is_zero() => Err(InvalidAlgorithm("zero refill period")),
+            Algorithm::TokenBucket { capacity: 0, .. } => Err(InvalidAlgorithm("zero capacity")),
+            Algorithm::TokenBucket { capacity, refill_period } if checked_times(refill_period, capacity).is_none() => Err(InvalidAlgorithm("capacity too large for the refill period")),
+            Algorithm::SlidingWindow { window, .. } if window.is_zero() => Err(InvalidAlgorithm("zero window")),
+            Algorithm::SlidingWindow { window, .. } if checked_times(window, 2).is_none() => Err(InvalidAlgorithm("window too long")),
+            Algorithm::Gcra { period, .. } if period.is_zero() => Err(InvalidAlgorithm("zero period")),
+            Algorithm::Gcra { limit: 0, .. } => Err(InvalidAlgorithm("zero limit")),
+            Algorithm::Gcra { burst: 0, .. } => Err(InvalidAlgorithm("zero burst")),
+            Algorithm::Gcra { limit, period, .. } if (limit as u128) > period.as_nanos() => Err(InvalidAlgorithm("more than one request per nanosecond")),
+            Algorithm::Gcra { limit, period, burst } if checked_times(emission_interval(limit, period), burst).is_none() => Err(InvalidAlgorithm("burst too large for the period")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn initial_state(&self, now: Duration) -> LimitState {
+        match self {
+            Algorithm::TokenBucket { capacity, refill_period } => LimitState::Bucket { credit: times(*refill_period, *capacity), updated: now },
+            Algorithm::SlidingWindow { .. } => LimitState::Window { start: now, current: 0, previous: 0 },
+            Algorithm::Gcra { .. } => LimitState::Gcra { theoretical_arrival: now },
+            Algorithm::Quota { period, .. } => LimitState::Quota { period_start: period.bounds(now).0, used: 0 },
+        }
+    }
+
+    /// Counts a request made at `now`, if it's allowed. A state that doesn't belong to this algorithm is reset
+    pub fn check(&self, state: &mut LimitState, now: Duration) -> Decision {
+        match (self, &mut *state) {
+            (Algorithm::TokenBucket { capacity, refill_period }, LimitState::Bucket { credit, updated }) =>
+                token_bucket(*capacity, *refill_period, credit, updated, now),
+            (Algorithm::SlidingWindow { limit, window }, LimitState::Window { start, current, previous }) =>
+                sliding_window(*limit, *window, start, current, previous, now),
+            (Algorithm::Gcra { limit, period, burst }, LimitState::Gcra { theoretical_arrival }) =>
+                gcra(*limit, *period, *burst, theoretical_arrival, now),
+            (Algorithm::Quota { limit, period }, LimitState::Quota { period_start, used }) =>
+                quota(*limit, *period, period_start, used, now),
+            _ => {
+                *state = self.initial_state(now);
+                self.check(state, now)
+            },
+        }
+    }
+
+    /// How long a state checked at `now` matters, after that it's equivalent to the initial one
+    pub fn retention(&self, now: Duration) -> Duration {
+        match self {
+            Algorithm::TokenBucket { capacity, refill_period } => times(*refill_period, *capacity),
+            Algorithm::SlidingWindow { window, .. } => times(*window, 2),
+            Algorithm::Gcra { limit, period, burst } => times(emission_interval(*limit, *period), (*burst).max(1)),
+            Algorithm::Quota { period, .. } => period.bounds(now).1 - now,
+        }
+    }
+}
+
+impl LimitState {
+
+    /// Compact representation for stores, i.e. "gcra:1700000000000000000"
+    pub fn encode(&self) -> String {
+        match self {
+            LimitState::Bucket { credit, updated } => format!("bucket:{}:{}", credit.as_nanos(), updated.as_nanos()),
+            LimitState::Window { start, current, previous } => format!("window:{}:{}:{}", start.as_nanos(), current, previous),
+            LimitState::Gcra { theoretical_arrival } => format!("gcra:{}", theoretical_arrival.as_nanos()),
+            LimitState::Quota { period_start, used } => format!("quota:{}:{}", period_start.as_nanos(), used),
+        }
+    }
+
+    pub fn decode(encoded: &str) -> Option<LimitState> {
+        let parts = encoded.split(':').collect::<Vec<&str>>();
+        let number = |i: usize| parts.get(i).and_then(|part| part.parse::<u64>().ok());
+        let nanos = |i: usize| number(i).map(Duration::from_nanos);
+        match (parts[0], parts.len()) {
+            ("bucket", 3) => Some(LimitState::Bucket { credit: nanos(1)?, updated: nanos(2)? }),
+            ("window", 4) => Some(LimitState::Window { start: nanos(1)?, current: number(2)?, previous: number(3)? }),
+            ("gcra", 2) => Some(LimitState::Gcra { theoretical_arrival: nanos(1)? }),
+            ("quota", 3) => Some(LimitState::Quota { period_start: nanos(1)?, used: number(2)? }),
+            _ => None,
+        }
+    }
+}
+
+/// `duration * n`, unless it overflows
+fn checked_times(duration: Duration, n: u64) -> Option<Duration> {
+    let nanos = duration.as_nanos().checked_mul(n as u128)?;
+    u64::try_from(nanos).ok().map(Duration::from_nanos)
+}
+
+/// `duration * n`, saturating (see `Algorithm::validate`)
+fn times(duration: Duration, n: u64) -> Duration {
+    checked_times(duration, n).unwrap_or_else(|| Duration::from_nanos(u64::MAX))
+}
+
+/// Interval between two requests of a GCRA, at least a nanosecond
+fn emission_interval(limit: u64, period: Duration) -> Duration {
+    Duration::from_nanos((period.as_nanos() / limit.max(1) as u128).clamp(1, u64::MAX as u128) as u64)
+}
+
+fn token_bucket(capacity: u64, refill_period: Duration, credit: &mut Duration, updated: &mut Duration, now: Duration) -> Decision {
+    let full = times(refill_period, capacity);
+    *credit = (*credit + now.saturating_sub(*updated)).min(full);
+    *updated = (*updated).max(now);
+    let allowed = *credit >= refill_period;
+    let retry_after = if allowed {
+        *credit -= refill_period;
+        None
+    } else {
+        Some(refill_period - *credit)
+    };
+    Decision {
+        allowed,
+        limit: capacity,
+        remaining: (credit.as_nanos() / refill_period.as_nanos().max(1)) as u64,
+        reset: full - *credit,
+        retry_after,
+    }
+}
+
+fn sliding_window(limit: u64, window: Duration, start: &mut Duration, current: &mut u64, previous: &mut u64, now: Duration) -> Decision {
+    let window = window.max(Duration::from_nanos(1));
+    let elapsed = now.saturating_sub(*start);
+    if elapsed >= window {
+        *previous = if elapsed < times(window, 2) { *current } else { 0 };
+        *current = 0;
+        *start = now - Duration::from_nanos((elapsed.as_nanos() % window.as_nanos()) as u64);
+    }
+    // counts are weighted by the window length, to keep the estimation exact: previous * (window - elapsed) / window + current
+    let (w, into) = (window.as_nanos(), now.saturating_sub(*start).as_nanos());
+    let reset = window - now.saturating_sub(*start);
+    let weighted = *previous as u128 * (w - into) + *current as u128 * w;
+    if weighted + w <= limit as u128 * w {
+        *current += 1;
+        let remaining = (limit as u128 * w - weighted - w) / w;
+        return Decision { allowed: true, limit, remaining: remaining as u64, reset, retry_after: None }
+    }
+    // the previous window weighs less and less: wait until the estimation leaves room for one more request
+    let room = limit.saturating_sub(1) as u128;
+    let retry_after = if *current as u128 > room || *previous == 0 {
+        let next_previous = (*current).max(1) as u128;
+        reset.as_nanos() + (w * next_previous.saturating_sub(room)).div_ceil(next_previous)
+    } else {
+        let previous = *previous as u128;
+        (w * (previous - (room - *current as u128))).div_ceil(previous).saturating_sub(into)
+    };
+    Decision { allowed: false, limit, remaining: 0, reset, retry_after: Some(Duration::from_nanos(retry_after as u64)) }
+}
+
+fn gcra(limit: u64, period: Duration, burst: u64, theoretical_arrival: &mut Duration, now: Duration) -> Decision {
+    let emission_interval = emission_interval(limit, period);
+    let tolerance = times(emission_interval, burst.max(1));
+    let next_arrival = (*theoretical_arrival).max(now) + emission_interval;
+    let ahead = next_arrival - now;
+    if ahead > tolerance {
+        let current_ahead = theoretical_arrival.saturating_sub(now);
+        return Decision {
+            allowed: false,
+            limit,
+            remaining: 0,
+            reset: current_ahead,
+            retry_after: Some(ahead - tolerance),
+        }
+    }
+    *theoretical_arrival = next_arrival;
+    Decision {
+        allowed: true,
+        limit,
+        remaining: ((tolerance - ahead).as_nanos() / emission_interval.as_nanos().max(1)) as u64,
+        reset: ahead,
+        retry_after: None,
+    }
+}
+
+fn quota(limit: u64, period: QuotaPeriod, period_start: &mut Duration, used: &mut u64, now: Duration) -> Decision {
+    let (start, end) = period.bounds(now);
+    if *period_start != start {
+        *period_start = start;
+        *used = 0;
+    }
+    let reset = end - now;
+    let allowed = *used < limit;
+    if allowed {
+        *used += 1;
+    }
+    Decision { allowed, limit, remaining: limit.saturating_sub(*used), reset, retry_after: if allowed { None } else { Some(reset) } }
+}
+
+impl QuotaPeriod {
+
+    /// Start and end of the period `now` belongs to
+    pub fn bounds(&self, now: Duration) -> (Duration, Duration) {
+        let day = now.as_secs() / SECONDS_PER_DAY;
+        let (start, end) = match self {
+            QuotaPeriod::Day => (day, day + 1),
+            QuotaPeriod::Month => {
+                let (year, month, _) = civil_from_days(day);
+                let next = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+                (days_from_civil(year, month, 1), days_from_civil(next.0, next.1, 1))
+            },
+        };
+        (Duration::from_secs(start * SECONDS_PER_DAY), Duration::from_secs(end * SECONDS_PER_DAY))
+    }
+}
+
+/// (year, month, day) of a number of days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153; // starting in March
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Time as seen by the algorithms
+pub fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+impl Decision {
+
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` (in seconds), plus `Retry-After` if the request isn't allowed
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(ceil_secs(self.reset)));
+        if let Some(retry_after) = self.retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(ceil_secs(retry_after).max(1)));
+        }
+        headers
+    }
+
+    /// 429, with the rate limit headers
+    pub fn too_many_requests(&self) -> Response<Body> {
+        let mut resp = Response::builder().status(StatusCode::TOO_MANY_REQUESTS).body(Body::empty()).unwrap();
+        *resp.headers_mut() = self.headers();
+        resp
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+/// What requests are counted together
+pub trait KeyExtractor: Send + Sync + Debug {
+    /// `None` when the request doesn't carry the key: such requests share a single limit
+    fn key(&self, req: &Request<Body>) -> Option<String>;
+}
+
+#[derive(Debug, Clone)]
+pub enum RateLimitKey {
+    ClientIp,                   // see `ClientInfo`, the address carried by PROXY protocol headers if any
+    Header(HeaderName),         // i.e. an API key
+    Route,                      // method and path
+    ClientCertificate,          // subject of the certificate authenticated by the listener (mTLS)
+    ApiKey,                     // ID of the key authenticated by an `ApiKeyHandler` (guarding before the limiter)
+    JwtSubject,                 // `sub` claim of the token validated by a `JwtHandler` (guarding before the limiter)
+    Combined(Vec<RateLimitKey>),// every part must be present
+}
+
+impl KeyExtractor for RateLimitKey {
+    fn key(&self, req: &Request<Body>) -> Option<String> {
+        match self {
+            RateLimitKey::ClientIp => req.extensions().get::<ClientInfo>().map(|info| info.remote_addr.ip().to_string()),
+            RateLimitKey::Header(name) => req.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string),
+            RateLimitKey::Route => Some(format!("{} {}", req.method(), req.uri().path())),
+            RateLimitKey::ClientCertificate => req.extensions().get::<ClientCertificate>().map(|cert| cert.subject.clone()),
+            RateLimitKey::ApiKey => req.extensions().get::<ApiKey>().map(|key| key.id.clone()),
+            RateLimitKey::JwtSubject => req.extensions().get::<JwtClaims>().and_then(|claims| claims.subject()).map(str::to_string),
+            RateLimitKey::Combined(keys) => keys.iter()
+                .map(|key| key.key(req))
+                .collect::<Option<Vec<String>>>()
+                .map(|parts| parts.join("|")),
+        }
+    }
+}
+
+/// Requests over the limit get a 429, every response carries the `RateLimit-*` headers
+/// Global by default (a single threshold protecting the backend), or keyed: one limit per client, API key, route...
+/// Combine limiters for both rates and quotas, responses report the limit with the fewest remaining requests
+/// States are kept in memory unless another store is set, i.e. one shared by a fleet of gateway instances
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub algorithm: Algorithm,
+    key: Option<Box<dyn KeyExtractor>>,
+    store: Arc<dyn RateLimitStore>,
+    namespace: String,
+    fail_open: bool,
+}
+
+impl RateLimiter {
+
+    pub fn new(algorithm: Algorithm) -> Result<Self, InvalidAlgorithm> {
+        algorithm.validate()?;
+        Ok(RateLimiter { algorithm, key: None, store: Arc::new(MemoryStore::new(1)), namespace: String::new(), fail_open: false })
+    }
+
+    /// One limit per key, for at most `max_keys` keys in memory (see `DEFAULT_MAX_KEYS`)
+    /// Forgetting the state of an idle key amounts to resetting its limit
+    pub fn keyed(algorithm: Algorithm, key: Box<dyn KeyExtractor>, max_keys: usize) -> Result<Self, InvalidAlgorithm> {
+        algorithm.validate()?;
+        Ok(RateLimiter { algorithm, key: Some(key), store: Arc::new(MemoryStore::new(max_keys)), namespace: String::new(), fail_open: false })
+    }
+
+    /// Keeps states in `store`, under keys prefixed by `namespace` so that limiters can share a store
+    pub fn with_store(&mut self, store: Arc<dyn RateLimitStore>, namespace: &str) {
+        self.store = store;
+        self.namespace = namespace.to_string();
+    }
+
+    /// Lets requests through when the store fails, rather than answering 503
+    pub fn fail_open(&mut self) {
+        self.fail_open = true;
+    }
+
+    /// Counts a request made now
+    pub async fn check(&self, key: &str) -> Result<Decision, StoreError> {
+        let key = if self.namespace.is_empty() { key.to_string() } else { format!("{}:{}", self.namespace, key) };
+        self.store.check(&key, &self.algorithm, now()).await
+    }
+}
+
+#[async_trait]
+impl RequestGuard for RateLimiter {
+    async fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+        let key = self.key.as_ref().and_then(|key| key.key(req)).unwrap_or_default();
+        match self.check(&key).await {
+            Err(e) => {
+                error!("Rate limiter couldn't count accesses: {}", e);
+                if self.fail_open {
+                    HandlerResponse::Continue
+                } else {
+                    HandlerResponse::Break(Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::empty()).unwrap())
+                }
+            },
+            Ok(decision) if !decision.allowed => HandlerResponse::Break(decision.too_many_requests()),
+            Ok(decision) => {
+                let reported = req.extensions().get::<ResponseHeaders>()
+                    .and_then(|headers| headers.0.get(RATELIMIT_REMAINING))
+                    .and_then(|remaining| remaining.to_str().ok()?.parse::<u64>().ok());
+                if reported.map(|remaining| decision.remaining < remaining).unwrap_or(true) {
+                    ResponseHeaders::append(req.extensions_mut(), decision.headers());
+                }
+                HandlerResponse::Continue
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{test_server, wait_for_gateway};
+    use crate::conf::api::Api;
+    use crate::gateway::start_local_gateway;
+    use crate::context::{ClientInfo, ResponseHeaders};
+    use crate::handlers::{HandlerResponse, RequestGuard};
+    use crate::handlers::rate_limiting::{Algorithm, Decision, LimitState, QuotaPeriod, RateLimiter, RateLimitKey, DEFAULT_MAX_KEYS};
+    use hyper::{Body, Request};
+    use hyper::header::HeaderName;
+    use tokio::time::{Duration, sleep};
+    use std::str::FromStr;
+    use hyper::{Client, Uri, StatusCode};
+
+    fn run(algorithm: Algorithm, at_millis: &[u64]) -> Vec<Decision> {
+        let start = Duration::from_secs(1_700_000_000);
+        let mut state = algorithm.initial_state(start);
+        at_millis.iter().map(|millis| algorithm.check(&mut state, start + Duration::from_millis(*millis))).collect()
+    }
+
+    fn allowed(decisions: &[Decision]) -> Vec<bool> {
+        decisions.iter().map(|decision| decision.allowed).collect()
+    }
+
+    #[test]
+    fn token_bucket() {
+        let bucket = Algorithm::TokenBucket { capacity: 3, refill_period: Duration::from_millis(100) };
+        let decisions = run(bucket, &[0, 0, 0, 0, 50, 100, 100, 400]);
+        assert_eq!(vec![true, true, true, false, false, true, false, true], allowed(&decisions));
+        assert_eq!(2, decisions[0].remaining);
+        assert_eq!(Some(Duration::from_millis(100)), decisions[3].retry_after);
+        assert_eq!(Duration::from_millis(300), decisions[2].reset);
+        assert_eq!(2, decisions[7].remaining); // refilled up to its capacity
+    }
+
+    #[test]
+    fn sliding_window() {
+        let window = Algorithm::SlidingWindow { limit: 4, window: Duration::from_secs(1) };
+        let decisions = run(window, &[0, 100, 200, 300, 400, 1000, 1500, 1500, 1500, 3000]);
+        // at 1000: the previous window still counts fully, at 1500: for half (2), leaving room for 2 more
+        assert_eq!(vec![true, true, true, true, false, false, true, true, false, true], allowed(&decisions));
+        assert_eq!(Some(Duration::from_millis(850)), decisions[4].retry_after); // at 1250, 4 * 3/4 leaves room for one more
+        assert_eq!(Duration::from_millis(600), decisions[4].reset);
+        assert_eq!(3, decisions[9].remaining);
+    }
+
+    #[test]
+    fn gcra() {
+        let gcra = Algorithm::Gcra { limit: 10, period: Duration::from_secs(1), burst: 2 };
+        let decisions = run(gcra, &[0, 0, 0, 50, 100, 100, 1000]);
+        assert_eq!(vec![true, true, false, false, true, false, true], allowed(&decisions));
+        assert_eq!(1, decisions[0].remaining);
+        assert_eq!(10, decisions[0].limit); // the rate, not the burst
+        assert_eq!(Some(Duration::from_millis(100)), decisions[2].retry_after);
+        assert_eq!(Some(Duration::from_millis(50)), decisions[3].retry_after);
+    }
+
+    #[test]
+    fn invalid_limits_are_rejected() {
+        let invalid = [
+            Algorithm::TokenBucket { capacity: 10, refill_period: Duration::ZERO },
+            Algorithm::TokenBucket { capacity: 1 << 32, refill_period: Duration::from_secs(3600) },
+            Algorithm::SlidingWindow { limit: 10, window: Duration::ZERO },
+            Algorithm::Gcra { limit: 1 << 32, period: Duration::from_secs(1), burst: 1 },
+            Algorithm::Gcra { limit: 10, period: Duration::ZERO, burst: 1 },
+            Algorithm::Gcra { limit: 0, period: Duration::from_secs(1), burst: 1 },
+        ];
+        for algorithm in &invalid {
+            assert!(RateLimiter::new(*algorithm).is_err(), "{:?}", algorithm);
+        }
+        assert!(RateLimiter::new(Algorithm::Gcra { limit: 1 << 32, period: Duration::from_secs(3600), burst: 1 << 32 }).is_ok());
+        // checked anyway, if only through a store
+        let decision = Algorithm::SlidingWindow { limit: 1, window: Duration::ZERO }.check(&mut LimitState::Window { start: Duration::ZERO, current: 0, previous: 0 }, Duration::from_secs(1));
+        assert!(decision.allowed);
+    }
+
+    #[test]
+    fn quotas_follow_the_calendar() {
+        let feb_29_2024 = Duration::from_secs(1_709_208_000); // 2024-02-29T12:00:00Z
+        let (start, end) = QuotaPeriod::Month.bounds(feb_29_2024);
+        assert_eq!((Duration::from_secs(1_706_745_600), Duration::from_secs(1_709_251_200)), (start, end)); // 2024-02-01, 2024-03-01
+        let (start, end) = QuotaPeriod::Month.bounds(Duration::from_secs(1_735_603_200)); // 2024-12-31
+        assert_eq!((Duration::from_secs(1_733_011_200), Duration::from_secs(1_735_689_600)), (start, end)); // 2024-12-01, 2025-01-01
+        assert_eq!((Duration::from_secs(1_709_164_800), Duration::from_secs(1_709_251_200)), QuotaPeriod::Day.bounds(feb_29_2024));
+
+        let quota = Algorithm::Quota { limit: 2, period: QuotaPeriod::Day };
+        let mut state = quota.initial_state(feb_29_2024);
+        assert!(quota.check(&mut state, feb_29_2024).allowed);
+        assert!(quota.check(&mut state, feb_29_2024).allowed);
+        let denied = quota.check(&mut state, feb_29_2024);
+        assert!(!denied.allowed);
+        assert_eq!(Some(Duration::from_secs(12 * 3600)), denied.retry_after);
+        assert!(quota.check(&mut state, Duration::from_secs(1_709_251_200)).allowed); // midnight
+
+        let lowered = Algorithm::Quota { limit: 1, period: QuotaPeriod::Day };
+        let mut state = LimitState::Quota { period_start: QuotaPeriod::Day.bounds(feb_29_2024).0, used: 2 }; // counted with the previous limit
+        let denied = lowered.check(&mut state, feb_29_2024);
+        assert!(!denied.allowed);
+        assert_eq!(0, denied.remaining);
+    }
+
+    #[test]
+    fn states_are_encoded() {
+        let states = [
+            LimitState::Bucket { credit: Duration::from_millis(1500), updated: Duration::from_secs(1_700_000_000) },
+            LimitState::Window { start: Duration::from_secs(1_700_000_000), current: 3, previous: 7 },
+            LimitState::Gcra { theoretical_arrival: Duration::from_nanos(1_700_000_000_123_456_789) },
+            LimitState::Quota { period_start: Duration::from_secs(1_706_745_600), used: 42 },
+        ];
+        for state in &states {
+            assert_eq!(Some(*state), LimitState::decode(&state.encode()));
+        }
+        assert_eq!("gcra:1700000000123456789", states[2].encode());
+        assert_eq!(None, LimitState::decode("gcra:1:2"));
+        assert_eq!(None, LimitState::decode("bucket:soon:1"));
+    }
+
+    fn request(api_key: Option<&str>, client: &str) -> Request<Body> {
+        let mut req = Request::get("/books").body(Body::empty()).unwrap();
+        if let Some(api_key) = api_key {
+            req.headers_mut().insert("x-api-key", api_key.parse().unwrap());
+        }
+        req.extensions_mut().insert(ClientInfo { remote_addr: client.parse().unwrap(), local_addr: "127.0.0.1:443".parse().unwrap() });
+        req
+    }
+
+    async fn is_allowed(limiter: &RateLimiter, mut req: Request<Body>) -> bool {
+        matches!(limiter.handle_req(&mut req).await, HandlerResponse::Continue)
+    }
+
+    #[tokio::test]
+    async fn keyed_limits() {
+        let per_minute = Algorithm::SlidingWindow { limit: 1, window: Duration::from_secs(60) };
+        let by_api_key = RateLimiter::keyed(per_minute, Box::new(RateLimitKey::Header(HeaderName::from_static("x-api-key"))), DEFAULT_MAX_KEYS).unwrap();
+        assert!(is_allowed(&by_api_key, request(Some("alice"), "10.0.0.1:1000")).await);
+        assert!(!is_allowed(&by_api_key, request(Some("alice"), "10.0.0.2:1000")).await);
+        assert!(is_allowed(&by_api_key, request(Some("bob"), "10.0.0.1:1000")).await);
+        assert!(is_allowed(&by_api_key, request(None, "10.0.0.1:1000")).await);
+        assert!(!is_allowed(&by_api_key, request(None, "10.0.0.3:1000")).await); // keyless requests share a limit
+
+        let by_client = RateLimiter::keyed(per_minute, Box::new(RateLimitKey::Combined(vec![RateLimitKey::ClientIp, RateLimitKey::Route])), 1).unwrap();
+        assert!(is_allowed(&by_client, request(None, "10.0.0.1:1000")).await);
+        assert!(!is_allowed(&by_client, request(None, "10.0.0.1:2000")).await);
+        assert!(is_allowed(&by_client, request(None, "10.0.0.2:1000")).await);
+        assert!(is_allowed(&by_client, request(None, "10.0.0.1:1000")).await); // forgotten, only one key is tracked
+    }
+
+    #[tokio::test]
+    async fn most_restrictive_limit_is_reported() {
+        let rate = RateLimiter::new(Algorithm::TokenBucket { capacity: 10, refill_period: Duration::from_secs(1) }).unwrap();
+        let quota = RateLimiter::new(Algorithm::Quota { limit: 3, period: QuotaPeriod::Month }).unwrap();
+        let mut req = request(None, "10.0.0.1:1000");
+        rate.handle_req(&mut req).await;
+        quota.handle_req(&mut req).await;
+        rate.handle_req(&mut req).await;
+        let headers = &req.extensions().get::<ResponseHeaders>().unwrap().0;
+        assert_eq!("3", headers["ratelimit-limit"]);
+        assert_eq!("2", headers["ratelimit-remaining"]);
+    }
+
+    #[test]
+    fn headers() {
+        let decision = Decision { allowed: false, limit: 10, remaining: 0, reset: Duration::from_millis(1500), retry_after: Some(Duration::from_millis(200)) };
+        let resp = decision.too_many_requests();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+        assert_eq!("10", resp.headers()["ratelimit-limit"]);
+        assert_eq!("0", resp.headers()["ratelimit-remaining"]);
+        assert_eq!("2", resp.headers()["ratelimit-reset"]);
+        assert_eq!("1", resp.headers()["retry-after"]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let gw_port = 8000;
+        let backend_port = 8001;
+        let prefix = "/limited";
+        let span = Duration::from_secs(1);
+        tokio::spawn(async move {
+            test_server("Ok!!", backend_port).await
+        });
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+            let limiter = RateLimiter::new(Algorithm::Gcra { limit: 2, period: span, burst: 2 }).unwrap();
+            api.add_guard(Box::new(limiter));
+            start_local_gateway(gw_port, vec![api]).await.unwrap();
+        });
+        wait_for_gateway(gw_port).await;
+        let client = Client::new();
+        let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix).as_str()).unwrap();
+        let resp = client.get(url.clone()).await.unwrap();
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("2", resp.headers()["ratelimit-limit"]);
+        assert_eq!("1", resp.headers()["ratelimit-remaining"]);
+        assert_eq!(StatusCode::OK, client.get(url.clone()).await.unwrap().status());
+        let resp = client.get(url.clone()).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+        assert!(resp.headers().contains_key("retry-after"));
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url.clone()).await.unwrap().status());
+        sleep(span).await;
+        assert_eq!(StatusCode::OK, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::OK, client.get(url.clone()).await.unwrap().status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, client.get(url).await.unwrap().status());
+    }
+
+}
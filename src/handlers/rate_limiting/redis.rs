@@ -0,0 +1,359 @@
+use crate::handlers::rate_limiting::{Algorithm, Decision, LimitState};
+use crate::handlers::rate_limiting::store::{RateLimitStore, StoreError};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use log::debug;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Checks must complete within this delay by default, connection included
+pub const DEFAULT_REDIS_TIMEOUT: Duration = Duration::from_millis(200);
+/// A check is retried this many times when its key is concurrently modified, more than enough for as many gateway instances
+const MAX_ATTEMPTS: usize = 16;
+const MAX_IDLE_CONNECTIONS: usize = 16;
+/// Keys expire a bit after their state is back to the initial one
+const EXPIRY_MARGIN: Duration = Duration::from_secs(1);
+
+/// States shared by a fleet of gateway instances through a Redis server (or anything speaking its protocol: Valkey, KeyDB...)
+/// A check reads the state of its key, then writes the updated state in a transaction failing if the key changed in between (WATCH)
+#[derive(Debug)]
+pub struct RedisStore {
+    address: String,
+    password: Option<String>,
+    timeout: Duration,
+    connections: Mutex<Vec<RedisConnection>>,
+}
+
+impl RedisStore {
+
+    /// Server listening on `address`, i.e. "127.0.0.1:6379"
+    pub fn new(address: &str) -> Self {
+        RedisStore {
+            address: address.to_string(),
+            password: None,
+            timeout: DEFAULT_REDIS_TIMEOUT,
+            connections: Mutex::new(vec![]),
+        }
+    }
+
+    /// Authenticates connections (AUTH)
+    pub fn with_password(&mut self, password: &str) {
+        self.password = Some(password.to_string());
+    }
+
+    pub fn with_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    async fn connection(&self) -> Result<RedisConnection, StoreError> {
+        if let Some(connection) = self.connections.lock().map_err(|_| StoreError::Poisoned)?.pop() {
+            return Ok(connection)
+        }
+        let stream = TcpStream::connect(&self.address).await?;
+        stream.set_nodelay(true)?; // pipelined commands and their replies are small
+        let mut connection = RedisConnection { stream: BufReader::new(stream) };
+        if let Some(password) = &self.password {
+            expect_ok(connection.query(&[&["AUTH", password]]).await?.pop())?;
+        }
+        Ok(connection)
+    }
+
+    /// Connections are only reused after successful checks, there's no telling what state the others are in
+    fn release(&self, connection: RedisConnection) {
+        if let Ok(mut connections) = self.connections.lock() {
+            if connections.len() < MAX_IDLE_CONNECTIONS {
+                connections.push(connection);
+            }
+        }
+    }
+
+    async fn check_with(&self, connection: &mut RedisConnection, key: &str, algorithm: &Algorithm, now: Duration) -> Result<Decision, StoreError> {
+        for _ in 0..MAX_ATTEMPTS {
+            let mut replies = connection.query(&[&["WATCH", key], &["GET", key]]).await?;
+            let stored = match replies.pop() {
+                Some(Reply::Bulk(value)) => value.and_then(|value| LimitState::decode(&String::from_utf8_lossy(&value))),
+                other => return Err(unexpected(other)),
+            };
+            expect_ok(replies.pop())?;
+            let mut state = stored.unwrap_or_else(|| algorithm.initial_state(now));
+            let decision = algorithm.check(&mut state, now);
+            if stored == Some(state) {
+                expect_ok(connection.query(&[&["UNWATCH"]]).await?.pop())?;
+                return Ok(decision)
+            }
+            let ttl = (algorithm.retention(now) + EXPIRY_MARGIN).as_millis().to_string();
+            let replies = connection.query(&[&["MULTI"], &["SET", key, &state.encode(), "PX", &ttl], &["EXEC"]]).await?;
+            match replies.last() {
+                Some(Reply::Array(Some(_))) => return Ok(decision),
+                Some(Reply::Array(None)) => debug!("Rate limit {} concurrently modified, retrying", key),
+                _ => return Err(unexpected(replies.into_iter().last())),
+            }
+        }
+        Err(StoreError::Contention(key.to_string()))
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    async fn check(&self, key: &str, algorithm: &Algorithm, now: Duration) -> Result<Decision, StoreError> {
+        let check = async {
+            let mut connection = self.connection().await?;
+            let decision = self.check_with(&mut connection, key, algorithm, now).await?;
+            self.release(connection);
+            Ok(decision)
+        };
+        tokio::time::timeout(self.timeout, check).await.unwrap_or(Err(StoreError::Timeout))
+    }
+}
+
+/// RESP (REdis Serialization Protocol) values
+#[derive(Debug)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Option<Vec<Reply>>),
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reply::Status(status) => write!(f, "{}", status),
+            Reply::Error(e) => write!(f, "error {}", e),
+            Reply::Integer(i) => write!(f, "{}", i),
+            Reply::Bulk(None) | Reply::Array(None) => write!(f, "nil"),
+            Reply::Bulk(Some(value)) => write!(f, "\"{}\"", String::from_utf8_lossy(value)),
+            Reply::Array(Some(values)) => write!(f, "{} values", values.len()),
+        }
+    }
+}
+
+fn expect_ok(reply: Option<Reply>) -> Result<(), StoreError> {
+    match reply {
+        Some(Reply::Status(status)) if status == "OK" => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+fn unexpected(reply: Option<Reply>) -> StoreError {
+    StoreError::Protocol(reply.map(|reply| reply.to_string()).unwrap_or_else(|| "no reply".to_string()))
+}
+
+#[derive(Debug)]
+struct RedisConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+
+    /// Pipelines `commands`, then reads one reply per command
+    async fn query(&mut self, commands: &[&[&str]]) -> Result<Vec<Reply>, StoreError> {
+        let mut buf = Vec::new();
+        for args in commands {
+            buf.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+            for arg in args.iter() {
+                buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+            }
+        }
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        let mut replies = Vec::with_capacity(commands.len());
+        for _ in commands {
+            replies.push(read_reply(&mut self.stream).await?);
+        }
+        Ok(replies)
+    }
+}
+
+fn read_reply(stream: &mut BufReader<TcpStream>) -> BoxFuture<'_, Result<Reply, StoreError>> {
+    Box::pin(async move {
+        let mut line = Vec::new();
+        stream.read_until(b'\n', &mut line).await?;
+        if !line.ends_with(b"\r\n") {
+            return Err(StoreError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+        }
+        line.truncate(line.len() - 2);
+        let content = String::from_utf8_lossy(line.get(1..).unwrap_or_default()).to_string();
+        let length = || content.parse::<i64>().map_err(|_| StoreError::Protocol(format!("invalid length {}", content)));
+        match line.first() {
+            Some(b'+') => Ok(Reply::Status(content)),
+            Some(b'-') => Ok(Reply::Error(content)),
+            Some(b':') => Ok(Reply::Integer(length()?)),
+            Some(b'$') => match length()? {
+                length if length < 0 => Ok(Reply::Bulk(None)),
+                length => {
+                    let mut value = vec![0; length as usize + 2];
+                    stream.read_exact(&mut value).await?;
+                    value.truncate(length as usize);
+                    Ok(Reply::Bulk(Some(value)))
+                },
+            },
+            Some(b'*') => match length()? {
+                length if length < 0 => Ok(Reply::Array(None)),
+                length => {
+                    let mut values = Vec::with_capacity(length as usize);
+                    for _ in 0..length {
+                        values.push(read_reply(stream).await?);
+                    }
+                    Ok(Reply::Array(Some(values)))
+                },
+            },
+            _ => Err(StoreError::Protocol(format!("unknown reply {}", String::from_utf8_lossy(&line)))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::rate_limiting::{Algorithm, QuotaPeriod, RateLimiter, RateLimitKey};
+    use crate::handlers::rate_limiting::redis::RedisStore;
+    use crate::handlers::rate_limiting::store::{RateLimitStore, StoreError};
+    use crate::handlers::{HandlerResponse, RequestGuard};
+    use crate::tests::wait_for_port;
+    use hyper::{Body, Request, StatusCode};
+    use hyper::header::HeaderName;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Stand-in for a Redis server, just enough for `RedisStore`: values are versioned to implement WATCH, expiry is ignored
+    async fn fake_redis(port: u16) {
+        let values: Arc<Mutex<HashMap<String, (String, u64)>>> = Arc::new(Mutex::new(HashMap::new()));
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            stream.set_nodelay(true).unwrap();
+            let values = values.clone();
+            tokio::spawn(async move { serve(BufReader::new(stream), values).await });
+        }
+    }
+
+    async fn serve(mut stream: BufReader<TcpStream>, values: Arc<Mutex<HashMap<String, (String, u64)>>>) -> Option<()> {
+        let mut watched: Vec<(String, u64)> = vec![];
+        let mut queued: Option<Vec<Vec<String>>> = None;
+        loop {
+            let command = read_command(&mut stream).await?;
+            let reply = match (command[0].as_str(), &mut queued) {
+                ("EXEC", queue) => {
+                    let queue = queue.take().unwrap_or_default();
+                    let mut values = values.lock().unwrap();
+                    let version = |values: &HashMap<String, (String, u64)>, key: &str| values.get(key).map(|(_, version)| *version).unwrap_or(0);
+                    let changed = watched.drain(..).any(|(key, watched)| version(&values, &key) != watched);
+                    if changed {
+                        "*-1\r\n".to_string()
+                    } else {
+                        for set in &queue {
+                            let version = version(&values, &set[1]) + 1;
+                            values.insert(set[1].clone(), (set[2].clone(), version));
+                        }
+                        format!("*{}\r\n{}", queue.len(), "+OK\r\n".repeat(queue.len()))
+                    }
+                },
+                ("SET", Some(queue)) => {
+                    queue.push(command);
+                    "+QUEUED\r\n".to_string()
+                },
+                ("MULTI", queue) => {
+                    *queue = Some(vec![]);
+                    "+OK\r\n".to_string()
+                },
+                ("WATCH", _) => {
+                    let version = values.lock().unwrap().get(&command[1]).map(|(_, version)| *version).unwrap_or(0);
+                    watched.push((command[1].clone(), version));
+                    "+OK\r\n".to_string()
+                },
+                ("UNWATCH", _) => {
+                    watched.clear();
+                    "+OK\r\n".to_string()
+                },
+                ("GET", _) => match values.lock().unwrap().get(&command[1]) {
+                    Some((value, _)) => format!("${}\r\n{}\r\n", value.len(), value),
+                    None => "$-1\r\n".to_string(),
+                },
+                (other, _) => format!("-ERR unknown command '{}'\r\n", other),
+            };
+            stream.write_all(reply.as_bytes()).await.ok()?;
+        }
+    }
+
+    async fn read_command(stream: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.ok()?;
+        let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+        let mut args = vec![];
+        for _ in 0..count {
+            line.clear();
+            stream.read_line(&mut line).await.ok()?;
+            let length: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; length + 2];
+            stream.read_exact(&mut arg).await.ok()?;
+            arg.truncate(length);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+
+    fn request(api_key: &str) -> Request<Body> {
+        Request::get("/books").header("x-api-key", api_key).body(Body::empty()).unwrap()
+    }
+
+    async fn is_allowed(limiter: &RateLimiter, mut req: Request<Body>) -> bool {
+        matches!(limiter.handle_req(&mut req).await, HandlerResponse::Continue)
+    }
+
+    #[tokio::test]
+    async fn limits_hold_across_instances() {
+        let port = 13300;
+        tokio::spawn(async move { fake_redis(port).await });
+        wait_for_port(port).await;
+        let per_minute = Algorithm::SlidingWindow { limit: 2, window: Duration::from_secs(60) };
+        let instance = || {
+            let key = RateLimitKey::Header(HeaderName::from_static("x-api-key"));
+            let mut limiter = RateLimiter::keyed(per_minute, Box::new(key), 0).unwrap();
+            limiter.with_store(Arc::new(RedisStore::new(&format!("127.0.0.1:{}", port))), "books");
+            limiter
+        };
+        let (first, second) = (instance(), instance());
+        assert!(is_allowed(&first, request("alice")).await);
+        assert!(is_allowed(&second, request("alice")).await);
+        assert!(!is_allowed(&first, request("alice")).await);
+        assert!(!is_allowed(&second, request("alice")).await);
+        assert!(is_allowed(&second, request("bob")).await);
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_are_retried() {
+        let port = 13301;
+        tokio::spawn(async move { fake_redis(port).await });
+        wait_for_port(port).await;
+        let quota = Algorithm::Quota { limit: 5, period: QuotaPeriod::Day };
+        let checks = (0..8).map(|_| async move {
+            RedisStore::new(&format!("127.0.0.1:{}", port)).check("quota", &quota, Duration::from_secs(1_700_000_000)).await.unwrap()
+        });
+        let decisions = futures::future::join_all(checks).await;
+        assert_eq!(5, decisions.iter().filter(|decision| decision.allowed).count());
+    }
+
+    #[tokio::test]
+    async fn unreachable_store() {
+        let limit = Algorithm::TokenBucket { capacity: 1, refill_period: Duration::from_secs(1) };
+        let store = Arc::new(RedisStore::new("127.0.0.1:13302"));
+        assert!(matches!(store.check("key", &limit, Duration::from_secs(1)).await, Err(StoreError::Io(_))));
+        let mut fail_closed = RateLimiter::new(limit).unwrap();
+        fail_closed.with_store(store.clone(), "");
+        match fail_closed.handle_req(&mut request("alice")).await {
+            HandlerResponse::Break(resp) => assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status()),
+            HandlerResponse::Continue => panic!("Request allowed without a store"),
+        }
+        let mut fail_open = RateLimiter::new(limit).unwrap();
+        fail_open.with_store(store, "");
+        fail_open.fail_open();
+        assert!(is_allowed(&fail_open, request("alice")).await);
+    }
+}
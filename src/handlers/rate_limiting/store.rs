@@ -0,0 +1,196 @@
+use crate::handlers::rate_limiting::{Algorithm, Decision, LimitState};
+use async_trait::async_trait;
+use log::{error, info};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Where limit states live: in memory for a single gateway instance, or shared by a fleet (see `RedisStore`)
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + Debug {
+    /// Counts a request made at `now` against the limit of `key`, atomically
+    async fn check(&self, key: &str, algorithm: &Algorithm, now: Duration) -> Result<Decision, StoreError>;
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Protocol(String),   // unexpected reply from the store
+    Contention(String), // the state of this key kept being modified concurrently
+    Timeout,
+    Poisoned,
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "Rate limit store unreachable: {}", e),
+            StoreError::Protocol(e) => write!(f, "Unexpected reply from the rate limit store: {}", e),
+            StoreError::Contention(key) => write!(f, "Too many concurrent updates of {}", key),
+            StoreError::Timeout => write!(f, "Rate limit store timed out"),
+            StoreError::Poisoned => write!(f, "Rate limit states poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+/// States of the keys seen recently by this gateway instance
+/// Snapshots let quotas survive restarts: `restore` one when starting, then `persist_every`
+#[derive(Debug)]
+pub struct MemoryStore {
+    states: Mutex<Lru<LimitState>>,
+}
+
+impl MemoryStore {
+
+    /// Keeps at most `max_keys` states, see `Lru`
+    pub fn new(max_keys: usize) -> Self {
+        MemoryStore { states: Mutex::new(Lru::new(max_keys)) }
+    }
+
+    /// Loads a snapshot written by `snapshot`, a missing file gives an empty store
+    pub fn restore(path: &Path, max_keys: usize) -> std::io::Result<Self> {
+        let store = MemoryStore::new(max_keys);
+        let content = match std::fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(store),
+            Err(e) => return Err(e),
+        };
+        let entries: Vec<(String, String)> = serde_json::from_slice(&content)?;
+        let mut states = store.states.lock().unwrap();
+        for (key, state) in entries {
+            if let Some(state) = LimitState::decode(&state) {
+                states.get_or_insert_with(&key, || state);
+            }
+        }
+        drop(states);
+        Ok(store)
+    }
+
+    /// Writes every state to `path` (through a temporary file, so that a crash never leaves a partial snapshot)
+    pub fn snapshot(&self, path: &Path) -> std::io::Result<()> {
+        let entries: Vec<Value> = {
+            let states = self.states.lock().map_err(|_| std::io::Error::other("Rate limit states poisoned"))?;
+            states.iter().map(|(key, state)| Value::from(vec![key.to_string(), state.encode()])).collect()
+        };
+        let mut tmp = PathBuf::from(path);
+        tmp.set_extension("tmp");
+        std::fs::write(&tmp, Value::from(entries).to_string())?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Snapshots the states every `interval`, forever
+    pub async fn persist_every(self: Arc<Self>, path: PathBuf, interval: Duration) {
+        info!("Persisting rate limits to {} every {:?}", path.display(), interval);
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.snapshot(&path) {
+                error!("Could not persist rate limits to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn check(&self, key: &str, algorithm: &Algorithm, now: Duration) -> Result<Decision, StoreError> {
+        let mut states = self.states.lock().map_err(|_| StoreError::Poisoned)?;
+        let state = states.get_or_insert_with(key, || algorithm.initial_state(now));
+        Ok(algorithm.check(state, now))
+    }
+}
+
+/// Map bounded to `capacity` entries, evicting the least recently used one to make room for a new one
+#[derive(Debug)]
+pub struct Lru<V> {
+    capacity: usize,
+    entries: HashMap<String, (V, u64)>,
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl<V> Lru<V> {
+
+    pub fn new(capacity: usize) -> Self {
+        Lru { capacity: capacity.max(1), entries: HashMap::new(), by_use: BTreeMap::new(), uses: 0 }
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: &str, default: F) -> &mut V {
+        self.uses += 1;
+        match self.entries.get_mut(key) {
+            Some((_, last_use)) => {
+                self.by_use.remove(last_use);
+                *last_use = self.uses;
+            },
+            None => {
+                if self.entries.len() >= self.capacity {
+                    if let Some((_, evicted)) = self.by_use.pop_first() {
+                        self.entries.remove(&evicted);
+                    }
+                }
+                self.entries.insert(key.to_string(), (default(), self.uses));
+            },
+        }
+        self.by_use.insert(self.uses, key.to_string());
+        &mut self.entries.get_mut(key).unwrap().0
+    }
+
+    /// From the least to the most recently used
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &V)> {
+        self.by_use.values().map(move |key| (key.as_str(), &self.entries[key].0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handlers::rate_limiting::{Algorithm, QuotaPeriod};
+    use crate::handlers::rate_limiting::store::{Lru, MemoryStore, RateLimitStore};
+    use crate::tests::temp_dir;
+    use std::time::Duration;
+
+    #[test]
+    fn least_recently_used_keys_are_evicted() {
+        let mut lru = Lru::new(2);
+        *lru.get_or_insert_with("a", || 0) += 1;
+        *lru.get_or_insert_with("b", || 0) += 1;
+        *lru.get_or_insert_with("a", || 0) += 1;
+        *lru.get_or_insert_with("c", || 0) += 1; // evicts b
+        assert_eq!(2, lru.len());
+        assert_eq!(vec![("a", &2), ("c", &1)], lru.iter().collect::<Vec<_>>());
+        assert_eq!(0, *lru.get_or_insert_with("b", || 0));
+    }
+
+    #[tokio::test]
+    async fn quotas_survive_restarts() {
+        let path = temp_dir().join("quotas.json");
+        let quota = Algorithm::Quota { limit: 2, period: QuotaPeriod::Month };
+        let now = Duration::from_secs(1_709_208_000);
+        let store = MemoryStore::restore(&path, 10).unwrap();
+        assert!(store.check("alice", &quota, now).await.unwrap().allowed);
+        assert!(store.check("alice", &quota, now).await.unwrap().allowed);
+        assert!(store.check("bob", &quota, now).await.unwrap().allowed);
+        store.snapshot(&path).unwrap();
+
+        let restarted = MemoryStore::restore(&path, 10).unwrap();
+        assert!(!restarted.check("alice", &quota, now).await.unwrap().allowed);
+        assert_eq!(0, restarted.check("bob", &quota, now).await.unwrap().remaining);
+    }
+}
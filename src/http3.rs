@@ -0,0 +1,224 @@
+use crate::conf::api::Api;
+use crate::conf::listener::ListenerConfig;
+use crate::conf::tls::{TlsConfig, TlsVersion};
+use crate::context::ClientInfo;
+use crate::gateway::{Gateway, GatewayError};
+use crate::tls::{load_certs, load_private_key, TlsError};
+use bytes::{Buf, Bytes};
+use h3::server::RequestResolver;
+use hyper::{Body, HeaderMap, Request, Version};
+use hyper::body::HttpBody;
+use hyper::service::Service;
+use log::{debug, info};
+use quic_rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+// Experimental: h3 (and the `http` 1.x types it relies on) is still evolving, requests and responses are converted at the boundary
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Headers HTTP/3 forbids (RFC 9114, 4.2), hop-by-hop anyway
+const CONNECTION_SPECIFIC: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Starts an HTTP/3 listener on the UDP port `listener.address`, serving `apis` like a TCP listener would
+/// TLS is mandatory: the default certificate of `listener.tls` is served over TLS 1.3, with the "h3" ALPN protocol
+/// Client certificates, certificate reloading, PROXY protocol and upgrades (WebSockets) are only supported by TCP listeners
+/// Advertise this listener on the TCP ones with `ListenerConfig::advertise_http3`
+pub async fn start_http3_gateway(listener: ListenerConfig, apis: Vec<Api>) -> Result<(), GatewayError> {
+    let tls = listener.tls.as_ref().ok_or_else(|| TlsError::Unsupported("HTTP/3 without TLS".to_string()))?;
+    let endpoint = quinn::Endpoint::server(server_config(tls)?, listener.address)?;
+    let local_addr = endpoint.local_addr()?;
+    info!("Listening on https://{} (HTTP/3)", local_addr);
+    let gateway = Gateway::new(apis.into_iter().map(Arc::new).collect());
+    while let Some(incoming) = endpoint.accept().await {
+        let mut gateway = gateway.clone();
+        let remote_addr = incoming.remote_address();
+        gateway.client_info = Some(ClientInfo { remote_addr, local_addr });
+        tokio::spawn(async move {
+            if let Err(e) = serve_connection(incoming, gateway).await {
+                debug!("HTTP/3 connection with {} closed: {}", remote_addr, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn server_config(tls: &TlsConfig) -> Result<quinn::ServerConfig, TlsError> {
+    if tls.client_auth.is_some() {
+        return Err(TlsError::Unsupported("Client certificates over HTTP/3".to_string()))
+    }
+    if !tls.versions.contains(&TlsVersion::Tls13) {
+        return Err(TlsError::Unsupported("HTTP/3 without TLS 1.3".to_string()))
+    }
+    let certificate = tls.certificates.first().ok_or_else(|| TlsError::NoCertificate("TLS configuration".to_string()))?;
+    let chain = load_certs(&certificate.cert_path)?.into_iter().map(|cert| CertificateDer::from(cert.0)).collect();
+    let key = PrivateKeyDer::try_from(load_private_key(&certificate.key_path)?.0)
+        .map_err(|_| TlsError::InvalidPrivateKey(certificate.key_path.display().to_string()))?;
+    let provider = Arc::new(quic_rustls::crypto::ring::default_provider());
+    let mut config = quic_rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&quic_rustls::version::TLS13])
+        .map_err(|e| TlsError::Quic(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .map_err(|e| TlsError::Quic(e.to_string()))?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(config).map_err(|e| TlsError::Quic(e.to_string()))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+async fn serve_connection(incoming: quinn::Incoming, gateway: Gateway) -> Result<(), BoxError> {
+    let connection = h3_quinn::Connection::new(incoming.await?);
+    let mut connection = h3::server::Connection::<_, Bytes>::new(connection).await?;
+    while let Some(resolver) = connection.accept().await? {
+        let gateway = gateway.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_request(resolver, gateway).await {
+                debug!("HTTP/3 request failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Streams the request body to the gateway while it's being proxied, then the response body back to the client
+async fn serve_request(resolver: RequestResolver<h3_quinn::Connection, Bytes>, mut gateway: Gateway) -> Result<(), BoxError> {
+    let (head, stream) = resolver.resolve_request().await?;
+    let (mut send, mut recv) = stream.split();
+    let (mut body_tx, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => if body_tx.send_data(chunk.copy_to_bytes(chunk.remaining())).await.is_err() {
+                    return
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("HTTP/3 request body interrupted: {}", e);
+                    return body_tx.abort()
+                },
+            }
+        }
+        if let Ok(Some(trailers)) = recv.recv_trailers().await {
+            let _ = body_tx.send_trailers(from_h3_headers(&trailers)).await;
+        }
+    });
+
+    let (parts, _) = head.into_parts();
+    let mut req = Request::builder()
+        .method(parts.method.as_str())
+        .uri(parts.uri.to_string())
+        .version(Version::HTTP_3)
+        .body(body)?;
+    *req.headers_mut() = from_h3_headers(&parts.headers);
+
+    let (parts, mut body) = gateway.call(req).await?.into_parts();
+    let mut resp = http1::Response::builder().status(parts.status.as_u16()).body(())?;
+    *resp.headers_mut() = to_h3_headers(&parts.headers);
+    send.send_response(resp).await?;
+    while let Some(chunk) = body.data().await {
+        send.send_data(chunk?).await?;
+    }
+    match body.trailers().await? {
+        Some(trailers) => send.send_trailers(to_h3_headers(&trailers)).await?,
+        None => send.finish().await?,
+    }
+    Ok(())
+}
+
+fn from_h3_headers(headers: &http1::HeaderMap) -> HeaderMap {
+    let mut converted = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if let (Ok(name), Ok(value)) = (hyper::header::HeaderName::from_bytes(name.as_ref()), hyper::header::HeaderValue::from_bytes(value.as_bytes())) {
+            converted.append(name, value);
+        }
+    }
+    converted
+}
+
+fn to_h3_headers(headers: &HeaderMap) -> http1::HeaderMap {
+    let mut converted = http1::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        if CONNECTION_SPECIFIC.contains(&name.as_str()) {
+            continue
+        }
+        if let (Ok(name), Ok(value)) = (http1::HeaderName::from_bytes(name.as_ref()), http1::HeaderValue::from_bytes(value.as_bytes())) {
+            converted.append(name, value);
+        }
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::listener::ListenerConfig;
+    use crate::conf::tls::TlsConfig;
+    use crate::gateway::start_gateway;
+    use crate::http3::start_http3_gateway;
+    use crate::tests::{self_signed, test_server, tls_connect, wait_for_port, TestCert, unwrap_body_as_str};
+    use bytes::Buf;
+    use hyper::{Body, Request};
+    use quic_rustls::pki_types::CertificateDer;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::version::TLS13;
+
+    /// No need to wait for the listener: QUIC clients retransmit their first packet until the server answers
+    async fn h3_get(port: u16, cert: &TestCert, path: &str) -> (u16, String) {
+        let mut roots = quic_rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(cert.cert_der.clone())).unwrap();
+        let mut tls = quic_rustls::ClientConfig::builder_with_provider(Arc::new(quic_rustls::crypto::ring::default_provider()))
+            .with_protocol_versions(&[&quic_rustls::version::TLS13]).unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+        let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let connection = endpoint.connect(([127, 0, 0, 1], port).into(), "localhost").unwrap().await.unwrap();
+        let (mut driver, mut sender) = h3::client::new(h3_quinn::Connection::new(connection)).await.unwrap();
+        tokio::spawn(async move { driver.wait_idle().await });
+        let req = http1::Request::get(format!("https://localhost:{}{}", port, path)).body(()).unwrap();
+        let mut stream = sender.send_request(req).await.unwrap();
+        stream.finish().await.unwrap();
+        let resp = stream.recv_response().await.unwrap();
+        let mut body = vec![];
+        while let Some(mut chunk) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+        }
+        (resp.status().as_u16(), String::from_utf8(body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn proxy_over_http3() {
+        let h3_port = 13200;
+        let tcp_port = 13201;
+        let backend_port = 13202;
+        let cert = self_signed(&["localhost"]);
+        tokio::spawn(async move { test_server("over h3", backend_port).await });
+        let apis = || vec![Api::http("127.0.0.1", backend_port, "/api".to_string()).unwrap()];
+        let mut listener = ListenerConfig::local(h3_port);
+        listener.with_tls(TlsConfig::new(&cert.cert_path, &cert.key_path));
+        let h3_apis = apis();
+        tokio::spawn(async move { start_http3_gateway(listener, h3_apis).await });
+        let mut listener = ListenerConfig::local(tcp_port);
+        listener.with_tls(TlsConfig::new(&cert.cert_path, &cert.key_path));
+        listener.advertise_http3(h3_port);
+        let tcp_apis = apis();
+        tokio::spawn(async move { start_gateway(listener, tcp_apis).await });
+        wait_for_port(backend_port).await;
+        wait_for_port(tcp_port).await;
+
+        // discovered through Alt-Svc
+        let stream = tls_connect(tcp_port, "localhost", &[&cert], vec![], &[&TLS13]).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let resp = sender.send_request(Request::get("/api").header("Host", "localhost").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!("h3=\":13200\"; ma=86400", resp.headers()["alt-svc"]);
+        assert_eq!("over h3", unwrap_body_as_str(resp).await);
+
+        assert_eq!((200, "over h3".to_string()), h3_get(h3_port, &cert, "/api/books").await);
+        assert_eq!(404, h3_get(h3_port, &cert, "/unknown").await.0);
+    }
+}
@@ -1,6 +1,18 @@
+pub mod concurrency;
 pub mod conf;
+pub mod context;
+mod forward;
 pub mod gateway;
 pub mod handlers;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod grpc;
+pub mod proxy_protocol;
+pub mod tcp;
+pub mod tls;
+#[cfg(unix)]
+pub mod unix;
+pub mod websocket;
 
 #[cfg(test)]
 mod tests {
@@ -11,8 +23,17 @@ mod tests {
     use std::str::FromStr;
     use log::*;
     use std::string::FromUtf8Error;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::convert::TryFrom;
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerName, SupportedProtocolVersion, Certificate, PrivateKey};
+    use crate::tls::{load_certs, load_private_key};
 
     #[derive(Debug)]
+    #[allow(dead_code)]
     pub enum BodyReadError {
         EncodingError(FromUtf8Error),
         BodyError(hyper::Error)
@@ -41,6 +62,119 @@ mod tests {
         }
     }
 
+    pub async fn wait_for_port(port: u16) {
+        let mut attempts = 0;
+        while attempts < 50 && TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            attempts += 1;
+        }
+    }
+
+    /// A self-signed certificate written as PEM files in a temporary directory
+    pub struct TestCert {
+        pub cert_path: PathBuf,
+        pub key_path: PathBuf,
+        pub cert_der: Vec<u8>,
+    }
+
+    pub(crate) fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("itinerarium-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_cert(cert_pem: String, key_pem: String) -> TestCert {
+        let dir = temp_dir();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert_pem).unwrap();
+        std::fs::write(&key_path, key_pem).unwrap();
+        let cert_der = load_certs(&cert_path).unwrap().remove(0).0;
+        TestCert { cert_path, key_path, cert_der }
+    }
+
+    pub fn self_signed(names: &[&str]) -> TestCert {
+        let cert = rcgen::generate_simple_self_signed(names.iter().map(|n| n.to_string()).collect::<Vec<String>>()).unwrap();
+        write_cert(cert.serialize_pem().unwrap(), cert.serialize_private_key_pem())
+    }
+
+    /// A certificate authority, able to sign client (or server) certificates
+    #[cfg(feature = "rustls")]
+    pub struct TestCa {
+        pub cert: rcgen::Certificate,
+        pub cert_path: PathBuf,
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn test_ca() -> TestCa {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "Test CA");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let cert_path = temp_dir().join("ca.pem");
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        TestCa { cert, cert_path }
+    }
+
+    #[cfg(feature = "rustls")]
+    pub fn signed_by(ca: &TestCa, common_name: &str, names: &[&str]) -> TestCert {
+        let mut params = rcgen::CertificateParams::new(names.iter().map(|n| n.to_string()).collect::<Vec<String>>());
+        params.distinguished_name.push(rcgen::DnType::CommonName, common_name);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        write_cert(cert.serialize_pem_with_signer(&ca.cert).unwrap(), cert.serialize_private_key_pem())
+    }
+
+    pub async fn tls_connect(port: u16, server_name: &str, roots: &[&TestCert], alpn: Vec<Vec<u8>>, versions: &[&'static SupportedProtocolVersion]) -> std::io::Result<TlsStream<TcpStream>> {
+        tls_connect_as(None, port, server_name, roots, alpn, versions).await
+    }
+
+    /// Connects presenting a client certificate (if any)
+    pub async fn tls_connect_as(client: Option<&TestCert>, port: u16, server_name: &str, roots: &[&TestCert], alpn: Vec<Vec<u8>>, versions: &[&'static SupportedProtocolVersion]) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut root_store = RootCertStore::empty();
+        for root in roots {
+            root_store.add(&Certificate(root.cert_der.clone())).unwrap();
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(root_store);
+        let mut config = match client {
+            None => builder.with_no_client_auth(),
+            Some(client) => builder.with_client_auth_cert(
+                load_certs(&client.cert_path).unwrap().into_iter().map(|cert| Certificate(cert.0)).collect(),
+                PrivateKey(load_private_key(&client.key_path).unwrap().0)
+            ).unwrap(),
+        };
+        config.alpn_protocols = alpn;
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(server_name).unwrap(), stream)
+            .await
+    }
+
+    /// Same as `test_server`, over TLS (terminated by the TLS backend picked by features)
+    pub async fn tls_test_server(payload: &str, port: u16, tls: crate::conf::tls::TlsConfig) {
+        let acceptor = crate::tls::server::TlsListener::new(tls).unwrap();
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        info!("Mock server listening on https://127.0.0.1:{}", port);
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            let payload = payload.to_string();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    let service = service_fn(move |_req| {
+                        let payload = payload.clone();
+                        async move { Ok::<_, Infallible>(Response::<Body>::new(payload.into())) }
+                    });
+                    let _ = hyper::server::conn::Http::new().http1_only(true).serve_connection(stream, service).await;
+                }
+            });
+        }
+    }
+
     pub async fn test_server(payload: &str, port: u16) {
         let addr = SocketAddr::from(([127, 0, 0, 1], port));
         let payload = payload.to_string();
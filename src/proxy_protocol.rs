@@ -0,0 +1,257 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// PROXY protocol, see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+// Headers are read without consuming anything past them, so the connection can be handed over as is (to TLS, HTTP...)
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+const V1_MAX_LEN: usize = 107;
+/// Clients have this long to send the header once connected
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,     // human readable
+    V2,     // binary
+}
+
+/// Addresses of the original connection, as seen by the proxy in front of us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Reads a v1 or v2 header. Headers without addresses (health checks sent by the proxy itself: `LOCAL`, `UNKNOWN`) give `None`
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Option<ProxyHeader>> {
+    let mut prefix = [0u8; 12]; // shorter than any v1 header
+    stream.read_exact(&mut prefix).await?;
+    if prefix == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let mut addresses = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(header[0], header[1], &addresses)
+    }
+    if !prefix.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"))
+    }
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"))
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("invalid PROXY protocol v1 header"))?)
+}
+
+/// Header to send upstream, `None` when the original addresses are unknown
+pub fn encode(version: ProxyProtocolVersion, header: Option<ProxyHeader>) -> Vec<u8> {
+    match (version, header) {
+        (ProxyProtocolVersion::V1, None) => b"PROXY UNKNOWN\r\n".to_vec(),
+        (ProxyProtocolVersion::V1, Some(header)) => {
+            let (source, destination) = same_family(header);
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), destination.ip(), source.port(), destination.port()).into_bytes()
+        },
+        (ProxyProtocolVersion::V2, None) => {
+            let mut encoded = V2_SIGNATURE.to_vec();
+            encoded.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]); // LOCAL
+            encoded
+        },
+        (ProxyProtocolVersion::V2, Some(header)) => {
+            let (source, destination) = same_family(header);
+            let mut addresses = vec![];
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    addresses.extend_from_slice(&src.octets());
+                    addresses.extend_from_slice(&dst.octets());
+                    0x11
+                },
+                (src, dst) => {
+                    addresses.extend_from_slice(&ipv6(src).octets());
+                    addresses.extend_from_slice(&ipv6(dst).octets());
+                    0x21
+                },
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+            let mut encoded = V2_SIGNATURE.to_vec();
+            encoded.extend_from_slice(&[0x21, family]); // PROXY command, TCP
+            encoded.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(&addresses);
+            encoded
+        },
+    }
+}
+
+fn parse_v1(line: &str) -> std::io::Result<Option<ProxyHeader>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if parts.len() == 6 => {},
+        _ => return Err(invalid("invalid PROXY protocol v1 header")),
+    }
+    let address = |ip: &str, port: &str| -> std::io::Result<SocketAddr> {
+        let ip = ip.parse::<IpAddr>().map_err(|_| invalid("invalid address in PROXY protocol header"))?;
+        let port = port.parse::<u16>().map_err(|_| invalid("invalid port in PROXY protocol header"))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some(ProxyHeader { source: address(parts[2], parts[4])?, destination: address(parts[3], parts[5])? }))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> std::io::Result<Option<ProxyHeader>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"))
+    }
+    match version_command & 0x0F {
+        0 => return Ok(None), // LOCAL
+        1 => {},
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    let header = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = |bytes: &[u8]| IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]));
+            ProxyHeader {
+                source: SocketAddr::new(ip(&addresses[0..4]), port(&addresses[8..10])),
+                destination: SocketAddr::new(ip(&addresses[4..8]), port(&addresses[10..12])),
+            }
+        },
+        2 if addresses.len() >= 36 => {
+            let ip = |bytes: &[u8]| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            ProxyHeader {
+                source: SocketAddr::new(ip(&addresses[0..16]), port(&addresses[32..34])),
+                destination: SocketAddr::new(ip(&addresses[16..32]), port(&addresses[34..36])),
+            }
+        },
+        _ => return Ok(None), // unix sockets, unspecified: the connection is accepted, without addresses
+    };
+    Ok(Some(header))
+}
+
+/// Both addresses must belong to the same family, IPv4 ones are mapped to IPv6 if they don't
+fn same_family(header: ProxyHeader) -> (SocketAddr, SocketAddr) {
+    let (source, destination) = (header.source, header.destination);
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination)
+    }
+    (SocketAddr::new(IpAddr::V6(ipv6(source.ip())), source.port()), SocketAddr::new(IpAddr::V6(ipv6(destination.ip())), destination.port()))
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::listener::ListenerConfig;
+    use crate::gateway::start_gateway;
+    use crate::proxy_protocol::{encode, read_header, ProxyHeader, ProxyProtocolVersion};
+    use crate::tests::{wait_for_port, unwrap_body_as_str};
+    use hyper::{Body, Request, Response};
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn decode(bytes: &[u8]) -> std::io::Result<Option<ProxyHeader>> {
+        let mut stream = bytes;
+        let header = read_header(&mut stream).await;
+        assert!(header.is_err() || stream == b"payload", "the header must be consumed, and only the header");
+        header
+    }
+
+    #[tokio::test]
+    async fn headers_round_trip() {
+        let v4 = ProxyHeader { source: "192.168.0.1:56324".parse().unwrap(), destination: "10.0.0.2:443".parse().unwrap() };
+        let v6 = ProxyHeader { source: "[2001:db8::1]:56324".parse().unwrap(), destination: "[::1]:443".parse().unwrap() };
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for header in [Some(v4), Some(v6), None] {
+                let mut bytes = encode(version, header);
+                bytes.extend_from_slice(b"payload");
+                assert_eq!(header, decode(&bytes).await.unwrap());
+            }
+        }
+        assert_eq!(b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\n".to_vec(), encode(ProxyProtocolVersion::V1, Some(v4)));
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        assert!(decode(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n").await.is_err());
+        assert!(decode(b"PROXY TCP4 192.168.0.1 10.0.0.2 56324\r\npayload").await.is_err());
+        assert!(decode(format!("PROXY TCP4 {}\r\n", "1".repeat(120)).as_bytes()).await.is_err());
+        assert!(decode(b"PROXY TCP4").await.is_err());
+    }
+
+    /// Expects a PROXY protocol header, then replies with the client address it carries and the X-Forwarded-For header
+    async fn proxied_server(port: u16) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let source = match read_header(&mut stream).await {
+                    Ok(header) => header.map(|header| header.source.to_string()).unwrap_or_default(),
+                    Err(_) => return,   // readiness probe
+                };
+                let service = service_fn(move |req: Request<Body>| {
+                    let forwarded_for = req.headers().get("x-forwarded-for").map(|value| value.to_str().unwrap().to_string()).unwrap_or_default();
+                    let body = format!("{} {}", source, forwarded_for);
+                    async move { Ok::<_, Infallible>(Response::new(Body::from(body))) }
+                });
+                let _ = hyper::server::conn::Http::new().serve_connection(stream, service).await;
+            });
+        }
+    }
+
+    async fn get_with_header(port: u16, header: &[u8]) -> hyper::Result<Response<Body>> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_all(header).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+        tokio::spawn(connection);
+        let req = Request::get("/api").header("Host", "localhost").header("X-Forwarded-For", "10.1.1.1").body(Body::empty()).unwrap();
+        sender.send_request(req).await
+    }
+
+    #[tokio::test]
+    async fn client_address_goes_through_proxy_protocol() {
+        let gw_port = 13100;
+        let backend_port = 13101;
+        tokio::spawn(proxied_server(backend_port));
+        let mut api = Api::http("127.0.0.1", backend_port, "/api".to_string()).unwrap();
+        api.send_proxy_protocol(ProxyProtocolVersion::V2);
+        let mut listener = ListenerConfig::local(gw_port);
+        listener.accept_proxy_protocol();
+        tokio::spawn(async move { start_gateway(listener, vec![api]).await });
+        wait_for_port(backend_port).await;
+        wait_for_port(gw_port).await;
+
+        let client = ProxyHeader { source: "192.168.0.7:40000".parse().unwrap(), destination: "192.168.0.1:443".parse().unwrap() };
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let resp = get_with_header(gw_port, &encode(version, Some(client))).await.unwrap();
+            assert_eq!("192.168.0.7:40000 10.1.1.1, 192.168.0.7", unwrap_body_as_str(resp).await);
+        }
+
+        let mut stream = TcpStream::connect(("127.0.0.1", gw_port)).await.unwrap();
+        stream.write_all(b"GET /api HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 64];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(read.map(|n| n == 0).unwrap_or(true), "connections without header are closed");
+    }
+}
@@ -0,0 +1,433 @@
+use crate::conf::tcp::{SniRoute, TcpListenerConfig, TcpUpstream};
+use crate::gateway::GatewayError;
+use crate::proxy_protocol::{self, ProxyHeader};
+use log::{debug, error, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Clients have this long to send their ClientHello, when routing by SNI
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Larger ClientHellos are not routed (TLS records are at most 16KB, a ClientHello rarely exceeds one)
+const MAX_CLIENT_HELLO: usize = 16 * 1024 + 5;
+
+/// Starts an L4 proxy: raw TCP forwarding, or TLS passthrough routed by SNI
+pub async fn start_tcp_proxy(conf: TcpListenerConfig) -> Result<(), GatewayError> {
+    let listener = TcpListener::bind(conf.address).await?;
+    info!("Listening on tcp://{}", conf.address);
+    let proxy = Arc::new(TcpProxy::new(conf));
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Could not accept connection: {}", e);
+                continue
+            }
+        };
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            if let Err(e) = proxy.handle(stream, remote_addr).await {
+                debug!("Connection with {} closed: {}", remote_addr, e);
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+struct TcpProxy {
+    routes: Vec<(SniRoute, Pool)>,
+    default: Option<Pool>,
+    idle_timeout: Duration,
+    sni: bool,
+    proxy_protocol: bool,
+}
+
+/// Runtime state of a `TcpUpstream`
+#[derive(Debug)]
+struct Pool {
+    upstream: TcpUpstream,
+    next: AtomicUsize,
+}
+
+impl Pool {
+
+    fn new(upstream: TcpUpstream) -> Self {
+        Pool { upstream, next: AtomicUsize::new(0) }
+    }
+
+    /// Connects to the next reachable endpoint
+    async fn connect(&self) -> std::io::Result<TcpStream> {
+        let endpoints = &self.upstream.endpoints;
+        let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No upstream endpoint");
+        for _ in 0..endpoints.len() {
+            let endpoint = &endpoints[self.next.fetch_add(1, Ordering::Relaxed) % endpoints.len()];
+            match tokio::time::timeout(self.upstream.connect_timeout, TcpStream::connect(endpoint)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = e,
+                Err(_) => last_error = std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Connecting to {} timed out", endpoint)),
+            }
+            debug!("Skipping upstream endpoint {}: {}", endpoint, last_error);
+        }
+        Err(last_error)
+    }
+}
+
+impl TcpProxy {
+
+    fn new(conf: TcpListenerConfig) -> Self {
+        TcpProxy {
+            sni: !conf.routes.is_empty(),
+            routes: conf.routes.into_iter().map(|route| {
+                let pool = Pool::new(route.upstream.clone());
+                (route, pool)
+            }).collect(),
+            default: conf.default.map(Pool::new),
+            idle_timeout: conf.idle_timeout,
+            proxy_protocol: conf.proxy_protocol,
+        }
+    }
+
+    async fn handle(&self, mut client: TcpStream, remote_addr: SocketAddr) -> std::io::Result<()> {
+        let addresses = if self.proxy_protocol {
+            tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, proxy_protocol::read_header(&mut client)).await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "No PROXY protocol header received"))??
+        } else {
+            Some(ProxyHeader { source: remote_addr, destination: client.local_addr()? })
+        };
+        let (pool, client_hello) = if self.sni {
+            let (client_hello, server_name) = tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(&mut client)).await
+                .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "No ClientHello received"))??;
+            (self.pool_for(server_name.as_deref()), client_hello)
+        } else {
+            (self.default.as_ref(), vec![])
+        };
+        let pool = pool.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No upstream for this connection"))?;
+        let mut upstream = pool.connect().await?;
+        upstream.set_nodelay(true)?;
+        if let Some(version) = pool.upstream.proxy_protocol {
+            upstream.write_all(&proxy_protocol::encode(version, addresses)).await?;
+        }
+        upstream.write_all(&client_hello).await?;
+        relay(client, upstream, self.idle_timeout, None).await
+    }
+
+    fn pool_for(&self, server_name: Option<&str>) -> Option<&Pool> {
+        let routed = server_name.and_then(|server_name| {
+            self.routes.iter()
+                .find(|(route, _)| route.matches(server_name))
+                .map(|(_, pool)| pool)
+        });
+        routed.or(self.default.as_ref())
+    }
+}
+
+/// Sees the bytes relayed in each direction, once they're written
+pub(crate) trait RelayHook: Send {
+    fn to_upstream(&mut self, data: &[u8]);
+    fn to_client(&mut self, data: &[u8]);
+}
+
+/// Relays bytes in both directions, each side is closed when the other one is, and both after `idle_timeout` without traffic
+pub(crate) async fn relay<C, U>(client: C, upstream: U, idle_timeout: Duration, mut hook: Option<&mut dyn RelayHook>) -> std::io::Result<()>
+    where C: AsyncRead + AsyncWrite, U: AsyncRead + AsyncWrite {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let mut client_buf = vec![0u8; 8 * 1024];
+    let mut upstream_buf = vec![0u8; 8 * 1024];
+    let (mut client_open, mut upstream_open) = (true, true);
+    while client_open || upstream_open {
+        tokio::select! {
+            read = client_read.read(&mut client_buf), if client_open => match read? {
+                0 => {
+                    client_open = false;
+                    upstream_write.shutdown().await?;
+                },
+                n => {
+                    upstream_write.write_all(&client_buf[..n]).await?;
+                    if let Some(hook) = hook.as_mut() {
+                        hook.to_upstream(&client_buf[..n]);
+                    }
+                },
+            },
+            read = upstream_read.read(&mut upstream_buf), if upstream_open => match read? {
+                0 => {
+                    upstream_open = false;
+                    client_write.shutdown().await?;
+                },
+                n => {
+                    client_write.write_all(&upstream_buf[..n]).await?;
+                    if let Some(hook) = hook.as_mut() {
+                        hook.to_client(&upstream_buf[..n]);
+                    }
+                },
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!("Closing connection idle for {:?}", idle_timeout);
+                let _ = client_write.shutdown().await;
+                let _ = upstream_write.shutdown().await;
+                return Ok(())
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Reads the TLS records carrying the ClientHello, returns them (to be replayed upstream) along with the SNI, if any
+/// Anything that isn't a TLS handshake is returned as is, without server name
+async fn read_client_hello<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<(Vec<u8>, Option<String>)> {
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        match client_hello(&buf) {
+            ClientHello::Complete(hello) => {
+                let server_name = server_name(&hello);
+                return Ok((buf, server_name))
+            },
+            ClientHello::Invalid => return Ok((buf, None)),
+            ClientHello::Partial if buf.len() >= MAX_CLIENT_HELLO => return Ok((buf, None)),
+            ClientHello::Partial => {
+                let mut chunk = [0u8; 2048];
+                match stream.read(&mut chunk).await? {
+                    0 => return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed before ClientHello")),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
+    }
+}
+
+enum ClientHello {
+    Partial,
+    Complete(Vec<u8>),  // handshake message, reassembled from its records
+    Invalid,
+}
+
+/// TLS 1.2 (RFC 5246) section 6.2.1: a handshake message can be fragmented across several records
+fn client_hello(buf: &[u8]) -> ClientHello {
+    let mut handshake = vec![];
+    let mut records = buf;
+    while records.len() >= 5 {
+        if records[0] != 22 { // handshake
+            return ClientHello::Invalid
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        if records.len() < 5 + len {
+            break
+        }
+        handshake.extend_from_slice(&records[5..5 + len]);
+        records = &records[5 + len..];
+        if handshake.len() >= 4 {
+            if handshake[0] != 1 { // client_hello
+                return ClientHello::Invalid
+            }
+            let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + hello_len {
+                handshake.truncate(4 + hello_len);
+                return ClientHello::Complete(handshake)
+            }
+        }
+    }
+    ClientHello::Partial
+}
+
+/// `server_name` extension (RFC 6066 section 3), `host_name` entry
+fn server_name(hello: &[u8]) -> Option<String> {
+    let mut body = Cursor(hello.get(4..)?);
+    body.skip(2 + 32)?;                 // version, random
+    let session_id = body.u8()? as usize;
+    body.skip(session_id)?;
+    let cipher_suites = body.u16()? as usize;
+    body.skip(cipher_suites)?;
+    let compression_methods = body.u8()? as usize;
+    body.skip(compression_methods)?;
+    let extensions_len = body.u16()? as usize;
+    let mut extensions = Cursor(body.take(extensions_len)?);
+    while let Some(extension_type) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let mut extension = Cursor(extensions.take(len)?);
+        if extension_type != 0 {
+            continue
+        }
+        let list_len = extension.u16()? as usize;
+        let mut names = Cursor(extension.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase)
+            }
+        }
+    }
+    None
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::tcp::{SniRoute, TcpListenerConfig, TcpUpstream};
+    use crate::conf::tls::TlsConfig;
+    use crate::proxy_protocol::{encode, read_header, ProxyHeader, ProxyProtocolVersion};
+    use crate::tcp::start_tcp_proxy;
+    use crate::tests::{self_signed, tls_connect, tls_test_server, wait_for_gateway, wait_for_port, unwrap_body_as_str};
+    use hyper::{Body, Request};
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::version::{TLS12, TLS13};
+
+    /// Greets with its name, then echoes
+    async fn echo_server(port: u16, name: &'static str) {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                stream.write_all(name.as_bytes()).await.unwrap();
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                        break
+                    }
+                }
+            });
+        }
+    }
+
+    async fn read_exactly(stream: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0u8; len];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut buf)).await.unwrap().unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn sni_wildcards() {
+        let route = |name: &str| SniRoute { server_name: name.to_string(), upstream: TcpUpstream::new(vec![]) };
+        assert!(route("db.example.com").matches("DB.example.com"));
+        assert!(!route("db.example.com").matches("example.com"));
+        assert!(route("*.example.com").matches("mqtt.example.com"));
+        assert!(!route("*.example.com").matches("example.com"));
+        assert!(!route("*.example.com").matches("a.mqtt.example.com"));
+        assert!(!route("*.example.com").matches("a.b.example.com"));
+        assert!(!route("*.example.com").matches(".example.com"));
+    }
+
+    #[tokio::test]
+    async fn tcp_is_forwarded_round_robin() {
+        let proxy_port = 12800;
+        let dead_port = 12801; // nothing listens here
+        tokio::spawn(async move { echo_server(12802, "A").await });
+        tokio::spawn(async move { echo_server(12803, "B").await });
+        let upstream = TcpUpstream::new(vec![format!("127.0.0.1:{}", dead_port), "127.0.0.1:12802".to_string(), "127.0.0.1:12803".to_string()]);
+        let mut conf = TcpListenerConfig::forward(([127, 0, 0, 1], proxy_port).into(), upstream);
+        conf.with_idle_timeout(Duration::from_millis(300));
+        tokio::spawn(async move { start_tcp_proxy(conf).await });
+        wait_for_port(12802).await;
+        wait_for_port(12803).await;
+        wait_for_gateway(proxy_port).await;
+
+        let mut names = HashSet::new();
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+            names.insert(read_exactly(&mut stream, 1).await);
+            stream.write_all(b"ping").await.unwrap();
+            assert_eq!("ping", read_exactly(&mut stream, 4).await);
+        }
+        assert_eq!(2, names.len()); // the dead endpoint is skipped
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        read_exactly(&mut stream, 1).await;
+        let mut buf = [0u8; 8];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert_eq!(0, read.unwrap()); // closed once idle
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol_is_relayed() {
+        let proxy_port = 12813;
+        let upstream_port = 12814;
+        tokio::spawn(async move {
+            let listener = TcpListener::bind(("127.0.0.1", upstream_port)).await.unwrap();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                if let Ok(Some(header)) = read_header(&mut stream).await {
+                    stream.write_all(header.source.to_string().as_bytes()).await.unwrap();
+                }
+            }
+        });
+        let mut upstream = TcpUpstream::new(vec![format!("127.0.0.1:{}", upstream_port)]);
+        upstream.send_proxy_protocol(ProxyProtocolVersion::V1);
+        let mut conf = TcpListenerConfig::forward(([127, 0, 0, 1], proxy_port).into(), upstream);
+        conf.accept_proxy_protocol();
+        tokio::spawn(async move { start_tcp_proxy(conf).await });
+        wait_for_port(upstream_port).await;
+        wait_for_gateway(proxy_port).await;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        let client = ProxyHeader { source: "203.0.113.9:5555".parse().unwrap(), destination: "127.0.0.1:80".parse().unwrap() };
+        stream.write_all(&encode(ProxyProtocolVersion::V2, Some(client))).await.unwrap();
+        assert_eq!("203.0.113.9:5555", read_exactly(&mut stream, 16).await);
+    }
+
+    async fn https_get(port: u16, server_name: &str, cert: &crate::tests::TestCert) -> std::io::Result<String> {
+        let stream = tls_connect(port, server_name, &[cert], vec![], &[&TLS12, &TLS13]).await?;
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let resp = sender.send_request(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        Ok(unwrap_body_as_str(resp).await)
+    }
+
+    #[tokio::test]
+    async fn tls_is_routed_by_sni() {
+        let proxy_port = 12810;
+        let db = self_signed(&["db.test"]);
+        let mqtt = self_signed(&["broker.mqtt.test"]);
+        let (db_tls, mqtt_tls) = (TlsConfig::new(&db.cert_path, &db.key_path), TlsConfig::new(&mqtt.cert_path, &mqtt.key_path));
+        tokio::spawn(async move { tls_test_server("db", 12811, db_tls).await });
+        tokio::spawn(async move { tls_test_server("mqtt", 12812, mqtt_tls).await });
+        let mut conf = TcpListenerConfig::passthrough(([127, 0, 0, 1], proxy_port).into());
+        conf.route("db.test", TcpUpstream::new(vec!["127.0.0.1:12811".to_string()]));
+        conf.route("*.mqtt.test", TcpUpstream::new(vec!["127.0.0.1:12812".to_string()]));
+        tokio::spawn(async move { start_tcp_proxy(conf).await });
+        wait_for_port(12811).await;
+        wait_for_port(12812).await;
+        wait_for_gateway(proxy_port).await;
+
+        // TLS is terminated by the upstreams: clients check their certificates
+        assert_eq!("db", https_get(proxy_port, "db.test", &db).await.unwrap());
+        assert_eq!("mqtt", https_get(proxy_port, "broker.mqtt.test", &mqtt).await.unwrap());
+        assert!(https_get(proxy_port, "unknown.test", &db).await.is_err()); // no default upstream
+
+        let mut plain = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(0, tokio::time::timeout(Duration::from_secs(5), plain.read(&mut buf)).await.unwrap().unwrap_or(0));
+    }
+
+}
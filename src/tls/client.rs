@@ -0,0 +1,224 @@
+use crate::conf::tls::UpstreamTlsConfig;
+use crate::tls::{TlsError, backend};
+use hyper::Uri;
+use hyper::client::HttpConnector;
+use hyper::client::connect::{Connection, Connected};
+use hyper::service::Service;
+use futures::task::{Context, Poll};
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+type PinnedConnectFuture = Pin<Box<dyn Future<Output = Result<MaybeTlsStream, BoxError>> + Send>>;
+
+/// Connects to upstream endpoints, over TLS for `https` URIs
+/// The name sent as SNI (and verified against the upstream certificate) can differ from the URI host
+/// `alpn_protocols` are offered to TLS upstreams, HTTP/2 is used if they pick `h2`
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    tls: backend::ClientBackend,
+    server_name: Option<String>,
+}
+
+impl UpstreamConnector {
+    pub fn new(conf: &UpstreamTlsConfig, alpn_protocols: &[String]) -> Result<Self, TlsError> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        Ok(UpstreamConnector {
+            http,
+            tls: backend::ClientBackend::new(conf, alpn_protocols)?,
+            server_name: conf.server_name.clone(),
+        })
+    }
+}
+
+impl UpstreamConnector {
+
+    pub(crate) fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.http.set_connect_timeout(Some(timeout));
+    }
+
+    /// TLS handshake over an already connected stream (i.e. once a PROXY protocol header has been written)
+    pub(crate) async fn handshake(&self, host: &str, tcp: TcpStream) -> Result<MaybeTlsStream, BoxError> {
+        let server_name = self.server_name.clone().unwrap_or_else(|| host.trim_start_matches('[').trim_end_matches(']').to_string());
+        Ok(MaybeTlsStream::Tls(Box::new(self.tls.connect(&server_name, tcp).await?)))
+    }
+}
+
+impl Debug for UpstreamConnector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamConnector")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = MaybeTlsStream;
+    type Error = BoxError;
+    type Future = PinnedConnectFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let is_https = dst.scheme_str() == Some("https");
+        let server_name = self.server_name.clone()
+            .or_else(|| dst.host().map(|host| host.trim_start_matches('[').trim_end_matches(']').to_string()));
+        let connecting = self.http.call(dst);
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if !is_https {
+                return Ok(MaybeTlsStream::Plain(tcp))
+            }
+            let server_name = server_name.ok_or("No server name to connect to")?;
+            Ok(MaybeTlsStream::Tls(Box::new(tls.connect(&server_name, tcp).await?)))
+        })
+    }
+}
+
+/// Connection to an upstream, its TLS stream type depends on the TLS backend feature
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<backend::ClientStream>),
+}
+
+impl Connection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            MaybeTlsStream::Plain(tcp) => tcp.connected(),
+            MaybeTlsStream::Tls(tls) => {
+                let connected = backend::client_tcp_stream(tls).connected();
+                if backend::client_alpn_protocol(tls).as_deref() == Some(b"h2") {
+                    connected.negotiated_h2()
+                } else {
+                    connected
+                }
+            },
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_read(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_write(cx, buf),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_flush(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            MaybeTlsStream::Plain(tcp) => Pin::new(tcp).poll_shutdown(cx),
+            MaybeTlsStream::Tls(tls) => Pin::new(tls.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::protocol::{ProtocolConfig, HttpProtocol};
+    use crate::conf::tls::{TlsConfig, UpstreamTlsConfig};
+    use crate::gateway::start_local_gateway;
+    use crate::tests::{self_signed, tls_test_server, wait_for_gateway, wait_for_port, unwrap_body_as_str};
+    use hyper::{Client, StatusCode, Uri};
+    use std::str::FromStr;
+
+    async fn status_and_body(gw_port: u16, prefix: &str) -> (StatusCode, String) {
+        let url = Uri::from_str(format!("http://127.0.0.1:{}{}", gw_port, prefix).as_str()).unwrap();
+        let resp = Client::new().get(url).await.unwrap();
+        (resp.status(), unwrap_body_as_str(resp).await)
+    }
+
+    #[tokio::test]
+    async fn custom_ca_and_server_name() {
+        let gw_port = 12200;
+        let backend_port = 12201;
+        let backend_cert = self_signed(&["backend.internal"]);
+        let backend_tls = TlsConfig::new(&backend_cert.cert_path, &backend_cert.key_path);
+        let address = format!("127.0.0.1:{}", backend_port);
+        let mut trusted = UpstreamTlsConfig::default();
+        trusted.trust_ca(&backend_cert.cert_path);
+        let mut trusted_with_sni = trusted.clone();
+        trusted_with_sni.with_server_name("backend.internal");
+        let mut insecure = UpstreamTlsConfig::default();
+        insecure.insecure_skip_verify();
+        tokio::spawn(async move { tls_test_server("internal PKI", backend_port, backend_tls).await });
+        tokio::spawn(async move {
+            let http1 = ProtocolConfig::new(HttpProtocol::Http1);
+            let apis = vec![
+                Api::https_with(&address, "/system_roots".to_string(), &UpstreamTlsConfig::default(), &http1).unwrap(),
+                Api::https_with(&address, "/wrong_name".to_string(), &trusted, &http1).unwrap(),
+                Api::https_with(&address, "/trusted".to_string(), &trusted_with_sni, &http1).unwrap(),
+                Api::https_with(&address, "/insecure".to_string(), &insecure, &http1).unwrap(),
+            ];
+            start_local_gateway(gw_port, apis).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        assert_eq!(StatusCode::BAD_GATEWAY, status_and_body(gw_port, "/system_roots").await.0);
+        assert_eq!(StatusCode::BAD_GATEWAY, status_and_body(gw_port, "/wrong_name").await.0);
+        assert_eq!((StatusCode::OK, "internal PKI".to_string()), status_and_body(gw_port, "/trusted").await);
+        assert_eq!((StatusCode::OK, "internal PKI".to_string()), status_and_body(gw_port, "/insecure").await);
+    }
+
+    #[cfg(feature = "rustls")] // the test server must authenticate clients
+    #[tokio::test]
+    async fn client_certificate_for_upstream_mtls() {
+        use crate::conf::tls::ClientAuth;
+        use crate::tests::{test_ca, signed_by};
+        let gw_port = 12210;
+        let backend_port = 12211;
+        let backend_cert = self_signed(&["backend.internal"]);
+        let ca = test_ca();
+        let gateway_cert = signed_by(&ca, "gateway", &[]);
+        let mut backend_tls = TlsConfig::new(&backend_cert.cert_path, &backend_cert.key_path);
+        backend_tls.with_client_auth(ClientAuth::Required(ca.cert_path.clone()));
+        let address = format!("127.0.0.1:{}", backend_port);
+        let mut anonymous = UpstreamTlsConfig::default();
+        anonymous.trust_ca(&backend_cert.cert_path);
+        anonymous.with_server_name("backend.internal");
+        let mut authenticated = anonymous.clone();
+        authenticated.with_client_cert(&gateway_cert.cert_path, &gateway_cert.key_path);
+        tokio::spawn(async move { tls_test_server("mutually authenticated", backend_port, backend_tls).await });
+        tokio::spawn(async move {
+            let http1 = ProtocolConfig::new(HttpProtocol::Http1);
+            let apis = vec![
+                Api::https_with(&address, "/anonymous".to_string(), &anonymous, &http1).unwrap(),
+                Api::https_with(&address, "/authenticated".to_string(), &authenticated, &http1).unwrap(),
+            ];
+            start_local_gateway(gw_port, apis).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        assert_eq!(StatusCode::BAD_GATEWAY, status_and_body(gw_port, "/anonymous").await.0);
+        assert_eq!((StatusCode::OK, "mutually authenticated".to_string()), status_and_body(gw_port, "/authenticated").await);
+    }
+
+}
@@ -0,0 +1,128 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::RootCertStore;
+#[cfg(feature = "rustls")]
+pub use tokio_rustls::rustls::{Certificate, PrivateKey};
+
+pub mod server;
+pub mod client;
+#[cfg(feature = "rustls")]
+pub mod rustls;
+#[cfg(feature = "native-tls")]
+pub mod native;
+
+#[cfg(feature = "rustls")]
+use self::rustls as backend;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+use self::native as backend;
+#[cfg(not(any(feature = "rustls", feature = "native-tls")))]
+compile_error!("Either the `rustls` or the `native-tls` feature must be enabled");
+
+/// DER encoded certificate
+#[cfg(not(feature = "rustls"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate(pub Vec<u8>);
+
+/// DER encoded private key
+#[cfg(not(feature = "rustls"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivateKey(pub Vec<u8>);
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(std::io::Error),
+    NoCertificate(String),          // no certificate found in the given file
+    NoPrivateKey(String),           // no private key found in the given file
+    InvalidPrivateKey(String),      // the private key isn't supported (or is malformed)
+    KeyMismatch(String, String),    // the private key doesn't match the certificate
+    UnknownCipherSuite(String),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::rustls::Error),
+    #[cfg(feature = "native-tls")]
+    NativeTls(native_tls::Error),
+    #[cfg(feature = "http3")]
+    Quic(String),                   // QUIC listeners rely on their own rustls version
+    Unsupported(String),            // setting not supported by the TLS backend picked at compile time
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "I/O error: {}", e),
+            TlsError::NoCertificate(path) => write!(f, "No certificate found in {}", path),
+            TlsError::NoPrivateKey(path) => write!(f, "No private key found in {}", path),
+            TlsError::InvalidPrivateKey(path) => write!(f, "Unsupported private key in {}", path),
+            TlsError::KeyMismatch(cert, key) => write!(f, "Private key {} doesn't match certificate {}", key, cert),
+            TlsError::UnknownCipherSuite(name) => write!(f, "Unknown cipher suite {}", name),
+            #[cfg(feature = "rustls")]
+            TlsError::Rustls(e) => write!(f, "TLS error: {}", e),
+            #[cfg(feature = "native-tls")]
+            TlsError::NativeTls(e) => write!(f, "TLS error: {}", e),
+            #[cfg(feature = "http3")]
+            TlsError::Quic(e) => write!(f, "QUIC TLS error: {}", e),
+            TlsError::Unsupported(setting) => write!(f, "{} isn't supported by the TLS backend", setting),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<std::io::Error> for TlsError {
+    fn from(e: std::io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<tokio_rustls::rustls::Error> for TlsError {
+    fn from(e: tokio_rustls::rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl From<native_tls::Error> for TlsError {
+    fn from(e: native_tls::Error) -> Self {
+        TlsError::NativeTls(e)
+    }
+}
+
+/// Reads every certificate of a PEM encoded chain
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.display().to_string()))
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key (PKCS8, RSA or SEC1) of a PEM file
+pub fn load_private_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) |
+            rustls_pemfile::Item::RSAKey(key) |
+            rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+/// Reads a PEM encoded CA bundle
+#[cfg(feature = "rustls")]
+pub fn load_roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert)?;
+    }
+    Ok(roots)
+}
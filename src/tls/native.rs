@@ -0,0 +1,146 @@
+use crate::conf::tls::{CertificateConf, TlsConfig, TlsVersion, UpstreamTlsConfig};
+use crate::tls::{TlsError, load_certs, load_private_key};
+use crate::tls::server::log_validity;
+use native_tls::{Identity, Protocol};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P384_SHA384_ASN1_SIGNING};
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsAcceptor, TlsConnector};
+
+pub type ServerStream = tokio_native_tls::TlsStream<TcpStream>;
+pub type ClientStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// Terminates TLS on listeners with the platform TLS library (OpenSSL on Linux)
+/// Only a single certificate is supported (no SNI based selection), private keys must be PKCS8 encoded
+/// Cipher suites selection and client certificates authentication aren't supported either: native-tls has no API for them
+/// TLS 1.3 only listeners are rejected, the minimum version can't be raised over TLS 1.2. Listeners without TLS 1.3 are capped at TLS 1.2
+#[derive(Clone)]
+pub struct ServerBackend {
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ServerBackend {
+    pub fn new(conf: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(ServerBackend { acceptor: Arc::new(RwLock::new(acceptor(conf)?)) })
+    }
+
+    pub fn reload(&self, conf: &TlsConfig) -> Result<(), TlsError> {
+        let loaded = acceptor(conf)?;
+        match self.acceptor.write() {
+            Ok(mut current) => *current = loaded,
+            Err(poisoned) => *poisoned.into_inner() = loaded,
+        }
+        Ok(())
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<ServerStream> {
+        let acceptor = match self.acceptor.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        acceptor.accept(stream).await.map_err(std::io::Error::other)
+    }
+}
+
+pub fn alpn_protocol(stream: &ServerStream) -> Option<Vec<u8>> {
+    stream.get_ref().negotiated_alpn().ok().flatten()
+}
+
+/// Client certificates aren't requested by this backend, but one may be sent anyway
+pub fn peer_certificate(stream: &ServerStream) -> Option<Vec<u8>> {
+    stream.get_ref().peer_certificate().ok().flatten().and_then(|cert| cert.to_der().ok())
+}
+
+fn acceptor(conf: &TlsConfig) -> Result<TlsAcceptor, TlsError> {
+    if conf.certificates.len() > 1 {
+        return Err(TlsError::Unsupported("Selecting certificates by SNI".to_string()))
+    }
+    if !conf.cipher_suites.is_empty() {
+        return Err(TlsError::Unsupported("Selecting cipher suites".to_string()))
+    }
+    if conf.client_auth.is_some() {
+        return Err(TlsError::Unsupported("Client certificates authentication".to_string()))
+    }
+    let cert = conf.certificates.first()
+        .ok_or_else(|| TlsError::NoCertificate("listener configuration".to_string()))?;
+    if !conf.versions.contains(&TlsVersion::Tls12) {
+        return Err(TlsError::Unsupported("TLS 1.3 only listeners".to_string()))
+    }
+    check_key_pair(cert)?;
+    let identity = Identity::from_pkcs8(&std::fs::read(&cert.cert_path)?, &std::fs::read(&cert.key_path)?)?;
+    let mut builder = native_tls::TlsAcceptor::builder(identity);
+    builder.min_protocol_version(Some(Protocol::Tlsv12));
+    if !conf.versions.contains(&TlsVersion::Tls13) {
+        builder.max_protocol_version(Some(Protocol::Tlsv12));
+    }
+    builder.accept_alpn(&conf.alpn_protocols);
+    Ok(builder.build()?.into())
+}
+
+/// Makes sure the private key belongs to the certificate, comparing their public keys, then logs validity
+fn check_key_pair(conf: &CertificateConf) -> Result<(), TlsError> {
+    let certs = load_certs(&conf.cert_path)?;
+    let key = load_private_key(&conf.key_path)?;
+    let public_key = public_key(&key.0).ok_or_else(|| TlsError::InvalidPrivateKey(conf.key_path.display().to_string()))?;
+    let matches = x509_parser::parse_x509_certificate(&certs[0].0)
+        .map(|(_, cert)| cert.public_key().subject_public_key.data.as_ref() == public_key.as_slice())
+        .unwrap_or(false);
+    if !matches {
+        return Err(TlsError::KeyMismatch(conf.cert_path.display().to_string(), conf.key_path.display().to_string()))
+    }
+    log_validity(conf, &certs[0]);
+    Ok(())
+}
+
+/// Public key of a PKCS8 encoded private key (RSA, ECDSA P-256 or P-384, Ed25519), as found in certificates
+fn public_key(pkcs8: &[u8]) -> Option<Vec<u8>> {
+    if let Ok(key) = RsaKeyPair::from_pkcs8(pkcs8) {
+        return Some(key.public_key().as_ref().to_vec())
+    }
+    let rng = SystemRandom::new();
+    for algorithm in [&ECDSA_P256_SHA256_ASN1_SIGNING, &ECDSA_P384_SHA384_ASN1_SIGNING] {
+        if let Ok(key) = EcdsaKeyPair::from_pkcs8(algorithm, pkcs8, &rng) {
+            return Some(key.public_key().as_ref().to_vec())
+        }
+    }
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8).ok().map(|key| key.public_key().as_ref().to_vec())
+}
+
+/// Opens TLS connections to upstreams with the platform TLS library
+#[derive(Clone)]
+pub struct ClientBackend {
+    connector: TlsConnector,
+}
+
+impl ClientBackend {
+    pub fn new(conf: &UpstreamTlsConfig, alpn_protocols: &[String]) -> Result<Self, TlsError> {
+        let mut builder = native_tls::TlsConnector::builder();
+        builder.request_alpns(&alpn_protocols.iter().map(String::as_str).collect::<Vec<_>>());
+        if let Some(ca_path) = &conf.ca_path {
+            for cert in load_certs(ca_path)? {
+                builder.add_root_certificate(native_tls::Certificate::from_der(&cert.0)?);
+            }
+        }
+        if let Some((cert_path, key_path)) = &conf.client_cert {
+            builder.identity(Identity::from_pkcs8(&std::fs::read(cert_path)?, &std::fs::read(key_path)?)?);
+        }
+        if conf.insecure_skip_verify {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        Ok(ClientBackend { connector: builder.build()?.into() })
+    }
+
+    pub async fn connect(&self, server_name: &str, stream: TcpStream) -> std::io::Result<ClientStream> {
+        self.connector.connect(server_name, stream).await.map_err(std::io::Error::other)
+    }
+}
+
+pub fn client_tcp_stream(stream: &ClientStream) -> &TcpStream {
+    stream.get_ref().get_ref().get_ref()
+}
+
+pub fn client_alpn_protocol(stream: &ClientStream) -> Option<Vec<u8>> {
+    stream.get_ref().negotiated_alpn().ok().flatten()
+}
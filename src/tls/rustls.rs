@@ -0,0 +1,213 @@
+use crate::conf::tls::{TlsConfig, TlsVersion, CertificateConf, ClientAuth, UpstreamTlsConfig};
+use crate::tls::{TlsError, load_certs, load_private_key, load_roots};
+use crate::tls::server::load_certified_key;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use log::warn;
+use tokio::net::TcpStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore, ServerConfig, ServerName, SupportedCipherSuite, SupportedProtocolVersion, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES};
+use tokio_rustls::rustls::client::{ServerCertVerifier, ServerCertVerified};
+use tokio_rustls::rustls::server::{ResolvesServerCert, ClientHello, AllowAnyAuthenticatedClient, AllowAnyAnonymousOrAuthenticatedClient, NoClientAuth};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::version::{TLS12, TLS13};
+
+pub type ServerStream = tokio_rustls::server::TlsStream<TcpStream>;
+pub type ClientStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// Picks the certificate presented to the client according to the SNI it sent
+/// Falls back to the default certificate if there's no SNI or if it doesn't match any certificate
+/// Certificates can be swapped while the listener is running, only new handshakes are affected
+pub struct SniResolver {
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+struct Certificates {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Arc<CertifiedKey>,
+}
+
+impl Certificates {
+    fn load(confs: &[CertificateConf]) -> Result<Self, TlsError> {
+        let mut by_name = HashMap::new();
+        let mut default = None;
+        for conf in confs {
+            let certified = Arc::new(load_certified_key(conf)?);
+            for name in &conf.server_names {
+                by_name.insert(name.to_lowercase(), certified.clone());
+            }
+            default.get_or_insert(certified);
+        }
+        match default {
+            None => Err(TlsError::NoCertificate("listener configuration".to_string())),
+            Some(default) => Ok(Certificates { by_name, default })
+        }
+    }
+}
+
+impl SniResolver {
+    pub fn load(certificates: &[CertificateConf]) -> Result<Self, TlsError> {
+        Ok(SniResolver { certificates: RwLock::new(Arc::new(Certificates::load(certificates)?)) })
+    }
+
+    /// Loads every certificate again then atomically switches to the new ones
+    /// If any of them can't be loaded, the previous certificates are kept
+    pub fn reload(&self, certificates: &[CertificateConf]) -> Result<(), TlsError> {
+        let loaded = Arc::new(Certificates::load(certificates)?);
+        match self.certificates.write() {
+            Ok(mut current) => *current = loaded,
+            Err(poisoned) => *poisoned.into_inner() = loaded,
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Arc<Certificates> {
+        match self.certificates.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.current();
+        let cert = client_hello.server_name()
+            .and_then(|name| certificates.by_name.get(&name.to_lowercase()))
+            .unwrap_or(&certificates.default);
+        Some(cert.clone())
+    }
+}
+
+/// Terminates TLS on listeners with rustls
+#[derive(Clone)]
+pub struct ServerBackend {
+    acceptor: TlsAcceptor,
+    resolver: Arc<SniResolver>,
+}
+
+impl ServerBackend {
+    pub fn new(conf: &TlsConfig) -> Result<Self, TlsError> {
+        let resolver = Arc::new(SniResolver::load(&conf.certificates)?);
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(conf, resolver.clone())?));
+        Ok(ServerBackend { acceptor, resolver })
+    }
+
+    pub fn reload(&self, conf: &TlsConfig) -> Result<(), TlsError> {
+        self.resolver.reload(&conf.certificates)
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<ServerStream> {
+        self.acceptor.accept(stream).await
+    }
+}
+
+pub fn alpn_protocol(stream: &ServerStream) -> Option<Vec<u8>> {
+    stream.get_ref().1.alpn_protocol().map(|p| p.to_vec())
+}
+
+pub fn peer_certificate(stream: &ServerStream) -> Option<Vec<u8>> {
+    stream.get_ref().1.peer_certificates()
+        .and_then(|chain| chain.first())
+        .map(|cert| cert.0.clone())
+}
+
+/// Builds the rustls configuration matching the listener's TLS settings
+pub fn server_config(conf: &TlsConfig, resolver: Arc<SniResolver>) -> Result<ServerConfig, TlsError> {
+    let versions: Vec<&'static SupportedProtocolVersion> = conf.versions.iter()
+        .map(|v| match v {
+            TlsVersion::Tls12 => &TLS12,
+            TlsVersion::Tls13 => &TLS13,
+        })
+        .collect();
+    let client_verifier = match &conf.client_auth {
+        None => NoClientAuth::boxed(),
+        Some(ClientAuth::Optional(ca_path)) => AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca_path)?).boxed(),
+        Some(ClientAuth::Required(ca_path)) => AllowAnyAuthenticatedClient::new(load_roots(ca_path)?).boxed(),
+    };
+    let mut config = ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites(&conf.cipher_suites)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&versions)?
+        .with_client_cert_verifier(client_verifier)
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = conf.alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+    Ok(config)
+}
+
+fn cipher_suites(names: &[String]) -> Result<Vec<SupportedCipherSuite>, TlsError> {
+    if names.is_empty() {
+        return Ok(DEFAULT_CIPHER_SUITES.to_vec())
+    }
+    names.iter()
+        .map(|name| {
+            ALL_CIPHER_SUITES.iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                .cloned()
+                .ok_or_else(|| TlsError::UnknownCipherSuite(name.clone()))
+        })
+        .collect()
+}
+
+/// Opens TLS connections to upstreams with rustls
+#[derive(Clone)]
+pub struct ClientBackend {
+    connector: TlsConnector,
+}
+
+impl ClientBackend {
+    pub fn new(conf: &UpstreamTlsConfig, alpn_protocols: &[String]) -> Result<Self, TlsError> {
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(upstream_roots(conf)?);
+        let mut config = match &conf.client_cert {
+            None => builder.with_no_client_auth(),
+            Some((cert_path, key_path)) => builder.with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)?,
+        };
+        if conf.insecure_skip_verify {
+            config.dangerous().set_certificate_verifier(Arc::new(NoVerification));
+        }
+        config.alpn_protocols = alpn_protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok(ClientBackend { connector: TlsConnector::from(Arc::new(config)) })
+    }
+
+    pub async fn connect(&self, server_name: &str, stream: TcpStream) -> std::io::Result<ClientStream> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.connector.connect(server_name, stream).await
+    }
+}
+
+pub fn client_tcp_stream(stream: &ClientStream) -> &TcpStream {
+    stream.get_ref().0
+}
+
+pub fn client_alpn_protocol(stream: &ClientStream) -> Option<Vec<u8>> {
+    stream.get_ref().1.alpn_protocol().map(|p| p.to_vec())
+}
+
+/// System trusted roots, plus the configured CA bundle
+fn upstream_roots(conf: &UpstreamTlsConfig) -> Result<RootCertStore, TlsError> {
+    let mut roots = match &conf.ca_path {
+        Some(ca_path) => load_roots(ca_path)?,
+        None => RootCertStore::empty(),
+    };
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => for cert in certs {
+            let _ = roots.add(&Certificate(cert.0));
+        },
+        Err(e) => warn!("Could not load system trusted roots: {}", e),
+    }
+    Ok(roots)
+}
+
+/// `insecure_skip_verify`: accepts any certificate
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(&self, _end_entity: &Certificate, _intermediates: &[Certificate], _server_name: &ServerName, _scts: &mut dyn Iterator<Item = &[u8]>, _ocsp_response: &[u8], _now: SystemTime) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -0,0 +1,325 @@
+use crate::conf::tls::{TlsConfig, CertificateConf};
+use crate::tls::{TlsError, Certificate, backend};
+#[cfg(feature = "rustls")]
+use crate::tls::{load_certs, load_private_key};
+#[cfg(feature = "rustls")]
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use log::{info, warn, error};
+use tokio::net::TcpStream;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::SignatureScheme;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::sign::{CertifiedKey, SigningKey, any_supported_type};
+
+/// A TLS connection accepted by a listener, its type depends on the TLS backend feature
+pub type TlsStream = backend::ServerStream;
+
+/// Loads a certificate chain and its private key, making sure they belong together
+#[cfg(feature = "rustls")]
+pub fn load_certified_key(conf: &CertificateConf) -> Result<CertifiedKey, TlsError> {
+    let certs = load_certs(&conf.cert_path)?;
+    let key = load_private_key(&conf.key_path)?;
+    let signing_key = any_supported_type(&key)
+        .map_err(|_| TlsError::InvalidPrivateKey(conf.key_path.display().to_string()))?;
+    check_key_pair(&certs[0], signing_key.as_ref())
+        .map_err(|_| TlsError::KeyMismatch(conf.cert_path.display().to_string(), conf.key_path.display().to_string()))?;
+    log_validity(conf, &certs[0]);
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Signs a probe with the private key, then verifies the signature against the certificate public key
+#[cfg(feature = "rustls")]
+fn check_key_pair(cert: &Certificate, key: &dyn SigningKey) -> Result<(), ()> {
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice()).map_err(|_| ())?;
+    let signer = key.choose_scheme(&[
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ED25519,
+        SignatureScheme::RSA_PSS_SHA256,
+    ]).ok_or(())?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        _ => return Err(()),
+    };
+    let probe = b"itinerarium key pair check";
+    let signature = signer.sign(probe).map_err(|_| ())?;
+    end_entity.verify_signature(algorithm, probe, &signature).map_err(|_| ())
+}
+
+/// Logs when a loaded certificate expires, warns if it isn't valid (yet)
+pub fn log_validity(conf: &CertificateConf, cert: &Certificate) {
+    match x509_parser::parse_x509_certificate(&cert.0) {
+        Err(e) => warn!("Could not read validity of {}: {}", conf.cert_path.display(), e),
+        Ok((_, parsed)) => {
+            let validity = parsed.validity();
+            if validity.is_valid() {
+                info!("Loaded certificate {} for {:?}, expires on {}", conf.cert_path.display(), conf.server_names, validity.not_after);
+            } else {
+                warn!("Loaded certificate {} for {:?} is not valid, valid from {} to {}", conf.cert_path.display(), conf.server_names, validity.not_before, validity.not_after);
+            }
+        }
+    }
+}
+
+/// TLS side of a running listener
+/// Cloning it is cheap, clones share the same certificates: reloading one reloads them all
+#[derive(Clone)]
+pub struct TlsListener {
+    conf: Arc<TlsConfig>,
+    backend: backend::ServerBackend,
+}
+
+impl TlsListener {
+    pub fn new(conf: TlsConfig) -> Result<Self, TlsError> {
+        let backend = backend::ServerBackend::new(&conf)?;
+        Ok(TlsListener { conf: Arc::new(conf), backend })
+    }
+
+    /// Runs the TLS handshake over an accepted TCP connection
+    pub async fn accept(&self, stream: TcpStream) -> std::io::Result<TlsStream> {
+        self.backend.accept(stream).await
+    }
+
+    pub fn conf(&self) -> &TlsConfig {
+        &self.conf
+    }
+
+    /// Reads certificates and keys from disk again, new handshakes will use them
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let res = self.backend.reload(&self.conf);
+        match &res {
+            Ok(_) => info!("TLS certificates reloaded"),
+            Err(e) => error!("Could not reload TLS certificates, keeping the previous ones: {}", e),
+        }
+        res
+    }
+
+    /// Polls certificate and key files, and reloads them as soon as one of them has been modified
+    pub async fn watch(self, interval: Duration) {
+        let mut last_modified = self.modification_times();
+        loop {
+            tokio::time::sleep(interval).await;
+            let modified = self.modification_times();
+            if modified != last_modified {
+                let _ = self.reload();
+                last_modified = modified;
+            }
+        }
+    }
+
+    fn modification_times(&self) -> Vec<Option<SystemTime>> {
+        self.conf.certificates.iter()
+            .flat_map(|c| vec![&c.cert_path, &c.key_path])
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+/// Did the client negotiate HTTP/2 through ALPN?
+pub fn negotiated_h2(stream: &TlsStream) -> bool {
+    backend::alpn_protocol(stream).as_deref() == Some(b"h2")
+}
+
+/// DER encoded certificate of an authenticated client (mTLS)
+pub fn peer_certificate(stream: &TlsStream) -> Option<Vec<u8>> {
+    backend::peer_certificate(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::conf::tls::{TlsConfig, TlsVersion};
+    use crate::gateway::{start_tls_gateway, start_tls_listener};
+    use crate::tests::{self_signed, test_server, wait_for_port, TestCert, tls_connect};
+    use crate::tls::TlsError;
+    use crate::tls::server::TlsListener;
+    use hyper::{Body, Request, StatusCode};
+    use hyper::client::conn;
+    use tokio_rustls::rustls::version::{TLS12, TLS13};
+    use std::time::Duration;
+
+    async fn get(port: u16, server_name: &str, roots: &[&TestCert], alpn: Vec<Vec<u8>>, path: &str) -> (Vec<u8>, StatusCode) {
+        let tls = tls_connect(port, server_name, roots, alpn, &[&TLS12, &TLS13]).await.unwrap();
+        let (_, session) = tls.get_ref();
+        let presented = session.peer_certificates().unwrap()[0].0.clone();
+        let h2 = session.alpn_protocol() == Some(b"h2");
+        let (mut sender, connection) = conn::Builder::new().http2_only(h2).handshake(tls).await.unwrap();
+        tokio::spawn(connection);
+        let req = Request::builder()
+            .uri(format!("https://{}:{}{}", server_name, port, path))
+            .body(Body::empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        (presented, resp.status())
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn certificate_is_selected_by_sni() {
+        let gw_port = 12000;
+        let backend_port = 12001;
+        let prefix = "/secured";
+        let default_cert = self_signed(&["localhost"]);
+        let other_cert = self_signed(&["other.localhost"]);
+        let mut tls = TlsConfig::new(&default_cert.cert_path, &default_cert.key_path);
+        tls.add_certificate(&other_cert.cert_path, &other_cert.key_path, vec!["other.localhost".to_string()]);
+        tokio::spawn(async move { test_server("over TLS", backend_port).await });
+        tokio::spawn(async move {
+            let api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+            start_tls_gateway(gw_port, vec![api], tls).await
+        });
+        wait_for_port(gw_port).await;
+
+        let roots = [&default_cert, &other_cert];
+        let (presented, status) = get(gw_port, "localhost", &roots, vec![], prefix).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(default_cert.cert_der, presented);
+
+        let (presented, status) = get(gw_port, "other.localhost", &roots, vec![], prefix).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(other_cert.cert_der, presented);
+    }
+
+    #[tokio::test]
+    async fn alpn_negotiates_http2() {
+        let gw_port = 12010;
+        let backend_port = 12011;
+        let prefix = "/h2";
+        let cert = self_signed(&["localhost"]);
+        let tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        tokio::spawn(async move { test_server("over h2", backend_port).await });
+        tokio::spawn(async move {
+            let api = Api::http("127.0.0.1", backend_port, prefix.to_string()).unwrap();
+            start_tls_gateway(gw_port, vec![api], tls).await
+        });
+        wait_for_port(gw_port).await;
+        let stream = tls_connect(gw_port, "localhost", &[&cert], vec![b"h2".to_vec()], &[&TLS12, &TLS13]).await.unwrap();
+        assert_eq!(Some(&b"h2"[..]), stream.get_ref().1.alpn_protocol());
+        drop(stream);
+        let (_, status) = get(gw_port, "localhost", &[&cert], vec![b"h2".to_vec()], prefix).await;
+        assert_eq!(StatusCode::OK, status);
+    }
+
+    #[cfg(feature = "rustls")]
+    #[tokio::test]
+    async fn protocol_versions_are_enforced() {
+        let gw_port = 12020;
+        let cert = self_signed(&["localhost"]);
+        let mut tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        tls.with_versions(vec![TlsVersion::Tls13]);
+        tokio::spawn(async move {
+            start_tls_gateway(gw_port, vec![], tls).await
+        });
+        wait_for_port(gw_port).await;
+        assert!(tls_connect(gw_port, "localhost", &[&cert], vec![], &[&TLS12]).await.is_err());
+        assert!(tls_connect(gw_port, "localhost", &[&cert], vec![], &[&TLS13]).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tls12_only_listeners_refuse_tls13() {
+        let gw_port = 12021;
+        let cert = self_signed(&["localhost"]);
+        let mut tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        tls.with_versions(vec![TlsVersion::Tls12]);
+        tokio::spawn(async move {
+            start_tls_gateway(gw_port, vec![], tls).await
+        });
+        wait_for_port(gw_port).await;
+        assert!(tls_connect(gw_port, "localhost", &[&cert], vec![], &[&TLS13]).await.is_err());
+        let stream = tls_connect(gw_port, "localhost", &[&cert], vec![], &[&TLS12, &TLS13]).await.unwrap();
+        assert_eq!(Some(tokio_rustls::rustls::ProtocolVersion::TLSv1_2), stream.get_ref().1.protocol_version());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn unknown_cipher_suite_is_rejected() {
+        let cert = self_signed(&["localhost"]);
+        let mut tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        tls.with_cipher_suites(vec!["TLS13_AES_256_GCM_SHA384".to_string()]);
+        assert!(TlsListener::new(tls.clone()).is_ok());
+        tls.with_cipher_suites(vec!["TLS_NOT_A_SUITE".to_string()]);
+        assert!(matches!(TlsListener::new(tls), Err(TlsError::UnknownCipherSuite(_))));
+    }
+
+    #[cfg(not(feature = "rustls"))]
+    #[test]
+    fn unsupported_settings_are_rejected() {
+        let cert = self_signed(&["localhost"]);
+        let other_cert = self_signed(&["other.localhost"]);
+        let tls = TlsConfig::new(&cert.cert_path, &cert.key_path);
+        assert!(TlsListener::new(tls.clone()).is_ok());
+        let mut with_sni = tls.clone();
+        with_sni.add_certificate(&other_cert.cert_path, &other_cert.key_path, vec!["other.localhost".to_string()]);
+        assert!(matches!(TlsListener::new(with_sni), Err(TlsError::Unsupported(_))));
+        let mut with_suites = tls.clone();
+        with_suites.with_cipher_suites(vec!["TLS13_AES_256_GCM_SHA384".to_string()]);
+        assert!(matches!(TlsListener::new(with_suites), Err(TlsError::Unsupported(_))));
+        let mut with_client_auth = tls.clone();
+        with_client_auth.with_client_auth(crate::conf::tls::ClientAuth::Optional(cert.cert_path.clone()));
+        assert!(matches!(TlsListener::new(with_client_auth), Err(TlsError::Unsupported(_))));
+        let mut tls13_only = tls;
+        tls13_only.with_versions(vec![TlsVersion::Tls13]);
+        assert!(matches!(TlsListener::new(tls13_only), Err(TlsError::Unsupported(_))));
+    }
+
+    async fn presented_cert(port: u16, roots: &[&TestCert]) -> Vec<u8> {
+        let stream = tls_connect(port, "localhost", roots, vec![], &[&TLS12, &TLS13]).await.unwrap();
+        stream.get_ref().1.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    fn replace_files(target: &TestCert, replacement: &TestCert) {
+        std::fs::copy(&replacement.cert_path, &target.cert_path).unwrap();
+        std::fs::copy(&replacement.key_path, &target.key_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded_on_demand() {
+        let gw_port = 12030;
+        let served = self_signed(&["localhost"]);
+        let original = served.cert_der.clone();
+        let rotated = self_signed(&["localhost"]);
+        let listener = TlsListener::new(TlsConfig::new(&served.cert_path, &served.key_path)).unwrap();
+        let reloader = listener.clone();
+        tokio::spawn(async move {
+            start_tls_listener(gw_port, vec![], listener).await
+        });
+        wait_for_port(gw_port).await;
+        let roots = [&served, &rotated];
+        assert_eq!(original, presented_cert(gw_port, &roots).await);
+
+        // mismatched key pair: refused, previous certificate is still served
+        std::fs::copy(&rotated.cert_path, &served.cert_path).unwrap();
+        assert!(matches!(reloader.reload(), Err(TlsError::KeyMismatch(_, _))));
+        assert_eq!(original, presented_cert(gw_port, &roots).await);
+
+        replace_files(&served, &rotated);
+        reloader.reload().unwrap();
+        assert_eq!(rotated.cert_der, presented_cert(gw_port, &roots).await);
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded_when_files_change() {
+        let gw_port = 12040;
+        let served = self_signed(&["localhost"]);
+        let original = served.cert_der.clone();
+        let rotated = self_signed(&["localhost"]);
+        let mut tls = TlsConfig::new(&served.cert_path, &served.key_path);
+        tls.watch_files(Duration::from_millis(20));
+        tokio::spawn(async move {
+            start_tls_gateway(gw_port, vec![], tls).await
+        });
+        wait_for_port(gw_port).await;
+        let roots = [&served, &rotated];
+        assert_eq!(original, presented_cert(gw_port, &roots).await);
+        tokio::time::sleep(Duration::from_millis(20)).await; // make sure modification times differ
+        replace_files(&served, &rotated);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(rotated.cert_der, presented_cert(gw_port, &roots).await);
+    }
+
+}
@@ -0,0 +1,140 @@
+use hyper::Uri;
+use hyper::client::connect::{Connection, Connected};
+use hyper::service::Service;
+use futures::task::{Context, Poll};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+
+type PinnedConnectFuture = Pin<Box<dyn Future<Output = Result<UnixConnection, std::io::Error>> + Send>>;
+
+/// Connects to an upstream listening on a Unix domain socket, whatever the request URI
+#[derive(Debug, Clone)]
+pub struct UnixConnector {
+    path: PathBuf,
+}
+
+impl UnixConnector {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        UnixConnector { path: path.into() }
+    }
+}
+
+impl Service<Uri> for UnixConnector {
+    type Response = UnixConnection;
+    type Error = std::io::Error;
+    type Future = PinnedConnectFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _dst: Uri) -> Self::Future {
+        let path = self.path.clone();
+        Box::pin(async move { Ok(UnixConnection(UnixStream::connect(path).await?)) })
+    }
+}
+
+pub struct UnixConnection(UnixStream);
+
+impl Connection for UnixConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for UnixConnection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UnixConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::gateway::{start_local_gateway, start_unix_gateway};
+    use crate::tests::{temp_dir, test_server, wait_for_gateway, wait_for_port, unwrap_body_as_str};
+    use hyper::{Body, Client, Request, Response, Uri};
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Replies with the request path
+    async fn unix_server(path: PathBuf) {
+        let listener = UnixListener::bind(path).unwrap();
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let service = service_fn(|req: Request<Body>| async move {
+                    Ok::<_, Infallible>(Response::new(Body::from(format!("unix {}", req.uri().path()))))
+                });
+                let _ = hyper::server::conn::Http::new().serve_connection(stream, service).await;
+            });
+        }
+    }
+
+    async fn wait_for_socket(path: &Path) {
+        let mut attempts = 0;
+        while attempts < 50 && UnixStream::connect(path).await.is_err() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            attempts += 1;
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_to_unix_socket() {
+        let gw_port = 12900;
+        let socket = temp_dir().join("upstream.sock");
+        let upstream = format!("unix:{}", socket.display());
+        tokio::spawn(unix_server(socket.clone()));
+        tokio::spawn(async move {
+            start_local_gateway(gw_port, vec![Api::unix(&upstream, "/sidecar".to_string()).unwrap()]).await
+        });
+        wait_for_socket(&socket).await;
+        wait_for_gateway(gw_port).await;
+        let url = Uri::from_str(&format!("http://127.0.0.1:{}/sidecar/status", gw_port)).unwrap();
+        let resp = Client::new().get(url).await.unwrap();
+        assert_eq!("unix /status", unwrap_body_as_str(resp).await);
+    }
+
+    #[tokio::test]
+    async fn listen_on_unix_socket() {
+        let backend_port = 12901;
+        let socket = temp_dir().join("gateway.sock");
+        drop(std::os::unix::net::UnixListener::bind(&socket).unwrap()); // stale socket, left by a previous run
+        tokio::spawn(async move { test_server("over unix", backend_port).await });
+        let gateway_socket = socket.clone();
+        tokio::spawn(async move {
+            start_unix_gateway(&gateway_socket, vec![Api::http("127.0.0.1", backend_port, "/api".to_string()).unwrap()]).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_socket(&socket).await;
+
+        let stream = UnixStream::connect(&socket).await.unwrap();
+        let (mut sender, connection) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let resp = sender.send_request(Request::get("/api").header("Host", "localhost").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!("over unix", unwrap_body_as_str(resp).await);
+        let resp = sender.send_request(Request::get("/health").body(Body::empty()).unwrap()).await.unwrap();
+        assert!(resp.status().is_success());
+    }
+
+}
@@ -0,0 +1,315 @@
+use crate::context::WebSocketStats;
+use crate::tcp::{relay, RelayHook};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use log::debug;
+use std::cmp::min;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// `Connection: Upgrade` + `Upgrade: websocket`
+pub fn is_upgrade(req: &Request<Body>) -> bool {
+    let has_token = |header, token: &str| req.headers().get_all(header).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+    has_token(CONNECTION, "upgrade") && has_token(UPGRADE, "websocket")
+}
+
+/// Client side of a WebSocket upgrade, until the upstream accepts it
+/// Stats are marked as closed when dropped: once spliced connections are closed, or if the upgrade never happens
+pub(crate) struct PendingUpgrade {
+    client: Option<OnUpgrade>,
+    stats: Arc<WebSocketStats>,
+}
+
+impl PendingUpgrade {
+
+    /// Takes the client connection of an upgrade request, and inserts the stats into its extensions
+    pub(crate) fn on(req: &mut Request<Body>) -> Option<Self> {
+        if !is_upgrade(req) {
+            return None
+        }
+        let stats = Arc::new(WebSocketStats::default());
+        req.extensions_mut().insert(stats.clone());
+        Some(PendingUpgrade { client: Some(hyper::upgrade::on(req)), stats })
+    }
+
+    /// If the upstream switched protocols, splices both connections once the response is sent back to the client
+    /// `tag` identifies the request in logs, see `CorrelationId::log_tag`
+    pub(crate) fn accept(mut self, resp: &mut Response<Body>, idle_timeout: Duration, tag: String) {
+        if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return
+        }
+        resp.extensions_mut().insert(self.stats.clone());
+        let upstream = hyper::upgrade::on(resp);
+        if let Some(client) = self.client.take() {
+            tokio::spawn(async move {
+                splice(client, upstream, &self.stats, idle_timeout, &tag).await;
+                drop(self);
+            });
+        }
+    }
+}
+
+impl Drop for PendingUpgrade {
+    fn drop(&mut self) {
+        self.stats.close();
+    }
+}
+
+/// Relays bytes between the client and the upstream once both connections have been upgraded
+/// Each side is closed when the other one is, and both are after `idle_timeout` without traffic
+async fn splice(client: OnUpgrade, upstream: OnUpgrade, stats: &WebSocketStats, idle_timeout: Duration, tag: &str) {
+    match futures::future::try_join(client, upstream).await {
+        Err(e) => debug!("WebSocket upgrade failed{}: {}", tag, e),
+        Ok((client, upstream)) => {
+            let mut counter = MessageCounter { stats, to_upstream: FrameCounter::default(), to_client: FrameCounter::default() };
+            if let Err(e) = relay(client, upstream, idle_timeout, Some(&mut counter)).await {
+                debug!("WebSocket connection closed{}: {}", tag, e);
+            }
+        },
+    }
+}
+
+/// Counts the bytes and messages relayed into the stats
+struct MessageCounter<'a> {
+    stats: &'a WebSocketStats,
+    to_upstream: FrameCounter,
+    to_client: FrameCounter,
+}
+
+impl RelayHook for MessageCounter<'_> {
+    fn to_upstream(&mut self, data: &[u8]) {
+        self.stats.to_upstream(data.len(), self.to_upstream.feed(data));
+    }
+
+    fn to_client(&mut self, data: &[u8]) {
+        self.stats.to_client(data.len(), self.to_client.feed(data));
+    }
+}
+
+/// Follows WebSocket frames (RFC 6455 section 5.2) through a byte stream, to count data messages
+#[derive(Debug, Default)]
+struct FrameCounter {
+    header: Vec<u8>,
+    payload_left: u64,
+}
+
+impl FrameCounter {
+
+    /// Returns the number of messages completed in `data`
+    fn feed(&mut self, mut data: &[u8]) -> u64 {
+        let mut messages = 0;
+        while !data.is_empty() {
+            if self.payload_left > 0 {
+                let skipped = min(self.payload_left, data.len() as u64);
+                self.payload_left -= skipped;
+                data = &data[skipped as usize..];
+                continue
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            if header_len(&self.header) == Some(self.header.len()) {
+                let fin = self.header[0] & 0x80 != 0;
+                let opcode = self.header[0] & 0x0F;
+                if fin && opcode < 0x8 { // continuation, text or binary
+                    messages += 1;
+                }
+                self.payload_left = payload_len(&self.header);
+                self.header.clear();
+            }
+        }
+        messages
+    }
+}
+
+fn header_len(header: &[u8]) -> Option<usize> {
+    if header.len() < 2 {
+        return None
+    }
+    let extended_len = match header[1] & 0x7F {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_len = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    Some(2 + extended_len + mask_len)
+}
+
+fn payload_len(header: &[u8]) -> u64 {
+    match header[1] & 0x7F {
+        126 => u16::from_be_bytes(header[2..4].try_into().unwrap()) as u64,
+        127 => u64::from_be_bytes(header[2..10].try_into().unwrap()),
+        len => len as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conf::api::Api;
+    use crate::context::WebSocketStats;
+    use crate::conf::listener::ListenerConfig;
+    use crate::gateway::{start_local_gateway, start_gateway};
+    use crate::handlers::{GlobalHandler, HandlerResponse};
+    use crate::handlers::HandlerResponse::Continue;
+    use crate::tests::{wait_for_gateway, wait_for_port};
+    use crate::websocket::FrameCounter;
+    use hyper::{Body, Request, Response, Server, StatusCode};
+    use hyper::header::{CONNECTION, UPGRADE};
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&[1, 2, 3, 4]); // masking key, payload is left as is
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn counts_messages_across_reads() {
+        let mut stream = frame(true, 0x1, b"hello");
+        stream.extend(frame(false, 0x1, b"wor"));
+        stream.extend(frame(true, 0x9, b"ping")); // control frames can be interleaved with fragments
+        stream.extend(frame(true, 0x0, b"ld"));
+        stream.extend(frame(true, 0x2, &[0u8; 70_000]));
+        let mut counter = FrameCounter::default();
+        assert_eq!(3, stream.chunks(3).map(|chunk| counter.feed(chunk)).sum::<u64>());
+        let mut counter = FrameCounter::default();
+        assert_eq!(3, counter.feed(&stream));
+    }
+
+    async fn echo_websocket_server(port: u16) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let make_svc = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|mut req: Request<Body>| async move {
+                let on_upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    if let Ok(upgraded) = on_upgrade.await {
+                        let (mut read, mut write) = tokio::io::split(upgraded);
+                        let _ = tokio::io::copy(&mut read, &mut write).await;
+                    }
+                });
+                Ok::<_, Infallible>(Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header(CONNECTION, "upgrade")
+                    .header(UPGRADE, "websocket")
+                    .body(Body::empty())
+                    .unwrap())
+            }))
+        });
+        Server::bind(&addr).serve(make_svc).await.unwrap();
+    }
+
+    /// Sends the handshake, returns the connection and the response head
+    async fn open_websocket(port: u16, path: &str) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let handshake = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            path
+        );
+        stream.write_all(handshake.as_bytes()).await.unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break
+            }
+            head.push(byte[0]);
+        }
+        (stream, String::from_utf8(head).unwrap())
+    }
+
+    #[derive(Debug, Default)]
+    struct StatsCollector {
+        stats: Mutex<Vec<Arc<WebSocketStats>>>,
+    }
+
+    impl GlobalHandler for Arc<StatsCollector> {
+        fn handle_req(&self, req: &mut Request<Body>) -> HandlerResponse {
+            if let Some(stats) = req.extensions().get::<Arc<WebSocketStats>>() {
+                self.stats.lock().unwrap().push(stats.clone());
+            }
+            Continue
+        }
+
+        fn handle_res(&self, _res: &mut Response<Body>) -> HandlerResponse {
+            Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_is_spliced() {
+        let gw_port = 12400;
+        let backend_port = 12401;
+        let collector = Arc::new(StatsCollector::default());
+        tokio::spawn(async move { echo_websocket_server(backend_port).await });
+        let handler = collector.clone();
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, "/ws".to_string()).unwrap();
+            api.add_global_handler(Box::new(handler));
+            start_local_gateway(gw_port, vec![api]).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        let (mut stream, head) = open_websocket(gw_port, "/ws/chat").await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        let mut sent = frame(false, 0x1, b"hel");
+        sent.extend(frame(true, 0x0, b"lo"));
+        sent.extend(frame(true, 0x2, &[42u8; 1000]));
+        stream.write_all(&sent).await.unwrap();
+        let mut echoed = vec![0u8; sent.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(sent, echoed);
+        drop(stream);
+
+        let stats = collector.stats.lock().unwrap()[0].clone();
+        tokio::time::timeout(Duration::from_secs(5), stats.closed()).await.unwrap();
+        assert_eq!(sent.len() as u64, stats.bytes_to_upstream.load(Ordering::Relaxed));
+        assert_eq!(sent.len() as u64, stats.bytes_to_client.load(Ordering::Relaxed));
+        assert_eq!(2, stats.messages_to_upstream.load(Ordering::Relaxed));
+        assert_eq!(2, stats.messages_to_client.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn idle_websocket_is_closed() {
+        let gw_port = 12410;
+        let backend_port = 12411;
+        tokio::spawn(async move { echo_websocket_server(backend_port).await });
+        tokio::spawn(async move {
+            let mut api = Api::http("127.0.0.1", backend_port, "/ws".to_string()).unwrap();
+            api.with_websocket_idle_timeout(Duration::from_millis(200));
+            start_gateway(ListenerConfig::local(gw_port), vec![api]).await
+        });
+        wait_for_port(backend_port).await;
+        wait_for_gateway(gw_port).await;
+
+        let (mut stream, head) = open_websocket(gw_port, "/ws").await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        let mut buf = [0u8; 16];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert_eq!(0, read.unwrap_or(0));
+    }
+
+}